@@ -36,6 +36,12 @@ BuildRequires: systemd-rpm-macros
 %{_libexecdir}/bootupd
 %{_prefix}/lib/bootupd/grub2-static/
 %{_unitdir}/bootloader-update.service
+%{_unitdir}/bootloader-update-on-deploy.path
+%{_unitdir}/bootloader-update-on-deploy.service
+%{_unitdir}/bootloader-mark-boot-successful.service
+%{_unitdir}/bootloader-status.service
+%{_unitdir}/bootloader-scrub.service
+%{_unitdir}/bootloader-scrub.timer
 
 %prep
 %autosetup -n %{crate}-%{version} -p1 -Sgit
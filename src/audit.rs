@@ -0,0 +1,296 @@
+//! An append-only record of the files bootupd creates, modifies or removes,
+//! for environments which must be able to show how the bootloader changed.
+//!
+//! With `audit-log` set, each change is appended to that file as one JSON
+//! object per line, with the SHA-512 of the file before and after.  With
+//! `audit-hmac-key` set too, every record also carries an HMAC-SHA512 of
+//! the previous record's HMAC and its own content, so removing, reordering
+//! or editing records can be detected by `bootupctl internals
+//! verify-audit-log`.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use fn_error_context::context;
+use fs2::FileExt;
+use openat_ext::OpenatDirExt;
+use openssl::hash::{Hasher, MessageDigest};
+use serde::{Deserialize, Serialize};
+
+use crate::sha512string::SHA512String;
+use crate::util::{hmac_sha512, read_hmac_key};
+
+/// How much of the end of the log to read when looking for the last
+/// record; far more than any single record.
+const TAIL_SIZE: u64 = 64 * 1024;
+
+/// What happened to a file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AuditOp {
+    Create,
+    Modify,
+    Remove,
+}
+
+/// A change to a single file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct AuditRecord {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) op: AuditOp,
+    /// Absolute path of the file
+    pub(crate) path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) before: Option<SHA512String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) after: Option<SHA512String>,
+    /// Keyed with `audit-hmac-key`, over the HMAC of the previous record
+    /// and this record without this field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hmac_sha512: Option<String>,
+}
+
+impl AuditRecord {
+    /// The HMAC of this record, chained to the HMAC `prev` of the previous
+    /// one.
+    fn seal(&self, key: &[u8], prev: Option<&str>) -> Result<String> {
+        let unsealed = Self {
+            hmac_sha512: None,
+            ..self.clone()
+        };
+        let mut buf = prev.unwrap_or_default().as_bytes().to_vec();
+        buf.push(b'\n');
+        serde_json::to_writer(&mut buf, &unsealed)?;
+        hmac_sha512(key, &buf)
+    }
+}
+
+/// The SHA-512 of `path` in `dir`, if it exists.
+fn digest(dir: &openat::Dir, path: &str) -> Result<Option<SHA512String>> {
+    let Some(mut f) = dir.open_file_optional(path)? else {
+        return Ok(None);
+    };
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    std::io::copy(&mut f, &mut hasher).with_context(|| format!("Reading {path}"))?;
+    Ok(Some(SHA512String::from_hasher(&mut hasher)))
+}
+
+/// Collects the changes made to files in a directory: the content of each
+/// file is noted before changing it, and the records are written once all
+/// changes are done.
+pub(crate) struct Recorder {
+    root: PathBuf,
+    before: BTreeMap<String, Option<SHA512String>>,
+}
+
+impl Recorder {
+    /// Start recording changes to files in `dir`, if an audit log is
+    /// configured.
+    pub(crate) fn new(dir: &openat::Dir) -> Result<Option<Self>> {
+        if crate::config::get().audit_log.is_none() {
+            return Ok(None);
+        }
+        let root = std::fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd()))
+            .context("Finding path of directory")?;
+        Ok(Some(Self::with_root(root)))
+    }
+
+    fn with_root(root: PathBuf) -> Self {
+        Self {
+            root,
+            before: BTreeMap::new(),
+        }
+    }
+
+    /// Note the content of `path`, relative to `dir`, before changing it.
+    pub(crate) fn before(&mut self, dir: &openat::Dir, path: &str) -> Result<()> {
+        if !self.before.contains_key(path) {
+            self.before.insert(path.to_string(), digest(dir, path)?);
+        }
+        Ok(())
+    }
+
+    /// The changes to the noted files, as found in `dir` now.
+    fn records(self, dir: &openat::Dir) -> Result<Vec<AuditRecord>> {
+        let timestamp = Utc::now();
+        let mut r = Vec::new();
+        for (path, before) in self.before {
+            let after = digest(dir, &path)?;
+            let op = match (&before, &after) {
+                (None, None) => continue,
+                (Some(b), Some(a)) if a == b => continue,
+                (None, Some(_)) => AuditOp::Create,
+                (Some(_), Some(_)) => AuditOp::Modify,
+                (Some(_), None) => AuditOp::Remove,
+            };
+            r.push(AuditRecord {
+                timestamp,
+                op,
+                path: self.root.join(&path).display().to_string(),
+                before,
+                after,
+                hmac_sha512: None,
+            });
+        }
+        Ok(r)
+    }
+
+    /// Append the changes to the noted files in `dir` to the audit log.
+    #[context("Writing audit log")]
+    pub(crate) fn commit(self, dir: &openat::Dir) -> Result<()> {
+        let records = self.records(dir)?;
+        let config = crate::config::get();
+        let Some(log) = config.audit_log.as_deref() else {
+            return Ok(());
+        };
+        let key = config.audit_hmac_key.as_deref().map(load_key).transpose()?;
+        append(Path::new(log), records, key.as_deref())
+    }
+}
+
+/// Load the key for sealing audit records from `path`.
+pub(crate) fn load_key(path: &str) -> Result<Vec<u8>> {
+    let f = std::fs::File::open(path).with_context(|| format!("Opening {path}"))?;
+    read_hmac_key(f, path)
+}
+
+/// The HMAC of the last record in the log `f`, if any.
+fn last_hmac(f: &mut std::fs::File) -> Result<Option<String>> {
+    let len = f.metadata()?.len();
+    f.seek(SeekFrom::Start(len.saturating_sub(TAIL_SIZE)))?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    let Some(last) = buf.split(|&c| c == b'\n').rfind(|l| !l.is_empty()) else {
+        return Ok(None);
+    };
+    let last: AuditRecord =
+        serde_json::from_slice(last).context("Parsing the last record of the audit log")?;
+    Ok(last.hmac_sha512)
+}
+
+/// Append `records` to the log at `path`, sealing them with `key`.
+fn append(path: &Path, mut records: Vec<AuditRecord>, key: Option<&[u8]>) -> Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut f = std::fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Opening {}", path.display()))?;
+    // Updates to several ESPs may run concurrently
+    f.lock_exclusive()?;
+    let mut prev = match key {
+        Some(_) => last_hmac(&mut f)?,
+        None => None,
+    };
+    let mut buf = Vec::new();
+    for r in records.iter_mut() {
+        if let Some(key) = key {
+            let hmac = r.seal(key, prev.as_deref())?;
+            r.hmac_sha512 = Some(hmac.clone());
+            prev = Some(hmac);
+        }
+        serde_json::to_writer(&mut buf, r)?;
+        buf.push(b'\n');
+    }
+    f.write_all(&buf)?;
+    f.sync_data()?;
+    Ok(())
+}
+
+/// Check the chain of HMACs in the audit log at `path` with `key`, or
+/// only that it can be parsed without one; returns the number of records.
+#[context("Verifying audit log {}", path.display())]
+pub(crate) fn verify(path: &Path, key: Option<&[u8]>) -> Result<usize> {
+    let f = std::fs::File::open(path)?;
+    let mut prev: Option<String> = None;
+    let mut n = 0;
+    for (i, line) in std::io::BufRead::lines(std::io::BufReader::new(f)).enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let lineno = i + 1;
+        let r: AuditRecord =
+            serde_json::from_str(&line).with_context(|| format!("Parsing line {lineno}"))?;
+        if let Some(key) = key {
+            let Some(found) = r.hmac_sha512.as_deref() else {
+                bail!("Line {lineno} is not sealed");
+            };
+            let expected = r.seal(key, prev.as_deref())?;
+            if !openssl::memcmp::eq(found.as_bytes(), expected.as_bytes()) {
+                bail!("Line {lineno}: HMAC mismatch");
+            }
+        }
+        prev = r.hmac_sha512;
+        n += 1;
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path().join("esp");
+        std::fs::create_dir_all(root.join("EFI/fedora"))?;
+        std::fs::write(root.join("EFI/fedora/grubx64.efi"), "grub")?;
+        std::fs::write(root.join("EFI/fedora/shimx64.efi"), "shim")?;
+        std::fs::write(root.join("EFI/fedora/old.efi"), "old")?;
+        let dir = openat::Dir::open(&root)?;
+
+        let mut rec = Recorder::with_root(PathBuf::from("/efi"));
+        for p in [
+            "EFI/fedora/grubx64.efi",
+            "EFI/fedora/shimx64.efi",
+            "EFI/fedora/old.efi",
+            "EFI/fedora/new.efi",
+            "EFI/fedora/unrelated.efi",
+        ] {
+            rec.before(&dir, p)?;
+        }
+        std::fs::write(root.join("EFI/fedora/grubx64.efi"), "grub2")?;
+        std::fs::remove_file(root.join("EFI/fedora/old.efi"))?;
+        std::fs::write(root.join("EFI/fedora/new.efi"), "new")?;
+        let records = rec.records(&dir)?;
+        let summary = records
+            .iter()
+            .map(|r| (r.op, r.path.as_str(), r.before.is_some(), r.after.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (AuditOp::Modify, "/efi/EFI/fedora/grubx64.efi", true, true),
+                (AuditOp::Create, "/efi/EFI/fedora/new.efi", false, true),
+                (AuditOp::Remove, "/efi/EFI/fedora/old.efi", true, false),
+            ]
+        );
+
+        let log = td.path().join("audit.jsonl");
+        let key = b"secret".as_slice();
+        append(&log, records[..1].to_vec(), Some(key))?;
+        append(&log, records[1..].to_vec(), Some(key))?;
+        assert_eq!(verify(&log, Some(key))?, 3);
+        assert!(verify(&log, Some(b"other")).is_err());
+
+        // Dropping a record breaks the chain
+        let contents = std::fs::read_to_string(&log)?;
+        let lines = contents.lines().collect::<Vec<_>>();
+        std::fs::write(&log, format!("{}\n{}\n", lines[0], lines[2]))?;
+        assert!(verify(&log, Some(key)).is_err());
+        assert_eq!(verify(&log, None)?, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//! Authenticode digests of PE images, which is what UEFI firmware and
+//! shim measure into PCR 4 when loading an image.
+//!
+//! See "Windows Authenticode Portable Executable Signature Format".
+
+use anyhow::{bail, Context, Result};
+use openssl::sha::Sha256;
+
+fn read_u16(data: &[u8], off: usize) -> Result<u16> {
+    let b = data
+        .get(off..off + 2)
+        .with_context(|| format!("Truncated PE image at {off:#x}"))?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32> {
+    let b = data
+        .get(off..off + 4)
+        .with_context(|| format!("Truncated PE image at {off:#x}"))?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn range(data: &[u8], start: usize, end: usize) -> Result<&[u8]> {
+    data.get(start..end)
+        .with_context(|| format!("Invalid PE image range {start:#x}..{end:#x}"))
+}
+
+/// Compute the SHA-256 Authenticode digest of the PE image `data`.
+pub(crate) fn sha256(data: &[u8]) -> Result<[u8; 32]> {
+    const PE32_MAGIC: u16 = 0x10b;
+    const PE32PLUS_MAGIC: u16 = 0x20b;
+    const CERT_TABLE_INDEX: usize = 4;
+
+    if data.get(..2) != Some(b"MZ") {
+        bail!("Not a PE image");
+    }
+    let pe = read_u32(data, 0x3c)? as usize;
+    if data.get(pe..pe + 4) != Some(b"PE\0\0") {
+        bail!("Missing PE signature");
+    }
+    let coff = pe + 4;
+    let nsections = read_u16(data, coff + 2)? as usize;
+    let optsize = read_u16(data, coff + 16)? as usize;
+    let opt = coff + 20;
+    let (ndirs_off, dirs_off) = match read_u16(data, opt)? {
+        PE32_MAGIC => (opt + 92, opt + 96),
+        PE32PLUS_MAGIC => (opt + 108, opt + 112),
+        o => bail!("Unknown optional header magic {o:#x}"),
+    };
+    let checksum_off = opt + 64;
+    let headers_size = read_u32(data, opt + 60)? as usize;
+    let ndirs = read_u32(data, ndirs_off)? as usize;
+
+    let mut h = Sha256::new();
+    h.update(range(data, 0, checksum_off)?);
+    // The certificate table, if any, is not covered by the digest.
+    let mut cert_size = 0;
+    if ndirs > CERT_TABLE_INDEX {
+        let cert_dir = dirs_off + CERT_TABLE_INDEX * 8;
+        cert_size = read_u32(data, cert_dir + 4)? as usize;
+        h.update(range(data, checksum_off + 4, cert_dir)?);
+        h.update(range(data, cert_dir + 8, headers_size)?);
+    } else {
+        h.update(range(data, checksum_off + 4, headers_size)?);
+    }
+
+    let mut sections = Vec::with_capacity(nsections);
+    let table = opt + optsize;
+    for i in 0..nsections {
+        let s = table + i * 40;
+        let size = read_u32(data, s + 16)? as usize;
+        let ptr = read_u32(data, s + 20)? as usize;
+        if size > 0 {
+            sections.push((ptr, size));
+        }
+    }
+    sections.sort();
+    let mut hashed = headers_size;
+    for (ptr, size) in sections {
+        h.update(range(data, ptr, ptr + size)?);
+        hashed += size;
+    }
+    // Trailing data other than the certificate table
+    let end = data
+        .len()
+        .checked_sub(cert_size)
+        .context("Invalid certificate table size")?;
+    if end > hashed {
+        h.update(range(data, hashed, end)?);
+    }
+    Ok(h.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal PE32+ image with one section and a certificate table.
+    fn synthetic_pe(section: &[u8], cert: &[u8]) -> Vec<u8> {
+        let mut d = vec![0u8; 0x200];
+        d[..2].copy_from_slice(b"MZ");
+        d[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        d[0x40..0x44].copy_from_slice(b"PE\0\0");
+        let coff = 0x44;
+        d[coff + 2..coff + 4].copy_from_slice(&1u16.to_le_bytes());
+        d[coff + 16..coff + 18].copy_from_slice(&240u16.to_le_bytes());
+        let opt = coff + 20;
+        d[opt..opt + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        d[opt + 60..opt + 64].copy_from_slice(&0x200u32.to_le_bytes());
+        d[opt + 108..opt + 112].copy_from_slice(&16u32.to_le_bytes());
+        let s = opt + 240;
+        d[s + 16..s + 20].copy_from_slice(&(section.len() as u32).to_le_bytes());
+        d[s + 20..s + 24].copy_from_slice(&0x200u32.to_le_bytes());
+        d.extend_from_slice(section);
+        let cert_dir = opt + 112 + 4 * 8;
+        let cert_offset = d.len() as u32;
+        d[cert_dir..cert_dir + 4].copy_from_slice(&cert_offset.to_le_bytes());
+        d[cert_dir + 4..cert_dir + 8].copy_from_slice(&(cert.len() as u32).to_le_bytes());
+        d.extend_from_slice(cert);
+        d
+    }
+
+    #[test]
+    fn test_sha256() -> Result<()> {
+        let a = synthetic_pe(b"code v1", b"signature");
+        // Neither the signature nor the checksum affect the digest
+        let mut b = synthetic_pe(b"code v1", b"other signature");
+        b[0x44 + 20 + 64] = 0xff;
+        assert_eq!(sha256(&a)?, sha256(&b)?);
+        let c = synthetic_pe(b"code v2", b"signature");
+        assert_ne!(sha256(&a)?, sha256(&c)?);
+        assert!(sha256(b"not a PE image").is_err());
+        assert!(sha256(&a[..0x100]).is_err());
+        Ok(())
+    }
+}
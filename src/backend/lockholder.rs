@@ -0,0 +1,129 @@
+//! Find the process holding a file lock, for diagnostics.
+
+use std::fmt;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+
+use anyhow::Result;
+
+/// A process holding a lock.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LockHolder {
+    pub(crate) pid: u32,
+    /// The process name
+    pub(crate) comm: Option<String>,
+    /// The systemd unit containing the process
+    pub(crate) unit: Option<String>,
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PID {}", self.pid)?;
+        if let Some(comm) = self.comm.as_deref() {
+            write!(f, " ({comm})")?;
+        }
+        if let Some(unit) = self.unit.as_deref() {
+            write!(f, " in {unit}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the PID holding a lock on the inode `dev:ino` in the contents of
+/// `/proc/locks`, where lines look like
+/// `1: FLOCK  ADVISORY  WRITE 1234 fd:00:5678 0 EOF`; the major and minor
+/// device numbers are in hex.  Lines for waiters contain `->`.
+fn parse_proc_locks(locks: &str, major: u32, minor: u32, ino: u64) -> Option<u32> {
+    let target = format!("{major:02x}:{minor:02x}:{ino}");
+    locks.lines().filter(|l| !l.contains("->")).find_map(|l| {
+        let fields: Vec<_> = l.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, _, _, _, pid, inode, ..] if *inode == target => pid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Find the systemd unit in the contents of `/proc/<pid>/cgroup`.
+fn parse_cgroup_unit(cgroup: &str) -> Option<String> {
+    // Prefer the unified hierarchy, i.e. `0::/system.slice/foo.service`
+    let path = cgroup
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .or_else(|| {
+            cgroup
+                .lines()
+                .find_map(|l| l.split_once(":name=systemd:").map(|(_, p)| p))
+        })?;
+    path.rsplit('/')
+        .find(|c| c.ends_with(".service") || c.ends_with(".scope"))
+        .map(ToOwned::to_owned)
+}
+
+/// Find the process holding a lock on `f`, if any.
+pub(crate) fn find(f: &File) -> Result<Option<LockHolder>> {
+    let meta = f.metadata()?;
+    let dev = meta.dev();
+    let locks = std::fs::read_to_string("/proc/locks")?;
+    let Some(pid) = parse_proc_locks(
+        &locks,
+        rustix::fs::major(dev),
+        rustix::fs::minor(dev),
+        meta.ino(),
+    ) else {
+        return Ok(None);
+    };
+    // The process may exit at any time, so these are best-effort.
+    let comm = std::fs::read_to_string(format!("/proc/{pid}/comm"))
+        .ok()
+        .map(|s| s.trim_end().to_owned());
+    let unit = std::fs::read_to_string(format!("/proc/{pid}/cgroup"))
+        .ok()
+        .and_then(|s| parse_cgroup_unit(&s));
+    Ok(Some(LockHolder { pid, comm, unit }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_locks() {
+        let locks = "\
+1: POSIX  ADVISORY  WRITE 812 00:19:1041 0 EOF
+2: FLOCK  ADVISORY  WRITE 4242 fd:01:133 0 EOF
+2: -> FLOCK  ADVISORY  WRITE 4343 fd:01:133 0 EOF
+";
+        assert_eq!(parse_proc_locks(locks, 0xfd, 1, 133), Some(4242));
+        assert_eq!(parse_proc_locks(locks, 0, 0x19, 1041), Some(812));
+        assert_eq!(parse_proc_locks(locks, 0xfd, 1, 134), None);
+    }
+
+    #[test]
+    fn test_parse_cgroup_unit() {
+        assert_eq!(
+            parse_cgroup_unit("0::/system.slice/bootupd.service\n").as_deref(),
+            Some("bootupd.service")
+        );
+        assert_eq!(
+            parse_cgroup_unit("0::/user.slice/user-0.slice/session-3.scope\n").as_deref(),
+            Some("session-3.scope")
+        );
+        assert_eq!(
+            parse_cgroup_unit("12:pids:/\n1:name=systemd:/system.slice/rpm-ostreed.service\n")
+                .as_deref(),
+            Some("rpm-ostreed.service")
+        );
+        assert_eq!(parse_cgroup_unit("0::/\n"), None);
+    }
+
+    #[test]
+    fn test_display() {
+        let h = LockHolder {
+            pid: 42,
+            comm: Some("bootupctl".into()),
+            unit: Some("bootupd.service".into()),
+        };
+        assert_eq!(h.to_string(), "PID 42 (bootupctl) in bootupd.service");
+    }
+}
@@ -1,3 +1,4 @@
 //! Internal logic for bootloader and system state manipulation.
 
+mod lockholder;
 mod statefile;
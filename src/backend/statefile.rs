@@ -1,13 +1,24 @@
 //! On-disk saved state.
 
+use super::lockholder;
+use crate::errors::ErrorKind;
+use crate::exitcode::Outcome;
 use crate::model::SavedState;
+use crate::sha512string::SHA512String;
+use crate::util::{hmac_sha512, read_hmac_key};
 use anyhow::{bail, Context, Result};
 use fn_error_context::context;
 use fs2::FileExt;
 use openat_ext::OpenatDirExt;
+use openssl::hash::MessageDigest;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How often to retry taking the write lock while waiting for it.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 /// Suppress SIGTERM while active
 // TODO: In theory we could record if we got SIGTERM and exit
@@ -35,15 +46,57 @@ impl SavedState {
     pub(crate) const STATEFILE_DIR: &'static str = "boot";
     /// On-disk bootloader statefile, akin to a tiny rpm/dpkg database, stored in `/boot`.
     pub(crate) const STATEFILE_NAME: &'static str = "bootupd-state.json";
+    /// Copy of the previous statefile, used if the current one is damaged.
+    pub(crate) const STATEFILE_BACKUP_NAME: &'static str = "bootupd-state.json.bak";
+    /// Optional key for authenticating the statefile (relative to sysroot);
+    /// this must only be accessible by root.
+    pub(crate) const HMAC_KEY_PATH: &'static str = "etc/bootupd/state-hmac-key";
 
     /// Try to acquire a system-wide lock to ensure non-conflicting state updates.
     ///
     /// While ordinarily the daemon runs as a systemd unit (which implicitly
     /// ensures a single instance) this is a double check against other
-    /// execution paths.
+    /// execution paths.  If another process holds the lock, this waits for
+    /// it to be released, e.g. when `bootupctl update` runs while an ostree
+    /// deployment is updating the bootloader.  Only with `lock-timeout` set,
+    /// this gives up after waiting that long and fails with
+    /// [`Outcome::LockContention`].
     pub(crate) fn acquire_write_lock(sysroot: openat::Dir) -> Result<StateLockGuard> {
         let lockfile = sysroot.write_file(Self::WRITE_LOCK_PATH, 0o644)?;
-        lockfile.lock_exclusive()?;
+        let timeout = crate::config::get().lock_timeout.map(Duration::from_secs);
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            match lockfile.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {}
+                Err(e) => return Err(e).context("Locking"),
+            }
+            let holder = match lockholder::find(&lockfile) {
+                Ok(Some(holder)) => holder.to_string(),
+                Ok(None) => "an unknown process".to_string(),
+                Err(e) => {
+                    log::debug!("Finding lock holder: {e:#}");
+                    "an unknown process".to_string()
+                }
+            };
+            let Some(timeout) = timeout else {
+                eprintln!("Waiting for lock held by {holder}");
+                lockfile.lock_exclusive().context("Locking")?;
+                break;
+            };
+            if start.elapsed() >= timeout {
+                return Err(Outcome::LockContention).with_context(|| format!("Held by {holder}"));
+            }
+            if !waiting {
+                eprintln!(
+                    "Waiting up to {}s for lock held by {holder}",
+                    timeout.as_secs()
+                );
+                waiting = true;
+            }
+            std::thread::sleep(LOCK_POLL_INTERVAL);
+        }
         let guard = StateLockGuard {
             sysroot,
             termguard: Some(SignalTerminationGuard::new()?),
@@ -69,30 +122,32 @@ impl SavedState {
         let sysroot = openat::Dir::open(root_path)
             .with_context(|| format!("opening sysroot '{}'", root_path.display()))?;
 
-        let statefile_path = Path::new(Self::STATEFILE_DIR).join(Self::STATEFILE_NAME);
-        let saved_state = if let Some(statusf) = sysroot.open_file_optional(&statefile_path)? {
-            let mut bufr = std::io::BufReader::new(statusf);
-            let mut s = String::new();
-            bufr.read_to_string(&mut s)?;
-            let state: serde_json::Result<SavedState> = serde_json::from_str(s.as_str());
-            let r = match state {
-                Ok(s) => s,
-                Err(orig_err) => {
-                    let state: serde_json::Result<crate::model_legacy::SavedState01> =
-                        serde_json::from_str(s.as_str());
-                    match state {
-                        Ok(s) => s.upconvert(),
-                        Err(_) => {
-                            return Err(orig_err.into());
-                        }
-                    }
-                }
-            };
-            Some(r)
+        let Some(statedir) = sysroot.sub_dir_optional(Self::STATEFILE_DIR)? else {
+            return Ok(None);
+        };
+        let key = load_hmac_key(&sysroot)?;
+        let err = match load_verified(&statedir, Self::STATEFILE_NAME, key.as_deref(), false) {
+            Ok(r) => return Ok(r),
+            Err(e) => e,
+        };
+        // The state may be damaged due to an interrupted write, but also
+        // e.g. written by an older bootupd which doesn't update the digest;
+        // the backup is then outdated, so it's never used implicitly.
+        let has_backup = load_verified(
+            &statedir,
+            Self::STATEFILE_BACKUP_NAME,
+            key.as_deref(),
+            false,
+        )
+        .is_ok_and(|s| s.is_some());
+        let hint = if has_backup {
+            "run `bootupctl backend recover-state` to accept it as it is, or \
+             `bootupctl backend recover-state --use-backup` to restore the \
+             state from before the last change"
         } else {
-            None
+            "run `bootupctl backend recover-state` to accept it as it is"
         };
-        Ok(saved_state)
+        Err(err.context(format!("Refusing to use the state; {hint}")))
     }
 
     /// Check whether statefile exists.
@@ -107,6 +162,212 @@ impl SavedState {
     }
 }
 
+/// Checksums of a statefile, stored alongside it.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct StateDigest {
+    sha512: SHA512String,
+    /// Keyed with [`SavedState::HMAC_KEY_PATH`], if present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hmac_sha512: Option<String>,
+}
+
+impl StateDigest {
+    fn new(buf: &[u8], key: Option<&[u8]>) -> Result<Self> {
+        let sha512 = openssl::hash::hash(MessageDigest::sha512(), buf)?;
+        let sha512 = SHA512String(format!("sha512:{}", hex::encode(sha512)));
+        let hmac_sha512 = key.map(|key| hmac_sha512(key, buf)).transpose()?;
+        Ok(Self {
+            sha512,
+            hmac_sha512,
+        })
+    }
+
+    /// Verify `buf` against this digest.  With a `key`, a digest without
+    /// an HMAC is only accepted if `allow_unauthenticated` is set.
+    fn verify(&self, buf: &[u8], key: Option<&[u8]>, allow_unauthenticated: bool) -> Result<()> {
+        let expected = Self::new(buf, key)?;
+        if self.sha512 != expected.sha512 {
+            bail!("Checksum mismatch");
+        }
+        match (self.hmac_sha512.as_deref(), expected.hmac_sha512.as_deref()) {
+            (Some(found), Some(expected)) => {
+                if !openssl::memcmp::eq(found.as_bytes(), expected.as_bytes()) {
+                    bail!("HMAC mismatch");
+                }
+            }
+            (None, Some(_)) if !allow_unauthenticated => bail!(unauthenticated()),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Load the key for authenticating the statefile, if one exists.
+#[context("Loading state HMAC key")]
+fn load_hmac_key(sysroot: &openat::Dir) -> Result<Option<Vec<u8>>> {
+    let Some(f) = sysroot.open_file_optional(SavedState::HMAC_KEY_PATH)? else {
+        return Ok(None);
+    };
+    read_hmac_key(f, SavedState::HMAC_KEY_PATH).map(Some)
+}
+
+/// The error for a state without an HMAC while the key exists; otherwise
+/// removing the HMAC (or the whole digest) would defeat it.
+fn unauthenticated() -> String {
+    format!(
+        "State is not authenticated, but {} exists; if the key was just \
+         created, run `bootupctl backend authenticate-state`",
+        SavedState::HMAC_KEY_PATH
+    )
+}
+
+fn digest_name(name: &str) -> String {
+    format!("{name}.digest")
+}
+
+fn read_optional(dir: &openat::Dir, name: &str) -> Result<Option<Vec<u8>>> {
+    let Some(f) = dir.open_file_optional(name)? else {
+        return Ok(None);
+    };
+    let mut bufr = std::io::BufReader::new(f);
+    let mut buf = Vec::new();
+    bufr.read_to_end(&mut buf)?;
+    Ok(Some(buf))
+}
+
+/// Load a statefile, verifying it against its digest if there is one;
+/// versions of bootupd before the digest was introduced do not write it.
+/// With a `key`, the digest and its HMAC are required, unless
+/// `allow_unauthenticated` is set.
+#[context("Loading {name}")]
+fn load_verified(
+    dir: &openat::Dir,
+    name: &str,
+    key: Option<&[u8]>,
+    allow_unauthenticated: bool,
+) -> Result<Option<SavedState>> {
+    let Some(buf) = read_optional(dir, name)? else {
+        return Ok(None);
+    };
+    let digest = read_optional(dir, &digest_name(name))?;
+    let verify = || -> Result<SavedState> {
+        if let Some(digest) = digest {
+            let digest: StateDigest = serde_json::from_slice(&digest).context("Parsing digest")?;
+            digest.verify(&buf, key, allow_unauthenticated)?;
+        } else if key.is_some() && !allow_unauthenticated {
+            bail!(unauthenticated());
+        } else {
+            log::debug!("No digest found for {name}");
+        }
+        let s = std::str::from_utf8(&buf)?;
+        parse_state(s)
+    };
+    Ok(Some(verify().context(ErrorKind::StateCorrupt)?))
+}
+
+/// Write a statefile along with its digest.  The digest is written
+/// first; if interrupted in between, the old statefile fails to verify
+/// until it is recovered from the backup, which has the same contents.
+fn write_with_digest(dir: &openat::Dir, name: &str, buf: &[u8], key: Option<&[u8]>) -> Result<()> {
+    let digest = StateDigest::new(buf, key)?;
+    dir.write_file_with_sync(digest_name(name), 0o644, |w| -> Result<()> {
+        serde_json::to_writer(w, &digest)?;
+        Ok(())
+    })?;
+    crate::try_fail_point!("state::write");
+    dir.write_file_with_sync(name, 0o644, |w| -> Result<()> {
+        w.write_all(buf)?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// A migration of the serialized state from one format version to the next.
+type Migration = fn(serde_json::Value) -> Result<serde_json::Value>;
+
+/// Migrations, indexed by the format version they upgrade from.
+const MIGRATIONS: &[Migration] = &[migrate_v0_to_v1, migrate_v1_to_v2];
+
+/// The original format lacks a version marker, and in its earliest
+/// incarnation used timestamps without a timezone.
+fn migrate_v0_to_v1(v: serde_json::Value) -> Result<serde_json::Value> {
+    let state = match serde_json::from_value::<SavedState>(v.clone()) {
+        Ok(s) => s,
+        Err(orig_err) => match serde_json::from_value::<crate::model_legacy::SavedState01>(v) {
+            Ok(s) => s.upconvert(),
+            Err(_) => return Err(orig_err.into()),
+        },
+    };
+    let mut v = serde_json::to_value(state)?;
+    v["format-version"] = 1.into();
+    Ok(v)
+}
+
+/// Format version 2 only adds optional fields, but a state without a
+/// version marker may be either of version 0 or 1.
+fn migrate_v1_to_v2(mut v: serde_json::Value) -> Result<serde_json::Value> {
+    let Some(o) = v.as_object_mut() else {
+        bail!("State is not an object");
+    };
+    o.insert("format-version".into(), 2.into());
+    Ok(v)
+}
+
+/// Serialize `state` in the oldest format version which can hold it.
+fn serialize_state(state: &SavedState) -> Result<Vec<u8>> {
+    let mut v = serde_json::to_value(state)?;
+    let Some(o) = v.as_object_mut() else {
+        bail!("State is not an object");
+    };
+    match state.disk_format_version() {
+        1 => o.remove("format-version"),
+        version => o.insert("format-version".into(), version.into()),
+    };
+    Ok(serde_json::to_vec(&v)?)
+}
+
+/// Parse serialized state, migrating it from older format versions.
+fn parse_state(s: &str) -> Result<SavedState> {
+    let mut v: serde_json::Value = serde_json::from_str(s)?;
+    let version = match v.get("format-version") {
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid format-version: {version}"))?,
+        None => 0,
+    };
+    if version > SavedState::FORMAT_VERSION {
+        bail!(
+            "State has format version {version}, but this bootupd only supports up to {}",
+            SavedState::FORMAT_VERSION
+        );
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::debug!("Migrating state from format version {from}");
+        v = migration(v).with_context(|| format!("Migrating from format version {from}"))?;
+    }
+    let state: SavedState = serde_json::from_value(v)?;
+    assert_eq!(state.format_version, SavedState::FORMAT_VERSION);
+    Ok(state)
+}
+
+/// Add the HMAC keyed with `key` to the statefile `name`, if it verifies
+/// otherwise; returns `false` if it already had a valid one.
+#[context("Authenticating {name}")]
+fn authenticate(dir: &openat::Dir, name: &str, key: &[u8]) -> Result<bool> {
+    if load_verified(dir, name, Some(key), false).is_ok_and(|s| s.is_some()) {
+        return Ok(false);
+    }
+    if load_verified(dir, name, Some(key), true)?.is_none() {
+        bail!("No state found");
+    }
+    // Keep the contents as they are, only the digest changes
+    let buf = read_optional(dir, name)?.ok_or_else(|| anyhow::anyhow!("State vanished"))?;
+    write_with_digest(dir, name, &buf, Some(key))?;
+    Ok(true)
+}
+
 /// Write-lock guard for statefile, protecting against concurrent state updates.
 #[derive(Debug)]
 pub(crate) struct StateLockGuard {
@@ -118,13 +379,242 @@ pub(crate) struct StateLockGuard {
 }
 
 impl StateLockGuard {
-    /// Atomically replace the on-disk state with a new version.
+    /// Atomically replace the on-disk state with a new version, keeping
+    /// the previous one as a backup.
     pub(crate) fn update_state(&mut self, state: &SavedState) -> Result<()> {
         let subdir = self.sysroot.sub_dir(SavedState::STATEFILE_DIR)?;
-        subdir.write_file_with_sync(SavedState::STATEFILE_NAME, 0o644, |w| -> Result<()> {
-            serde_json::to_writer(w, state)?;
-            Ok(())
-        })?;
+        let key = load_hmac_key(&self.sysroot)?;
+        // Only back up a state which verifies, so a damaged state never
+        // replaces a good backup.
+        match load_verified(&subdir, SavedState::STATEFILE_NAME, key.as_deref(), false) {
+            Ok(Some(_)) => {
+                let prev = read_optional(&subdir, SavedState::STATEFILE_NAME)?
+                    .ok_or_else(|| anyhow::anyhow!("State vanished"))?;
+                write_with_digest(
+                    &subdir,
+                    SavedState::STATEFILE_BACKUP_NAME,
+                    &prev,
+                    key.as_deref(),
+                )
+                .context("Writing state backup")?;
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Not backing up state: {e:#}"),
+        }
+        let buf = serialize_state(state)?;
+        write_with_digest(&subdir, SavedState::STATEFILE_NAME, &buf, key.as_deref())
+    }
+
+    /// Add the HMAC to a state written before the key was created.  This
+    /// is the only way a state without one is accepted while the key
+    /// exists; returns `false` if the state was already authenticated.
+    pub(crate) fn authenticate_state(&mut self) -> Result<bool> {
+        let Some(key) = load_hmac_key(&self.sysroot)? else {
+            bail!("No key found at {}", SavedState::HMAC_KEY_PATH);
+        };
+        let subdir = self.sysroot.sub_dir(SavedState::STATEFILE_DIR)?;
+        authenticate(&subdir, SavedState::STATEFILE_NAME, &key)
+    }
+
+    /// Make a state which fails to verify usable again: with `use_backup`,
+    /// by restoring the backup (if it verifies), otherwise by accepting the
+    /// current state (if it parses) and writing its digest anew.
+    #[context("Recovering state")]
+    pub(crate) fn recover_state(&mut self, use_backup: bool) -> Result<()> {
+        let subdir = self.sysroot.sub_dir(SavedState::STATEFILE_DIR)?;
+        let key = load_hmac_key(&self.sysroot)?;
+        let buf = if use_backup {
+            let name = SavedState::STATEFILE_BACKUP_NAME;
+            if load_verified(&subdir, name, key.as_deref(), false)?.is_none() {
+                bail!("No backup found");
+            }
+            read_optional(&subdir, name)?.ok_or_else(|| anyhow::anyhow!("Backup vanished"))?
+        } else {
+            let buf = read_optional(&subdir, SavedState::STATEFILE_NAME)?
+                .ok_or_else(|| anyhow::anyhow!("No state found"))?;
+            parse_state(std::str::from_utf8(&buf)?)?;
+            buf
+        };
+        write_with_digest(&subdir, SavedState::STATEFILE_NAME, &buf, key.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_state() -> Result<()> {
+        for data in [
+            include_str!("../../tests/fixtures/example-state-v0.json"),
+            include_str!("../../tests/fixtures/example-state-v0-legacy.json"),
+        ] {
+            let state = parse_state(data)?;
+            assert_eq!(state.format_version, SavedState::FORMAT_VERSION);
+            let efi = state.installed.get("EFI").expect("EFI");
+            assert_eq!(
+                efi.meta.version,
+                "grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"
+            );
+            // Writing out and reading back in again is lossless
+            let reparsed = parse_state(&serde_json::to_string(&state)?)?;
+            assert_eq!(
+                serde_json::to_value(&reparsed)?,
+                serde_json::to_value(&state)?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_digest() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = openat::Dir::open(td.path())?;
+        sysroot.create_dir(SavedState::STATEFILE_DIR, 0o755)?;
+        let statedir = sysroot.sub_dir(SavedState::STATEFILE_DIR)?;
+        let mut guard = SavedState::unlocked(sysroot.try_clone()?)?;
+
+        let mut state = SavedState::default();
+        guard.update_state(&state)?;
+        assert!(!statedir.exists(SavedState::STATEFILE_BACKUP_NAME)?);
+        state.static_configs = Some(crate::model::ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "v1".into(),
+            firmware: None,
+        });
+        guard.update_state(&state)?;
+        assert!(statedir.exists(SavedState::STATEFILE_BACKUP_NAME)?);
+        let loaded = SavedState::load_from_disk(td.path())?.unwrap();
+        assert!(loaded.static_configs.is_some());
+
+        // Damage the state; the backup is only used when asked for
+        let statefile = td
+            .path()
+            .join(SavedState::STATEFILE_DIR)
+            .join(SavedState::STATEFILE_NAME);
+        let mut buf = std::fs::read(&statefile)?;
+        buf.truncate(buf.len() / 2);
+        std::fs::write(&statefile, &buf)?;
+        let e = SavedState::load_from_disk(td.path()).unwrap_err();
+        assert_eq!(ErrorKind::of(&e), ErrorKind::StateCorrupt);
+        assert!(format!("{e:#}").contains("--use-backup"));
+        assert!(guard.recover_state(false).is_err());
+        guard.recover_state(true)?;
+        let loaded = SavedState::load_from_disk(td.path())?.unwrap();
+        assert!(loaded.static_configs.is_none());
+
+        // A state written without updating the digest, e.g. by an older
+        // bootupd, is accepted as it is when asked for
+        std::fs::write(&statefile, serialize_state(&state)?)?;
+        assert!(SavedState::load_from_disk(td.path()).is_err());
+        guard.recover_state(false)?;
+        let loaded = SavedState::load_from_disk(td.path())?.unwrap();
+        assert!(loaded.static_configs.is_some());
+
+        // A valid but modified state is also detected
+        statedir.remove_file(SavedState::STATEFILE_BACKUP_NAME)?;
+        std::fs::write(&statefile, br#"{"format-version": 1, "installed": {}}"#)?;
+        let e = SavedState::load_from_disk(td.path()).unwrap_err();
+        assert_eq!(ErrorKind::of(&e), ErrorKind::StateCorrupt);
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_hmac() -> Result<()> {
+        let buf = b"some state";
+        let digest = StateDigest::new(buf, Some(b"key"))?;
+        digest.verify(buf, Some(b"key"), false)?;
+        assert!(digest.verify(buf, Some(b"other key"), false).is_err());
+        assert!(digest.verify(b"other state", Some(b"key"), false).is_err());
+        let unkeyed = StateDigest::new(buf, None)?;
+        assert!(unkeyed.verify(buf, Some(b"key"), false).is_err());
+        unkeyed.verify(buf, Some(b"key"), true)?;
         Ok(())
     }
+
+    #[test]
+    fn test_state_hmac_required() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = openat::Dir::open(td.path())?;
+        let name = SavedState::STATEFILE_NAME;
+        let buf = serialize_state(&SavedState::default())?;
+        write_with_digest(&dir, name, &buf, None)?;
+
+        // After creating the key, the state needs an explicit step to
+        // authenticate it
+        let key = b"secret".as_slice();
+        let e = load_verified(&dir, name, Some(key), false).unwrap_err();
+        assert_eq!(ErrorKind::of(&e), ErrorKind::StateCorrupt);
+        assert!(authenticate(&dir, name, key)?);
+        assert!(!authenticate(&dir, name, key)?);
+        assert!(load_verified(&dir, name, Some(key), false)?.is_some());
+        assert_eq!(dir.read_to_string(name)?.as_bytes(), buf);
+
+        // Neither the HMAC nor the digest can just be removed
+        let digest = digest_name(name);
+        let mut v: serde_json::Value = serde_json::from_str(&dir.read_to_string(&digest)?)?;
+        v.as_object_mut().unwrap().remove("hmac-sha512");
+        dir.write_file_contents(&digest, 0o644, v.to_string())?;
+        assert!(load_verified(&dir, name, Some(key), false).is_err());
+        dir.remove_file(&digest)?;
+        assert!(load_verified(&dir, name, Some(key), false).is_err());
+        // A damaged state can't be authenticated
+        dir.write_file_contents(name, 0o644, "{")?;
+        assert!(authenticate(&dir, name, key).is_err());
+        Ok(())
+    }
+
+    /// The state as read by bootupd before the format was versioned.
+    #[derive(Deserialize)]
+    #[serde(rename_all = "kebab-case", deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct StateV1 {
+        installed: std::collections::BTreeMap<String, serde_json::Value>,
+        pending: Option<serde_json::Value>,
+        static_configs: Option<serde_json::Value>,
+    }
+
+    #[test]
+    fn test_serialize_state() -> Result<()> {
+        let data = include_str!("../../tests/fixtures/example-state-v0.json");
+        let mut state = parse_state(data)?;
+        // Without the newer features, older bootupd can read the state
+        let v: serde_json::Value = serde_json::from_slice(&serialize_state(&state)?)?;
+        assert!(v.get("format-version").is_none());
+        let old: StateV1 = serde_json::from_value(v)?;
+        assert_eq!(old.installed.len(), state.installed.len());
+        let reparsed = parse_state(std::str::from_utf8(&serialize_state(&state)?)?)?;
+        assert_eq!(reparsed.format_version, SavedState::FORMAT_VERSION);
+
+        state.pinned.insert(
+            "EFI".into(),
+            crate::model::Pin {
+                timestamp: chrono::Utc::now(),
+                reason: None,
+            },
+        );
+        let buf = serialize_state(&state)?;
+        let v: serde_json::Value = serde_json::from_slice(&buf)?;
+        assert_eq!(v["format-version"], SavedState::FORMAT_VERSION);
+        assert!(parse_state(std::str::from_utf8(&buf)?)?
+            .pinned
+            .contains_key("EFI"));
+
+        // Fields added by newer versions are ignored
+        let mut v = v;
+        v["from-the-future"] = true.into();
+        parse_state(&v.to_string())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_state_future() {
+        assert_eq!(MIGRATIONS.len(), SavedState::FORMAT_VERSION as usize);
+        let data = format!(
+            r#"{{"format-version": {}, "installed": {{}}}}"#,
+            SavedState::FORMAT_VERSION + 1
+        );
+        assert!(parse_state(&data).is_err());
+        assert!(parse_state(r#"{"format-version": "1", "installed": {}}"#).is_err());
+    }
 }
@@ -1,4 +1,8 @@
+#[cfg(target_arch = "powerpc64")]
+use anyhow::Context;
 use anyhow::{bail, Result};
+#[cfg(target_arch = "powerpc64")]
+use fn_error_context::context;
 use std::io::prelude::*;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
@@ -12,26 +16,156 @@ use crate::packagesystem;
 // grub2-install file path
 pub(crate) const GRUB_BIN: &str = "usr/sbin/grub2-install";
 
+/// GRUB modules for this platform, owned by e.g. `grub2-pc-modules`.
+#[cfg(target_arch = "x86_64")]
+const GRUB_MODULES_DIR: &str = "/usr/lib/grub/i386-pc";
+#[cfg(target_arch = "powerpc64")]
+const GRUB_MODULES_DIR: &str = "/usr/lib/grub/powerpc-ieee1275";
+
+/// Checksum the content of the partition `device`.
+#[cfg(target_arch = "powerpc64")]
+fn hash_partition(device: &Path, partuuid: &str) -> Result<PartitionContent> {
+    use openssl::hash::{Hasher, MessageDigest};
+    let mut f = std::fs::File::open(device)?;
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    let size = std::io::copy(&mut f, &mut hasher)?;
+    Ok(PartitionContent {
+        partuuid: partuuid.to_string(),
+        size,
+        sha512: crate::sha512string::SHA512String::from_hasher(&mut hasher),
+    })
+}
+
+/// The content of the partitions written by `grub2-install` on `device`;
+/// on ppc64le this is the PReP partition, which holds the GRUB core image.
+#[cfg(target_arch = "powerpc64")]
+fn partition_contents(device: &str) -> Result<Option<Vec<PartitionContent>>> {
+    let Some(prep) = blockdev::get_prep_partition(device)? else {
+        log::debug!("No PReP partition found on {device}");
+        return Ok(None);
+    };
+    let Some(partuuid) = blockdev::get_esp_info(&prep)?.partuuid else {
+        log::debug!("PReP partition {prep} has no PARTUUID");
+        return Ok(None);
+    };
+    let content = hash_partition(Path::new(&prep), &partuuid)
+        .with_context(|| format!("Reading PReP partition {prep}"))?;
+    Ok(Some(vec![content]))
+}
+
+/// The boot code is written to the MBR and BIOS boot partition, which
+/// we don't track.
+#[cfg(target_arch = "x86_64")]
+fn partition_contents(_device: &str) -> Result<Option<Vec<PartitionContent>>> {
+    Ok(None)
+}
+
+/// The `boot-device` list with the OpenFirmware paths `ofpaths` first,
+/// followed by the other devices of `current`.
+#[cfg(target_arch = "powerpc64")]
+fn boot_device_list(ofpaths: &[String], current: &str) -> String {
+    let others = current
+        .split_whitespace()
+        .filter(|d| !ofpaths.iter().any(|p| p == d));
+    ofpaths
+        .iter()
+        .map(String::as_str)
+        .chain(others)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Make OpenFirmware boot from the PReP partitions of `devices`, via the
+/// `boot-device` NVRAM variable.
+#[cfg(target_arch = "powerpc64")]
+#[context("Updating OpenFirmware boot-device")]
+fn update_boot_device(devices: &[String]) -> Result<()> {
+    use crate::util::{cmd_output, CommandRunExt};
+    let mut ofpaths = Vec::new();
+    for device in devices {
+        let Some(prep) = blockdev::get_prep_partition(device)? else {
+            bail!("No PReP partition found on {device}");
+        };
+        let ofpath = cmd_output(Command::new("ofpathname").arg(&prep))?;
+        ofpaths.push(ofpath.trim().to_string());
+    }
+    let current = cmd_output(Command::new("nvram").arg("--print-config=boot-device"))?;
+    let current = current.trim();
+    let new = boot_device_list(&ofpaths, current);
+    if new == current {
+        log::debug!("boot-device is already {new}");
+        return Ok(());
+    }
+    log::info!("Setting boot-device to {new}");
+    Command::new("nvram")
+        .arg("--update-config")
+        .arg(format!("boot-device={new}"))
+        .run()
+}
+
+#[cfg(target_arch = "powerpc64")]
+fn validate_partitions(partitions: &[PartitionContent]) -> Result<ValidationResult> {
+    let mut errs = Vec::new();
+    for p in partitions {
+        let path = Path::new("/dev/disk/by-partuuid").join(&p.partuuid);
+        if !path.try_exists()? {
+            errs.push(format!("Removed: PReP partition {}", p.partuuid));
+            continue;
+        }
+        if hash_partition(&path, &p.partuuid)? != *p {
+            errs.push(format!("Changed: PReP partition {}", p.partuuid));
+        }
+    }
+    if errs.is_empty() {
+        Ok(ValidationResult::Valid)
+    } else {
+        Ok(ValidationResult::Errors(errs))
+    }
+}
+
 #[derive(Default)]
 pub(crate) struct Bios {}
 
 impl Bios {
     // Return `true` if grub2-modules installed
     fn check_grub_modules(&self) -> Result<bool> {
-        let usr_path = Path::new("/usr/lib/grub");
-        #[cfg(target_arch = "x86_64")]
-        {
-            usr_path.join("i386-pc").try_exists().map_err(Into::into)
+        Path::new(GRUB_MODULES_DIR).try_exists().map_err(Into::into)
+    }
+
+    /// With `update_firmware` or `ofw-nvram` (and without `no-nvram`),
+    /// make the firmware boot from `devices`.  Unless `require-nvram` is
+    /// set, failures are returned as warnings.
+    #[cfg(target_arch = "powerpc64")]
+    fn update_nvram(
+        &self,
+        devices: &[String],
+        update_firmware: bool,
+    ) -> Result<Option<Vec<String>>> {
+        let config = crate::config::get();
+        if config.no_nvram || !(update_firmware || config.ofw_nvram) {
+            return Ok(None);
         }
-        #[cfg(target_arch = "powerpc64")]
-        {
-            usr_path
-                .join("powerpc-ieee1275")
-                .try_exists()
-                .map_err(Into::into)
+        match update_boot_device(devices) {
+            Ok(()) => Ok(None),
+            Err(e) if !config.require_nvram => {
+                let msg = format!("{e:#}");
+                log::warn!("{msg}");
+                Ok(Some(vec![msg]))
+            }
+            Err(e) => Err(e),
         }
     }
 
+    /// GRUB updates EFI variables itself on x86_64, if at all.
+    #[cfg(target_arch = "x86_64")]
+    fn update_nvram(
+        &self,
+        _devices: &[String],
+        _update_firmware: bool,
+    ) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+
     // Run grub2-install
     fn run_grub_install(&self, dest_root: &str, device: &str) -> Result<()> {
         if !self.check_grub_modules()? {
@@ -92,20 +226,46 @@ impl Component for Bios {
         src_root: &openat::Dir,
         dest_root: &str,
         device: &str,
-        _update_firmware: bool,
+        update_firmware: bool,
     ) -> Result<InstalledContent> {
-        let Some(meta) = get_component_update(src_root, self)? else {
-            anyhow::bail!("No update metadata for component {} found", self.name());
-        };
+        let meta = require_component_update(src_root, self)?;
 
         self.run_grub_install(dest_root, device)?;
         Ok(InstalledContent {
             meta,
             filetree: None,
             adopted_from: None,
+            vendors: None,
+            partitions: partition_contents(device)?,
+            warnings: self.update_nvram(&[device.to_string()], update_firmware)?,
         })
     }
 
+    fn install_to_devices(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        devices: &[String],
+        update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let Some((first, others)) = devices.split_first() else {
+            bail!("No target device");
+        };
+        let mut r = self.install(src_root, dest_root, first, false)?;
+        for device in others {
+            self.run_grub_install(dest_root, device)?;
+            if let Some(p) = partition_contents(device)? {
+                r.partitions.get_or_insert_with(Vec::new).extend(p);
+            }
+        }
+        r.warnings = self.update_nvram(devices, update_firmware)?;
+        Ok(r)
+    }
+
+    fn install_destination(&self, _dest_root: &str, device: &str) -> Result<String> {
+        Ok(device.to_string())
+    }
+
     fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
         let grub_install = Path::new(sysroot_path).join(GRUB_BIN);
         if !grub_install.exists() {
@@ -124,7 +284,7 @@ impl Component for Bios {
             log::debug!("Skip BIOS adopt");
             return Ok(None);
         }
-        crate::component::query_adopt_state()
+        crate::component::query_adopt_state(&[GRUB_MODULES_DIR.to_string()])
     }
 
     fn adopt_update(&self, _: &openat::Dir, update: &ContentMetadata) -> Result<InstalledContent> {
@@ -140,6 +300,9 @@ impl Component for Bios {
             meta: update.clone(),
             filetree: None,
             adopted_from: Some(meta.version),
+            vendors: None,
+            partitions: partition_contents(&device)?,
+            warnings: self.update_nvram(std::slice::from_ref(&device), false)?,
         })
     }
 
@@ -147,9 +310,14 @@ impl Component for Bios {
         get_component_update(sysroot, self)
     }
 
-    fn run_update(&self, sysroot: &openat::Dir, _: &InstalledContent) -> Result<InstalledContent> {
-        let updatemeta = self.query_update(sysroot)?.expect("update available");
-        let dest_fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
+    fn run_update(
+        &self,
+        payload_root: &openat::Dir,
+        dest_root: &openat::Dir,
+        _: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(payload_root)?.expect("update available");
+        let dest_fd = format!("/proc/self/fd/{}", dest_root.as_raw_fd());
         let dest_root = std::fs::read_link(dest_fd)?;
         let device = blockdev::get_single_device(&dest_root)?;
 
@@ -162,11 +330,30 @@ impl Component for Bios {
             meta: updatemeta,
             filetree: None,
             adopted_from,
+            vendors: None,
+            partitions: partition_contents(&device)?,
+            warnings: self.update_nvram(std::slice::from_ref(&device), false)?,
         })
     }
 
-    fn validate(&self, _: &InstalledContent) -> Result<ValidationResult> {
-        Ok(ValidationResult::Skip)
+    fn validate(
+        &self,
+        _: &openat::Dir,
+        current: &InstalledContent,
+        _fast: bool,
+    ) -> Result<ValidationResult> {
+        match current.partitions.as_deref() {
+            #[cfg(target_arch = "powerpc64")]
+            Some(partitions) => validate_partitions(partitions),
+            _ => Ok(ValidationResult::Skip),
+        }
+    }
+
+    fn uninstall(&self, _: &openat::Dir, _: &InstalledContent) -> Result<()> {
+        // The boot code lives in the MBR and BIOS boot partition; we don't
+        // track any files for it, so there is nothing to remove.
+        log::info!("No files to remove for {}", self.name());
+        Ok(())
     }
 
     fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
@@ -1,24 +1,41 @@
 use camino::Utf8Path;
 use std::path::Path;
+use std::process::Command;
 
 use anyhow::{bail, Context, Result};
 use bootc_blockdev::PartitionTable;
+use bootc_utils::CommandRunExt;
 use fn_error_context::context;
+use serde::{Deserialize, Deserializer};
 
-#[context("get parent devices from mount point boot")]
-pub fn get_devices<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>> {
-    let target_root = target_root.as_ref();
-    let bootdir = target_root.join("boot");
-    if !bootdir.exists() {
-        bail!("{} does not exist", bootdir.display());
+use crate::model::{
+    BootPartition, BootPartitionKind, DeviceIdentity, DiskInfo, DiskTopology, EspInfo,
+    MountTopology,
+};
+
+const ESP_TYPE_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+const BIOS_BOOT_TYPE_GUID: &str = "21686148-6449-6E6F-744E-656564454649";
+/// GPT and MBR partition types of PReP partitions
+const PREP_TYPES: &[&str] = &["9E1A2D38-C612-4316-AA26-8B49521E5A8B", "41"];
+
+/// The source of the filesystem mounted at `dir`, and its parent devices.
+fn mount_parents(dir: &Path) -> Result<(String, Vec<String>)> {
+    if !dir.exists() {
+        bail!("{} does not exist", dir.display());
     }
-    let bootdir = openat::Dir::open(&bootdir)?;
-    // Run findmnt to get the source path of mount point boot
-    let fsinfo = crate::filesystem::inspect_filesystem(&bootdir, ".")?;
+    let dir = openat::Dir::open(dir)?;
+    // Run findmnt to get the source path of the mount point
+    let fsinfo = crate::filesystem::inspect_filesystem(&dir, ".")?;
     // Find the parent devices of the source path
     let parent_devices = bootc_blockdev::find_parent_devices(&fsinfo.source)
         .with_context(|| format!("while looking for backing devices of {}", fsinfo.source))?;
     log::debug!("Find parent devices: {parent_devices:?}");
+    Ok((fsinfo.source, parent_devices))
+}
+
+#[context("get parent devices from mount point boot")]
+pub fn get_devices<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>> {
+    let (_, parent_devices) = mount_parents(&target_root.as_ref().join("boot"))?;
     Ok(parent_devices)
 }
 
@@ -39,7 +56,6 @@ pub fn get_single_device<P: AsRef<Path>>(target_root: P) -> Result<String> {
 /// using sfdisk to get partitiontable
 #[allow(dead_code)]
 pub fn get_esp_partition(device: &str) -> Result<Option<String>> {
-    const ESP_TYPE_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
     let device_info: PartitionTable = bootc_blockdev::partitions_of(Utf8Path::new(device))?;
     let esp = device_info
         .partitions
@@ -70,7 +86,6 @@ pub fn find_colocated_esps<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>
 
 /// Find bios_boot partition on the same device
 pub fn get_bios_boot_partition(device: &str) -> Result<Option<String>> {
-    const BIOS_BOOT_TYPE_GUID: &str = "21686148-6449-6E6F-744E-656564454649";
     let device_info = bootc_blockdev::partitions_of(Utf8Path::new(device))?;
     let bios_boot = device_info
         .partitions
@@ -82,6 +97,18 @@ pub fn get_bios_boot_partition(device: &str) -> Result<Option<String>> {
     Ok(None)
 }
 
+/// Find the PReP partition on `device`, for either GPT or MBR partition tables
+#[cfg(target_arch = "powerpc64")]
+pub fn get_prep_partition(device: &str) -> Result<Option<String>> {
+    let device_info = bootc_blockdev::partitions_of(Utf8Path::new(device))?;
+    let prep = device_info.partitions.into_iter().find(|p| {
+        PREP_TYPES
+            .iter()
+            .any(|t| p.parttype.eq_ignore_ascii_case(t))
+    });
+    Ok(prep.map(|p| p.node))
+}
+
 /// Find all bios_boot partitions on the devices with mountpoint boot
 #[allow(dead_code)]
 pub fn find_colocated_bios_boot<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>> {
@@ -99,3 +126,289 @@ pub fn find_colocated_bios_boot<P: AsRef<Path>>(target_root: P) -> Result<Vec<St
     log::debug!("Find bios_boot partitions: {bios_boots:?}");
     Ok(bios_boots)
 }
+
+/// The kind of a partition with type `parttype`, if bootupd uses it.
+fn boot_partition_kind(parttype: &str) -> Option<BootPartitionKind> {
+    if parttype.eq_ignore_ascii_case(ESP_TYPE_GUID) {
+        Some(BootPartitionKind::Esp)
+    } else if parttype.eq_ignore_ascii_case(BIOS_BOOT_TYPE_GUID) {
+        Some(BootPartitionKind::BiosBoot)
+    } else if PREP_TYPES.iter().any(|t| parttype.eq_ignore_ascii_case(t)) {
+        Some(BootPartitionKind::Prep)
+    } else {
+        None
+    }
+}
+
+/// The directory of the block device `node` (e.g. `/dev/mapper/mpatha`)
+/// in `/sys/class/block`.
+fn sysfs_dir(node: &str) -> Result<std::path::PathBuf> {
+    let path = std::fs::canonicalize(node).with_context(|| format!("Resolving {node}"))?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid device {node}"))?;
+    Ok(Path::new("/sys/class/block").join(name))
+}
+
+/// The number of the partition `node`, as in the kernel's device name.
+/// Partitions of multipath devices are device-mapper devices, whose UUID
+/// starts with `part<N>-`.
+fn partition_number(node: &str) -> Option<u32> {
+    let r = sysfs_dir(node).and_then(|d| {
+        if let Ok(n) = std::fs::read_to_string(d.join("partition")) {
+            return Ok(n.trim().parse()?);
+        }
+        let uuid = std::fs::read_to_string(d.join("dm/uuid"))?;
+        let n = uuid
+            .strip_prefix("part")
+            .and_then(|s| s.split_once('-'))
+            .ok_or_else(|| anyhow::anyhow!("Not a partition"))?
+            .0;
+        Ok(n.parse()?)
+    });
+    r.map_err(|e| log::debug!("No partition number for {node}: {e:#}"))
+        .ok()
+}
+
+/// The paths of `device` if it is a multipath device, otherwise empty.
+fn multipath_paths(device: &str) -> Result<Vec<String>> {
+    let dir = sysfs_dir(device)?;
+    let uuid = std::fs::read_to_string(dir.join("dm/uuid")).unwrap_or_default();
+    if !uuid.starts_with("mpath-") {
+        return Ok(Vec::new());
+    }
+    let mut paths = std::fs::read_dir(dir.join("slaves"))?
+        .map(|e| Ok(format!("/dev/{}", e?.file_name().to_string_lossy())))
+        .collect::<Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// The partitions bootupd uses on `device`.
+#[context("Inspecting {device}")]
+fn inspect_disk(device: &str) -> Result<DiskInfo> {
+    let table = bootc_blockdev::partitions_of(Utf8Path::new(device))?;
+    let partitions = table
+        .partitions
+        .into_iter()
+        .filter_map(|p| {
+            let kind = boot_partition_kind(&p.parttype)?;
+            Some(BootPartition {
+                kind,
+                number: partition_number(&p.node),
+                device: p.node,
+            })
+        })
+        .collect();
+    Ok(DiskInfo {
+        device: device.to_string(),
+        multipath_paths: multipath_paths(device)?,
+        partitions,
+    })
+}
+
+/// How bootupd finds the disks backing `/boot` and `/sysroot` (on ostree
+/// systems) under `target_root`, and the partitions it uses on them.
+pub fn inspect_disks<P: AsRef<Path>>(target_root: P) -> Result<DiskTopology> {
+    let target_root = target_root.as_ref();
+    let mut mounts = Vec::new();
+    let mut devices: Vec<String> = Vec::new();
+    for path in ["boot", "sysroot"] {
+        let dir = target_root.join(path);
+        if path == "sysroot" && !dir.exists() {
+            continue;
+        }
+        let mut m = MountTopology {
+            path: dir.display().to_string(),
+            ..Default::default()
+        };
+        match mount_parents(&dir) {
+            Ok((source, parents)) => {
+                for p in parents.iter() {
+                    if !devices.contains(p) {
+                        devices.push(p.clone());
+                    }
+                }
+                m.source = Some(source);
+                m.parents = parents;
+            }
+            Err(e) => m.error = Some(format!("{e:#}")),
+        }
+        mounts.push(m);
+    }
+    let disks = devices
+        .iter()
+        .map(|d| inspect_disk(d))
+        .collect::<Result<_>>()?;
+    Ok(DiskTopology { mounts, disks })
+}
+
+#[derive(Deserialize, Debug)]
+struct LsblkOutput {
+    blockdevices: Vec<LsblkDevice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LsblkDevice {
+    path: String,
+    partuuid: Option<String>,
+    uuid: Option<String>,
+    mountpoint: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_lsblk_size")]
+    size: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_lsblk_size")]
+    fsavail: Option<u64>,
+    #[serde(default)]
+    wwn: Option<String>,
+    #[serde(default)]
+    serial: Option<String>,
+}
+
+/// Older versions of lsblk emit sizes as strings even with `--bytes`.
+fn deserialize_lsblk_size<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Num(u64),
+        Str(String),
+    }
+    match Option::<Size>::deserialize(d)? {
+        None => Ok(None),
+        Some(Size::Num(n)) => Ok(Some(n)),
+        Some(Size::Str(s)) => s.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+impl From<LsblkDevice> for EspInfo {
+    fn from(dev: LsblkDevice) -> Self {
+        EspInfo {
+            device: dev.path,
+            partuuid: dev.partuuid,
+            uuid: dev.uuid,
+            mountpoint: dev.mountpoint,
+            size: dev.size,
+            free: dev.fsavail,
+            installed: None,
+        }
+    }
+}
+
+/// Gather details about an ESP partition
+#[context("Inspecting ESP {device}")]
+pub fn get_esp_info(device: &str) -> Result<EspInfo> {
+    let o: LsblkOutput = Command::new("lsblk")
+        .args([
+            "--json",
+            "--bytes",
+            "--nodeps",
+            "--output",
+            "PATH,PARTUUID,UUID,MOUNTPOINT,SIZE,FSAVAIL",
+            device,
+        ])
+        .run_and_parse_json()?;
+    o.blockdevices
+        .into_iter()
+        .next()
+        .map(Into::into)
+        .ok_or_else(|| anyhow::anyhow!("lsblk returned no data"))
+}
+
+/// Identify the disks backing `/boot`, along with their ESPs.
+#[context("Identifying boot devices")]
+pub fn get_device_identities<P: AsRef<Path>>(target_root: P) -> Result<Vec<DeviceIdentity>> {
+    identify_devices(get_devices(target_root)?)
+}
+
+/// Identify the given disks, along with their ESPs.
+#[context("Identifying devices")]
+pub fn identify_devices(devices: Vec<String>) -> Result<Vec<DeviceIdentity>> {
+    devices
+        .into_iter()
+        .map(|device| {
+            let o: LsblkOutput = Command::new("lsblk")
+                .args(["--json", "--nodeps", "--output", "PATH,WWN,SERIAL", &device])
+                .run_and_parse_json()?;
+            let disk = o
+                .blockdevices
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("lsblk returned no data for {device}"))?;
+            let esp_partuuid = match get_esp_partition(&device)? {
+                Some(esp) => get_esp_info(&esp)?.partuuid,
+                None => None,
+            };
+            Ok(DeviceIdentity {
+                device,
+                wwn: disk.wwn,
+                serial: disk.serial,
+                esp_partuuid,
+            })
+        })
+        .collect()
+}
+
+/// Gather details about all ESPs on the devices with mountpoint boot
+pub fn find_colocated_esp_info<P: AsRef<Path>>(target_root: P) -> Result<Vec<EspInfo>> {
+    find_colocated_esps(target_root)?
+        .iter()
+        .map(|esp| get_esp_info(esp))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_partition_kind() {
+        let cases = [
+            (ESP_TYPE_GUID, Some(BootPartitionKind::Esp)),
+            (
+                "c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
+                Some(BootPartitionKind::Esp),
+            ),
+            (BIOS_BOOT_TYPE_GUID, Some(BootPartitionKind::BiosBoot)),
+            ("41", Some(BootPartitionKind::Prep)),
+            (
+                "9E1A2D38-C612-4316-AA26-8B49521E5A8B",
+                Some(BootPartitionKind::Prep),
+            ),
+            // Linux filesystem
+            ("0FC63DAF-8483-4772-8E79-3D69D8477DE4", None),
+            ("83", None),
+        ];
+        for (parttype, expected) in cases {
+            assert_eq!(boot_partition_kind(parttype), expected, "{parttype}");
+        }
+    }
+
+    #[test]
+    fn test_parse_lsblk_esp() -> Result<()> {
+        let data = r#"{
+   "blockdevices": [
+      {
+         "path": "/dev/vda2",
+         "partuuid": "94ff4025-5276-4bec-adea-e98da271b64c",
+         "uuid": "7B77-95E7",
+         "mountpoint": "/boot/efi",
+         "size": 133169152,
+         "fsavail": "125495296"
+      }
+   ]
+}"#;
+        let o: LsblkOutput = serde_json::from_str(data)?;
+        let esp: EspInfo = o.blockdevices.into_iter().next().unwrap().into();
+        assert_eq!(esp.device, "/dev/vda2");
+        assert_eq!(esp.uuid.as_deref(), Some("7B77-95E7"));
+        assert_eq!(esp.mountpoint.as_deref(), Some("/boot/efi"));
+        assert_eq!(esp.size, Some(133169152));
+        assert_eq!(esp.free, Some(125495296));
+
+        let data = r#"{"blockdevices": [{"path": "/dev/vdb2", "partuuid": null, "uuid": null, "mountpoint": null, "size": 133169152, "fsavail": null}]}"#;
+        let o: LsblkOutput = serde_json::from_str(data)?;
+        let esp: EspInfo = o.blockdevices.into_iter().next().unwrap().into();
+        assert_eq!(esp.mountpoint, None);
+        assert_eq!(esp.free, None);
+        Ok(())
+    }
+}
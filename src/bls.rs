@@ -0,0 +1,187 @@
+//! Sanity checks for Boot Loader Specification entries in
+//! `/boot/loader/entries`, which GRUB's `blscfg` command turns into menu
+//! entries.  This catches e.g. entries still referencing a deleted kernel
+//! before rebooting into them.
+//!
+//! See <https://uapi-group.org/specifications/specs/boot_loader_specification/>.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+
+/// Where entries live, relative to `/boot`.
+const ENTRIES_DIR: &str = "loader/entries";
+/// The GRUB config, relative to `/boot`.
+const GRUB_CFG: &str = "grub2/grub.cfg";
+
+/// The files referenced by an entry.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Entry {
+    linux: Option<String>,
+    initrd: Vec<String>,
+    devicetree: Option<String>,
+}
+
+fn parse_entry(contents: &str) -> Entry {
+    let mut r = Entry::default();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(char::is_whitespace) else {
+            continue;
+        };
+        let value = value.trim();
+        match key {
+            "linux" => r.linux = Some(value.to_string()),
+            // May be repeated, or list several files
+            "initrd" => r
+                .initrd
+                .extend(value.split_whitespace().map(ToOwned::to_owned)),
+            "devicetree" => r.devicetree = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    r
+}
+
+/// Check the entries in `boot`; paths in entries are relative to `fs_root`,
+/// the root of the filesystem holding `/boot`.
+fn check_entries(boot: &Path, fs_root: &Path) -> Result<Vec<String>> {
+    let dir = boot.join(ENTRIES_DIR);
+    let mut names = Vec::new();
+    for e in std::fs::read_dir(&dir).with_context(|| format!("Reading {dir:?}"))? {
+        let path = e?.path();
+        if path.extension().is_some_and(|e| e == "conf") {
+            names.push(path);
+        }
+    }
+    names.sort();
+
+    let mut errs = Vec::new();
+    if names.is_empty() {
+        errs.push(format!("No BLS entries in {}", dir.display()));
+    }
+    for path in names {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let contents =
+            std::fs::read_to_string(&path).with_context(|| format!("Reading {path:?}"))?;
+        let entry = parse_entry(&contents);
+        if entry.linux.is_none() {
+            errs.push(format!("BLS entry {name}: no linux"));
+        }
+        let files = entry
+            .linux
+            .iter()
+            .map(|p| ("linux", p))
+            .chain(entry.initrd.iter().map(|p| ("initrd", p)))
+            .chain(entry.devicetree.iter().map(|p| ("devicetree", p)));
+        for (key, file) in files {
+            if !fs_root.join(file.trim_start_matches('/')).exists() {
+                errs.push(format!("BLS entry {name}: {key} {file} not found"));
+            }
+        }
+    }
+    Ok(errs)
+}
+
+/// Check that the GRUB config in `boot`, if any, reads BLS entries.
+fn check_grub_config(boot: &Path) -> Result<Vec<String>> {
+    let path = boot.join(GRUB_CFG);
+    let config = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {path:?}")),
+    };
+    let uses_blscfg = config
+        .lines()
+        .any(|l| l.split_whitespace().next() == Some("blscfg"));
+    if uses_blscfg {
+        Ok(Vec::new())
+    } else {
+        Ok(vec![format!(
+            "{} doesn't run blscfg, so BLS entries won't be used",
+            path.display()
+        )])
+    }
+}
+
+/// Validate the BLS entries under `boot` (normally `/boot`), returning a
+/// description of each problem found.
+#[context("Validating BLS entries")]
+pub(crate) fn validate(boot: &Path) -> Result<Vec<String>> {
+    // GRUB resolves paths relative to the filesystem holding /boot, which
+    // is either /boot itself or the root filesystem.
+    let parent = boot.parent().unwrap_or(Path::new("/"));
+    let boot_is_mount = std::fs::metadata(boot)?.dev() != std::fs::metadata(parent)?.dev();
+    let fs_root = if boot_is_mount { boot } else { parent };
+    let mut errs = check_grub_config(boot)?;
+    errs.extend(check_entries(boot, fs_root)?);
+    Ok(errs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entry() {
+        let entry = parse_entry(
+            "# comment\ntitle Fedora Linux 40\nversion 6.8.5\n\
+             linux /vmlinuz-6.8.5\ninitrd /initramfs-6.8.5.img /microcode.img\n\
+             options root=UUID=abcd ro\n",
+        );
+        assert_eq!(
+            entry,
+            Entry {
+                linux: Some("/vmlinuz-6.8.5".into()),
+                initrd: vec!["/initramfs-6.8.5.img".into(), "/microcode.img".into()],
+                devicetree: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let boot = td.path().join("boot");
+        std::fs::create_dir_all(boot.join("loader/entries"))?;
+        std::fs::create_dir_all(boot.join("grub2"))?;
+        std::fs::write(boot.join("grub2/grub.cfg"), "insmod blscfg\nblscfg\n")?;
+        assert_eq!(
+            validate(&boot)?,
+            [format!(
+                "No BLS entries in {}/loader/entries",
+                boot.display()
+            )]
+        );
+
+        std::fs::write(boot.join("vmlinuz-1"), "")?;
+        std::fs::write(boot.join("initramfs-1.img"), "")?;
+        std::fs::write(
+            boot.join("loader/entries/a.conf"),
+            "linux /boot/vmlinuz-1\ninitrd /boot/initramfs-1.img\n",
+        )?;
+        assert!(validate(&boot)?.is_empty());
+
+        std::fs::write(
+            boot.join("loader/entries/b.conf"),
+            "linux /boot/vmlinuz-2\ninitrd /boot/initramfs-1.img\n",
+        )?;
+        std::fs::write(boot.join("grub2/grub.cfg"), "configfile foo.cfg\n")?;
+        assert_eq!(
+            validate(&boot)?,
+            [
+                format!(
+                    "{}/grub2/grub.cfg doesn't run blscfg, so BLS entries won't be used",
+                    boot.display()
+                ),
+                "BLS entry b.conf: linux /boot/vmlinuz-2 not found".to_string(),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -5,7 +5,13 @@ use crate::component::{Component, ValidationResult};
 use crate::coreos;
 #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 use crate::efi;
-use crate::model::{ComponentStatus, ComponentUpdatable, ContentMetadata, SavedState, Status};
+use crate::errors::ErrorKind;
+use crate::exitcode::Outcome;
+use crate::filetree::FileChange;
+use crate::model::{
+    ComponentStatus, ComponentUpdatable, ContentMetadata, DeferredUpdate, DeploymentInfo, EspInfo,
+    FirmwareStatus, HostInfo, LastUpdate, Pin, PlatformInfo, SavedState, ScrubResult, Status,
+};
 use crate::util;
 use anyhow::{anyhow, Context, Result};
 use clap::crate_version;
@@ -15,14 +21,21 @@ use libc::{S_IRGRP, S_IROTH, S_IRUSR, S_IWUSR};
 use openat_ext::OpenatDirExt;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::collections::BTreeMap;
-use std::fs::{self, File};
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
+/// A world-readable copy of the last full status, for unprivileged
+/// `bootupctl status`.
+pub(crate) const STATUS_SNAPSHOT: &str = "/run/bootupd/status.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum ConfigMode {
     None,
     Static,
+    #[serde(rename = "with-uuid")]
     WithUUID,
 }
 
@@ -36,26 +49,90 @@ impl ConfigMode {
     }
 }
 
-pub(crate) fn install(
+/// What `install` will do, computed without changing anything so that
+/// installers can review and log it.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct InstallPlan {
+    pub(crate) source_root: String,
+    pub(crate) dest_root: String,
+    /// Target devices, e.g. the disks of a mirrored `/boot`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) devices: Vec<String>,
+    pub(crate) configs: ConfigMode,
+    pub(crate) update_firmware: bool,
+    /// Components to install, in order
+    pub(crate) components: Vec<ComponentInstallPlan>,
+    /// Components which were selected but will be skipped, with the reason
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) skipped: BTreeMap<String, String>,
+}
+
+/// The installation of a single component.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComponentInstallPlan {
+    pub(crate) name: String,
+    /// The version in the source root
+    pub(crate) version: Option<ContentMetadata>,
+    /// Where the component is written, e.g. the ESP or block devices
+    pub(crate) destination: String,
+    /// Files copied from the update payload in the source root
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) files: Vec<String>,
+}
+
+/// The files in the update payload for `component` in `source_root`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn payload_files(source_root: &openat::Dir, component: &dyn Component) -> Result<Vec<String>> {
+    let dirname = component::component_updatedirname(component);
+    let Some(dir) = source_root.sub_dir_optional(&dirname)? else {
+        return Ok(Vec::new());
+    };
+    let ft = crate::treecache::payload_tree(source_root, component, &dir)?;
+    Ok(ft.children.into_keys().collect())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+fn payload_files(_source_root: &openat::Dir, _component: &dyn Component) -> Result<Vec<String>> {
+    Ok(Vec::new())
+}
+
+/// Compute what `install` would do; `None` if there are no components for
+/// this platform.
+#[context("Planning installation")]
+pub(crate) fn plan_install(
     source_root: &str,
     dest_root: &str,
-    device: Option<&str>,
+    devices: &[String],
     configs: ConfigMode,
     update_firmware: bool,
     target_components: Option<&[String]>,
     auto_components: bool,
-) -> Result<()> {
-    // TODO: Change this to an Option<&str>; though this probably balloons into having
-    // DeviceComponent and FileBasedComponent
-    let device = device.unwrap_or("");
-    let source_root = openat::Dir::open(source_root).context("Opening source root")?;
+) -> Result<Option<InstallPlan>> {
+    let source_root_dir = openat::Dir::open(source_root).context("Opening source root")?;
     SavedState::ensure_not_present(dest_root)
         .context("failed to install, invalid re-install attempted")?;
 
-    let all_components = get_components_impl(auto_components);
+    let mut all_components = get_components_impl(auto_components);
+    // Components outside the default set, e.g. Extlinux, may still be
+    // requested by name.
+    for name in target_components.into_iter().flatten() {
+        if !all_components.contains_key(name.as_str()) {
+            let c = crate::component::new_from_name(name)?;
+            all_components.insert(c.name().to_string(), c);
+        }
+    }
     if all_components.is_empty() {
-        println!("No components available for this platform.");
-        return Ok(());
+        return Ok(None);
     }
     let target_components = if let Some(target_components) = target_components {
         // Checked by CLI parser
@@ -76,38 +153,93 @@ pub(crate) fn install(
         anyhow::bail!("No components specified");
     }
 
-    let mut state = SavedState::default();
-    let mut installed_efi_vendor = None;
+    let mut plan = InstallPlan {
+        source_root: source_root.to_string(),
+        dest_root: dest_root.to_string(),
+        devices: devices.to_vec(),
+        configs,
+        update_firmware,
+        components: Vec::new(),
+        skipped: BTreeMap::new(),
+    };
     for &component in target_components.iter() {
+        let name = component.name();
         // skip for BIOS if device is empty
-        if component.name() == "BIOS" && device.is_empty() {
-            println!(
-                "Skip installing component {} without target device",
-                component.name()
-            );
+        if name == "BIOS" && devices.is_empty() {
+            plan.skipped
+                .insert(name.to_string(), "no target device".to_string());
+            continue;
+        }
+        // Only the images for boards which need DTBs ship them
+        #[cfg(target_arch = "riscv64")]
+        if name == crate::devicetree::NAME && component.query_update(&source_root_dir)?.is_none() {
+            plan.skipped
+                .insert(name.to_string(), "no DTBs in the image".to_string());
             continue;
         }
+        // Components which don't need a device still have a destination
+        let targets = match devices {
+            [] => vec![""],
+            devices => devices.iter().map(String::as_str).collect(),
+        };
+        let mut destinations = Vec::new();
+        for device in targets {
+            let d = component
+                .install_destination(dest_root, device)
+                .with_context(|| format!("Finding destination for {name}"))?;
+            if !destinations.contains(&d) {
+                destinations.push(d);
+            }
+        }
+        plan.components.push(ComponentInstallPlan {
+            name: name.to_string(),
+            version: component.query_update(&source_root_dir)?,
+            destination: destinations.join(", "),
+            files: payload_files(&source_root_dir, component.as_ref())?,
+        });
+    }
+    Ok(Some(plan))
+}
+
+/// Perform the installation described by `plan`.
+pub(crate) fn apply_install_plan(plan: &InstallPlan) -> Result<()> {
+    let dest_root = plan.dest_root.as_str();
+    let source_root =
+        openat::Dir::open(plan.source_root.as_str()).context("Opening source root")?;
+    SavedState::ensure_not_present(dest_root)
+        .context("failed to install, invalid re-install attempted")?;
+
+    for (name, reason) in plan.skipped.iter() {
+        println!("Skip installing component {name}: {reason}");
+    }
+    let target_components = plan
+        .components
+        .iter()
+        .map(|c| component::new_from_name(&c.name))
+        .collect::<Result<Vec<_>>>()?;
 
+    let mut state = SavedState::default();
+    let mut installed_efi_vendors = Vec::new();
+    for component in target_components.iter() {
+        crate::try_fail_point!("install::component");
         let meta = component
-            .install(&source_root, dest_root, device, update_firmware)
+            .install_to_devices(&source_root, dest_root, &plan.devices, plan.update_firmware)
             .with_context(|| format!("installing component {}", component.name()))?;
         log::info!("Installed {} {}", component.name(), meta.meta.version);
         state.installed.insert(component.name().into(), meta);
         // Yes this is a hack...the Component thing just turns out to be too generic.
-        if let Some(vendor) = component.get_efi_vendor(&source_root)? {
-            assert!(installed_efi_vendor.is_none());
-            installed_efi_vendor = Some(vendor);
-        }
+        installed_efi_vendors.extend(component.get_efi_vendors(&source_root)?);
     }
     let sysroot = &openat::Dir::open(dest_root)?;
 
-    match configs.enabled_with_uuid() {
+    match plan.configs.enabled_with_uuid() {
         Some(uuid) => {
             let self_bin_meta =
                 std::fs::metadata("/proc/self/exe").context("Querying self meta")?;
             let self_meta = ContentMetadata {
                 timestamp: self_bin_meta.modified()?.into(),
                 version: crate_version!().into(),
+                firmware: None,
             };
             state.static_configs = Some(self_meta);
             #[cfg(any(
@@ -115,7 +247,7 @@ pub(crate) fn install(
                 target_arch = "aarch64",
                 target_arch = "powerpc64"
             ))]
-            crate::grubconfigs::install(sysroot, installed_efi_vendor.as_deref(), uuid)?;
+            crate::grubconfigs::install(sysroot, &installed_efi_vendors, uuid)?;
             // On other architectures, assume that there's nothing to do.
         }
         None => {}
@@ -124,6 +256,20 @@ pub(crate) fn install(
     // Unmount the ESP, etc.
     drop(target_components);
 
+    // This fails e.g. when installing to a directory rather than a disk,
+    // in which case there's nothing to verify later.  A mirrored install
+    // records each of its disks.
+    let identities = if plan.devices.len() > 1 {
+        crate::blockdev::identify_devices(plan.devices.clone())
+    } else {
+        crate::blockdev::get_device_identities(dest_root)
+    };
+    match identities {
+        Ok(devices) => state.devices = Some(devices),
+        Err(e) => log::debug!("Not recording boot devices: {e:#}"),
+    }
+
+    crate::try_fail_point!("install::state");
     let mut state_guard =
         SavedState::unlocked(sysroot.try_clone()?).context("failed to acquire write lock")?;
     state_guard
@@ -133,7 +279,7 @@ pub(crate) fn install(
     Ok(())
 }
 
-type Components = BTreeMap<&'static str, Box<dyn Component>>;
+type Components = BTreeMap<String, Box<dyn Component>>;
 
 #[allow(clippy::box_default)]
 /// Return the set of known components; if `auto` is specified then the system
@@ -142,7 +288,7 @@ pub(crate) fn get_components_impl(auto: bool) -> Components {
     let mut components = BTreeMap::new();
 
     fn insert_component(components: &mut Components, component: Box<dyn Component>) {
-        components.insert(component.name(), component);
+        components.insert(component.name().to_string(), component);
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -169,6 +315,13 @@ pub(crate) fn get_components_impl(auto: bool) -> Components {
     #[cfg(target_arch = "powerpc64")]
     insert_component(&mut components, Box::new(bios::Bios::default()));
 
+    // Boards are booted by firmware which loads the DTB from disk
+    #[cfg(target_arch = "riscv64")]
+    insert_component(
+        &mut components,
+        Box::new(crate::devicetree::DeviceTree::default()),
+    );
+
     components
 }
 
@@ -176,13 +329,134 @@ pub(crate) fn get_components() -> Components {
     get_components_impl(false)
 }
 
+/// Warn if the disks backing `/boot` differ from those recorded at install
+/// time, e.g. because a disk was replaced or the system was cloned.
+fn check_boot_devices() -> Result<()> {
+    let Some(recorded) = SavedState::load_from_disk("/")?.and_then(|s| s.devices) else {
+        return Ok(());
+    };
+    let current = match crate::blockdev::get_device_identities("/") {
+        Ok(c) => c,
+        Err(e) => {
+            log::debug!("{e:#}");
+            return Ok(());
+        }
+    };
+    if !crate::model::DeviceIdentity::same_set(&recorded, &current) {
+        let names = |devs: &[crate::model::DeviceIdentity]| {
+            devs.iter()
+                .map(|d| d.device.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        log::warn!(
+            "Boot devices ({}) differ from those at install time ({})",
+            names(&current),
+            names(&recorded)
+        );
+    }
+    Ok(())
+}
+
+/// Fail with [`Outcome::UnsupportedPlatform`] if no components are
+/// supported on this architecture.
+pub(crate) fn ensure_supported_platform() -> Result<()> {
+    if get_components().is_empty() {
+        return Err(Outcome::UnsupportedPlatform.into());
+    }
+    Ok(())
+}
+
+/// Record the files in the payload for `component`, so that it can be
+/// signed along with the update metadata, and the packages they came from.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn write_payload_manifest(
+    sysroot_path: &str,
+    updates_dir: &Path,
+    component: &dyn Component,
+) -> Result<()> {
+    let updates = openat::Dir::open(updates_dir)?;
+    let Some(payload) = updates.sub_dir_optional(component.name())? else {
+        return Ok(());
+    };
+    let tree = crate::filetree::FileTree::new_from_dir(&payload)?;
+    if let Some(limit) = crate::config::get().payload_size_warning_mib {
+        let size = tree.total_size();
+        if size > limit * 1024 * 1024 {
+            log::warn!(
+                "Payload for {} is {} MiB, more than the configured {limit} MiB",
+                component.name(),
+                size.div_ceil(1024 * 1024)
+            );
+        }
+    }
+    updates.write_file_with(
+        crate::signature::manifest_name(component.name()),
+        0o644,
+        |w| -> Result<_> { Ok(serde_json::to_writer(w, &tree)?) },
+    )?;
+    let prov = crate::provenance::generate(sysroot_path, component, &tree);
+    crate::provenance::write(&updates, component.name(), &prov)?;
+    Ok(())
+}
+
+/// Add `src` to the payload of the component `name` in `sysroot_path`.
+#[context("Extending payload for {name}")]
+pub(crate) fn extend_payload(
+    sysroot_path: &str,
+    name: &str,
+    src: &Path,
+    pkg_name: Option<&str>,
+    version: Option<&str>,
+    keep: usize,
+) -> Result<component::ExtendedPayload> {
+    let component = component::new_from_name(name)?;
+    component.extend_payload(sysroot_path, src, pkg_name, version, keep)
+}
+
 pub(crate) fn generate_update_metadata(sysroot_path: &str) -> Result<()> {
     // create bootupd update dir which will save component metadata files for both components
     let updates_dir = Path::new(sysroot_path).join(crate::model::BOOTUPD_UPDATES_DIR);
     std::fs::create_dir_all(&updates_dir)
         .with_context(|| format!("Failed to create updates dir {:?}", &updates_dir))?;
-    for component in get_components().values() {
+    #[allow(unused_mut)]
+    let mut components = get_components();
+    // Only adopted explicitly, but ship an update if the image has it
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if crate::systemdboot::SystemdBoot::present_in(sysroot_path) {
+        let c = Box::<crate::systemdboot::SystemdBoot>::default();
+        components.insert(c.name().to_string(), c);
+    }
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    if crate::devicetree::DeviceTree::present_in(sysroot_path) {
+        let c = Box::<crate::devicetree::DeviceTree>::default();
+        components.insert(c.name().to_string(), c);
+    } else {
+        // A default component on riscv64, but not every board needs DTBs
+        components.remove(crate::devicetree::NAME);
+    }
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    if crate::emmcboot::EmmcBoot::present_in(sysroot_path) {
+        let c = Box::<crate::emmcboot::EmmcBoot>::default();
+        components.insert(c.name().to_string(), c);
+    }
+    #[cfg(all(feature = "extlinux", target_arch = "x86_64"))]
+    if crate::extlinux::Extlinux::present_in(sysroot_path) {
+        let c = Box::<crate::extlinux::Extlinux>::default();
+        components.insert(c.name().to_string(), c);
+    }
+    for component in components.values() {
         let v = component.generate_update_metadata(sysroot_path)?;
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
+        write_payload_manifest(sysroot_path, &updates_dir, component.as_ref())?;
         println!(
             "Generated update layout for {}: {}",
             component.name(),
@@ -193,10 +467,25 @@ pub(crate) fn generate_update_metadata(sysroot_path: &str) -> Result<()> {
     Ok(())
 }
 
+/// Generate the EFI update payload in `sysroot_path` from the packages in
+/// `rpmdir`, rather than from the files installed in it.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn generate_update_metadata_from_rpms(sysroot_path: &str, rpmdir: &Path) -> Result<()> {
+    let updates_dir = Path::new(sysroot_path).join(crate::model::BOOTUPD_UPDATES_DIR);
+    std::fs::create_dir_all(&updates_dir)
+        .with_context(|| format!("Failed to create updates dir {:?}", &updates_dir))?;
+    let efi = efi::Efi::default();
+    let v = efi.generate_update_metadata_from_rpms(sysroot_path, rpmdir)?;
+    write_payload_manifest(sysroot_path, &updates_dir, &efi)?;
+    println!("Generated update layout for {}: {}", efi.name(), v.version);
+    Ok(())
+}
+
 /// Return value from daemon → client for component update
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) enum ComponentUpdateResult {
+#[allow(clippy::large_enum_variant)]
+pub enum ComponentUpdateResult {
     AtLatestVersion,
     Updated {
         previous: ContentMetadata,
@@ -205,43 +494,138 @@ pub(crate) enum ComponentUpdateResult {
     },
 }
 
+/// `f_type` of filesystems backed by read-only images, which can't be
+/// made writable; composefs is an overlay of an erofs image.
+const IMAGE_FILESYSTEMS: &[(i64, &str)] = &[
+    (0x794c7630, "overlay"),
+    (0xe0f5e1e2, "erofs"),
+    (0x73717368, "squashfs"),
+];
+
+/// The name of the filesystem with `f_type` (whose type differs between
+/// architectures), if it's backed by a read-only image.
+fn image_filesystem(f_type: impl Into<i64>) -> Option<&'static str> {
+    let f_type = f_type.into();
+    IMAGE_FILESYSTEMS
+        .iter()
+        .find(|(magic, _)| *magic == f_type)
+        .map(|(_, name)| *name)
+}
+
+/// Make `/boot` writable, remounting it if needed.  If it isn't a
+/// separate mount but part of a root filesystem built from a read-only
+/// image (e.g. with composefs), the real `/boot` is in the physical root
+/// at `/sysroot/boot`, which is bind mounted over it in our private mount
+/// namespace first.
+#[context("Making /boot writable")]
 fn ensure_writable_boot() -> Result<()> {
-    util::ensure_writable_mount("/boot")
+    let boot = Path::new("/boot");
+    let st = rustix::fs::statfs(boot)?;
+    if let Some(fs) = image_filesystem(st.f_type) {
+        let physical = Path::new("/sysroot/boot");
+        let usable =
+            physical.exists() && image_filesystem(rustix::fs::statfs(physical)?.f_type).is_none();
+        if !usable {
+            return Err(ErrorKind::BootReadOnly).with_context(|| {
+                format!(
+                    "/boot is on a read-only {fs} filesystem and there is no /sysroot/boot; \
+                     mount the boot partition on /boot"
+                )
+            });
+        }
+        log::debug!("/boot is on {fs}; using {}", physical.display());
+        crate::mount::enter_private_namespace()?;
+        util::ensure_writable_mount("/sysroot").context(ErrorKind::BootReadOnly)?;
+        crate::mount::bind_mount(physical, boot)?;
+    }
+    util::ensure_writable_mount(boot).context(ErrorKind::BootReadOnly)
+}
+
+/// Flush all changes to /boot using a strategy suitable for its filesystem;
+/// done before recording them in the state, so that it never describes
+/// files which may not have reached the disk.
+fn sync_boot(sysroot: &openat::Dir) -> Result<()> {
+    let bootdir = sysroot.sub_dir("boot").context("Opening /boot")?;
+    crate::notify::progress("sync", None, &[("path", "/boot".into())]);
+    crate::syncpolicy::sync_filesystem(&bootdir)?;
+    Ok(())
 }
 
 /// daemon implementation of component update
 pub(crate) fn update(name: &str) -> Result<ComponentUpdateResult> {
+    update_from(name, &openat::Dir::open("/")?)
+}
+
+/// Update a component using the update payload in `payload_root`, which
+/// may be e.g. a new ostree deployment rather than the booted root.
+fn update_from(name: &str, payload_root: &openat::Dir) -> Result<ComponentUpdateResult> {
     let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
     let component = component::new_from_name(name)?;
+    if let Some(pin) = state.pinned.get(name) {
+        anyhow::bail!("Component {name} is pinned {pin}");
+    }
     let inst = if let Some(inst) = state.installed.get(name) {
         inst.clone()
     } else {
         anyhow::bail!("Component {} is not installed", name);
     };
     let sysroot = openat::Dir::open("/")?;
-    let update = component.query_update(&sysroot)?;
+    let update = component.query_update(payload_root)?;
+    // During an A/B update, the updated ESP already has a newer version
+    let unconfirmed = state
+        .unconfirmed_esp
+        .as_ref()
+        .filter(|_| component.name() == "EFI")
+        .map(|u| u.update.meta.clone());
     let update = match update.as_ref() {
-        Some(p) if inst.meta.can_upgrade_to(p) => p,
+        Some(p) if unconfirmed.as_ref().unwrap_or(&inst.meta).can_upgrade_to(p) => p,
         _ => return Ok(ComponentUpdateResult::AtLatestVersion),
     };
+    if unconfirmed.is_some() {
+        anyhow::bail!(
+            "The last update of the ESPs is not confirmed; run `bootupctl confirm` or `bootupctl rollback` first"
+        );
+    }
 
-    ensure_writable_boot()?;
+    let hook_update = crate::hooks::HookUpdate {
+        operation: crate::hooks::Operation::Update,
+        component: component.name(),
+        from: &inst.meta,
+        to: Some(update),
+    };
+    let interrupted = crate::hooks::run_around(&hook_update, || {
+        ensure_writable_boot()?;
 
-    let mut pending_container = state.pending.take().unwrap_or_default();
-    let interrupted = pending_container.get(component.name()).cloned();
-    pending_container.insert(component.name().into(), update.clone());
-    let mut state_guard =
-        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
-    state_guard
-        .update_state(&state)
-        .context("Failed to update state")?;
+        let mut pending_container = state.pending.take().unwrap_or_default();
+        let interrupted = pending_container.get(component.name()).cloned();
+        pending_container.insert(component.name().into(), update.clone());
+        let mut state_guard =
+            SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+        state_guard
+            .update_state(&state)
+            .context("Failed to update state")?;
+        crate::try_fail_point!("update::pending");
 
-    let newinst = component
-        .run_update(&state_guard.sysroot, &inst)
+        crate::notify::progress(
+            "update",
+            Some(component.name()),
+            &[("version", update.version.clone())],
+        );
+        run_update(
+            component.as_ref(),
+            payload_root,
+            &state_guard.sysroot,
+            &inst,
+            &mut state,
+        )
         .with_context(|| format!("Failed to update {}", component.name()))?;
-    state.installed.insert(component.name().into(), newinst);
-    pending_container.remove(component.name());
-    state_guard.update_state(&state)?;
+        record_update(&mut state);
+        state.deferred = None;
+        pending_container.remove(component.name());
+        sync_boot(&state_guard.sysroot)?;
+        state_guard.update_state(&state)?;
+        Ok(interrupted)
+    })?;
 
     Ok(ComponentUpdateResult::Updated {
         previous: inst.meta,
@@ -250,6 +634,155 @@ pub(crate) fn update(name: &str) -> Result<ComponentUpdateResult> {
     })
 }
 
+/// Note in `state` that the bootloader just changed, so that it needs to be
+/// confirmed by `mark-boot-successful` on a later boot.
+fn record_update(state: &mut SavedState) {
+    let boot_id = util::boot_id().map_err(|e| log::warn!("{e:#}")).ok();
+    state.last_update = Some(LastUpdate {
+        timestamp: chrono::Utc::now(),
+        boot_id,
+        confirmed: false,
+    });
+}
+
+/// Update `component` in `dest_root` from `inst` to the payload in
+/// `payload_root`, recording the new content in `state`.  With `ab-esp`,
+/// EFI updates only go to the ESP which wasn't booted, and are only
+/// recorded as installed once confirmed.
+fn run_update(
+    component: &dyn Component,
+    payload_root: &openat::Dir,
+    dest_root: &openat::Dir,
+    inst: &crate::model::InstalledContent,
+    state: &mut SavedState,
+) -> Result<()> {
+    let _span = tracing::info_span!("update", component = component.name()).entered();
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if component.name() == "EFI" && crate::config::get().ab_esp {
+        let unconfirmed = efi::Efi::default().run_update_ab(payload_root, inst)?;
+        state.unconfirmed_esp = Some(unconfirmed);
+        return Ok(());
+    }
+    // There's no EFI component here to update one ESP at a time
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    if crate::config::get().ab_esp {
+        anyhow::bail!("ab-esp is not supported on this architecture");
+    }
+    let newinst = component.run_update(payload_root, dest_root, inst)?;
+    state.installed.insert(component.name().into(), newinst);
+    Ok(())
+}
+
+/// daemon implementation of `confirm`: after booting from the ESP updated
+/// last, apply the update to the other one too.  Returns the ESP updated,
+/// if an update was waiting for confirmation.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn confirm() -> Result<Option<String>> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(unconfirmed) = state.unconfirmed_esp.clone() else {
+        return Ok(None);
+    };
+    let efi = efi::Efi::default();
+    let Some(inst) = state.installed.get(efi.name()).cloned() else {
+        anyhow::bail!("Component {} is not installed", efi.name());
+    };
+
+    ensure_writable_boot()?;
+
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let hook_update = crate::hooks::HookUpdate {
+        operation: crate::hooks::Operation::Confirm,
+        component: efi.name(),
+        from: &inst.meta,
+        to: Some(&unconfirmed.update.meta),
+    };
+    crate::hooks::run_around(&hook_update, || {
+        efi.confirm_ab_update(&unconfirmed, &inst)?;
+        state
+            .installed
+            .insert(efi.name().into(), unconfirmed.update.clone());
+        state.unconfirmed_esp = None;
+        sync_boot(&state_guard.sysroot)?;
+        state_guard.update_state(&state)
+    })?;
+    Ok(Some(unconfirmed.other))
+}
+
+/// daemon implementation of `rollback`: undo the A/B update of the ESPs
+/// waiting for confirmation, e.g. after the firmware fell back to booting
+/// the other ESP.  Returns the ESP restored, if there was such an update.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn rollback() -> Result<Option<String>> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(unconfirmed) = state.unconfirmed_esp.clone() else {
+        return Ok(None);
+    };
+    let efi = efi::Efi::default();
+    let Some(inst) = state.installed.get(efi.name()).cloned() else {
+        anyhow::bail!("Component {} is not installed", efi.name());
+    };
+
+    ensure_writable_boot()?;
+
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let hook_update = crate::hooks::HookUpdate {
+        operation: crate::hooks::Operation::Rollback,
+        component: efi.name(),
+        from: &unconfirmed.update.meta,
+        to: Some(&inst.meta),
+    };
+    crate::hooks::run_around(&hook_update, || {
+        efi.rollback_ab_update(&unconfirmed, &inst)?;
+        state.unconfirmed_esp = None;
+        sync_boot(&state_guard.sysroot)?;
+        state_guard.update_state(&state)
+    })?;
+    Ok(Some(unconfirmed.partuuid))
+}
+
+/// The result of `mark-boot-successful`.
+pub(crate) enum BootConfirmation {
+    /// No update is waiting for a successful boot
+    NothingToConfirm,
+    /// The last update was applied during the current boot
+    NotRebooted,
+    /// The last update is now confirmed
+    Confirmed(LastUpdate),
+}
+
+/// daemon implementation of `mark-boot-successful`: the system booted
+/// successfully since the last update, which completes an A/B update of
+/// the ESPs too.
+pub(crate) fn mark_boot_successful() -> Result<BootConfirmation> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(last) = state.last_update.filter(|l| !l.confirmed) else {
+        return Ok(BootConfirmation::NothingToConfirm);
+    };
+    if last.boot_id.is_some() && last.boot_id == Some(util::boot_id()?) {
+        return Ok(BootConfirmation::NotRebooted);
+    }
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    confirm()?;
+
+    ensure_writable_boot()?;
+
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(last) = state.last_update.as_mut() else {
+        return Ok(BootConfirmation::NothingToConfirm);
+    };
+    last.confirmed = true;
+    let last = last.clone();
+    state_guard.update_state(&state)?;
+    Ok(BootConfirmation::Confirmed(last))
+}
+
 /// daemon implementation of component adoption
 pub(crate) fn adopt_and_update(name: &str) -> Result<ContentMetadata> {
     let sysroot = openat::Dir::open("/")?;
@@ -258,123 +791,834 @@ pub(crate) fn adopt_and_update(name: &str) -> Result<ContentMetadata> {
     if state.installed.contains_key(name) {
         anyhow::bail!("Component {} is already installed", name);
     };
+    if let Some(pin) = state.pinned.get(name) {
+        anyhow::bail!("Component {name} is pinned {pin}");
+    }
 
     ensure_writable_boot()?;
 
     let Some(update) = component.query_update(&sysroot)? else {
         anyhow::bail!("Component {} has no available update", name);
     };
+    let Some(adoptable) = component.query_adopt()? else {
+        anyhow::bail!("Component {} is not adoptable", name);
+    };
     let mut state_guard =
         SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
 
-    let inst = component
-        .adopt_update(&state_guard.sysroot, &update)
-        .context("Failed adopt and update")?;
-    state.installed.insert(component.name().into(), inst);
+    let hook_update = crate::hooks::HookUpdate {
+        operation: crate::hooks::Operation::Adopt,
+        component: component.name(),
+        from: &adoptable.version,
+        to: Some(&update),
+    };
+    crate::hooks::run_around(&hook_update, || {
+        crate::try_fail_point!("adopt");
+        let inst = component
+            .adopt_update(&state_guard.sysroot, &update)
+            .context("Failed adopt and update")?;
+        state.installed.insert(component.name().into(), inst);
+        record_update(&mut state);
+        state.deferred = None;
 
-    state_guard.update_state(&state)?;
+        sync_boot(&state_guard.sysroot)?;
+        state_guard.update_state(&state)
+    })?;
     Ok(update)
 }
 
 /// daemon implementation of component validate
-pub(crate) fn validate(name: &str) -> Result<ValidationResult> {
+pub(crate) fn validate(name: &str, fast: bool) -> Result<ValidationResult> {
     let state = SavedState::load_from_disk("/")?.unwrap_or_default();
     let component = component::new_from_name(name)?;
     let Some(inst) = state.installed.get(name) else {
         anyhow::bail!("Component {} is not installed", name);
     };
-    component.validate(inst)
-}
-
-pub(crate) fn status() -> Result<Status> {
-    let mut ret: Status = Default::default();
-    let mut known_components = get_components();
+    let _span = tracing::info_span!("validate", component = name, fast).entered();
     let sysroot = openat::Dir::open("/")?;
-    let state = SavedState::load_from_disk("/")?;
-    if let Some(state) = state {
-        for (name, ic) in state.installed.iter() {
-            log::trace!("Gathering status for installed component: {}", name);
-            let component = known_components
-                .remove(name.as_str())
-                .ok_or_else(|| anyhow!("Unknown component installed: {}", name))?;
-            let component = component.as_ref();
-            let interrupted = state.pending.as_ref().and_then(|p| p.get(name.as_str()));
-            let update = component.query_update(&sysroot)?;
-            let updatable = ComponentUpdatable::from_metadata(&ic.meta, update.as_ref());
-            let adopted_from = ic.adopted_from.clone();
-            ret.components.insert(
-                name.to_string(),
-                ComponentStatus {
-                    installed: ic.meta.clone(),
-                    interrupted: interrupted.cloned(),
-                    update,
-                    updatable,
-                    adopted_from,
-                },
-            );
-        }
-    } else {
-        log::trace!("No saved state");
-    }
+    validate_installed(&sysroot, &state, component.as_ref(), inst, fast)
+}
 
-    // Process the remaining components not installed
-    log::trace!("Remaining known components: {}", known_components.len());
-    for (name, component) in known_components {
-        if let Some(adopt_ver) = component.query_adopt()? {
-            ret.adoptable.insert(name.to_string(), adopt_ver);
-        } else {
-            log::trace!("Not adoptable: {}", name);
-        }
+/// Validate the installed content `inst` of `component`; during an A/B
+/// update, each ESP is checked against the content it should have.
+fn validate_installed(
+    sysroot: &openat::Dir,
+    state: &SavedState,
+    component: &dyn Component,
+    inst: &crate::model::InstalledContent,
+    fast: bool,
+) -> Result<ValidationResult> {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if let Some(u) = state
+        .unconfirmed_esp
+        .as_ref()
+        .filter(|_| component.name() == "EFI")
+    {
+        return efi::Efi::default().validate_ab(u, inst, fast);
     }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let _ = state;
+    component.validate(sysroot, inst, fast)
+}
 
-    Ok(ret)
+/// daemon implementation of `scrub`: read back all installed files from
+/// the storage at idle I/O priority, and record the result.  Returns it,
+/// with the errors which the previous scrub didn't find.
+/// The `errors` which the `last` scrub didn't find already.
+fn new_scrub_errors(errors: &[String], last: Option<&ScrubResult>) -> Vec<String> {
+    let known = last.map(|s| s.errors.as_slice()).unwrap_or_default();
+    errors
+        .iter()
+        .filter(|e| !known.contains(e))
+        .cloned()
+        .collect()
 }
 
-pub(crate) fn print_status_avail(status: &Status) -> Result<()> {
-    let mut avail = Vec::new();
-    for (name, component) in status.components.iter() {
-        if let ComponentUpdatable::Upgradable = component.updatable {
-            avail.push(name.as_str());
+pub(crate) fn scrub() -> Result<(ScrubResult, Vec<String>)> {
+    if let Err(e) = util::set_idle_io_priority() {
+        log::debug!("{e:#}");
+    }
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    // Otherwise we'd only validate what's in the page cache
+    util::drop_file_caches(Path::new("/boot"))?;
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if state.installed.contains_key("EFI") {
+        for esp in efi::Efi::default().ensure_mounted_esps()? {
+            util::drop_file_caches(&esp)?;
         }
     }
-    for (name, adoptable) in status.adoptable.iter() {
-        if adoptable.confident {
-            avail.push(name.as_str());
+    let sysroot = openat::Dir::open("/")?;
+    let mut errors = Vec::new();
+    for (name, inst) in state.installed.iter() {
+        let component = component::new_from_name(name)?;
+        if let ValidationResult::Errors(errs) =
+            validate_installed(&sysroot, &state, component.as_ref(), inst, false)?
+        {
+            errors.extend(errs.into_iter().map(|e| format!("{name}: {e}")));
         }
     }
-    if !avail.is_empty() {
-        println!("Updates available: {}", avail.join(" "));
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if let (ValidationResult::Errors(errs), _) = validate_esps(false)? {
+        errors.extend(errs.into_iter().map(|e| format!("ESPs: {e}")));
     }
-    Ok(())
+    let new = new_scrub_errors(&errors, state.last_scrub.as_ref());
+    let result = ScrubResult {
+        timestamp: chrono::Utc::now(),
+        errors,
+    };
+
+    ensure_writable_boot()?;
+
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    state.last_scrub = Some(result.clone());
+    state_guard.update_state(&state)?;
+    Ok((result, new))
 }
 
-pub(crate) fn print_status(status: &Status) -> Result<()> {
-    if status.components.is_empty() {
-        println!("No components installed.");
+/// Compare the content of all ESPs with each other.  With `resync`, the
+/// installed EFI files are first copied from an ESP which has them
+/// unmodified onto any which don't; returns the ESPs changed.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn validate_esps(resync: bool) -> Result<(ValidationResult, Vec<String>)> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let efi = efi::Efi::default();
+    let Some(inst) = state.installed.get(efi.name()) else {
+        return Ok((ValidationResult::Skip, Vec::new()));
+    };
+    // The ESPs differ until an A/B update is confirmed or rolled back
+    if state.unconfirmed_esp.is_some() {
+        return Ok((ValidationResult::Skip, Vec::new()));
     }
-    for (name, component) in status.components.iter() {
-        println!("Component {}", name);
-        println!("  Installed: {}", component.installed.version);
-
-        if let Some(i) = component.interrupted.as_ref() {
-            println!(
-                "  WARNING: Previous update to {} was interrupted",
-                i.version
-            );
-        }
-        let msg = match component.updatable {
-            ComponentUpdatable::NoUpdateAvailable => Cow::Borrowed("No update found"),
-            ComponentUpdatable::AtLatestVersion => Cow::Borrowed("At latest version"),
-            ComponentUpdatable::WouldDowngrade => Cow::Borrowed("Ignoring downgrade"),
-            ComponentUpdatable::Upgradable => Cow::Owned(format!(
-                "Available: {}",
-                component.update.as_ref().expect("update").version
-            )),
+    let mut resynced = Vec::new();
+    if resync {
+        let tree = inst
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow!("No filetree for installed EFI found!"))?;
+        ensure_writable_boot()?;
+        let sysroot = openat::Dir::open("/")?;
+        let _guard =
+            SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+        let hook_update = crate::hooks::HookUpdate {
+            operation: crate::hooks::Operation::Resync,
+            component: efi.name(),
+            from: &inst.meta,
+            to: Some(&inst.meta),
         };
-        println!("  Update: {}", msg);
+        resynced = crate::hooks::run_around(&hook_update, || efi.resync_esps(tree))?;
     }
+    let errs = efi.compare_esps()?;
+    let r = if errs.is_empty() {
+        ValidationResult::Valid
+    } else {
+        ValidationResult::Errors(errs)
+    };
+    Ok((r, resynced))
+}
 
-    if status.adoptable.is_empty() {
+/// The files an update of a component would change.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComponentDiff {
+    pub(crate) installed: ContentMetadata,
+    pub(crate) update: Option<ContentMetadata>,
+    /// `None` if the component doesn't track its files
+    pub(crate) changes: Option<Vec<FileChange>>,
+}
+
+/// daemon implementation of component diff
+pub(crate) fn diff(name: &str) -> Result<ComponentDiff> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let component = component::new_from_name(name)?;
+    let Some(inst) = state.installed.get(name) else {
+        anyhow::bail!("Component {} is not installed", name);
+    };
+    let sysroot = openat::Dir::open("/")?;
+    let update = component.query_update(&sysroot)?;
+    let changes = component.diff_update(&sysroot, inst)?;
+    Ok(ComponentDiff {
+        installed: inst.meta.clone(),
+        update,
+        changes,
+    })
+}
+
+/// daemon implementation of component uninstall
+pub(crate) fn uninstall(name: &str, keep_files: bool) -> Result<ContentMetadata> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let component = component::new_from_name(name)?;
+    let Some(inst) = state.installed.remove(name) else {
+        anyhow::bail!("Component {} is not installed", name);
+    };
+    if let Some(pending) = state.pending.as_mut() {
+        pending.remove(name);
+    }
+
+    ensure_writable_boot()?;
+
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let hook_update = crate::hooks::HookUpdate {
+        operation: crate::hooks::Operation::Uninstall,
+        component: component.name(),
+        from: &inst.meta,
+        to: None,
+    };
+    crate::hooks::run_around(&hook_update, || {
+        if !keep_files {
+            component
+                .uninstall(&state_guard.sysroot, &inst)
+                .with_context(|| format!("Failed to uninstall {}", component.name()))?;
+        }
+        sync_boot(&state_guard.sysroot)?;
+        state_guard.update_state(&state)
+    })?;
+    Ok(inst.meta)
+}
+
+/// The files of `old` which `new` doesn't have, comparing names the way
+/// FAT does, so that removing them doesn't remove any file of `new`.
+fn stale_files(
+    old: &crate::filetree::FileTree,
+    new: &crate::filetree::FileTree,
+) -> crate::filetree::FileTree {
+    let children = old
+        .children
+        .iter()
+        .filter(|(k, _)| !new.children.keys().any(|n| n.eq_ignore_ascii_case(k)))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    crate::filetree::FileTree { children }
+}
+
+/// daemon implementation of `reinstall`: write the complete payload of the
+/// component `name` again over the installed files, rather than just the
+/// differences, e.g. after the ESP was corrupted.  Installed files which
+/// the payload no longer has are only removed once that succeeded, so
+/// that a failure never leaves the system without a bootloader.
+pub(crate) fn reinstall(name: &str) -> Result<ContentMetadata> {
+    let sysroot = openat::Dir::open("/")?;
+    let component = component::new_from_name(name)?;
+    let devices = crate::blockdev::get_devices("/")?;
+
+    ensure_writable_boot()?;
+
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    if let Some(pin) = state.pinned.get(name) {
+        anyhow::bail!("Component {name} is pinned {pin}");
+    }
+    let Some(inst) = state.installed.get(name).cloned() else {
+        anyhow::bail!("Component {} is not installed", name);
+    };
+    let to = component.query_update(&state_guard.sysroot)?;
+    let hook_update = crate::hooks::HookUpdate {
+        operation: crate::hooks::Operation::Reinstall,
+        component: component.name(),
+        from: &inst.meta,
+        to: Some(to.as_ref().unwrap_or(&inst.meta)),
+    };
+    crate::hooks::run_around(&hook_update, || {
+        let mut newinst = component
+            .install_to_devices(&state_guard.sysroot, "/", &devices, false)
+            .with_context(|| format!("Failed to reinstall {}", component.name()))?;
+        // Directories which are no longer shipped are left in place, like on
+        // updates, and so still tracked
+        if let (Some(vendors), Some(old)) = (newinst.vendors.as_mut(), inst.vendors.as_ref()) {
+            for (vendor, ft) in old {
+                if !vendors.contains_key(vendor) {
+                    log::info!("Retaining EFI/{vendor} which is not part of the payload");
+                    vendors.insert(vendor.clone(), ft.clone());
+                }
+            }
+            let children = vendors.values().flat_map(|t| t.children.clone()).collect();
+            newinst.filetree = Some(crate::filetree::FileTree { children });
+        }
+        let stale = match (inst.filetree.as_ref(), newinst.filetree.as_ref()) {
+            (Some(old), Some(new)) => Some(stale_files(old, new)),
+            _ => None,
+        };
+        let meta = newinst.meta.clone();
+        state.installed.insert(name.into(), newinst);
+        if let Some(pending) = state.pending.as_mut() {
+            pending.remove(name);
+        }
+        sync_boot(&state_guard.sysroot)?;
+        state_guard.update_state(&state)?;
+        if let Some(stale) = stale.filter(|t| !t.children.is_empty()) {
+            log::info!("Removing {} files no longer shipped", stale.children.len());
+            let stale = crate::model::InstalledContent {
+                filetree: Some(stale),
+                ..inst.clone()
+            };
+            if let Err(e) = component.uninstall(&state_guard.sysroot, &stale) {
+                log::warn!(
+                    "Failed to remove stale files of {}: {e:#}",
+                    component.name()
+                );
+            }
+        }
+        Ok(meta)
+    })
+}
+
+/// Record that the updates of `components` were deferred until the next
+/// maintenance window.
+fn defer_updates(components: &[&str]) -> Result<()> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    state.deferred = Some(DeferredUpdate {
+        timestamp: chrono::Utc::now(),
+        components: components.iter().map(ToString::to_string).collect(),
+    });
+
+    ensure_writable_boot()?;
+
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    state_guard.update_state(&state)?;
+    Ok(())
+}
+
+/// daemon implementation of `pin`: exclude the component `name` from
+/// updates and adoption.
+pub(crate) fn pin(name: &str, reason: Option<&str>) -> Result<()> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    // Only known components can be pinned
+    component::new_from_name(name)?;
+    if let Some(pin) = state.pinned.get(name) {
+        anyhow::bail!("Component {name} is already pinned {pin}");
+    }
+    state.pinned.insert(
+        name.to_string(),
+        Pin {
+            timestamp: chrono::Utc::now(),
+            reason: reason.map(ToOwned::to_owned),
+        },
+    );
+
+    ensure_writable_boot()?;
+
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    state_guard.update_state(&state)?;
+    Ok(())
+}
+
+/// daemon implementation of `unpin`; returns the removed pin, if any.
+pub(crate) fn unpin(name: &str) -> Result<Option<Pin>> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(pin) = state.pinned.remove(name) else {
+        return Ok(None);
+    };
+
+    ensure_writable_boot()?;
+
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    state_guard.update_state(&state)?;
+    Ok(Some(pin))
+}
+
+pub(crate) fn status() -> Result<Status> {
+    status_of(&[])
+}
+
+/// The status of the components in `only`, or all if it's empty; skipping
+/// the others avoids e.g. slow probes for adoptable BIOS installations.
+pub(crate) fn status_of(only: &[String]) -> Result<Status> {
+    status_of_root(only, Path::new("/"))
+}
+
+/// Like [`status_of`], with the updates shipped in `root`, e.g. a new
+/// ostree deployment, rather than in the booted root.
+fn status_of_root(only: &[String], root: &Path) -> Result<Status> {
+    for name in only {
+        // Reject unknown component names
+        component::new_from_name(name)?;
+    }
+    let wanted = |name: &str| only.is_empty() || only.iter().any(|n| n == name);
+    let mut ret: Status = Default::default();
+    let mut known_components = get_components();
+    known_components.retain(|name, _| wanted(name));
+    let sysroot = openat::Dir::open(root)?;
+    let state = SavedState::load_from_disk("/")?;
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    let recorded_devices = state.as_ref().and_then(|s| s.devices.clone());
+    if let Some(state) = state {
+        ret.last_update = state.last_update.clone();
+        ret.last_scrub = state.last_scrub.clone();
+        ret.pinned = state
+            .pinned
+            .iter()
+            .filter(|(name, _)| wanted(name))
+            .map(|(name, pin)| (name.clone(), pin.clone()))
+            .collect();
+        ret.deferred = state.deferred.clone();
+        for (name, ic) in state.installed.iter().filter(|(name, _)| wanted(name)) {
+            log::trace!("Gathering status for installed component: {}", name);
+            // Components which are only adopted explicitly aren't in the
+            // default set.
+            let component = match known_components.remove(name.as_str()) {
+                Some(c) => c,
+                None => component::new_from_name(name)
+                    .with_context(|| format!("Unknown component installed: {}", name))?,
+            };
+            let component = component.as_ref();
+            let interrupted = state.pending.as_ref().and_then(|p| p.get(name.as_str()));
+            let update = component.query_update(&sysroot)?;
+            let unconfirmed = state.unconfirmed_esp.as_ref().filter(|_| name == "EFI");
+            // The updated ESP of an A/B update already has a newer version
+            let latest = unconfirmed.map_or(&ic.meta, |u| &u.update.meta);
+            let updatable = ComponentUpdatable::from_metadata(latest, update.as_ref());
+            let adopted_from = ic.adopted_from.clone();
+            let partitions = ic
+                .partitions
+                .iter()
+                .flatten()
+                .map(|p| (p.partuuid.clone(), p.sha512.to_string()))
+                .collect();
+            let mut warnings = ic.warnings.clone().unwrap_or_default();
+            if let Some(u) = unconfirmed {
+                warnings.push(format!(
+                    "Only the ESP {} is updated to {}; run `bootupctl confirm` once booted from it, or `bootupctl rollback`",
+                    u.partuuid, u.update.meta.version
+                ));
+            }
+            // Like the ESP information below, this is informational only.
+            match component.status_warnings(&sysroot, ic) {
+                Ok(w) => warnings.extend(w),
+                Err(e) => log::debug!("Failed to check {name}: {e:#}"),
+            }
+            let firmware = firmware_status(component, Some(&ic.meta), update.as_ref())?;
+            ret.components.insert(
+                name.to_string(),
+                ComponentStatus {
+                    installed: ic.meta.clone(),
+                    interrupted: interrupted.cloned(),
+                    update,
+                    updatable,
+                    adopted_from,
+                    partitions,
+                    warnings,
+                    firmware,
+                },
+            );
+        }
+    } else {
+        log::trace!("No saved state");
+    }
+
+    // Process the remaining components not installed
+    log::trace!("Remaining known components: {}", known_components.len());
+    for (name, component) in known_components {
+        if let Some(adopt_ver) = component.query_adopt()? {
+            ret.adoptable.insert(name.to_string(), adopt_ver);
+        } else {
+            log::trace!("Not adoptable: {}", name);
+        }
+    }
+
+    // This is informational only, so don't fail status if e.g. the
+    // block device topology is unusual.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if wanted("EFI") {
+        match crate::blockdev::find_colocated_esp_info("/") {
+            Ok(esp) => ret.esp = esp,
+            Err(e) => log::debug!("Failed to gather ESP information: {e:#}"),
+        }
+        if let Some(devices) = recorded_devices.as_ref() {
+            for esp in ret.esp.iter_mut() {
+                esp.installed = Some(
+                    devices
+                        .iter()
+                        .any(|d| d.esp_partuuid.is_some() && d.esp_partuuid == esp.partuuid),
+                );
+            }
+        }
+        match efi::boot_info() {
+            Ok(boot) => ret.boot = Some(boot),
+            Err(e) => log::debug!("Failed to gather boot information: {e:#}"),
+        }
+    }
+    if !util::running_in_container() && Path::new(crate::ostreeutil::OSTREE_BOOTED).exists() {
+        match deployment_info(&ret.components) {
+            Ok(d) => ret.deployment = Some(d),
+            Err(e) => log::debug!("Failed to gather deployment information: {e:#}"),
+        }
+    }
+    ret.platform = Some(platform_info());
+    ret.host = Some(host_info());
+    ret.generated_at = Some(chrono::Utc::now());
+
+    if only.is_empty() && root == Path::new("/") {
+        if let Err(e) = write_status_snapshot(Path::new(STATUS_SNAPSHOT), &ret) {
+            log::debug!("Failed to write {STATUS_SNAPSHOT}: {e:#}");
+        }
+    }
+    Ok(ret)
+}
+
+/// Write `status` to `path`, readable by everyone.
+fn write_status_snapshot(path: &Path, status: &Status) -> Result<()> {
+    let dir = path.parent().expect("parent");
+    std::fs::create_dir_all(dir)?;
+    let dir = openat::Dir::open(dir)?;
+    let name = path.file_name().expect("filename");
+    dir.write_file_contents(name, 0o644, serde_json::to_vec(status)?)?;
+    Ok(())
+}
+
+/// Update the status snapshot after changing what's installed.
+fn refresh_status_snapshot() {
+    if let Err(e) = status() {
+        log::debug!("Failed to refresh the status snapshot: {e:#}");
+    }
+}
+
+/// The status as of the last time root ran `status` or an update, from
+/// the snapshot at `path`; returns it with the time it was written.
+#[context("Reading status snapshot {}", path.display())]
+pub(crate) fn read_status_snapshot(path: &Path) -> Result<(Status, chrono::DateTime<chrono::Utc>)> {
+    let f = File::open(path)?;
+    let written = f.metadata()?.modified()?.into();
+    let status = serde_json::from_reader(BufReader::new(f))?;
+    Ok((status, written))
+}
+
+/// The booted ostree deployment, and whether the update payloads it ships
+/// are what's installed according to `components`.
+fn deployment_info(components: &BTreeMap<String, ComponentStatus>) -> Result<DeploymentInfo> {
+    let cmdline = std::fs::read_to_string("/proc/cmdline")?;
+    let deployment = crate::ostreeutil::find_booted_deployment(Path::new("/sysroot"), &cmdline)?;
+    let checksum = crate::ostreeutil::deployment_checksum(&deployment)?;
+    let image = crate::ostreeutil::deployment_image(&deployment)?;
+    let image_digest = match image {
+        Some(_) => crate::ostreeutil::manifest_digest(&checksum)?,
+        None => None,
+    };
+    // The booted deployment is the root, whose payloads status compared
+    // with the installed components already
+    let payload_matches = !components.values().any(|c| {
+        matches!(
+            c.updatable,
+            ComponentUpdatable::Upgradable | ComponentUpdatable::WouldDowngrade
+        )
+    });
+    let bootloader = crate::ostreeutil::get_ostree_bootloader()?;
+    let static_config = crate::ostreeutil::static_grub_config(Path::new("/boot"))?;
+    let bootloader_warning =
+        crate::ostreeutil::bootloader_mismatch(bootloader.as_deref(), static_config);
+    Ok(DeploymentInfo {
+        checksum,
+        image,
+        image_digest,
+        payload_matches,
+        bootloader,
+        bootloader_warning,
+    })
+}
+
+/// Read the first line of `path`, if it exists and isn't empty.
+fn read_first_line(path: &str) -> Option<String> {
+    let s = std::fs::read_to_string(path).ok()?;
+    let line = s.lines().next()?.trim();
+    (!line.is_empty()).then(|| line.to_string())
+}
+
+/// Identify this system, for status collected from many.
+fn host_info() -> HostInfo {
+    let booted_deployment = if Path::new(crate::ostreeutil::OSTREE_BOOTED).exists() {
+        std::fs::read_to_string("/proc/cmdline")
+            .map_err(anyhow::Error::from)
+            .and_then(|cmdline| {
+                crate::ostreeutil::find_booted_deployment(Path::new("/sysroot"), &cmdline)
+            })
+            .map_err(|e| log::debug!("Failed to find the booted deployment: {e:#}"))
+            .ok()
+            .and_then(|d| Some(d.file_name()?.to_str()?.to_string()))
+    } else {
+        None
+    };
+    HostInfo {
+        hostname: read_first_line("/proc/sys/kernel/hostname"),
+        machine_id: read_first_line("/etc/machine-id"),
+        bootupd_version: crate_version!().to_string(),
+        booted_deployment,
+    }
+}
+
+/// The components and optional features supported on this system.
+pub(crate) fn platform_info() -> PlatformInfo {
+    #[allow(unused_mut)]
+    let mut features = Vec::new();
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        let nvram = efi::is_efi_booted().and_then(|booted| {
+            Ok(booted && !efi::efivars_readonly()? && !crate::config::get().no_nvram)
+        });
+        match nvram {
+            Ok(true) => features.push("nvram"),
+            Ok(false) => {}
+            Err(e) => log::debug!("Failed to check EFI variables: {e:#}"),
+        }
+        if crate::tpm::available() {
+            features.push("tpm");
+        }
+    }
+    if crate::label::enabled() {
+        features.push("selinux");
+    }
+    #[cfg(all(feature = "extlinux", target_arch = "x86_64"))]
+    features.push("extlinux");
+    PlatformInfo {
+        protocol_version: crate::model::PROTOCOL_VERSION,
+        verbs: crate::cli::ctl_verbs(),
+        arch: std::env::consts::ARCH.to_string(),
+        components: component::builtin_names()
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        features: features.into_iter().map(String::from).collect(),
+    }
+}
+
+/// Print the platform section of `status`.
+pub(crate) fn print_platform(platform: &PlatformInfo) {
+    println!("Platform: {}", platform.arch);
+    println!("  Protocol version: {}", platform.protocol_version);
+    println!("  Components: {}", platform.components.join(", "));
+    if !platform.features.is_empty() {
+        println!("  Features: {}", platform.features.join(", "));
+    }
+    println!("  Verbs: {}", platform.verbs.join(", "));
+}
+
+/// Names of components with an update available, including confidently
+/// adoptable ones.
+pub(crate) fn updates_available(status: &Status) -> Vec<&str> {
+    let mut avail = Vec::new();
+    for (name, component) in status.components.iter() {
+        if let ComponentUpdatable::Upgradable = component.updatable {
+            avail.push(name.as_str());
+        }
+    }
+    for (name, adoptable) in status.adoptable.iter() {
+        if adoptable.confident {
+            avail.push(name.as_str());
+        }
+    }
+    // Pinned components won't be updated anyway
+    avail.retain(|name| !status.pinned.contains_key(*name));
+    avail
+}
+
+/// Wait until an update is available, re-checking whenever the update
+/// payloads change.  On ostree systems the booted `/usr` never changes, so
+/// these are the payloads of the newest deployment, e.g. one staged by an
+/// upgrade, and new deployments are watched for too.
+pub(crate) fn wait_for_update(only: &[String]) -> Result<Status> {
+    let ostree_sysroot = (!util::running_in_container()
+        && Path::new(crate::ostreeutil::OSTREE_BOOTED).exists())
+    .then(|| Path::new("/sysroot"));
+    loop {
+        let (root, mut watched) = match ostree_sysroot {
+            Some(sysroot) => (
+                crate::ostreeutil::find_newest_deployment(sysroot)?,
+                crate::ostreeutil::deploy_dirs(sysroot)?,
+            ),
+            None => (PathBuf::from("/"), Vec::new()),
+        };
+        let updates = root.join(crate::model::BOOTUPD_UPDATES_DIR);
+        // Also watch the parent, in case the updates are replaced wholesale.
+        watched.push(updates.parent().expect("parent").to_path_buf());
+        watched.push(updates.clone());
+        let watched = watched.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+        // Start watching before checking, so we can't miss a change.
+        let watcher = util::DirWatcher::new(&watched)?;
+        let status = status_of_root(only, &root)?;
+        if !updates_available(&status).is_empty() {
+            return Ok(status);
+        }
+        log::info!("No updates available; watching {updates:?}");
+        watcher.wait()?;
+        // Let the writer finish before looking again.
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+}
+
+pub(crate) fn print_status_avail(status: &Status) -> Result<()> {
+    let avail = updates_available(status);
+    if !avail.is_empty() {
+        println!("Updates available: {}", avail.join(" "));
+    }
+    Ok(())
+}
+
+/// Describe the ESPs found for the EFI component, e.g. `2/2-ok` if the
+/// bootloader was installed to both; just the number found if the disks
+/// installed to weren't recorded.
+fn summarize_esps(esps: &[EspInfo]) -> String {
+    let installed = esps.iter().filter_map(|e| e.installed).collect::<Vec<_>>();
+    if installed.is_empty() {
+        return esps.len().to_string();
+    }
+    let n = installed.iter().filter(|&&i| i).count();
+    let health = if n == esps.len() { "ok" } else { "degraded" };
+    format!("{n}/{}-{health}", esps.len())
+}
+
+/// Describe the status of component `name` in one line of `key=value`
+/// fields, e.g. for dashboards.
+fn summary_line(status: &Status, name: &str, component: &ComponentStatus) -> String {
+    let state = match component.updatable {
+        ComponentUpdatable::NoUpdateAvailable => "no-update-available",
+        ComponentUpdatable::AtLatestVersion => "at-latest-version",
+        ComponentUpdatable::Upgradable => "upgradable",
+        ComponentUpdatable::WouldDowngrade => "would-downgrade",
+    };
+    let mut r = format!(
+        "{name} installed={} available={} state={state}",
+        component.installed.version,
+        component
+            .update
+            .as_ref()
+            .map_or("none", |u| u.version.as_str())
+    );
+    if component.interrupted.is_some() {
+        r.push_str(" interrupted=yes");
+    }
+    if status.pinned.contains_key(name) {
+        r.push_str(" pinned=yes");
+    }
+    if !component.warnings.is_empty() {
+        r.push_str(&format!(" warnings={}", component.warnings.len()));
+    }
+    if name == "EFI" && !status.esp.is_empty() {
+        r.push_str(&format!(" esp={}", summarize_esps(&status.esp)));
+    }
+    r
+}
+
+/// Print one line per installed component.
+pub(crate) fn print_status_summary(status: &Status) {
+    for (name, component) in status.components.iter() {
+        println!("{}", summary_line(status, name, component));
+    }
+}
+
+/// Print the package each installed file came from, according to the
+/// provenance shipped with the updates.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn print_provenance() -> Result<()> {
+    let sysroot = openat::Dir::open("/")?;
+    let Some(state) = SavedState::load_from_disk("/")? else {
+        return Ok(());
+    };
+    for (name, ic) in state.installed.iter() {
+        let Some(tree) = ic.filetree.as_ref() else {
+            continue;
+        };
+        let prov = crate::provenance::load(&sysroot, name)?;
+        println!("Component {} files:", name);
+        for (path, meta) in tree.children.iter() {
+            let package = crate::provenance::package_for(&prov, &meta.sha512);
+            println!("  {}: {}", path, package.unwrap_or("unknown package"));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn print_status(status: &Status) -> Result<()> {
+    if status.components.is_empty() {
+        println!("No components installed.");
+    }
+    for (name, component) in status.components.iter() {
+        println!("Component {}", name);
+        println!("  Installed: {}", component.installed.version);
+        if let Some(pin) = status.pinned.get(name) {
+            println!("  Pinned {pin}");
+        }
+        for (partuuid, digest) in component.partitions.iter() {
+            println!("  Partition {}: {}", partuuid, digest);
+        }
+
+        if let Some(i) = component.interrupted.as_ref() {
+            println!(
+                "  WARNING: Previous update to {} was interrupted",
+                i.version
+            );
+        }
+        for w in component.warnings.iter() {
+            println!("  WARNING: {}", w);
+        }
+        let msg = match component.updatable {
+            ComponentUpdatable::NoUpdateAvailable => Cow::Borrowed("No update found"),
+            ComponentUpdatable::AtLatestVersion => Cow::Borrowed("At latest version"),
+            ComponentUpdatable::WouldDowngrade => Cow::Borrowed("Ignoring downgrade"),
+            ComponentUpdatable::Upgradable => Cow::Owned(format!(
+                "Available: {}",
+                component.update.as_ref().expect("update").version
+            )),
+        };
+        println!("  Update: {}", msg);
+        for f in component.firmware.iter() {
+            println!("  Firmware {}", describe_firmware(f));
+        }
+    }
+
+    if status.adoptable.is_empty() {
         println!("No components are adoptable.");
     }
     for (name, adopt) in status.adoptable.iter() {
@@ -385,146 +1629,956 @@ pub(crate) fn print_status(status: &Status) -> Result<()> {
             println!("Adoptable: {}: {}", name, ver);
         }
     }
+    for (name, pin) in status.pinned.iter() {
+        if !status.components.contains_key(name) {
+            println!("Pinned: {name} {pin}");
+        }
+    }
+
+    if let Some(last) = status.last_update.as_ref() {
+        let confirmed = if last.confirmed {
+            "confirmed"
+        } else {
+            "not yet confirmed"
+        };
+        println!(
+            "Last update: {} ({} by a successful boot)",
+            last.timestamp.to_rfc3339(),
+            confirmed
+        );
+    }
+
+    if let Some(deferred) = status.deferred.as_ref() {
+        println!(
+            "Deferred until the next maintenance window: {} (since {})",
+            deferred.components.join(" "),
+            deferred.timestamp.to_rfc3339()
+        );
+    }
+
+    if let Some(scrub) = status.last_scrub.as_ref() {
+        println!(
+            "Last scrub: {} ({} errors)",
+            scrub.timestamp.to_rfc3339(),
+            scrub.errors.len()
+        );
+    }
+
+    if let Some(d) = status.deployment.as_ref() {
+        println!("Booted deployment: {}", d.checksum);
+        if let Some(image) = d.image.as_deref() {
+            match d.image_digest.as_deref() {
+                Some(digest) => println!("  Image: {image}@{digest}"),
+                None => println!("  Image: {image}"),
+            }
+        }
+        if !d.payload_matches {
+            println!("  WARNING: The bootloader doesn't match the payload in this deployment");
+        }
+        if let Some(b) = d.bootloader.as_deref() {
+            println!("  Bootloader config: {b}");
+        }
+        if let Some(w) = d.bootloader_warning.as_deref() {
+            println!("  WARNING: {w}");
+        }
+    }
 
     if let Some(coreos_aleph) = coreos::get_aleph_version(Path::new("/"))? {
         println!("CoreOS aleph version: {}", coreos_aleph.aleph.version);
     }
 
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    {
-        let boot_method = if efi::is_efi_booted()? { "EFI" } else { "BIOS" };
-        println!("Boot method: {}", boot_method);
+    if let Some(boot) = status.boot.as_ref() {
+        println!("Boot method: {}", boot.method);
+        if let Some(secure_boot) = boot.secure_boot {
+            let state = if secure_boot { "enabled" } else { "disabled" };
+            println!("Secure Boot: {}", state);
+        }
+        match (
+            boot.firmware_vendor.as_ref(),
+            boot.firmware_version.as_ref(),
+        ) {
+            (Some(vendor), Some(version)) => println!("Firmware: {} {}", vendor, version),
+            (Some(v), None) | (None, Some(v)) => println!("Firmware: {}", v),
+            (None, None) => {}
+        }
+        if let Some(loader) = boot.loader_info.as_ref() {
+            println!("Loader: {}", loader);
+        }
+        if let Some(stub) = boot.stub_info.as_ref() {
+            println!("Stub: {}", stub);
+        }
+    }
+
+    Ok(())
+}
+
+/// Update all components; with `migrate_static_configs`, then also
+/// migrate ostree systems to a static GRUB config.
+pub(crate) fn client_run_update(migrate_static_configs: bool, now: bool) -> Result<()> {
+    let r = update_all(migrate_static_configs, now);
+    // Whether or not anything changed: the snapshot is on /run, which is
+    // cleared at boot, and this is what the boot-time unit runs.
+    refresh_status_snapshot();
+    r
+}
+
+fn update_all(migrate_static_configs: bool, now: bool) -> Result<()> {
+    crate::try_fail_point!("update");
+    ensure_supported_platform()?;
+    check_boot_devices()?;
+    let status: Status = status()?;
+    if status.components.is_empty() && status.adoptable.is_empty() {
+        println!("No components installed.");
+        return Ok(());
+    }
+    if !now && !crate::maintenance::in_window()? {
+        let avail = updates_available(&status);
+        if avail.is_empty() {
+            println!("No update available for any component.");
+        } else {
+            defer_updates(&avail)?;
+            println!(
+                "Outside of the maintenance windows; deferred updates of: {}",
+                avail.join(" ")
+            );
+        }
+        return Ok(());
+    }
+    let mut updated = false;
+    for (name, cstatus) in status.components.iter() {
+        match cstatus.updatable {
+            ComponentUpdatable::Upgradable => {}
+            _ => continue,
+        };
+        if let Some(pin) = status.pinned.get(name) {
+            println!("Skipping {name}, pinned {pin}");
+            continue;
+        }
+        match update(name)? {
+            ComponentUpdateResult::AtLatestVersion => {
+                // Shouldn't happen unless we raced with another client
+                eprintln!(
+                    "warning: Expected update for {}, raced with a different client?",
+                    name
+                );
+                continue;
+            }
+            ComponentUpdateResult::Updated {
+                previous,
+                interrupted,
+                new,
+            } => {
+                if let Some(i) = interrupted {
+                    eprintln!(
+                        "warning: Continued from previous interrupted update: {}",
+                        i.version,
+                    );
+                }
+                println!("Previous {}: {}", name, previous.version);
+                println!("Updated {}: {}", name, new.version);
+            }
+        }
+        updated = true;
+    }
+    for (name, adoptable) in status.adoptable.iter() {
+        if status.pinned.contains_key(name) {
+            println!("Not adopting pinned component {name}");
+        } else if adoptable.confident {
+            let r: ContentMetadata = adopt_and_update(name)?;
+            println!("Adopted and updated: {}: {}", name, r.version);
+            updated = true;
+        } else {
+            println!("Component {} requires explicit adopt-and-update", name);
+        }
+    }
+    if !updated {
+        println!("No update available for any component.");
+    }
+    if migrate_static_configs {
+        if Path::new(crate::ostreeutil::SYSROOT_REPO).exists() {
+            client_run_migrate_static_grub_config(false, false)?;
+        } else {
+            println!("Not an ostree system, skipping static GRUB config migration");
+        }
+    }
+    Ok(())
+}
+
+/// Check the newest ostree deployment (or `deployment`) for bootloader
+/// updates, applying them if `update-on-deploy` is configured.
+pub(crate) fn client_run_ostree_post_deploy(deployment: Option<&Path>) -> Result<()> {
+    ensure_supported_platform()?;
+    let deployment = match deployment {
+        Some(d) => d.to_owned(),
+        None => crate::ostreeutil::find_newest_deployment(Path::new("/sysroot"))?,
+    };
+    let payload_root = openat::Dir::open(&deployment)
+        .with_context(|| format!("Opening deployment {deployment:?}"))?;
+    let Some(state) = SavedState::load_from_disk("/")? else {
+        println!("No components installed.");
+        return Ok(());
+    };
+    let mut updatable = Vec::new();
+    for (name, ic) in state.installed.iter() {
+        if state.pinned.contains_key(name) {
+            continue;
+        }
+        let component = component::new_from_name(name)?;
+        let update = component.query_update(&payload_root)?;
+        if let ComponentUpdatable::Upgradable =
+            ComponentUpdatable::from_metadata(&ic.meta, update.as_ref())
+        {
+            let update = update.expect("update");
+            println!(
+                "Update available in {}: {}: {} -> {}",
+                deployment.display(),
+                name,
+                ic.meta.version,
+                update.version
+            );
+            updatable.push(name.as_str());
+        }
+    }
+    if updatable.is_empty() {
+        println!("No update available for any component.");
+        return Ok(());
+    }
+    if !crate::config::get().update_on_deploy {
+        println!("Run `bootupctl update` after booting the new deployment.");
+        return Ok(());
+    }
+    if !crate::maintenance::in_window()? {
+        println!(
+            "Outside of the maintenance windows; run `bootupctl update` after \
+             booting the new deployment."
+        );
+        return Ok(());
+    }
+    check_boot_devices()?;
+    for name in updatable {
+        match update_from(name, &payload_root)? {
+            ComponentUpdateResult::AtLatestVersion => {
+                eprintln!(
+                    "warning: Expected update for {}, raced with a different client?",
+                    name
+                );
+            }
+            ComponentUpdateResult::Updated { previous, new, .. } => {
+                println!("Previous {}: {}", name, previous.version);
+                println!("Updated {}: {}", name, new.version);
+            }
+        }
+    }
+    refresh_status_snapshot();
+    Ok(())
+}
+
+pub(crate) fn client_run_adopt_and_update(from: Option<&str>) -> Result<()> {
+    ensure_supported_platform()?;
+    if let Some(name) = from {
+        let r: ContentMetadata = adopt_and_update(name)?;
+        println!("Adopted and updated: {}: {}", name, r.version);
+        refresh_status_snapshot();
+        return Ok(());
+    }
+    let status: Status = status()?;
+    if status.adoptable.is_empty() {
+        println!("No components are adoptable.");
+    } else {
+        for (name, _) in status.adoptable.iter() {
+            if status.pinned.contains_key(name) {
+                println!("Not adopting pinned component {name}");
+                continue;
+            }
+            let r: ContentMetadata = adopt_and_update(name)?;
+            println!("Adopted and updated: {}: {}", name, r.version);
+        }
+        refresh_status_snapshot();
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn client_run_confirm() -> Result<()> {
+    match confirm()? {
+        Some(other) => println!("Confirmed update; updated ESP {other}"),
+        None => println!("No update to confirm."),
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn client_run_rollback() -> Result<()> {
+    match rollback()? {
+        Some(esp) => println!("Rolled back update; restored ESP {esp}"),
+        None => println!("No update to roll back."),
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn client_run_bootorder_gc(dry_run: bool) -> Result<()> {
+    let stale = efi::Efi::default().gc_boot_entries(dry_run)?;
+    if stale.is_empty() {
+        println!("No stale EFI boot entries.");
+    }
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    for e in stale {
+        println!("{verb} Boot{} {:?}: {}", e.id, e.name, e.reason);
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_mark_boot_successful() -> Result<()> {
+    match mark_boot_successful()? {
+        BootConfirmation::NothingToConfirm => println!("No update to confirm."),
+        BootConfirmation::NotRebooted => {
+            println!("The last update was applied during this boot; not confirming it yet.")
+        }
+        BootConfirmation::Confirmed(last) => {
+            println!("Confirmed update from {}", last.timestamp.to_rfc3339())
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_scrub() -> Result<()> {
+    ensure_supported_platform()?;
+    let (result, new) = scrub()?;
+    for err in result.errors.iter() {
+        if new.contains(err) {
+            eprintln!("New: {err}");
+        } else {
+            eprintln!("Known: {err}");
+        }
+    }
+    if !new.is_empty() {
+        return Err(Outcome::ValidationFailed.into());
+    }
+    if result.errors.is_empty() {
+        println!("Scrubbed: all installed files are intact.");
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_pin(name: &str, reason: Option<&str>) -> Result<()> {
+    pin(name, reason)?;
+    println!("Pinned {name}");
+    Ok(())
+}
+
+pub(crate) fn client_run_unpin(name: &str) -> Result<()> {
+    match unpin(name)? {
+        Some(pin) => println!("Unpinned {name}, pinned {pin}"),
+        None => println!("Component {name} is not pinned."),
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_authenticate_state() -> Result<()> {
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    if state_guard.authenticate_state()? {
+        println!("Authenticated the state.");
+    } else {
+        println!("State is already authenticated.");
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_recover_state(use_backup: bool) -> Result<()> {
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    state_guard.recover_state(use_backup)?;
+    if use_backup {
+        println!("Restored the state from the backup.");
+    } else {
+        println!("Accepted the current state.");
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_uninstall(components: &[String], keep_files: bool) -> Result<()> {
+    for name in components {
+        let r = uninstall(name, keep_files)?;
+        if keep_files {
+            println!("Forgot {}: {}", name, r.version);
+        } else {
+            println!("Uninstalled {}: {}", name, r.version);
+        }
     }
+    Ok(())
+}
 
+pub(crate) fn client_run_reinstall(components: &[String]) -> Result<()> {
+    for name in components {
+        let r = reinstall(name)?;
+        println!("Reinstalled {}: {}", name, r.version);
+    }
+    refresh_status_snapshot();
     Ok(())
 }
 
-pub(crate) fn client_run_update() -> Result<()> {
-    crate::try_fail_point!("update");
-    let status: Status = status()?;
-    if status.components.is_empty() && status.adoptable.is_empty() {
+pub(crate) fn client_run_diff(
+    components: &[String],
+    format: crate::cli::OutputFormat,
+) -> Result<()> {
+    ensure_supported_platform()?;
+    let names = if components.is_empty() {
+        status()?.components.into_keys().collect()
+    } else {
+        components.to_vec()
+    };
+    let diffs = names
+        .into_iter()
+        .map(|name| {
+            let d = diff(&name)?;
+            Ok((name, d))
+        })
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    if format != crate::cli::OutputFormat::Human {
+        return format.print(&diffs);
+    }
+    if diffs.is_empty() {
         println!("No components installed.");
-        return Ok(());
     }
-    let mut updated = false;
-    for (name, cstatus) in status.components.iter() {
-        match cstatus.updatable {
-            ComponentUpdatable::Upgradable => {}
-            _ => continue,
+    for (name, d) in diffs.iter() {
+        let Some(update) = d.update.as_ref() else {
+            println!("Component {}: No update found", name);
+            continue;
         };
-        match update(name)? {
-            ComponentUpdateResult::AtLatestVersion => {
-                // Shouldn't happen unless we raced with another client
-                eprintln!(
-                    "warning: Expected update for {}, raced with a different client?",
-                    name
-                );
-                continue;
-            }
-            ComponentUpdateResult::Updated {
-                previous,
-                interrupted,
-                new,
-            } => {
-                if let Some(i) = interrupted {
-                    eprintln!(
-                        "warning: Continued from previous interrupted update: {}",
-                        i.version,
-                    );
+        println!(
+            "Component {}: {} -> {}",
+            name, d.installed.version, update.version
+        );
+        match d.changes.as_deref() {
+            None => println!("  File changes not tracked"),
+            Some([]) => println!("  No file changes"),
+            Some(changes) => {
+                for change in changes {
+                    println!("  {}", change);
                 }
-                println!("Previous {}: {}", name, previous.version);
-                println!("Updated {}: {}", name, new.version);
             }
         }
-        updated = true;
     }
-    for (name, adoptable) in status.adoptable.iter() {
-        if adoptable.confident {
-            let r: ContentMetadata = adopt_and_update(name)?;
-            println!("Adopted and updated: {}: {}", name, r.version);
-            updated = true;
-        } else {
-            println!("Component {} requires explicit adopt-and-update", name);
+    Ok(())
+}
+
+/// Read the files in `dir`, hashing each of them.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+#[context("Reading {}", dir.display())]
+pub(crate) fn file_tree(dir: &Path) -> Result<crate::filetree::FileTree> {
+    crate::filetree::FileTree::new_from_dir(&openat::Dir::open(dir)?)
+}
+
+/// Print the changes from the files in directory `from` to those in `to`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_diff_trees(
+    from: &Path,
+    to: &Path,
+    format: crate::cli::OutputFormat,
+) -> Result<()> {
+    let current = file_tree(from)?;
+    let updated = file_tree(to)?;
+    let changes = current.diff(&updated)?.describe(&current, &updated);
+    if format != crate::cli::OutputFormat::Human {
+        return format.print(&changes);
+    }
+    if changes.is_empty() {
+        println!("No changes");
+    }
+    for change in changes.iter() {
+        println!("{}", change);
+    }
+    Ok(())
+}
+
+/// Print how the disks backing `root` are found, and the partitions used on
+/// them.
+pub(crate) fn client_run_inspect_disk(root: &Path, format: crate::cli::OutputFormat) -> Result<()> {
+    let r = crate::blockdev::inspect_disks(root)?;
+    if format != crate::cli::OutputFormat::Human {
+        return format.print(&r);
+    }
+    for m in r.mounts.iter() {
+        match (&m.source, &m.error) {
+            (_, Some(e)) => println!("{}: {e}", m.path),
+            (Some(source), None) => {
+                println!("{}: {source} on {}", m.path, m.parents.join(", "))
+            }
+            (None, None) => println!("{}: unknown", m.path),
         }
     }
-    if !updated {
-        println!("No update available for any component.");
+    for disk in r.disks.iter() {
+        println!("Disk {}", disk.device);
+        if !disk.multipath_paths.is_empty() {
+            println!("  Multipath: {}", disk.multipath_paths.join(", "));
+        }
+        if disk.partitions.is_empty() {
+            println!("  No boot partitions");
+        }
+        for p in disk.partitions.iter() {
+            match p.number {
+                Some(n) => println!("  {}: {} (partition {n})", p.kind, p.device),
+                None => println!("  {}: {}", p.kind, p.device),
+            }
+        }
     }
     Ok(())
 }
 
-pub(crate) fn client_run_adopt_and_update() -> Result<()> {
-    let status: Status = status()?;
-    if status.adoptable.is_empty() {
-        println!("No components are adoptable.");
+/// Check the audit log at `log`, with the HMAC key in `key` if given.
+pub(crate) fn client_run_verify_audit_log(log: &Path, key: Option<&str>) -> Result<()> {
+    let key = key.map(crate::audit::load_key).transpose()?;
+    let n = crate::audit::verify(log, key.as_deref())?;
+    if key.is_some() {
+        println!("Verified {n} records");
     } else {
-        for (name, _) in status.adoptable.iter() {
-            let r: ContentMetadata = adopt_and_update(name)?;
-            println!("Adopted and updated: {}: {}", name, r.version);
+        println!("Read {n} records; not verified without an HMAC key");
+    }
+    Ok(())
+}
+
+/// The versions of each supplemental firmware package of `component`
+/// available on the system.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn available_firmware(component: &dyn Component) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut r: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for v in component.firmware_versions("/")? {
+        r.entry(v.package).or_default().push(v.version);
+    }
+    Ok(r)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn available_firmware(_component: &dyn Component) -> Result<BTreeMap<String, Vec<String>>> {
+    Ok(BTreeMap::new())
+}
+
+/// The supplemental firmware of `component`, given its installed and
+/// update metadata.
+fn firmware_status(
+    component: &dyn Component,
+    installed: Option<&ContentMetadata>,
+    update: Option<&ContentMetadata>,
+) -> Result<Vec<FirmwareStatus>> {
+    let empty = BTreeMap::new();
+    let installed = installed
+        .and_then(|m| m.firmware.as_ref())
+        .unwrap_or(&empty);
+    let update = update.and_then(|m| m.firmware.as_ref()).unwrap_or(&empty);
+    let available = available_firmware(component)?;
+    let packages: BTreeSet<&String> = installed
+        .keys()
+        .chain(update.keys())
+        .chain(available.keys())
+        .collect();
+    let r = packages
+        .into_iter()
+        .map(|package| {
+            let installed = installed.get(package).cloned();
+            let update = update.get(package).cloned();
+            FirmwareStatus {
+                package: package.clone(),
+                updatable: ComponentUpdatable::from_versions(
+                    installed.as_deref(),
+                    update.as_deref(),
+                ),
+                installed,
+                update,
+                available: available.get(package).cloned().unwrap_or_default(),
+            }
+        })
+        .collect();
+    Ok(r)
+}
+
+/// Describe the state of supplemental firmware in one line.
+fn describe_firmware(f: &FirmwareStatus) -> String {
+    let installed = f.installed.as_deref().unwrap_or("not installed");
+    let update = f.update.as_deref().unwrap_or_default();
+    match f.updatable {
+        ComponentUpdatable::NoUpdateAvailable => format!("{}: {installed}", f.package),
+        ComponentUpdatable::AtLatestVersion => {
+            format!("{}: {installed}, at latest version", f.package)
+        }
+        ComponentUpdatable::WouldDowngrade => {
+            format!("{}: {installed}, ignoring downgrade to {update}", f.package)
+        }
+        ComponentUpdatable::Upgradable => {
+            format!("{}: {installed}, available: {update}", f.package)
+        }
+    }
+}
+
+/// The supplemental firmware of each component: installed, in the update
+/// payload, and all versions shipped in the booted root.
+pub(crate) fn firmware_list() -> Result<BTreeMap<String, Vec<FirmwareStatus>>> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let sysroot = openat::Dir::open("/")?;
+    let mut r = BTreeMap::new();
+    for (name, component) in get_components() {
+        let installed = state.installed.get(name.as_str()).map(|ic| &ic.meta);
+        let update = component.query_update(&sysroot)?;
+        let firmware = firmware_status(component.as_ref(), installed, update.as_ref())?;
+        if !firmware.is_empty() {
+            r.insert(name, firmware);
+        }
+    }
+    Ok(r)
+}
+
+pub(crate) fn client_run_firmware_list(format: crate::cli::OutputFormat) -> Result<()> {
+    ensure_supported_platform()?;
+    let r = firmware_list()?;
+    if format != crate::cli::OutputFormat::Human {
+        return format.print(&r);
+    }
+    if r.is_empty() {
+        println!("No supplemental firmware.");
+    }
+    for (name, firmware) in r.iter() {
+        println!("Component {}", name);
+        for f in firmware.iter() {
+            println!("  {}", describe_firmware(f));
+            if !f.available.is_empty() {
+                println!("    Shipped: {}", f.available.join(", "));
+            }
         }
     }
     Ok(())
 }
 
-pub(crate) fn client_run_validate() -> Result<()> {
+/// Check the installed EFI boot images against the TPM event log.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn validate_measurements() -> Result<ValidationResult> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let efi = efi::Efi::default();
+    match state.installed.get(efi.name()) {
+        Some(inst) => efi.validate_measurements(inst),
+        None => Ok(ValidationResult::Skip),
+    }
+}
+
+/// Check the boot chain of the installed EFI component.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn validate_boot_chain() -> Result<ValidationResult> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let efi = efi::Efi::default();
+    match state.installed.get(efi.name()) {
+        Some(inst) => efi.validate_boot_chain(&openat::Dir::open("/")?, inst),
+        None => Ok(ValidationResult::Skip),
+    }
+}
+
+/// Predict the TPM PCR values after the pending EFI update.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn pcr_predict() -> Result<crate::tpm::PcrPrediction> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let efi = efi::Efi::default();
+    let Some(inst) = state.installed.get(efi.name()) else {
+        anyhow::bail!("Component {} is not installed", efi.name());
+    };
+    let sysroot = openat::Dir::open("/")?;
+    let images = match efi.query_update(&sysroot)? {
+        Some(update) if inst.meta.can_upgrade_to(&update) => efi.image_changes(&sysroot, inst)?,
+        _ => Vec::new(),
+    };
+    crate::tpm::predict(images)
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn client_run_pcr_predict(format: crate::cli::OutputFormat) -> Result<()> {
+    let prediction = pcr_predict()?;
+    if format != crate::cli::OutputFormat::Human {
+        return format.print(&prediction);
+    }
+    if prediction.images.is_empty() {
+        println!("No pending update changes measured boot images.");
+    }
+    for image in prediction.images.iter() {
+        let note = if image.measured {
+            ""
+        } else {
+            " (not measured in this boot)"
+        };
+        println!("Image {}{note}", image.path);
+        println!("  Current: {}", image.current);
+        println!("  Update:  {}", image.update);
+    }
+    for (pcr, v) in prediction.pcrs.iter() {
+        println!("PCR {pcr} ({}):", prediction.bank);
+        println!("  Current:   {}", v.current);
+        println!("  Predicted: {}", v.predicted);
+    }
+    println!("PCR 7 assumes the certificates signing the boot images are unchanged.");
+    if prediction.log_matches_tpm == Some(false) {
+        eprintln!("warning: Replaying the event log doesn't give the current PCR values");
+    }
+    Ok(())
+}
+
+/// What `validate` checks besides the installed files.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ValidateChecks {
+    pub(crate) bls: bool,
+    pub(crate) boot_chain: bool,
+    /// Don't hash files which look unchanged
+    pub(crate) fast: bool,
+    pub(crate) cross_esp: bool,
+    /// With `cross_esp`, first resync the ESPs
+    pub(crate) resync: bool,
+    pub(crate) tpm: bool,
+}
+
+pub(crate) fn client_run_validate(
+    format: crate::cli::OutputFormat,
+    checks: ValidateChecks,
+    notices: crate::notice::Targets,
+) -> Result<()> {
+    ensure_supported_platform()?;
+    check_boot_devices()?;
     let status: Status = status()?;
-    if status.components.is_empty() {
+    if status.components.is_empty() && !checks.bls && format == crate::cli::OutputFormat::Human {
         println!("No components installed.");
         return Ok(());
     }
-    let mut caught_validation_error = false;
-    for (name, _) in status.components.iter() {
-        match validate(name)? {
-            ValidationResult::Valid => {
-                println!("Validated: {}", name);
-            }
-            ValidationResult::Skip => {
-                println!("Skipped: {}", name);
+    let mut results = status
+        .components
+        .keys()
+        .map(|name| Ok((name.as_str(), validate(name, checks.fast)?)))
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    if checks.bls {
+        let errs = crate::bls::validate(Path::new("/boot"))?;
+        let r = if errs.is_empty() {
+            ValidationResult::Valid
+        } else {
+            ValidationResult::Errors(errs)
+        };
+        results.insert("BLS", r);
+    }
+    if checks.boot_chain {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        results.insert("Boot chain", validate_boot_chain()?);
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        results.insert("Boot chain", ValidationResult::Skip);
+    }
+    if checks.cross_esp {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        {
+            let (r, resynced) = validate_esps(checks.resync)?;
+            if format == crate::cli::OutputFormat::Human {
+                for esp in resynced {
+                    println!("Resynced: {esp}");
+                }
             }
-            ValidationResult::Errors(errs) => {
-                for err in errs {
-                    eprintln!("{}", err);
+            results.insert("ESPs", r);
+        }
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        results.insert("ESPs", ValidationResult::Skip);
+    }
+    if checks.tpm {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        results.insert("TPM", validate_measurements()?);
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        results.insert("TPM", ValidationResult::Skip);
+    }
+    let failed = results
+        .iter()
+        .filter(|(_, r)| matches!(r, ValidationResult::Errors(_)))
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+    crate::notice::write(notices, crate::notice::Topic::Validation, &failed)?;
+    print_validation(format, &results)
+}
+
+/// Print the validation `results`, failing if any found errors.
+fn print_validation(
+    format: crate::cli::OutputFormat,
+    results: &BTreeMap<&str, ValidationResult>,
+) -> Result<()> {
+    if format != crate::cli::OutputFormat::Human {
+        format.print(results)?;
+    } else {
+        for (name, r) in results.iter() {
+            match r {
+                ValidationResult::Valid => {
+                    println!("Validated: {}", name);
+                }
+                ValidationResult::Skip => {
+                    println!("Skipped: {}", name);
+                }
+                ValidationResult::Errors(errs) => {
+                    for err in errs {
+                        eprintln!("{}", err);
+                    }
                 }
-                caught_validation_error = true;
             }
         }
     }
+    let caught_validation_error = results
+        .values()
+        .any(|r| matches!(r, ValidationResult::Errors(_)));
     if caught_validation_error {
-        anyhow::bail!("Caught validation errors");
+        return Err(Outcome::ValidationFailed.into());
     }
     Ok(())
 }
 
-#[context("Migrating to a static GRUB config")]
-pub(crate) fn client_run_migrate_static_grub_config() -> Result<()> {
-    // Did we already complete the migration?
-    let mut cmd = std::process::Command::new("ostree");
-    let result = cmd
-        .args([
-            "config",
-            "--repo=/sysroot/ostree/repo",
-            "get",
-            "sysroot.bootloader",
-        ])
-        .output()
-        .context("Querying ostree sysroot.bootloader")?;
-    if !result.status.success() {
-        // ostree will exit with a non zero return code if the key does not exists
-        println!("ostree repo 'sysroot.bootloader' config option is not set yet");
+/// The status of the bootloader installed in the disk `image`, as far as
+/// it can be told offline; updates aren't looked for.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn image_status(image: &crate::diskimage::DiskImage) -> Result<Status> {
+    let mut ret = Status {
+        generated_at: Some(chrono::Utc::now()),
+        ..Default::default()
+    };
+    let Some(state) = SavedState::load_from_disk(image.root())? else {
+        return Ok(ret);
+    };
+    for (name, ic) in state.installed.iter() {
+        let interrupted = state.pending.as_ref().and_then(|p| p.get(name.as_str()));
+        ret.components.insert(
+            name.clone(),
+            ComponentStatus {
+                installed: ic.meta.clone(),
+                interrupted: interrupted.cloned(),
+                update: None,
+                updatable: ComponentUpdatable::NoUpdateAvailable,
+                adopted_from: ic.adopted_from.clone(),
+                partitions: ic
+                    .partitions
+                    .iter()
+                    .flatten()
+                    .map(|p| (p.partuuid.clone(), p.sha512.to_string()))
+                    .collect(),
+                warnings: ic.warnings.clone().unwrap_or_default(),
+                firmware: Vec::new(),
+            },
+        );
+    }
+    ret.last_update = state.last_update.clone();
+    ret.last_scrub = state.last_scrub.clone();
+    ret.pinned = state.pinned.clone();
+    ret.deferred = state.deferred.clone();
+    if let Some(device) = image.esp_device() {
+        let mut esp = crate::blockdev::get_esp_info(device)?;
+        // Only mounted by us, in our own namespace
+        esp.mountpoint = None;
+        esp.installed = state.devices.as_ref().map(|devices| {
+            devices
+                .iter()
+                .any(|d| d.esp_partuuid.is_some() && d.esp_partuuid == esp.partuuid)
+        });
+        ret.esp = vec![esp];
+    }
+    Ok(ret)
+}
+
+/// Print the status of the bootloader in the disk image at `path`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn client_run_image_status(path: &Path, format: crate::cli::OutputFormat) -> Result<()> {
+    let image = crate::diskimage::DiskImage::attach(path)?;
+    let status = image_status(&image)?;
+    if format != crate::cli::OutputFormat::Human {
+        format.print(&status)?;
+    } else if status.components.is_empty() {
+        println!("No components installed.");
     } else {
-        let res = String::from_utf8(result.stdout)
-            .with_context(|| "decoding as UTF-8 output of ostree command")?;
-        let bootloader = res.trim_end();
-        if bootloader == "none" {
+        print_status_summary(&status);
+    }
+    Ok(())
+}
+
+/// Compare the files installed in the disk image at `path` with those
+/// recorded in its state.  Only the EFI component is checked; the others
+/// are skipped.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn client_run_image_validate(
+    path: &Path,
+    format: crate::cli::OutputFormat,
+    fast: bool,
+) -> Result<()> {
+    let image = crate::diskimage::DiskImage::attach(path)?;
+    let state = SavedState::load_from_disk(image.root())?.unwrap_or_default();
+    let mut results = BTreeMap::new();
+    for (name, ic) in state.installed.iter() {
+        let r = match (name.as_str(), image.esp(), ic.filetree.as_ref()) {
+            ("EFI", Some(esp), Some(tree)) => {
+                let dir = openat::Dir::open(&esp.join("EFI"))?;
+                let diff = tree.validate_in(&dir, fast)?;
+                let errs = diff
+                    .changes
+                    .iter()
+                    .map(|f| format!("Changed: {f}"))
+                    .chain(diff.removals.iter().map(|f| format!("Removed: {f}")))
+                    .collect::<Vec<_>>();
+                if errs.is_empty() {
+                    ValidationResult::Valid
+                } else {
+                    ValidationResult::Errors(errs)
+                }
+            }
+            ("EFI", None, _) => ValidationResult::Errors(vec!["No ESP found in image".into()]),
+            _ => ValidationResult::Skip,
+        };
+        results.insert(name.as_str(), r);
+    }
+    print_validation(format, &results)
+}
+
+/// Migrate to a static GRUB config, unless already done or `force` is set.
+/// With `check`, only report whether the migration is needed, via
+/// [`Outcome::UpdatesAvailable`].
+pub(crate) fn client_run_migrate_static_grub_config(check: bool, force: bool) -> Result<()> {
+    // Did we already complete the migration?
+    let bootloader = crate::ostreeutil::get_ostree_bootloader()?;
+    let migrated = match bootloader.as_deref() {
+        None => {
+            println!("ostree repo 'sysroot.bootloader' config option is not set yet");
+            false
+        }
+        Some("none") => {
             println!("Already using a static GRUB config");
+            true
+        }
+        Some(bootloader) => {
+            println!(
+                "ostree repo 'sysroot.bootloader' config option is currently set to: '{}'",
+                bootloader
+            );
+            false
+        }
+    };
+    if check {
+        if migrated {
             return Ok(());
         }
-        println!(
-            "ostree repo 'sysroot.bootloader' config option is currently set to: '{}'",
-            bootloader
-        );
+        println!("Migration to a static GRUB config is needed");
+        return Err(Outcome::UpdatesAvailable.into());
     }
+    if migrated && !force {
+        return Ok(());
+    }
+    migrate_static_grub_config(bootloader)
+}
 
+/// Do the migration; `bootloader` is the previous value of
+/// `sysroot.bootloader`, recorded for `restore-grub-config`.
+#[context("Migrating to a static GRUB config")]
+fn migrate_static_grub_config(bootloader: Option<String>) -> Result<()> {
     // Remount /boot read write just for this unit (we are called in a slave mount namespace by systemd)
     ensure_writable_boot()?;
 
@@ -553,55 +2607,78 @@ pub(crate) fn client_run_migrate_static_grub_config() -> Result<()> {
             println!("Migrating to a static GRUB config...");
 
             // Resolve symlink location
-            let mut current_config = grub_config_dir.clone();
-            current_config.push(path);
+            let current_config = grub_config_dir.join(&path);
+            let current = std::fs::read_to_string(&current_config)
+                .context("Could not read current GRUB config")?;
+            // Local customizations are moved to drop-ins, so that they
+            // survive the config being replaced by the static one
+            let split = crate::grubcustom::split(&current);
+            let user_cfg = crate::grubcustom::drop_in_block(
+                &split.customizations,
+                crate::grubcustom::USER_CFG,
+            );
+            let custom_cfg = crate::grubcustom::drop_in_block(
+                &split.customizations,
+                crate::grubcustom::CUSTOM_CFG,
+            );
 
             // Backup the current GRUB config which is hopefully working right now
-            let backup_config = PathBuf::from("/boot/grub2/grub.cfg.backup");
             println!(
-                "Creating a backup of the current GRUB config '{}' in '{}'...",
+                "Creating a backup of the current GRUB config '{}'...",
                 current_config.display(),
-                backup_config.display()
             );
-            fs::copy(&current_config, &backup_config).context("Failed to backup GRUB config")?;
+            let meta = crate::grubbackup::BackupMeta {
+                symlink: Some(path),
+                bootloader,
+                user_cfg: user_cfg.clone(),
+                custom_cfg: custom_cfg.clone(),
+            };
+            let backup_name = crate::grubbackup::backup(
+                &grub_config_dir,
+                &current_config,
+                &meta,
+                chrono::Utc::now(),
+            )?;
+            println!("Saved backup '{}'", backup_name);
+
+            for c in split.customizations.iter() {
+                println!(
+                    "Carrying over {} lines from {} to {}...",
+                    c.lines.len(),
+                    c.source,
+                    c.drop_in()
+                );
+            }
+            for title in crate::grubcustom::menuentry_titles(&split.customizations) {
+                println!("Keeping boot menu entry '{title}'");
+            }
+            for (name, block) in [
+                (crate::grubcustom::USER_CFG, &user_cfg),
+                (crate::grubcustom::CUSTOM_CFG, &custom_cfg),
+            ] {
+                if let Some(block) = block.as_deref() {
+                    crate::grubcustom::append_drop_in(&dirfd, name, block)?;
+                    crate::label::set_default_label(&dirfd, name, &grub_config_dir.join(name));
+                }
+            }
 
-            // Read the current config, strip the ostree generated GRUB entries and
-            // write the result to a temporary file
+            // Write the current config without the ostree generated GRUB
+            // entries and the customizations to a temporary file
             println!("Stripping ostree generated entries from GRUB config...");
-            let current_config_file =
-                File::open(current_config).context("Could not open current GRUB config")?;
             let stripped_config = String::from("grub.cfg.stripped");
             // mode = -rw-r--r-- (644)
-            let mut writer = BufWriter::new(
-                dirfd
-                    .write_file(
-                        &stripped_config,
-                        (S_IRUSR | S_IWUSR | S_IRGRP | S_IROTH) as mode_t,
-                    )
-                    .context("Failed to open temporary GRUB config")?,
-            );
-            let mut skip = false;
-            for line in BufReader::new(current_config_file).lines() {
-                let line = line.context("Failed to read line from GRUB config")?;
-                if line == "### END /etc/grub.d/15_ostree ###" {
-                    skip = false;
-                }
-                if skip {
-                    continue;
-                }
-                if line == "### BEGIN /etc/grub.d/15_ostree ###" {
-                    skip = true;
-                }
-                writer
-                    .write_all(&line.as_bytes())
-                    .context("Failed to write stripped GRUB config")?;
-                writer
-                    .write_all(b"\n")
-                    .context("Failed to write stripped GRUB config")?;
-            }
-            writer
-                .flush()
+            dirfd
+                .write_file_contents(
+                    &stripped_config,
+                    (S_IRUSR | S_IWUSR | S_IRGRP | S_IROTH) as mode_t,
+                    split.config,
+                )
                 .context("Failed to write stripped GRUB config")?;
+            crate::label::set_default_label(
+                &dirfd,
+                &stripped_config,
+                &grub_config_dir.join("grub.cfg"),
+            );
 
             // Sync changes to the filesystem (ignore failures)
             let _ = dirfd.syncfs();
@@ -611,8 +2688,9 @@ pub(crate) fn client_run_migrate_static_grub_config() -> Result<()> {
                 .local_exchange(&stripped_config, "grub.cfg")
                 .context("Failed to exchange symlink with current GRUB config")?;
 
-            // Sync changes to the filesystem (ignore failures)
-            let _ = dirfd.syncfs();
+            // Sync changes to the filesystem (ignore failures); on XFS this
+            // also ensures GRUB can see them
+            let _ = crate::syncpolicy::sync_filesystem(&dirfd);
 
             println!("GRUB config symlink successfully replaced with the current config");
 
@@ -622,23 +2700,83 @@ pub(crate) fn client_run_migrate_static_grub_config() -> Result<()> {
     };
 
     println!("Setting 'sysroot.bootloader' to 'none' in ostree repo config...");
-    let status = std::process::Command::new("ostree")
-        .args([
-            "config",
-            "--repo=/sysroot/ostree/repo",
-            "set",
-            "sysroot.bootloader",
-            "none",
-        ])
-        .status()?;
-    if !status.success() {
-        anyhow::bail!("Failed to set 'sysroot.bootloader' to 'none' in ostree repo config");
-    }
+    crate::ostreeutil::set_ostree_bootloader(Some("none"))?;
 
     println!("Static GRUB config migration completed successfully");
     Ok(())
 }
 
+/// Print `sysroot.bootloader`, and whether it goes with the GRUB config.
+pub(crate) fn client_run_bootloader_config_get() -> Result<()> {
+    let bootloader = crate::ostreeutil::get_ostree_bootloader()?;
+    match bootloader.as_deref() {
+        Some(b) => println!("{b}"),
+        None => println!("auto (not set)"),
+    }
+    let static_config = crate::ostreeutil::static_grub_config(Path::new("/boot"))?;
+    if let Some(w) = crate::ostreeutil::bootloader_mismatch(bootloader.as_deref(), static_config) {
+        eprintln!("warning: {w}");
+    }
+    Ok(())
+}
+
+/// Set `sysroot.bootloader` to `value`, refusing values which don't go
+/// with the GRUB config unless `force` is set.
+#[context("Setting sysroot.bootloader")]
+pub(crate) fn client_run_bootloader_config_set(value: &str, force: bool) -> Result<()> {
+    crate::ostreeutil::validate_bootloader(value)?;
+    let static_config = crate::ostreeutil::static_grub_config(Path::new("/boot"))?;
+    if let Some(w) = crate::ostreeutil::bootloader_mismatch(Some(value), static_config) {
+        if !force {
+            anyhow::bail!("{w}; use --force to set it anyway");
+        }
+        log::warn!("{w}");
+    }
+    println!("Setting 'sysroot.bootloader' to '{value}' in ostree repo config...");
+    crate::ostreeutil::set_ostree_bootloader(Some(value))
+}
+
+/// Roll back a static GRUB config migration by restoring the backup `name`
+/// (by default the newest), or list the backups with `list`.
+#[context("Restoring GRUB config")]
+pub(crate) fn client_run_restore_grub_config(name: Option<&str>, list: bool) -> Result<()> {
+    let grub_config_dir = Path::new("/boot/grub2");
+    if list {
+        for b in crate::grubbackup::list(grub_config_dir)? {
+            match b.timestamp {
+                Some(t) => println!("{}  {}", b.name, t.format("%Y-%m-%d %H:%M:%S UTC")),
+                None => println!("{}", b.name),
+            }
+        }
+        return Ok(());
+    }
+
+    ensure_writable_boot()?;
+    let (name, meta) = crate::grubbackup::restore(grub_config_dir, name)?;
+    let dirfd = openat::Dir::open(grub_config_dir).context("Opening /boot/grub2")?;
+    crate::syncpolicy::sync_filesystem(&dirfd)?;
+    println!("Restored GRUB config from '{}'", name);
+
+    // The backup is of the config from before migrating; let ostree manage
+    // it again as it did then.
+    match meta.bootloader.as_deref() {
+        Some(b) => println!("Setting 'sysroot.bootloader' to '{b}' in ostree repo config..."),
+        None => println!("Unsetting 'sysroot.bootloader' in ostree repo config..."),
+    }
+    crate::ostreeutil::set_ostree_bootloader(meta.bootloader.as_deref())?;
+    // The restored config has the customizations moved to the drop-ins again
+    for (name, block) in [
+        (crate::grubcustom::USER_CFG, &meta.user_cfg),
+        (crate::grubcustom::CUSTOM_CFG, &meta.custom_cfg),
+    ] {
+        if let Some(block) = block.as_deref() {
+            println!("Removing carried over customizations from {name}...");
+            crate::grubcustom::remove_from_drop_in(&dirfd, name, block)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -647,8 +2785,144 @@ mod tests {
     fn test_failpoint_update() {
         let guard = fail::FailScenario::setup();
         fail::cfg("update", "return").unwrap();
-        let r = client_run_update();
+        let r = client_run_update(false, false);
         assert_eq!(r.is_err(), true);
         guard.teardown();
     }
+
+    #[test]
+    fn test_new_scrub_errors() {
+        let errors = vec![
+            "EFI: Changed file: fedora/grubx64.efi".to_string(),
+            "ESPs: Differs on esp1: fedora/shimx64.efi".to_string(),
+        ];
+        // Without a previous scrub, all errors are new
+        assert_eq!(new_scrub_errors(&errors, None), errors);
+        let last = ScrubResult {
+            timestamp: chrono::Utc::now(),
+            errors: vec![
+                errors[0].clone(),
+                // Since fixed
+                "EFI: Missing file: fedora/mmx64.efi".to_string(),
+            ],
+        };
+        assert_eq!(new_scrub_errors(&errors, Some(&last)), &errors[1..]);
+        assert!(new_scrub_errors(&errors[..1], Some(&last)).is_empty());
+        assert!(new_scrub_errors(&[], Some(&last)).is_empty());
+    }
+
+    #[test]
+    fn test_image_filesystem() {
+        assert_eq!(image_filesystem(0x794c7630), Some("overlay"));
+        assert_eq!(image_filesystem(0xe0f5e1e2_i64), Some("erofs"));
+        // ext4 and xfs
+        assert_eq!(image_filesystem(0xef53), None);
+        assert_eq!(image_filesystem(0x58465342), None);
+    }
+
+    #[test]
+    fn test_status_snapshot() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let td = tempfile::tempdir()?;
+        let path = td.path().join("bootupd/status.json");
+        let mut status = Status::default();
+        status.pinned.insert(
+            "BIOS".into(),
+            Pin {
+                timestamp: chrono::Utc::now(),
+                reason: None,
+            },
+        );
+        write_status_snapshot(&path, &status)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+        let (read, _) = read_status_snapshot(&path)?;
+        assert_eq!(read.pinned, status.pinned);
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_line() -> Result<()> {
+        let meta = |version: &str| ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: version.into(),
+            firmware: None,
+        };
+        let esp = |partuuid: &str, installed| EspInfo {
+            device: "/dev/vda2".into(),
+            partuuid: Some(partuuid.into()),
+            uuid: None,
+            mountpoint: None,
+            size: None,
+            free: None,
+            installed,
+        };
+        let component = ComponentStatus {
+            installed: meta("grub2-1"),
+            interrupted: None,
+            update: Some(meta("grub2-2")),
+            updatable: ComponentUpdatable::Upgradable,
+            adopted_from: None,
+            partitions: BTreeMap::new(),
+            warnings: Vec::new(),
+            firmware: Vec::new(),
+        };
+        let mut status = Status {
+            esp: vec![esp("a", Some(true)), esp("b", Some(true))],
+            ..Default::default()
+        };
+        assert_eq!(
+            summary_line(&status, "EFI", &component),
+            "EFI installed=grub2-1 available=grub2-2 state=upgradable esp=2/2-ok"
+        );
+        status.esp[1].installed = Some(false);
+        status.pinned.insert(
+            "EFI".into(),
+            Pin {
+                timestamp: chrono::Utc::now(),
+                reason: None,
+            },
+        );
+        assert_eq!(
+            summary_line(&status, "EFI", &component),
+            "EFI installed=grub2-1 available=grub2-2 state=upgradable pinned=yes esp=1/2-degraded"
+        );
+        // Without recorded disks, only the number of ESPs is known
+        for e in status.esp.iter_mut() {
+            e.installed = None;
+        }
+        assert!(summary_line(&status, "EFI", &component).ends_with(" esp=2"));
+        assert_eq!(
+            summary_line(&status, "BIOS", &component),
+            "BIOS installed=grub2-1 available=grub2-2 state=upgradable"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_plan_serialization() -> Result<()> {
+        let plan = InstallPlan {
+            source_root: "/".into(),
+            dest_root: "/target".into(),
+            devices: vec!["/dev/vda".into(), "/dev/vdb".into()],
+            configs: ConfigMode::WithUUID,
+            update_firmware: false,
+            components: vec![ComponentInstallPlan {
+                name: "EFI".into(),
+                version: None,
+                destination: "/target/boot/efi".into(),
+                files: vec!["EFI/fedora/shimx64.efi".into()],
+            }],
+            skipped: [("BIOS".to_string(), "no target device".to_string())].into(),
+        };
+        let v = serde_json::to_value(&plan)?;
+        assert_eq!(v["configs"], "with-uuid");
+        assert_eq!(v["components"][0]["destination"], "/target/boot/efi");
+        assert_eq!(v["skipped"]["BIOS"], "no target device");
+        assert_eq!(v["devices"][1], "/dev/vdb");
+        let plan: InstallPlan = serde_json::from_value(v)?;
+        assert_eq!(plan.components[0].files, ["EFI/fedora/shimx64.efi"]);
+        assert_eq!(plan.devices.len(), 2);
+        Ok(())
+    }
 }
@@ -0,0 +1,126 @@
+//! Exercise crash recovery by injecting failures via failpoints.
+//!
+//! Each scenario runs entirely in a temporary directory, so this needs
+//! neither privileges nor real block devices.
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::model::{ContentMetadata, SavedState};
+
+/// Run `f` with the failpoint `name` configured with `actions`, and
+/// check that it fails.
+fn expect_injected_failure<T>(
+    name: &str,
+    actions: &str,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<()> {
+    fail::cfg(name, actions).map_err(|e| anyhow!("Configuring failpoint {name}: {e}"))?;
+    let r = f();
+    fail::remove(name);
+    match r {
+        Ok(_) => bail!("Expected failure from {name}={actions}"),
+        Err(e) => {
+            log::debug!("Injected failure: {e:#}");
+            Ok(())
+        }
+    }
+}
+
+/// An interrupted state write must leave a state which is refused until it
+/// is recovered explicitly, which then gives the old one.
+fn state_write_recovery() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let sysroot = openat::Dir::open(td.path())?;
+    sysroot.create_dir(SavedState::STATEFILE_DIR, 0o755)?;
+    let mut guard = SavedState::unlocked(sysroot.try_clone()?)?;
+    let mut state = SavedState::default();
+    guard.update_state(&state)?;
+    for i in 0..3 {
+        let prev = serde_json::to_value(&state)?;
+        state.static_configs = Some(ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: format!("v{i}"),
+            firmware: None,
+        });
+        expect_injected_failure("state::write", "return", || guard.update_state(&state))?;
+        if SavedState::load_from_disk(td.path()).is_ok() {
+            bail!("Interrupted state write went unnoticed");
+        }
+        guard.recover_state(true)?;
+        let loaded =
+            SavedState::load_from_disk(td.path())?.ok_or_else(|| anyhow!("State vanished"))?;
+        if serde_json::to_value(&loaded)? != prev {
+            bail!("Recovered unexpected state: {loaded:?}");
+        }
+        // And we can complete the write afterwards
+        guard.update_state(&state)?;
+        let loaded =
+            SavedState::load_from_disk(td.path())?.ok_or_else(|| anyhow!("State vanished"))?;
+        if serde_json::to_value(&loaded)? != serde_json::to_value(&state)? {
+            bail!("Failed to write state after recovery: {loaded:?}");
+        }
+    }
+    println!("Recovered from interrupted state writes");
+    Ok(())
+}
+
+/// An interrupted update must be completed by applying the same diff again,
+/// which is what happens on the next `bootupctl update`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn apply_diff_recovery() -> Result<()> {
+    use crate::filetree::{self, FileTree};
+    use crate::testutils::write_tree;
+
+    let old_files = [
+        ("BOOT/BOOTX64.EFI", "boot v1"),
+        ("BOOT/fbx64.efi", "fallback v1"),
+        ("fedora/shimx64.efi", "shim v1"),
+        ("fedora/grubx64.efi", "grub v1"),
+    ];
+    let new_files = [
+        ("BOOT/BOOTX64.EFI", "boot v2"),
+        ("fedora/shimx64.efi", "shim v2"),
+        ("fedora/grubx64.efi", "grub v1"),
+        ("fedora/fw/fw.bin", "firmware v1"),
+        ("centos/shimx64.efi", "shim v1"),
+    ];
+    // The diff touches three top-level directories, each of which is
+    // exchanged separately.
+    let scenarios = [
+        ("update::apply-diff", "return"),
+        ("update::exchange", "return"),
+        ("update::exchange", "1*off->return"),
+        ("update::exchange", "2*off->return"),
+    ];
+
+    let td = tempfile::tempdir()?;
+    let new = write_tree(&td.path().join("new"), &new_files)?;
+    let newt = FileTree::new_from_dir(&new)?;
+    for (i, (name, actions)) in scenarios.into_iter().enumerate() {
+        let dest = write_tree(&td.path().join(format!("dest{i}")), &old_files)?;
+        let diff = FileTree::new_from_dir(&dest)?.diff(&newt)?;
+        expect_injected_failure(name, actions, || {
+            filetree::apply_diff(&new, &dest, &diff, None)
+        })?;
+        filetree::apply_diff(&new, &dest, &diff, None)
+            .with_context(|| format!("Recovering from {name}={actions}"))?;
+        if FileTree::new_from_dir(&dest)? != newt {
+            bail!("Unexpected content after recovering from {name}={actions}");
+        }
+        let leftover = filetree::leftover_tmp(&dest)?;
+        if !leftover.is_empty() {
+            bail!("Left over after recovering from {name}={actions}: {leftover:?}");
+        }
+    }
+    println!("Recovered from interrupted updates");
+    Ok(())
+}
+
+/// Run all scenarios.
+pub(crate) fn run() -> Result<()> {
+    state_write_recovery().context("State writes")?;
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    apply_diff_recovery().context("Applying updates")?;
+    println!("Chaos test passed");
+    Ok(())
+}
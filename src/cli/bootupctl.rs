@@ -1,6 +1,6 @@
 use crate::bootupd;
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser};
 use log::LevelFilter;
 
 use std::os::unix::process::CommandExt;
@@ -17,6 +17,8 @@ static SYSTEMD_PROPERTIES: &[&str] = &[
     // want systemd to send it to other processes.
     "KillMode=mixed",
     "MountFlags=slave",
+    // Allow progress to be reported via sd_notify
+    "NotifyAccess=main",
 ];
 
 /// `bootupctl` sub-commands.
@@ -27,6 +29,17 @@ pub struct CtlCommand {
     #[clap(short = 'v', action = clap::ArgAction::Count, global = true)]
     verbosity: u8,
 
+    #[clap(flatten)]
+    config: super::ConfigOpts,
+
+    #[clap(flatten)]
+    trace: super::TraceOpts,
+
+    /// Operate read-only on the given disk image (raw or qcow2) rather
+    /// than the running system; only `status` and `validate` support this
+    #[clap(long, global = true, value_name = "PATH")]
+    image: Option<std::path::PathBuf>,
+
     /// CLI sub-command.
     #[clap(subcommand)]
     pub cmd: CtlVerb,
@@ -42,6 +55,37 @@ impl CtlCommand {
             _ => LevelFilter::Trace,
         }
     }
+
+    /// Return the options for tracing the run.
+    pub(crate) fn trace_opts(&self) -> &super::TraceOpts {
+        &self.trace
+    }
+
+    /// The output format requested for the verb, which failures are also
+    /// reported in.
+    pub(crate) fn output_format(&self) -> super::OutputFormat {
+        match &self.cmd {
+            CtlVerb::Status(opts) => opts.format(crate::util::running_in_container()),
+            CtlVerb::Validate(ValidateOpts { format, .. })
+            | CtlVerb::Diff(DiffOpts { format, .. })
+            | CtlVerb::InspectDisk(InspectDiskOpts { format, .. })
+            | CtlVerb::Capabilities(CapabilitiesOpts { format, .. })
+            | CtlVerb::Firmware(CtlFirmware::List(FirmwareListOpts { format, .. })) => {
+                format.format
+            }
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            CtlVerb::PcrPredict(PcrPredictOpts { format, .. }) => format.format,
+            #[cfg(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            ))]
+            CtlVerb::Internals(CtlInternals::DiffTrees(DiffTreesOpts { format, .. })) => {
+                format.format
+            }
+            _ => super::OutputFormat::Human,
+        }
+    }
 }
 
 /// CLI sub-commands.
@@ -54,17 +98,146 @@ pub enum CtlVerb {
     #[clap(name = "status", about = "Show components status")]
     Status(StatusOpts),
     #[clap(name = "update", about = "Update all components")]
-    Update,
+    Update(UpdateOpts),
     #[clap(name = "adopt-and-update", about = "Update all adoptable components")]
-    AdoptAndUpdate,
+    AdoptAndUpdate(AdoptOpts),
     #[clap(name = "validate", about = "Validate system state")]
-    Validate,
+    Validate(ValidateOpts),
+    #[clap(name = "diff", about = "Show the files an update would change")]
+    Diff(DiffOpts),
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[clap(
+        name = "pcr-predict",
+        about = "Predict TPM PCR values after the pending EFI update"
+    )]
+    PcrPredict(PcrPredictOpts),
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[clap(
+        name = "confirm",
+        about = "Update the other ESP once booted from the one updated by an A/B update"
+    )]
+    Confirm,
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[clap(
+        name = "rollback",
+        about = "Undo an A/B update of the ESPs which wasn't confirmed"
+    )]
+    Rollback,
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[clap(name = "bootorder", about = "Manage EFI boot entries", subcommand)]
+    BootOrder(CtlBootOrder),
+    #[clap(
+        name = "mark-boot-successful",
+        about = "Record that the system booted successfully after the last update"
+    )]
+    MarkBootSuccessful,
+    #[clap(
+        name = "scrub",
+        about = "Read back all installed files to detect corruption of the storage"
+    )]
+    Scrub,
+    #[clap(name = "pin", about = "Exclude a component from updates and adoption")]
+    Pin(PinOpts),
+    #[clap(name = "unpin", about = "Allow updates of a pinned component again")]
+    Unpin(UnpinOpts),
+    #[clap(name = "uninstall", about = "Remove installed components")]
+    Uninstall(UninstallOpts),
+    #[clap(
+        name = "reinstall",
+        about = "Rewrite the complete payload of installed components"
+    )]
+    Reinstall(ReinstallOpts),
     #[clap(
         name = "migrate-static-grub-config",
-        hide = true,
-        about = "Migrate a system to a static GRUB config"
+        about = "Migrate an ostree system to a static GRUB config"
+    )]
+    MigrateStaticGrubConfig(MigrateStaticGrubConfigOpts),
+    #[clap(
+        name = "restore-grub-config",
+        about = "Restore a GRUB config saved by migrate-static-grub-config"
     )]
-    MigrateStaticGrubConfig,
+    RestoreGrubConfig(RestoreGrubConfigOpts),
+    #[clap(
+        name = "bootloader-config",
+        about = "Query or set the ostree sysroot.bootloader config",
+        subcommand
+    )]
+    BootloaderConfig(CtlBootloaderConfig),
+    #[clap(
+        name = "inspect-disk",
+        about = "Show the disks and boot partitions bootupd would use"
+    )]
+    InspectDisk(InspectDiskOpts),
+    #[clap(
+        name = "capabilities",
+        about = "Show the protocol version, verbs, components and features supported"
+    )]
+    Capabilities(CapabilitiesOpts),
+    #[clap(name = "firmware", about = "Inspect supplemental firmware", subcommand)]
+    Firmware(CtlFirmware),
+    #[clap(name = "internals", hide = true, subcommand)]
+    Internals(CtlInternals),
+}
+
+/// Commands for supplemental firmware, as added by `extend-payload`.
+#[derive(Debug, Parser)]
+pub enum CtlFirmware {
+    /// List the installed and available versions of each package
+    #[clap(name = "list")]
+    List(FirmwareListOpts),
+}
+
+/// Commands for the EFI boot entries.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[derive(Debug, Parser)]
+pub enum CtlBootOrder {
+    /// Remove duplicate boot entries, and those whose files no longer
+    /// exist on the ESPs
+    #[clap(name = "gc")]
+    Gc(BootOrderGcOpts),
+}
+
+/// Commands for the `sysroot.bootloader` option of the ostree repo
+/// config, which decides whether ostree generates GRUB configs.
+#[derive(Debug, Parser)]
+pub enum CtlBootloaderConfig {
+    /// Print the current value, warning if it doesn't go with the GRUB config
+    #[clap(name = "get")]
+    Get,
+    /// Set the value
+    #[clap(name = "set")]
+    Set(BootloaderConfigSetOpts),
+}
+
+/// Commands used at package build time.
+#[derive(Debug, Parser)]
+pub enum CtlInternals {
+    /// Write shell completions for `bootupctl` to standard output
+    #[clap(name = "completions")]
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Write man pages for `bootupctl` and its verbs
+    #[clap(name = "man")]
+    Man {
+        /// Directory in which to write one page per verb; by default
+        /// only the top-level page is written to standard output
+        #[clap(long, value_name = "DIR")]
+        output_dir: Option<std::path::PathBuf>,
+    },
+    /// Compare the files in two directories, e.g. an update payload and
+    /// the ESP it was installed to
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    #[clap(name = "diff-trees")]
+    DiffTrees(DiffTreesOpts),
+    /// Check that an audit log (`--audit-log`) is complete and unmodified
+    #[clap(name = "verify-audit-log")]
+    VerifyAuditLog(VerifyAuditLogOpts),
 }
 
 #[derive(Debug, Parser)]
@@ -73,6 +246,38 @@ pub enum CtlBackend {
     Generate(super::bootupd::GenerateOpts),
     #[clap(name = "install", hide = true)]
     Install(super::bootupd::InstallOpts),
+    /// Add supplemental files, e.g. firmware not shipped with the
+    /// bootloader, to an update payload; run before
+    /// `generate-update-metadata` when building an image
+    #[clap(name = "extend-payload")]
+    ExtendPayload(super::bootupd::ExtendPayloadOpts),
+    /// Check a new ostree deployment for bootloader updates; run after
+    /// `ostree admin deploy`
+    #[clap(name = "ostree-post-deploy")]
+    OstreePostDeploy(OstreePostDeployOpts),
+    /// Add an HMAC to the state after creating
+    /// `/etc/bootupd/state-hmac-key`; until then, the state is rejected
+    #[clap(name = "authenticate-state")]
+    AuthenticateState,
+    /// Accept a state which fails to verify, e.g. after a downgrade, or
+    /// restore it from the backup of the previous state
+    #[clap(name = "recover-state")]
+    RecoverState(RecoverStateOpts),
+}
+
+#[derive(Debug, Parser)]
+pub struct RecoverStateOpts {
+    /// Restore the state from before the last change, rather than
+    /// accepting the current one
+    #[clap(long, action)]
+    use_backup: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct OstreePostDeployOpts {
+    /// Root of the deployment; by default the newest one in `/sysroot`
+    #[clap(long, value_parser)]
+    deployment: Option<std::path::PathBuf>,
 }
 
 #[derive(Debug, Parser)]
@@ -83,71 +288,650 @@ pub struct StatusOpts {
     #[clap(long, action)]
     print_if_available: bool,
 
-    /// Output JSON
+    /// Exit with code 2 if there are updates available
+    #[clap(long, action)]
+    check: bool,
+
+    /// Wait until an update is available, watching for changes to the
+    /// update payloads (on ostree systems, those of the newest deployment,
+    /// e.g. one staged by an upgrade), then output the status
+    #[clap(long, action)]
+    watch: bool,
+
+    /// Also list the package each installed file came from, and the
+    /// components and features supported on this system
     #[clap(long, action)]
+    verbose: bool,
+
+    /// Print one line per component, e.g. `EFI installed=... available=...
+    /// state=upgradable esp=2/2-ok`
+    #[clap(long, action, conflicts_with_all = ["print_if_available", "verbose", "json", "format"])]
+    summary: bool,
+
+    /// Only show these components, e.g. `EFI,BIOS`; the others aren't
+    /// probed at all
+    #[clap(
+        long,
+        value_delimiter = ',',
+        value_name = "COMPONENTS",
+        conflicts_with_all = ["motd", "issue"]
+    )]
+    components: Vec<String>,
+
+    #[clap(flatten)]
+    notices: super::NoticeOpts,
+
+    /// Output JSON; equivalent to `--format json-pretty`, or to `--format
+    /// json-compact` in a container
+    #[clap(long, action, conflicts_with = "format")]
     json: bool,
+
+    #[clap(flatten)]
+    format: super::FormatOpts,
+}
+
+impl StatusOpts {
+    /// The output format, when run in a container or not.
+    pub(crate) fn format(&self, in_container: bool) -> super::OutputFormat {
+        match self.json {
+            // As always, since consumers may read it line by line
+            true if in_container => super::OutputFormat::JsonCompact,
+            true => super::OutputFormat::JsonPretty,
+            false => self.format.format,
+        }
+    }
+}
+
+/// Bootloaders which are only adopted on request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AdoptFrom {
+    /// systemd-boot, as installed by `bootctl install`
+    SystemdBoot,
+}
+
+impl AdoptFrom {
+    fn component(self) -> &'static str {
+        match self {
+            AdoptFrom::SystemdBoot => "SystemdBoot",
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct AdoptOpts {
+    /// Adopt this bootloader, which is otherwise left alone
+    #[clap(long, value_enum)]
+    from: Option<AdoptFrom>,
+
+    /// Write to the ESP even if its filesystem wasn't unmounted cleanly or
+    /// had errors
+    #[clap(long, action)]
+    force: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct UpdateOpts {
+    /// After updating, migrate ostree systems to a static GRUB config, as
+    /// with `migrate-static-grub-config`
+    #[clap(long, action)]
+    migrate_static_configs: bool,
+
+    /// Update even outside of the configured maintenance windows
+    #[clap(long, action)]
+    now: bool,
+
+    /// Write to the ESP even if its filesystem wasn't unmounted cleanly or
+    /// had errors
+    #[clap(long, action)]
+    force: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct MigrateStaticGrubConfigOpts {
+    /// Only check whether the migration is needed; exits with 2 if it is
+    #[clap(long, action, conflicts_with = "force")]
+    check: bool,
+
+    /// Migrate again even if `sysroot.bootloader` is already `none`, e.g.
+    /// after `grub2-mkconfig` replaced the static config
+    #[clap(long, action)]
+    force: bool,
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[derive(Debug, Parser)]
+pub struct BootOrderGcOpts {
+    /// Only show the boot entries which would be removed
+    #[clap(long, action)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct BootloaderConfigSetOpts {
+    /// Set the value even if it doesn't go with the GRUB config
+    #[clap(long, action)]
+    force: bool,
+
+    /// The new value
+    #[clap(value_parser = clap::builder::PossibleValuesParser::new(crate::ostreeutil::BOOTLOADERS))]
+    value: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct RestoreGrubConfigOpts {
+    /// List the saved configs instead of restoring one
+    #[clap(long, action, conflicts_with = "backup")]
+    list: bool,
+
+    /// Name of the backup in /boot/grub2 to restore; by default the newest
+    #[clap(value_parser)]
+    backup: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct ValidateOpts {
+    #[clap(flatten)]
+    format: super::FormatOpts,
+
+    /// Also check that the BLS entries in /boot/loader/entries reference
+    /// existing kernels and initramfs images
+    #[clap(long)]
+    bls: bool,
+
+    /// Also check that the firmware finds shim via an NVRAM entry or the
+    /// fallback path, and that shim and GRUB find their configs and the
+    /// boot loader entries in /boot
+    #[clap(long)]
+    boot_chain: bool,
+
+    /// Assume files whose size and modification time are unchanged since
+    /// they were written are intact, rather than hashing them
+    #[clap(long)]
+    fast: bool,
+
+    /// Also compare the content of all ESPs (e.g. on mirrored disks) with
+    /// each other
+    #[clap(long)]
+    cross_esp: bool,
+
+    /// Before comparing, copy the installed EFI files from an ESP where
+    /// they are unmodified onto those where they aren't
+    #[clap(long, requires = "cross_esp")]
+    resync: bool,
+
+    /// Also check that the installed shim and GRUB images are those
+    /// measured into the TPM event log during this boot
+    #[clap(long)]
+    tpm: bool,
+
+    #[clap(flatten)]
+    notices: super::NoticeOpts,
+}
+
+#[derive(Debug, Parser)]
+pub struct DiffOpts {
+    #[clap(flatten)]
+    format: super::FormatOpts,
+
+    /// Components to compare; by default all installed components
+    #[clap(value_parser)]
+    components: Vec<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct InspectDiskOpts {
+    #[clap(flatten)]
+    format: super::FormatOpts,
+
+    /// Root of the system to inspect, e.g. one being installed
+    #[clap(long, value_parser, default_value = "/")]
+    root: std::path::PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct DiffTreesOpts {
+    #[clap(flatten)]
+    format: super::FormatOpts,
+
+    /// The current directory
+    #[clap(value_parser)]
+    from: std::path::PathBuf,
+
+    /// The updated directory
+    #[clap(value_parser)]
+    to: std::path::PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct VerifyAuditLogOpts {
+    /// HMAC key the records were sealed with; without it, only check that
+    /// the log can be parsed
+    #[clap(long, value_name = "PATH")]
+    hmac_key: Option<String>,
+
+    /// The audit log
+    #[clap(value_parser)]
+    log: std::path::PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct CapabilitiesOpts {
+    #[clap(flatten)]
+    format: super::FormatOpts,
+}
+
+#[derive(Debug, Parser)]
+pub struct FirmwareListOpts {
+    #[clap(flatten)]
+    format: super::FormatOpts,
+}
+
+#[derive(Debug, Parser)]
+pub struct PcrPredictOpts {
+    #[clap(flatten)]
+    format: super::FormatOpts,
+}
+
+#[derive(Debug, Parser)]
+pub struct PinOpts {
+    /// Why the component is pinned, shown in the status
+    #[clap(long)]
+    reason: Option<String>,
+
+    /// Component to pin, e.g. `EFI`
+    #[clap(value_parser)]
+    component: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct UnpinOpts {
+    /// Component to unpin
+    #[clap(value_parser)]
+    component: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct UninstallOpts {
+    /// Only forget the component in the saved state, leaving its files in place
+    #[clap(long, action)]
+    keep_files: bool,
+
+    /// Components to uninstall
+    #[clap(value_parser, required = true)]
+    components: Vec<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct ReinstallOpts {
+    /// Write to the ESP even if its filesystem wasn't unmounted cleanly or
+    /// had errors
+    #[clap(long, action)]
+    force: bool,
+
+    /// Components to reinstall
+    #[clap(value_parser, required = true)]
+    components: Vec<String>,
 }
 
 impl CtlCommand {
     /// Run CLI application.
     pub fn run(self) -> Result<()> {
+        self.config.init()?;
+        if let Some(image) = self.image.as_deref() {
+            return Self::run_on_image(image, self.cmd);
+        }
         match self.cmd {
             CtlVerb::Status(opts) => Self::run_status(opts),
-            CtlVerb::Update => Self::run_update(),
-            CtlVerb::AdoptAndUpdate => Self::run_adopt_and_update(),
-            CtlVerb::Validate => Self::run_validate(),
+            CtlVerb::Update(opts) => Self::run_update(opts),
+            CtlVerb::AdoptAndUpdate(opts) => Self::run_adopt_and_update(opts),
+            CtlVerb::Validate(opts) => Self::run_validate(opts),
+            CtlVerb::Diff(opts) => Self::run_diff(opts),
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            CtlVerb::PcrPredict(opts) => Self::run_pcr_predict(opts),
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            CtlVerb::Confirm => Self::run_confirm(),
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            CtlVerb::Rollback => Self::run_rollback(),
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            CtlVerb::BootOrder(CtlBootOrder::Gc(opts)) => Self::run_bootorder_gc(opts),
+            CtlVerb::MarkBootSuccessful => Self::run_mark_boot_successful(),
+            CtlVerb::Scrub => Self::run_scrub(),
+            CtlVerb::Pin(opts) => Self::run_pin(opts),
+            CtlVerb::Unpin(opts) => Self::run_unpin(opts),
+            CtlVerb::Uninstall(opts) => Self::run_uninstall(opts),
+            CtlVerb::Reinstall(opts) => Self::run_reinstall(opts),
             CtlVerb::Backend(CtlBackend::Generate(opts)) => {
                 super::bootupd::DCommand::run_generate_meta(opts)
             }
             CtlVerb::Backend(CtlBackend::Install(opts)) => {
                 super::bootupd::DCommand::run_install(opts)
             }
-            CtlVerb::MigrateStaticGrubConfig => Self::run_migrate_static_grub_config(),
+            CtlVerb::Backend(CtlBackend::ExtendPayload(opts)) => {
+                super::bootupd::DCommand::run_extend_payload(opts)
+            }
+            CtlVerb::Backend(CtlBackend::OstreePostDeploy(opts)) => {
+                Self::run_ostree_post_deploy(opts)
+            }
+            CtlVerb::Backend(CtlBackend::AuthenticateState) => Self::run_authenticate_state(),
+            CtlVerb::Backend(CtlBackend::RecoverState(opts)) => Self::run_recover_state(opts),
+            CtlVerb::MigrateStaticGrubConfig(opts) => Self::run_migrate_static_grub_config(opts),
+            CtlVerb::RestoreGrubConfig(opts) => Self::run_restore_grub_config(opts),
+            CtlVerb::BootloaderConfig(CtlBootloaderConfig::Get) => {
+                Self::run_bootloader_config_get()
+            }
+            CtlVerb::BootloaderConfig(CtlBootloaderConfig::Set(opts)) => {
+                Self::run_bootloader_config_set(opts)
+            }
+            CtlVerb::InspectDisk(opts) => Self::run_inspect_disk(opts),
+            CtlVerb::Capabilities(opts) => Self::run_capabilities(opts),
+            CtlVerb::Firmware(CtlFirmware::List(opts)) => Self::run_firmware_list(opts),
+            CtlVerb::Internals(CtlInternals::Completions { shell }) => Self::run_completions(shell),
+            CtlVerb::Internals(CtlInternals::Man { output_dir }) => {
+                Self::run_man(output_dir.as_deref())
+            }
+            #[cfg(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            ))]
+            CtlVerb::Internals(CtlInternals::DiffTrees(opts)) => Self::run_diff_trees(opts),
+            CtlVerb::Internals(CtlInternals::VerifyAuditLog(opts)) => {
+                Self::run_verify_audit_log(opts)
+            }
         }
     }
 
     /// Runner for `status` verb.
     fn run_status(opts: StatusOpts) -> Result<()> {
-        if crate::util::running_in_container() {
-            return run_status_in_container(opts.json);
+        let in_container = crate::util::running_in_container();
+        let format = opts.format(in_container);
+        if in_container {
+            return run_status_in_container(format);
         }
-        ensure_running_in_systemd()?;
-        let r = bootupd::status()?;
-        if opts.json {
-            let stdout = std::io::stdout();
-            let mut stdout = stdout.lock();
-            serde_json::to_writer_pretty(&mut stdout, &r)?;
+        bootupd::ensure_supported_platform()?;
+        let r = if !rustix::process::getuid().is_root() && !opts.watch {
+            // Unprivileged, e.g. a monitoring agent
+            let path = std::path::Path::new(bootupd::STATUS_SNAPSHOT);
+            let (mut r, written) = bootupd::read_status_snapshot(path)?;
+            if !opts.components.is_empty() {
+                r.components
+                    .retain(|name, _| opts.components.contains(name));
+                r.adoptable.retain(|name, _| opts.components.contains(name));
+            }
+            if format == super::OutputFormat::Human {
+                eprintln!("Note: status as of {}", written.to_rfc3339());
+            }
+            r
+        } else {
+            ensure_running_in_systemd()?;
+            if opts.watch {
+                bootupd::wait_for_update(&opts.components)?
+            } else {
+                bootupd::status_of(&opts.components)?
+            }
+        };
+        crate::notice::write(
+            opts.notices.targets(),
+            crate::notice::Topic::Updates,
+            &bootupd::updates_available(&r),
+        )?;
+        if format != super::OutputFormat::Human {
+            format.print(&r)?;
         } else if opts.print_if_available {
             bootupd::print_status_avail(&r)?;
+        } else if opts.summary {
+            bootupd::print_status_summary(&r);
         } else {
             bootupd::print_status(&r)?;
+            #[cfg(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            ))]
+            if opts.verbose {
+                bootupd::print_provenance()?;
+            }
+            if let Some(platform) = r.platform.as_ref().filter(|_| opts.verbose) {
+                bootupd::print_platform(platform);
+            }
         }
 
+        if opts.check && !bootupd::updates_available(&r).is_empty() {
+            return Err(crate::exitcode::Outcome::UpdatesAvailable.into());
+        }
         Ok(())
     }
 
+    /// Run `cmd` on the disk image at `path`.
+    fn run_on_image(path: &std::path::Path, cmd: CtlVerb) -> Result<()> {
+        require_root_permission()?;
+        match cmd {
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            CtlVerb::Status(opts) => bootupd::client_run_image_status(path, opts.format(false)),
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            CtlVerb::Validate(opts) => {
+                bootupd::client_run_image_validate(path, opts.format.format, opts.fast)
+            }
+            #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+            CtlVerb::Status(_) | CtlVerb::Validate(_) => {
+                anyhow::bail!(
+                    "--image {} isn't supported on this architecture",
+                    path.display()
+                )
+            }
+            _ => anyhow::bail!("--image is only supported by status and validate"),
+        }
+    }
+
     /// Runner for `update` verb.
-    fn run_update() -> Result<()> {
+    fn run_update(opts: UpdateOpts) -> Result<()> {
         ensure_running_in_systemd()?;
-        bootupd::client_run_update()
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if opts.force {
+            crate::fatcheck::set_force();
+        }
+        bootupd::client_run_update(opts.migrate_static_configs, opts.now)
     }
 
-    /// Runner for `update` verb.
-    fn run_adopt_and_update() -> Result<()> {
+    /// Runner for `adopt-and-update` verb.
+    fn run_adopt_and_update(opts: AdoptOpts) -> Result<()> {
         ensure_running_in_systemd()?;
-        bootupd::client_run_adopt_and_update()
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if opts.force {
+            crate::fatcheck::set_force();
+        }
+        bootupd::client_run_adopt_and_update(opts.from.map(AdoptFrom::component))
     }
 
     /// Runner for `validate` verb.
-    fn run_validate() -> Result<()> {
+    fn run_validate(opts: ValidateOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        let checks = bootupd::ValidateChecks {
+            bls: opts.bls,
+            boot_chain: opts.boot_chain,
+            fast: opts.fast,
+            cross_esp: opts.cross_esp,
+            resync: opts.resync,
+            tpm: opts.tpm,
+        };
+        bootupd::client_run_validate(opts.format.format, checks, opts.notices.targets())
+    }
+
+    /// Runner for `diff` verb.
+    fn run_diff(opts: DiffOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_diff(&opts.components, opts.format.format)
+    }
+
+    /// Runner for `pcr-predict` verb.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn run_pcr_predict(opts: PcrPredictOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_pcr_predict(opts.format.format)
+    }
+
+    /// Runner for `confirm` verb.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn run_confirm() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_confirm()
+    }
+
+    /// Runner for `rollback` verb.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn run_rollback() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_rollback()
+    }
+
+    /// Runner for `bootorder gc` verb.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn run_bootorder_gc(opts: BootOrderGcOpts) -> Result<()> {
         ensure_running_in_systemd()?;
-        bootupd::client_run_validate()
+        bootupd::client_run_bootorder_gc(opts.dry_run)
+    }
+
+    /// Runner for `mark-boot-successful` verb.
+    fn run_mark_boot_successful() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_mark_boot_successful()
+    }
+
+    /// Runner for `scrub` verb.
+    fn run_scrub() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_scrub()
+    }
+
+    /// Runner for `backend ostree-post-deploy` verb.
+    fn run_ostree_post_deploy(opts: OstreePostDeployOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_ostree_post_deploy(opts.deployment.as_deref())
+    }
+
+    /// Runner for `backend authenticate-state` verb.
+    fn run_authenticate_state() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_authenticate_state()
+    }
+
+    /// Runner for `backend recover-state` verb.
+    fn run_recover_state(opts: RecoverStateOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_recover_state(opts.use_backup)
+    }
+
+    /// Runner for `pin` verb.
+    fn run_pin(opts: PinOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_pin(&opts.component, opts.reason.as_deref())
+    }
+
+    /// Runner for `unpin` verb.
+    fn run_unpin(opts: UnpinOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_unpin(&opts.component)
+    }
+
+    /// Runner for `uninstall` verb.
+    fn run_uninstall(opts: UninstallOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_uninstall(&opts.components, opts.keep_files)
+    }
+
+    /// Runner for `reinstall` verb.
+    fn run_reinstall(opts: ReinstallOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if opts.force {
+            crate::fatcheck::set_force();
+        }
+        bootupd::client_run_reinstall(&opts.components)
     }
 
     /// Runner for `migrate-static-grub-config` verb.
-    fn run_migrate_static_grub_config() -> Result<()> {
+    fn run_migrate_static_grub_config(opts: MigrateStaticGrubConfigOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_migrate_static_grub_config(opts.check, opts.force)
+    }
+
+    /// Runner for `restore-grub-config` verb.
+    fn run_restore_grub_config(opts: RestoreGrubConfigOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_restore_grub_config(opts.backup.as_deref(), opts.list)
+    }
+
+    /// Runner for `bootloader-config get` verb.
+    fn run_bootloader_config_get() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_bootloader_config_get()
+    }
+
+    /// Runner for `bootloader-config set` verb.
+    fn run_bootloader_config_set(opts: BootloaderConfigSetOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_bootloader_config_set(&opts.value, opts.force)
+    }
+
+    /// Runner for `inspect-disk` verb.
+    fn run_inspect_disk(opts: InspectDiskOpts) -> Result<()> {
+        // Only reads partition tables, so it doesn't need to run in the
+        // daemon, and works on a target root during installation
+        require_root_permission()?;
+        bootupd::client_run_inspect_disk(&opts.root, opts.format.format)
+    }
+
+    /// Runner for `capabilities` verb.
+    fn run_capabilities(opts: CapabilitiesOpts) -> Result<()> {
+        // Only introspection, so that it works for unprivileged tools and
+        // is cheap to call before anything else
+        let platform = bootupd::platform_info();
+        if opts.format.format != super::OutputFormat::Human {
+            opts.format.format.print(&platform)?;
+        } else {
+            bootupd::print_platform(&platform);
+        }
+        Ok(())
+    }
+
+    /// Runner for `firmware list` verb.
+    fn run_firmware_list(opts: FirmwareListOpts) -> Result<()> {
         ensure_running_in_systemd()?;
-        bootupd::client_run_migrate_static_grub_config()
+        bootupd::client_run_firmware_list(opts.format.format)
+    }
+
+    /// Runner for `internals diff-trees` verb.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn run_diff_trees(opts: DiffTreesOpts) -> Result<()> {
+        bootupd::client_run_diff_trees(&opts.from, &opts.to, opts.format.format)
+    }
+
+    /// Runner for `internals verify-audit-log` verb.
+    fn run_verify_audit_log(opts: VerifyAuditLogOpts) -> Result<()> {
+        bootupd::client_run_verify_audit_log(&opts.log, opts.hmac_key.as_deref())
+    }
+
+    /// Runner for `internals completions` verb.
+    fn run_completions(shell: clap_complete::Shell) -> Result<()> {
+        let mut cmd = Self::command();
+        clap_complete::generate(shell, &mut cmd, "bootupctl", &mut std::io::stdout().lock());
+        Ok(())
+    }
+
+    /// Runner for `internals man` verb.
+    fn run_man(output_dir: Option<&std::path::Path>) -> Result<()> {
+        let cmd = Self::command();
+        if let Some(dir) = output_dir {
+            clap_mangen::generate_to(cmd, dir)
+                .with_context(|| format!("Writing man pages to {dir:?}"))?;
+        } else {
+            clap_mangen::Man::new(cmd).render(&mut std::io::stdout().lock())?;
+        }
+        Ok(())
     }
 }
 
@@ -165,6 +949,22 @@ fn require_root_permission() -> Result<()> {
     Ok(())
 }
 
+/// The properties of the transient unit: [`SYSTEMD_PROPERTIES`] and the
+/// configured resource limits.
+fn systemd_properties(config: &crate::config::Config) -> Vec<String> {
+    let mut r: Vec<String> = SYSTEMD_PROPERTIES.iter().map(|&v| v.into()).collect();
+    if let Some(timeout) = config.timeout {
+        r.push(format!("RuntimeMaxSec={timeout}"));
+    }
+    if let Some(cpu_quota) = config.cpu_quota {
+        r.push(format!("CPUQuota={cpu_quota}%"));
+    }
+    if let Some(io_weight) = config.io_weight {
+        r.push(format!("IOWeight={io_weight}"));
+    }
+    r
+}
+
 /// Detect if we're running in systemd; if we're not, we re-exec ourselves via
 /// systemd-run. Then we can just directly run code in what is now the daemon.
 fn ensure_running_in_systemd() -> Result<()> {
@@ -182,9 +982,9 @@ fn ensure_running_in_systemd() -> Result<()> {
         let r = Command::new("systemd-run")
             .args(SYSTEMD_ARGS_BOOTUPD)
             .args(
-                SYSTEMD_PROPERTIES
+                systemd_properties(crate::config::get())
                     .into_iter()
-                    .flat_map(|&v| ["--property", v]),
+                    .flat_map(|v| ["--property".into(), v]),
             )
             .args(std::env::args())
             .exec();
@@ -195,19 +995,18 @@ fn ensure_running_in_systemd() -> Result<()> {
 }
 
 /// If running in container, just print the available payloads
-fn run_status_in_container(json_format: bool) -> Result<()> {
+fn run_status_in_container(format: super::OutputFormat) -> Result<()> {
     let all_components = crate::bootupd::get_components();
     if all_components.is_empty() {
         return Ok(());
     }
     let avail: Vec<_> = all_components.keys().cloned().collect();
-    if json_format {
-        let stdout = std::io::stdout();
-        let mut stdout = stdout.lock();
+    if format != super::OutputFormat::Human {
         let output: serde_json::Value = serde_json::json!({
-            "components": avail
+            "components": avail,
+            "platform": crate::bootupd::platform_info(),
         });
-        serde_json::to_writer(&mut stdout, &output)?;
+        format.print(&output)?;
     } else {
         println!("Available components: {}", avail.join(" "));
     }
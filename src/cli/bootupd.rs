@@ -2,6 +2,7 @@ use crate::bootupd::{self, ConfigMode};
 use anyhow::{Context, Result};
 use clap::Parser;
 use log::LevelFilter;
+use std::path::Path;
 
 /// `bootupd` sub-commands.
 #[derive(Debug, Parser)]
@@ -11,6 +12,12 @@ pub struct DCommand {
     #[clap(short = 'v', action = clap::ArgAction::Count, global = true)]
     verbosity: u8,
 
+    #[clap(flatten)]
+    config: super::ConfigOpts,
+
+    #[clap(flatten)]
+    trace: super::TraceOpts,
+
     /// CLI sub-command.
     #[clap(subcommand)]
     pub cmd: DVerb,
@@ -26,6 +33,11 @@ impl DCommand {
             _ => LevelFilter::Trace,
         }
     }
+
+    /// Return the options for tracing the run.
+    pub(crate) fn trace_opts(&self) -> &super::TraceOpts {
+        &self.trace
+    }
 }
 
 /// CLI sub-commands.
@@ -35,6 +47,22 @@ pub enum DVerb {
     GenerateUpdateMetadata(GenerateOpts),
     #[clap(name = "install", about = "Install components")]
     Install(InstallOpts),
+    #[clap(
+        name = "extend-payload",
+        about = "Add supplemental files to an update payload"
+    )]
+    ExtendPayload(ExtendPayloadOpts),
+    #[clap(name = "internals", hide = true, subcommand)]
+    Internals(InternalsVerb),
+}
+
+/// Commands for development and testing.
+#[derive(Debug, Parser)]
+pub enum InternalsVerb {
+    /// Inject failures via failpoints and check that we recover from them;
+    /// this runs entirely in a temporary directory.
+    #[clap(name = "chaos-test")]
+    ChaosTest,
 }
 
 #[derive(Debug, Parser)]
@@ -42,13 +70,32 @@ pub struct InstallOpts {
     /// Source root
     #[clap(long, value_parser, default_value_t = String::from("/"))]
     src_root: String,
-    /// Target root
-    #[clap(value_parser)]
-    dest_root: String,
+    /// Target root; defaults to the `--from-ostree-deployment` physical root
+    #[clap(value_parser, required_unless_present = "from_ostree_deployment")]
+    dest_root: Option<String>,
+
+    /// Path to an ostree physical root; its (only) deployment is used as
+    /// the source root
+    #[clap(long, value_parser, conflicts_with = "src_root")]
+    from_ostree_deployment: Option<String>,
+
+    /// Container image to use as the source root, pulled from its registry
+    /// unless given as `containers-storage:<image>`
+    #[clap(
+        long,
+        value_name = "IMAGE",
+        conflicts_with_all = ["src_root", "from_ostree_deployment"]
+    )]
+    source_image: Option<String>,
 
-    /// Target device, used by bios bootloader installation
+    /// Target device, used by bios bootloader installation; may be repeated
+    /// to install to each disk of a mirrored `/boot`
     #[clap(long)]
-    device: Option<String>,
+    device: Vec<String>,
+
+    /// Install to all the disks backing `/boot` in the target root
+    #[clap(long, conflicts_with = "device")]
+    auto_devices: bool,
 
     /// Enable installation of the built-in static config files
     #[clap(long)]
@@ -73,6 +120,38 @@ pub struct InstallOpts {
     /// then only enable installation to the ESP.
     #[clap(long)]
     auto: bool,
+
+    /// Print what would be installed as JSON, without changing anything.
+    #[clap(long)]
+    plan_only: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct ExtendPayloadOpts {
+    /// Component whose payload to extend
+    #[clap(long)]
+    component: String,
+
+    /// File or directory to add; installed relative to the component's
+    /// destination, e.g. `EFI/` on the ESP
+    #[clap(long, value_parser)]
+    src: std::path::PathBuf,
+
+    /// Package name, instead of the name of the rpm owning `--src`
+    #[clap(long)]
+    pkg_name: Option<String>,
+
+    /// Package version, instead of the version of the rpm owning `--src`
+    #[clap(long)]
+    version: Option<String>,
+
+    /// How many versions of the package to keep, including this one; the
+    /// newest is included in the update payload
+    #[clap(long, default_value_t = 1)]
+    keep: usize,
+
+    #[clap(flatten)]
+    format: super::FormatOpts,
 }
 
 #[derive(Debug, Parser)]
@@ -80,14 +159,22 @@ pub struct GenerateOpts {
     /// Physical root mountpoint
     #[clap(value_parser)]
     sysroot: Option<String>,
+
+    /// Generate the EFI payload from the shim and GRUB packages in this
+    /// directory, which don't need to be installed
+    #[clap(long, value_name = "DIR")]
+    from_rpms: Option<std::path::PathBuf>,
 }
 
 impl DCommand {
     /// Run CLI application.
     pub fn run(self) -> Result<()> {
+        self.config.init()?;
         match self.cmd {
             DVerb::Install(opts) => Self::run_install(opts),
             DVerb::GenerateUpdateMetadata(opts) => Self::run_generate_meta(opts),
+            DVerb::ExtendPayload(opts) => Self::run_extend_payload(opts),
+            DVerb::Internals(InternalsVerb::ChaosTest) => crate::chaos::run(),
         }
     }
 
@@ -97,10 +184,43 @@ impl DCommand {
         if sysroot != "/" {
             anyhow::bail!("Using a non-default sysroot is not supported: {}", sysroot);
         }
+        if let Some(rpmdir) = opts.from_rpms.as_deref() {
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            return bootupd::generate_update_metadata_from_rpms(sysroot, rpmdir)
+                .context("generating metadata failed");
+            #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+            anyhow::bail!("--from-rpms {rpmdir:?} is only supported with EFI");
+        }
         bootupd::generate_update_metadata(sysroot).context("generating metadata failed")?;
         Ok(())
     }
 
+    /// Runner for `extend-payload` verb.
+    pub(crate) fn run_extend_payload(opts: ExtendPayloadOpts) -> Result<()> {
+        let r = bootupd::extend_payload(
+            "/",
+            &opts.component,
+            &opts.src,
+            opts.pkg_name.as_deref(),
+            opts.version.as_deref(),
+            opts.keep,
+        )?;
+        if opts.format.format != super::OutputFormat::Human {
+            return opts.format.format.print(&r);
+        }
+        println!(
+            "Added {} {} to {} payload in {}:",
+            r.package, r.version, r.component, r.path
+        );
+        for f in r.files.iter() {
+            println!("  {}", f);
+        }
+        for v in r.removed.iter() {
+            println!("Removed {} {}", r.package, v);
+        }
+        Ok(())
+    }
+
     /// Runner for `install` verb.
     pub(crate) fn run_install(opts: InstallOpts) -> Result<()> {
         let configmode = if opts.write_uuid {
@@ -110,16 +230,56 @@ impl DCommand {
         } else {
             ConfigMode::None
         };
-        bootupd::install(
-            &opts.src_root,
-            &opts.dest_root,
-            opts.device.as_deref(),
+        // Kept mounted until the installation is done
+        let source_image = opts
+            .source_image
+            .as_deref()
+            .map(crate::sourceimage::SourceImage::mount)
+            .transpose()?;
+        let (src_root, dest_root) = match opts.from_ostree_deployment.as_deref() {
+            Some(sysroot) => {
+                let deployment = crate::ostreeutil::find_deployment(Path::new(sysroot))?;
+                let deployment = deployment
+                    .to_str()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid path {deployment:?}"))?
+                    .to_string();
+                let dest_root = opts.dest_root.unwrap_or_else(|| sysroot.to_string());
+                (deployment, dest_root)
+            }
+            None => {
+                let src_root = match source_image.as_ref() {
+                    Some(image) => image
+                        .root()
+                        .to_str()
+                        .ok_or_else(|| anyhow::anyhow!("Invalid path {:?}", image.root()))?
+                        .to_string(),
+                    None => opts.src_root,
+                };
+                (src_root, opts.dest_root.expect("dest_root"))
+            }
+        };
+        let devices = if opts.auto_devices {
+            crate::blockdev::get_devices(&dest_root)?
+        } else {
+            opts.device
+        };
+        let plan = bootupd::plan_install(
+            &src_root,
+            &dest_root,
+            &devices,
             configmode,
             opts.update_firmware,
             opts.components.as_deref(),
             opts.auto,
-        )
-        .context("boot data installation failed")?;
+        )?;
+        let Some(plan) = plan else {
+            println!("No components available for this platform.");
+            return Ok(());
+        };
+        if opts.plan_only {
+            return super::OutputFormat::JsonPretty.print(&plan);
+        }
+        bootupd::apply_install_plan(&plan).context("boot data installation failed")?;
         Ok(())
     }
 }
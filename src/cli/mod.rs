@@ -3,9 +3,337 @@
 use anyhow::Result;
 use clap::Parser;
 use log::LevelFilter;
+use serde::Serialize;
 mod bootupctl;
 mod bootupd;
 
+/// Output format, shared by all verbs which produce output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text; not intended to be parsed
+    Human,
+    /// YAML
+    Yaml,
+    /// Indented JSON
+    JsonPretty,
+    /// JSON on a single line
+    JsonCompact,
+}
+
+/// Options for the format of output.
+#[derive(Debug, Parser)]
+pub struct FormatOpts {
+    /// Output format
+    #[clap(long, value_enum, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
+}
+
+/// Options for notices shown at login.
+#[derive(Debug, Parser)]
+pub struct NoticeOpts {
+    /// Also write a summary to `/run/motd.d`, shown after logging in
+    #[clap(long)]
+    motd: bool,
+
+    /// Also write a summary to `/run/issue.d`, shown on consoles before
+    /// logging in
+    #[clap(long)]
+    issue: bool,
+}
+
+impl NoticeOpts {
+    pub(crate) fn targets(&self) -> crate::notice::Targets {
+        crate::notice::Targets {
+            motd: self.motd,
+            issue: self.issue,
+        }
+    }
+}
+
+/// Make `path` absolute, as `bootupctl` re-executes itself in a systemd
+/// unit running in `/`.
+fn absolute_path(path: &str) -> std::io::Result<std::path::PathBuf> {
+    Ok(std::env::current_dir()?.join(path))
+}
+
+impl OutputFormat {
+    /// Write `value` to stdout; this must not be called for `Human`,
+    /// which each verb formats itself.
+    pub(crate) fn print<T: Serialize>(self, value: &T) -> Result<()> {
+        use std::io::Write;
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        match self {
+            OutputFormat::Human => anyhow::bail!("No structured output for human format"),
+            OutputFormat::Yaml => serde_norway::to_writer(&mut stdout, value)?,
+            OutputFormat::JsonPretty => {
+                serde_json::to_writer_pretty(&mut stdout, value)?;
+                writeln!(stdout)?;
+            }
+            OutputFormat::JsonCompact => {
+                serde_json::to_writer(&mut stdout, value)?;
+                writeln!(stdout)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options for tracing the slow steps (mounting, hashing, diffing,
+/// applying updates).
+#[derive(Debug, Parser)]
+pub struct TraceOpts {
+    /// Write a Chrome trace of the slow steps to this file, e.g. to load in
+    /// Perfetto
+    #[clap(long, global = true, value_name = "PATH", value_parser = absolute_path)]
+    trace_file: Option<std::path::PathBuf>,
+
+    /// Export the slow steps as OpenTelemetry spans to this OTLP/HTTP
+    /// traces endpoint, e.g. `http://localhost:4318/v1/traces`
+    #[cfg(feature = "otlp")]
+    #[clap(long, global = true, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+}
+
+impl TraceOpts {
+    /// Return the file to write a trace of the run to, if requested.
+    pub(crate) fn trace_file(&self) -> Option<&std::path::Path> {
+        self.trace_file.as_deref()
+    }
+
+    /// Return the endpoint to export the spans of the run to, if requested.
+    pub(crate) fn otlp_endpoint(&self) -> Option<&str> {
+        #[cfg(feature = "otlp")]
+        {
+            self.otlp_endpoint.as_deref()
+        }
+        #[cfg(not(feature = "otlp"))]
+        {
+            None
+        }
+    }
+
+    /// Set up tracing as requested; the traces are flushed when the
+    /// returned guard is dropped.
+    pub(crate) fn init(&self) -> Result<crate::trace::Guard> {
+        crate::trace::init(self.trace_file(), self.otlp_endpoint())
+    }
+}
+
+/// Options overriding settings from the configuration file.
+#[derive(Debug, Parser)]
+pub struct ConfigOpts {
+    /// Mount the ESP at a private temporary directory for the duration of
+    /// the operation, rather than using the well-known mountpoints.
+    #[clap(long, global = true)]
+    esp_private_mount: bool,
+
+    /// Path where the ESP is mounted, instead of probing the well-known
+    /// mountpoints (`/boot/efi`, `/efi`, `/boot`).
+    #[clap(long, global = true, value_name = "PATH")]
+    esp_mount: Option<String>,
+
+    /// Name of the EFI vendor directory (e.g. `fedora`), instead of
+    /// detecting it from the location of shim.
+    #[clap(long, global = true, value_name = "NAME")]
+    efi_vendor: Option<String>,
+
+    /// Name of the EFI boot entry, which is also written to the fallback
+    /// CSV next to shim, instead of the name of the OS.
+    #[clap(long, global = true, value_name = "LABEL")]
+    efi_boot_label: Option<String>,
+
+    /// Wait for up to this many seconds if another bootupd process holds
+    /// the lock, rather than until it is released; 0 fails immediately.
+    #[clap(long, global = true, value_name = "SECONDS")]
+    lock_timeout: Option<u64>,
+
+    /// Apply bootloader updates from a new ostree deployment as soon as it
+    /// is deployed, rather than only reporting them.
+    #[clap(long, global = true)]
+    update_on_deploy: bool,
+
+    /// Retry operations which often fail transiently (mounting the ESP,
+    /// writing EFI variables) this many times.
+    #[clap(long, global = true, value_name = "N")]
+    retries: Option<u32>,
+
+    /// Wait this many milliseconds before the first retry, doubling each
+    /// time.
+    #[clap(long, global = true, value_name = "MS")]
+    retry_delay_ms: Option<u64>,
+
+    /// Fail if EFI boot entries can't be written (e.g. because efivarfs
+    /// is read-only), rather than recording a warning in the status.
+    #[clap(long, global = true)]
+    require_nvram: bool,
+
+    /// Never modify EFI variables, e.g. when building disk images or
+    /// installing to removable media; boot via the fallback path instead.
+    #[clap(long, global = true, conflicts_with = "require_nvram")]
+    no_nvram: bool,
+
+    /// Remove all EFI boot entries with our label before creating ours,
+    /// as older versions did, even those loading another system's files.
+    #[clap(long, global = true, conflicts_with = "no_nvram")]
+    force_label_match: bool,
+
+    /// On ppc64le, update the OpenFirmware `boot-device` variable to boot
+    /// from the PReP partition, e.g. on bare-metal Power systems.
+    #[clap(long, global = true, conflicts_with = "no_nvram")]
+    ofw_nvram: bool,
+
+    /// On systems with two ESPs, update only the one which wasn't booted
+    /// and make the firmware boot it first; `bootupctl confirm` then
+    /// updates the other one.
+    #[clap(long, global = true, conflicts_with = "no_nvram")]
+    ab_esp: bool,
+
+    /// Only update during the times matching this cron expression (e.g.
+    /// `* 2-3 * * sat,sun`); may be repeated.
+    #[clap(long = "maintenance-window", global = true, value_name = "CRON")]
+    maintenance_windows: Vec<String>,
+
+    /// Keep this many backups of the GRUB config when migrating to a
+    /// static config.
+    #[clap(long, global = true, value_name = "N")]
+    grub_config_backups: Option<usize>,
+
+    /// Warn when generating an update payload larger than this many MiB.
+    #[clap(long, global = true, value_name = "MIB")]
+    payload_size_warning_mib: Option<u64>,
+
+    /// Append a record of every file created, modified or removed, with
+    /// its SHA-512 before and after, to this file.
+    #[clap(long, global = true, value_name = "PATH")]
+    audit_log: Option<String>,
+
+    /// Seal the records in the audit log with the HMAC key in this file.
+    #[clap(long, global = true, value_name = "PATH")]
+    audit_hmac_key: Option<String>,
+
+    /// When verifying the files written to the ESP, read them back from
+    /// the storage (with `O_DIRECT`) rather than the page cache.
+    #[clap(long, global = true)]
+    verify_direct_io: bool,
+
+    /// Repair an ESP which wasn't unmounted cleanly with `fsck.fat`
+    /// before mounting it.
+    #[clap(long, global = true)]
+    esp_fsck: bool,
+
+    /// Whether a failing update hook (in `/etc/bootupd/hooks.d`) stops
+    /// the update or is only logged.
+    #[clap(long, global = true, value_enum)]
+    hook_failure: Option<crate::hooks::HookFailure>,
+
+    /// Stop the operation (run as a transient systemd unit) after this
+    /// many seconds.
+    #[clap(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Limit the operation to this percentage (1-100) of the time of one
+    /// CPU.
+    #[clap(long, global = true, value_name = "PERCENT", value_parser = clap::value_parser!(u32).range(1..=100))]
+    cpu_quota: Option<u32>,
+
+    /// The IO weight of the operation, from 1 to 10000 (default 100).
+    #[clap(long, global = true, value_name = "WEIGHT", value_parser = clap::value_parser!(u64).range(1..=10000))]
+    io_weight: Option<u64>,
+}
+
+impl ConfigOpts {
+    /// Load the configuration file, apply overrides and make it active.
+    pub(crate) fn init(&self) -> Result<()> {
+        let mut config = crate::config::Config::load()?;
+        if self.esp_private_mount {
+            config.esp_private_mount = true;
+        }
+        if let Some(esp_mount) = self.esp_mount.as_ref() {
+            config.esp_mount = Some(esp_mount.clone());
+        }
+        if let Some(efi_vendor) = self.efi_vendor.as_ref() {
+            config.efi_vendor = Some(efi_vendor.clone());
+        }
+        if let Some(efi_boot_label) = self.efi_boot_label.as_ref() {
+            config.efi_boot_label = Some(efi_boot_label.clone());
+        }
+        if let Some(lock_timeout) = self.lock_timeout {
+            config.lock_timeout = Some(lock_timeout);
+        }
+        if self.update_on_deploy {
+            config.update_on_deploy = true;
+        }
+        if let Some(retries) = self.retries {
+            config.retries = Some(retries);
+        }
+        if let Some(retry_delay_ms) = self.retry_delay_ms {
+            config.retry_delay_ms = Some(retry_delay_ms);
+        }
+        if self.require_nvram {
+            config.require_nvram = true;
+        }
+        if self.no_nvram {
+            config.no_nvram = true;
+        }
+        if self.force_label_match {
+            config.force_label_match = true;
+        }
+        if self.ofw_nvram {
+            config.ofw_nvram = true;
+        }
+        if self.ab_esp {
+            config.ab_esp = true;
+        }
+        if !self.maintenance_windows.is_empty() {
+            config.maintenance_windows = self.maintenance_windows.clone();
+        }
+        if let Some(grub_config_backups) = self.grub_config_backups {
+            config.grub_config_backups = Some(grub_config_backups);
+        }
+        if let Some(payload_size_warning_mib) = self.payload_size_warning_mib {
+            config.payload_size_warning_mib = Some(payload_size_warning_mib);
+        }
+        if let Some(audit_log) = self.audit_log.as_ref() {
+            config.audit_log = Some(audit_log.clone());
+        }
+        if let Some(audit_hmac_key) = self.audit_hmac_key.as_ref() {
+            config.audit_hmac_key = Some(audit_hmac_key.clone());
+        }
+        if self.verify_direct_io {
+            config.verify_direct_io = true;
+        }
+        if self.esp_fsck {
+            config.esp_fsck = true;
+        }
+        if let Some(hook_failure) = self.hook_failure {
+            config.hook_failure = Some(hook_failure);
+        }
+        if let Some(timeout) = self.timeout {
+            config.timeout = Some(timeout);
+        }
+        if let Some(cpu_quota) = self.cpu_quota {
+            config.cpu_quota = Some(cpu_quota);
+        }
+        if let Some(io_weight) = self.io_weight {
+            config.io_weight = Some(io_weight);
+        }
+        config.validate()?;
+        crate::config::init(config);
+        Ok(())
+    }
+}
+
+/// The names of the `bootupctl` verbs, except hidden ones.
+pub(crate) fn ctl_verbs() -> Vec<String> {
+    use clap::CommandFactory;
+    bootupctl::CtlCommand::command()
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set())
+        .map(|c| c.get_name().to_string())
+        .collect()
+}
+
 /// Top-level multicall CLI.
 #[derive(Debug, Parser)]
 pub enum MultiCall {
@@ -32,10 +360,13 @@ impl MultiCall {
     }
 
     pub fn run(self) -> Result<()> {
-        match self {
+        let r = match self {
             MultiCall::Ctl(ctl_cmd) => ctl_cmd.run(),
             MultiCall::D(d_cmd) => d_cmd.run(),
-        }
+        };
+        // Also on errors, so that the status doesn't stay at the failed step
+        crate::notify::finished(&r);
+        r
     }
 
     /// Return the log-level set via command-line flags.
@@ -45,6 +376,22 @@ impl MultiCall {
             MultiCall::D(cmd) => cmd.loglevel(),
         }
     }
+
+    /// Return the options for tracing the run.
+    pub fn trace_opts(&self) -> &TraceOpts {
+        match self {
+            MultiCall::Ctl(cmd) => cmd.trace_opts(),
+            MultiCall::D(cmd) => cmd.trace_opts(),
+        }
+    }
+
+    /// Return the output format set via command-line flags.
+    pub fn output_format(&self) -> OutputFormat {
+        match self {
+            MultiCall::Ctl(cmd) => cmd.output_format(),
+            MultiCall::D(_) => OutputFormat::Human,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -89,6 +436,189 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ctl_verbs() {
+        let verbs = ctl_verbs();
+        assert!(verbs.iter().any(|v| v == "status"));
+        assert!(verbs.iter().any(|v| v == "capabilities"));
+        assert!(!verbs.iter().any(|v| v == "backend" || v == "internals"));
+    }
+
+    #[test]
+    fn test_status_format() {
+        let format = |args: &[&str], in_container| {
+            let cmd =
+                bootupctl::CtlCommand::try_parse_from(["bootupctl", "status"].iter().chain(args))
+                    .unwrap();
+            match cmd.cmd {
+                bootupctl::CtlVerb::Status(opts) => opts.format(in_container),
+                o => panic!("{o:?}"),
+            }
+        };
+        assert_eq!(format(&["--json"], false), OutputFormat::JsonPretty);
+        assert_eq!(format(&["--json"], true), OutputFormat::JsonCompact);
+        assert_eq!(
+            format(&["--format", "json-pretty"], true),
+            OutputFormat::JsonPretty
+        );
+        assert_eq!(format(&[], true), OutputFormat::Human);
+    }
+
+    #[test]
+    fn test_migrate_static_grub_config_opts() {
+        let parse = |args: &[&str]| {
+            bootupctl::CtlCommand::try_parse_from(
+                ["bootupctl", "migrate-static-grub-config"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        assert!(parse(&["--check"]).is_ok());
+        assert!(parse(&["--force"]).is_ok());
+        assert!(parse(&["--check", "--force"]).is_err());
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn test_bootorder_opts() {
+        let parse = |args: &[&str]| bootupctl::CtlCommand::try_parse_from(args.iter());
+        assert!(parse(&["bootupctl", "bootorder", "gc"]).is_ok());
+        assert!(parse(&["bootupctl", "bootorder", "gc", "--dry-run"]).is_ok());
+        assert!(parse(&["bootupctl", "bootorder"]).is_err());
+    }
+
+    #[test]
+    fn test_bootloader_config_opts() {
+        let parse = |args: &[&str]| {
+            bootupctl::CtlCommand::try_parse_from(
+                ["bootupctl", "bootloader-config"].iter().chain(args),
+            )
+        };
+        assert!(parse(&["get"]).is_ok());
+        assert!(parse(&["set", "none"]).is_ok());
+        assert!(parse(&["set", "--force", "auto"]).is_ok());
+        assert!(parse(&["set", "grub"]).is_err());
+        assert!(parse(&["set"]).is_err());
+    }
+
+    #[test]
+    fn test_source_image_opts() {
+        let parse = |args: &[&str]| {
+            bootupctl::CtlCommand::try_parse_from(
+                ["bootupctl", "backend", "install"].iter().chain(args),
+            )
+        };
+        assert!(parse(&[
+            "--source-image",
+            "quay.io/fedora/fedora-bootc:41",
+            "/target"
+        ])
+        .is_ok());
+        assert!(parse(&[
+            "--source-image",
+            "localhost/os",
+            "--src-root",
+            "/",
+            "/target"
+        ])
+        .is_err());
+        assert!(parse(&["--source-image", "localhost/os"]).is_err());
+    }
+
+    #[test]
+    fn test_output_format() {
+        use clap::ValueEnum;
+        let names: Vec<_> = OutputFormat::value_variants()
+            .iter()
+            .map(|v| v.to_possible_value().unwrap().get_name().to_string())
+            .collect();
+        assert_eq!(names, ["human", "yaml", "json-pretty", "json-compact"]);
+    }
+
+    #[test]
+    fn test_resource_limit_opts() {
+        let parse = |args: &[&str]| {
+            bootupctl::CtlCommand::try_parse_from(["bootupctl", "update"].iter().chain(args))
+        };
+        assert!(parse(&[
+            "--timeout",
+            "600",
+            "--cpu-quota",
+            "100",
+            "--io-weight",
+            "10"
+        ])
+        .is_ok());
+        assert!(parse(&["--io-weight", "0"]).is_err());
+        assert!(parse(&["--io-weight", "10001"]).is_err());
+        assert!(parse(&["--cpu-quota", "0"]).is_err());
+        assert!(parse(&["--cpu-quota", "101"]).is_err());
+    }
+
+    #[test]
+    fn test_force_opts() {
+        let parse = |args: &[&str]| bootupctl::CtlCommand::try_parse_from(args.iter());
+        assert!(parse(&["bootupctl", "update", "--force"]).is_ok());
+        assert!(parse(&["bootupctl", "adopt-and-update", "--force", "--esp-fsck"]).is_ok());
+        // Still distinct from the --force of migrate-static-grub-config
+        assert!(parse(&["bootupctl", "migrate-static-grub-config", "--force"]).is_ok());
+        assert!(parse(&["bootupctl", "status", "--force"]).is_err());
+        assert!(parse(&["bootupctl", "reinstall", "--force", "EFI"]).is_ok());
+        assert!(parse(&["bootupctl", "reinstall"]).is_err());
+    }
+
+    #[test]
+    fn test_notice_opts() {
+        let parse = |args: &[&str]| bootupctl::CtlCommand::try_parse_from(args.iter());
+        assert!(parse(&["bootupctl", "status", "--motd", "--issue"]).is_ok());
+        assert!(parse(&["bootupctl", "validate", "--motd"]).is_ok());
+        assert!(parse(&["bootupctl", "validate", "--tpm", "--motd"]).is_ok());
+        // A notice about some of the components would hide the others
+        assert!(parse(&["bootupctl", "status", "--motd", "--components", "EFI"]).is_err());
+    }
+
+    #[test]
+    fn test_image_opts() {
+        let parse = |args: &[&str]| bootupctl::CtlCommand::try_parse_from(args.iter());
+        let cmd = parse(&["bootupctl", "--image", "disk.qcow2", "status"]).unwrap();
+        assert!(matches!(cmd.cmd, bootupctl::CtlVerb::Status(_)));
+        assert!(parse(&["bootupctl", "validate", "--image", "disk.raw", "--fast"]).is_ok());
+        assert!(parse(&["bootupctl", "status", "--image"]).is_err());
+    }
+
+    #[test]
+    fn test_status_summary_opts() {
+        let parse = |args: &[&str]| bootupctl::CtlCommand::try_parse_from(args.iter());
+        assert!(parse(&["bootupctl", "status", "--summary"]).is_ok());
+        assert!(parse(&["bootupctl", "status", "--summary", "--components", "EFI"]).is_ok());
+        assert!(parse(&["bootupctl", "status", "--summary", "--json"]).is_err());
+        assert!(parse(&["bootupctl", "status", "--summary", "--format", "json"]).is_err());
+        assert!(parse(&["bootupctl", "status", "--summary", "--verbose"]).is_err());
+    }
+
+    #[test]
+    fn test_trace_file_opts() {
+        let cmd = MultiCall::from_args(vec![
+            "bootupctl".to_string(),
+            "validate".to_string(),
+            "--trace-file".to_string(),
+            "validate.json".to_string(),
+        ]);
+        let path = cmd.trace_opts().trace_file().unwrap();
+        assert!(path.is_absolute());
+        assert!(path.ends_with("validate.json"));
+
+        let cmd = MultiCall::from_args(vec![
+            "bootupd".to_string(),
+            "--trace-file=/tmp/generate.json".to_string(),
+            "generate-update-metadata".to_string(),
+        ]);
+        assert_eq!(
+            cmd.trace_opts().trace_file(),
+            Some(std::path::Path::new("/tmp/generate.json"))
+        );
+    }
+
     #[test]
     fn test_verbosity() {
         let default = MultiCall::from_args(vec![
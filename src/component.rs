@@ -10,11 +10,16 @@ use openat_ext::OpenatDirExt;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
+use crate::errors::ErrorKind;
+use crate::filetree::FileChange;
 use crate::model::*;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::payloadversion::PayloadVersion;
 
+/// Result of validating a component
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) enum ValidationResult {
+pub enum ValidationResult {
     Valid,
     Skip,
     Errors(Vec<String>),
@@ -24,7 +29,7 @@ pub(crate) enum ValidationResult {
 pub(crate) trait Component {
     /// Returns the name of the component; this will be used for serialization
     /// and should remain stable.
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
 
     /// In an operating system whose initially booted disk image is not
     /// using bootupd, detect whether it looks like the component exists
@@ -53,6 +58,25 @@ pub(crate) trait Component {
         update_firmware: bool,
     ) -> Result<InstalledContent>;
 
+    /// Install to each of `devices`, e.g. the disks of a mirrored `/boot`.
+    /// By default the component is installed once, with the first device.
+    fn install_to_devices(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        devices: &[String],
+        update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let device = devices.first().map_or("", String::as_str);
+        self.install(src_root, dest_root, device, update_firmware)
+    }
+
+    /// Describe where `install` would write for the given target, e.g. a
+    /// mountpoint or block device, without changing anything.
+    fn install_destination(&self, dest_root: &str, _device: &str) -> Result<String> {
+        Ok(dest_root.to_string())
+    }
+
     /// Implementation of `bootupd generate-update-metadata` for a given component.
     /// This expects to be run during an "image update build" process.  For CoreOS
     /// this is an `rpm-ostree compose tree` for example.  For a dual-partition
@@ -60,21 +84,103 @@ pub(crate) trait Component {
     /// while the filesystem for the partition is mounted.
     fn generate_update_metadata(&self, sysroot: &str) -> Result<ContentMetadata>;
 
+    /// Add the file or directory `src` to the payloads in `sysroot`, for
+    /// `generate-update-metadata` to include; used by image builds to ship
+    /// supplemental files such as firmware.  The package and version are
+    /// those of the rpm owning `src`, unless given.  Up to `keep` versions
+    /// of the package are retained, dropping the oldest.
+    fn extend_payload(
+        &self,
+        _sysroot_path: &str,
+        _src: &Path,
+        _pkg_name: Option<&str>,
+        _version: Option<&str>,
+        _keep: usize,
+    ) -> Result<ExtendedPayload> {
+        anyhow::bail!("Component {} can't be extended", self.name())
+    }
+
+    /// The versions of supplemental payloads in `sysroot_path` added by
+    /// [`Component::extend_payload`], by package and then oldest first.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn firmware_versions(&self, _sysroot_path: &str) -> Result<Vec<PayloadVersion>> {
+        Ok(Vec::new())
+    }
+
+    /// The path in the image which the payload file `path` was generated
+    /// from, used to find the package which shipped it.
+    fn payload_source(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+
     /// Used on the client to query for an update cached in the current booted OS.
     fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>>;
 
-    /// Used on the client to run an update.
+    /// Used on the client to run an update, reading the payload from
+    /// `payload_root` (e.g. a new ostree deployment) and writing to the
+    /// bootloader of the system rooted at `dest_root`.
     fn run_update(
         &self,
-        sysroot: &openat::Dir,
+        payload_root: &openat::Dir,
+        dest_root: &openat::Dir,
         current: &InstalledContent,
     ) -> Result<InstalledContent>;
 
-    /// Used on the client to validate an installed version.
-    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult>;
+    /// Used on the client to validate an installed version.  With `fast`,
+    /// files whose size and mtime are unchanged since they were written
+    /// are not hashed.
+    fn validate(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        fast: bool,
+    ) -> Result<ValidationResult>;
+
+    /// Used on the client to list the files an update would change; `None`
+    /// if this component doesn't track its files.
+    fn diff_update(
+        &self,
+        _sysroot: &openat::Dir,
+        _current: &InstalledContent,
+    ) -> Result<Option<Vec<FileChange>>> {
+        Ok(None)
+    }
+
+    /// Used on the client to find problems with an installed version
+    /// which should be shown in `status`.
+    fn status_warnings(
+        &self,
+        _sysroot: &openat::Dir,
+        _current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Used on the client to remove the files of an installed version.
+    fn uninstall(&self, sysroot: &openat::Dir, current: &InstalledContent) -> Result<()>;
 
     /// Locating efi vendor dir
     fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>>;
+
+    /// Locating all efi vendor dirs, for images which carry more than one
+    fn get_efi_vendors(&self, sysroot: &openat::Dir) -> Result<Vec<String>> {
+        Ok(self.get_efi_vendor(sysroot)?.into_iter().collect())
+    }
+}
+
+/// Files added to a payload by [`Component::extend_payload`].
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ExtendedPayload {
+    pub(crate) component: String,
+    pub(crate) package: String,
+    pub(crate) version: String,
+    /// Where the files were written, relative to the root
+    pub(crate) path: String,
+    /// The files written, relative to `path`
+    pub(crate) files: Vec<String>,
+    /// Older versions of the package removed
+    pub(crate) removed: Vec<String>,
 }
 
 /// Given a component name, create an implementation.
@@ -83,24 +189,63 @@ pub(crate) fn new_from_name(name: &str) -> Result<Box<dyn Component>> {
         #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
         #[allow(clippy::box_default)]
         "EFI" => Box::new(crate::efi::Efi::default()),
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        #[allow(clippy::box_default)]
+        crate::systemdboot::NAME => Box::new(crate::systemdboot::SystemdBoot::default()),
         #[cfg(any(target_arch = "x86_64", target_arch = "powerpc64"))]
         #[allow(clippy::box_default)]
         "BIOS" => Box::new(crate::bios::Bios::default()),
-        _ => anyhow::bail!("No component {}", name),
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        #[allow(clippy::box_default)]
+        crate::devicetree::NAME => Box::new(crate::devicetree::DeviceTree::default()),
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        #[allow(clippy::box_default)]
+        crate::emmcboot::NAME => Box::new(crate::emmcboot::EmmcBoot::default()),
+        #[cfg(all(feature = "extlinux", target_arch = "x86_64"))]
+        #[allow(clippy::box_default)]
+        crate::extlinux::NAME => Box::new(crate::extlinux::Extlinux::default()),
+        _ => match crate::plugin::PluginComponent::find(name)? {
+            Some(plugin) => Box::new(plugin),
+            None => anyhow::bail!("No component {}", name),
+        },
     };
     Ok(r)
 }
 
+/// Names of the components built in for this architecture, as accepted
+/// by [`new_from_name`].
+pub(crate) fn builtin_names() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut r = Vec::new();
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    r.extend(["EFI", crate::systemdboot::NAME]);
+    #[cfg(any(target_arch = "x86_64", target_arch = "powerpc64"))]
+    r.push("BIOS");
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    r.extend([crate::devicetree::NAME, crate::emmcboot::NAME]);
+    #[cfg(all(feature = "extlinux", target_arch = "x86_64"))]
+    r.push(crate::extlinux::NAME);
+    r
+}
+
 /// Returns the path to the payload directory for an available update for
 /// a component.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) fn component_updatedirname(component: &dyn Component) -> PathBuf {
     Path::new(BOOTUPD_UPDATES_DIR).join(component.name())
 }
 
 /// Returns the path to the payload directory for an available update for
 /// a component.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) fn component_updatedir(sysroot: &str, component: &dyn Component) -> PathBuf {
     Path::new(sysroot).join(component_updatedirname(component))
 }
@@ -135,6 +280,7 @@ pub(crate) fn get_component_update(
     let name = component_update_data_name(component);
     let path = Path::new(BOOTUPD_UPDATES_DIR).join(name);
     if let Some(f) = sysroot.open_file_optional(&path)? {
+        crate::signature::verify_update(sysroot, component.name())?;
         let mut f = std::io::BufReader::new(f);
         let u = serde_json::from_reader(&mut f)
             .with_context(|| format!("failed to parse {:?}", &path))?;
@@ -144,34 +290,54 @@ pub(crate) fn get_component_update(
     }
 }
 
+/// Like [`get_component_update`], but fails with
+/// [`ErrorKind::PayloadMissing`] if there's no update metadata.
+pub(crate) fn require_component_update(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+) -> Result<ContentMetadata> {
+    get_component_update(sysroot, component)?
+        .ok_or(ErrorKind::PayloadMissing)
+        .with_context(|| {
+            format!(
+                "No update metadata for component {} found",
+                component.name()
+            )
+        })
+}
+
+/// Detect whether the system looks adoptable.  `package_files` are
+/// absolute paths of files identifying the component; on package-mode
+/// systems (e.g. installed by Anaconda) the packages owning them in the
+/// rpm database are used to synthesize the installed version.
 #[context("Querying adoptable state")]
-pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
-    // This would be extended with support for other operating systems later
-    if let Some(coreos_aleph) = crate::coreos::get_aleph_version(Path::new("/"))? {
+pub(crate) fn query_adopt_state(package_files: &[String]) -> Result<Option<Adoptable>> {
+    if let Some(v) = crate::installversion::get_install_version(Path::new("/"))? {
         let meta = ContentMetadata {
-            timestamp: coreos_aleph.ts,
-            version: coreos_aleph.aleph.version,
+            timestamp: v.timestamp,
+            version: v.version,
+            firmware: None,
         };
-        log::trace!("Adoptable: {:?}", &meta);
+        log::trace!("Adoptable from {}: {:?}", v.source, &meta);
         return Ok(Some(Adoptable {
             version: meta,
-            confident: true,
+            confident: v.confident,
         }));
-    } else {
-        log::trace!("No CoreOS aleph detected");
     }
-    let ostree_deploy_dir = Path::new("/ostree/deploy");
-    if ostree_deploy_dir.exists() {
-        let btime = ostree_deploy_dir.metadata()?.created()?;
-        let timestamp = chrono::DateTime::from(btime);
-        let meta = ContentMetadata {
-            timestamp,
-            version: "unknown".to_string(),
-        };
-        return Ok(Some(Adoptable {
-            version: meta,
-            confident: true,
-        }));
+    if !package_files.is_empty() {
+        // Without an ostree deployment, the rpm database describes what
+        // is actually installed; we only know the files are present though,
+        // not that they're unmodified.
+        match crate::packagesystem::query_files("/", package_files) {
+            Ok(meta) => {
+                log::trace!("Adoptable from rpm database: {:?}", &meta);
+                return Ok(Some(Adoptable {
+                    version: meta,
+                    confident: false,
+                }));
+            }
+            Err(e) => log::debug!("Not adoptable from rpm database: {e:#}"),
+        }
     }
     Ok(None)
 }
@@ -180,6 +346,16 @@ pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_builtin_names() -> Result<()> {
+        let names = builtin_names();
+        assert!(!names.is_empty());
+        for name in names {
+            assert_eq!(new_from_name(name)?.name(), name);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_get_efi_vendor() -> Result<()> {
         let td = tempfile::tempdir()?;
@@ -207,6 +383,10 @@ mod tests {
             if component.name() == "EFI" {
                 let x = component.get_efi_vendor(&td);
                 assert_eq!(x.is_err(), true);
+                assert_eq!(
+                    component.get_efi_vendors(&td)?,
+                    ["centos".to_string(), "fedora".to_string()]
+                );
                 std::fs::remove_dir_all(tdp_updates.join("EFI/centos"))?;
                 assert_eq!(component.get_efi_vendor(&td)?, Some("fedora".to_string()));
             }
@@ -0,0 +1,273 @@
+//! Process-wide runtime configuration.
+//!
+//! This is loaded from `/etc/bootupd/config.json` (if present), and
+//! individual settings may be overridden on the command line.
+
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+/// Path to the configuration file.
+pub(crate) const CONFIG_PATH: &str = "/etc/bootupd/config.json";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings which affect how components operate on the system.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub(crate) struct Config {
+    /// Mount the ESP at a private temporary directory under `/run/bootupd`
+    /// instead of reusing (or mounting on) the well-known mountpoints.
+    pub(crate) esp_private_mount: bool,
+    /// Path (relative to the root) where the ESP is mounted; replaces
+    /// the default list of well-known mountpoints.
+    pub(crate) esp_mount: Option<String>,
+    /// Name of the vendor directory under `EFI/`; by default this is
+    /// found by looking for shim in the update payload.
+    pub(crate) efi_vendor: Option<String>,
+    /// Name of the EFI boot entry, also written to the fallback CSVs; by
+    /// default the name of the OS.
+    pub(crate) efi_boot_label: Option<String>,
+    /// Seconds to wait for another bootupd process to release its lock
+    /// before failing, with 0 failing immediately; by default we wait as
+    /// long as it takes.
+    pub(crate) lock_timeout: Option<u64>,
+    /// Apply bootloader updates carried by a new ostree deployment as soon
+    /// as it is deployed, rather than only reporting them.
+    pub(crate) update_on_deploy: bool,
+    /// How many times to retry operations which often fail transiently,
+    /// such as mounting the ESP or writing EFI variables; default 3.
+    pub(crate) retries: Option<u32>,
+    /// Milliseconds to wait before the first retry, doubling each time;
+    /// default 200.
+    pub(crate) retry_delay_ms: Option<u64>,
+    /// Fail installation if EFI boot entries can't be written, rather than
+    /// recording a warning.
+    pub(crate) require_nvram: bool,
+    /// Never modify EFI variables, even with `--update-firmware`; the
+    /// system then boots via the fallback path.  For removable media and
+    /// image builds, where the NVRAM is the build host's.
+    pub(crate) no_nvram: bool,
+    /// Remove all EFI boot entries with our label before creating ours,
+    /// even those which don't load from our vendor directory.
+    pub(crate) force_label_match: bool,
+    /// On ppc64le, point the OpenFirmware `boot-device` variable at the
+    /// PReP partition when installing, adopting or updating.
+    pub(crate) ofw_nvram: bool,
+    /// With two ESPs, update only the one not booted from and boot it
+    /// first; the other is updated by `bootupctl confirm`.
+    pub(crate) ab_esp: bool,
+    /// Cron expressions for the times during which `bootupctl update` may
+    /// write the bootloader; by default any time.
+    pub(crate) maintenance_windows: Vec<String>,
+    /// How many backups of the GRUB config to keep when migrating to a
+    /// static config, besides the one from before the first migration;
+    /// default 5.
+    pub(crate) grub_config_backups: Option<usize>,
+    /// Warn when generating an update payload larger than this many MiB,
+    /// e.g. one which may no longer fit on small ESPs.
+    pub(crate) payload_size_warning_mib: Option<u64>,
+    /// Append a record of every file written or removed to this file.
+    pub(crate) audit_log: Option<String>,
+    /// Seal the records in the audit log with the HMAC key in this file.
+    pub(crate) audit_hmac_key: Option<String>,
+    /// Read back files written to the ESP with `O_DIRECT`, i.e. from the
+    /// storage rather than the page cache, when verifying them.
+    pub(crate) verify_direct_io: bool,
+    /// Run `fsck.fat -a` on an ESP which wasn't unmounted cleanly before
+    /// mounting it, rather than refusing to write to it.
+    pub(crate) esp_fsck: bool,
+    /// What to do when a hook in `/etc/bootupd/hooks.d` fails; by default
+    /// `abort`.
+    pub(crate) hook_failure: Option<crate::hooks::HookFailure>,
+    /// Stop `bootupctl` operations (run as a transient systemd unit) after
+    /// this many seconds; `RuntimeMaxSec=`.
+    pub(crate) timeout: Option<u64>,
+    /// Limit the CPU time of `bootupctl` operations to this percentage (1-100) of
+    /// one CPU; `CPUQuota=`.
+    pub(crate) cpu_quota: Option<u32>,
+    /// The IO weight (1-10000, default 100) of `bootupctl` operations;
+    /// `IOWeight=`.
+    pub(crate) io_weight: Option<u64>,
+}
+
+impl Config {
+    /// Load the configuration file if it exists, otherwise use defaults.
+    #[context("Loading configuration")]
+    pub(crate) fn load() -> Result<Self> {
+        Self::load_from(Path::new(CONFIG_PATH))
+    }
+
+    fn load_from(path: &Path) -> Result<Self> {
+        let f = match std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e).with_context(|| format!("Opening {path:?}")),
+        };
+        let bufr = std::io::BufReader::new(f);
+        let config: Self =
+            serde_json::from_reader(bufr).with_context(|| format!("Parsing {path:?}"))?;
+        config
+            .validate()
+            .with_context(|| format!("Validating {path:?}"))?;
+        Ok(config)
+    }
+
+    /// Check the settings which can't be checked when parsing them.
+    pub(crate) fn validate(&self) -> Result<()> {
+        for w in &self.maintenance_windows {
+            w.parse::<crate::maintenance::Window>()?;
+        }
+        if let Some(cpu_quota) = self.cpu_quota {
+            if !(1..=100).contains(&cpu_quota) {
+                anyhow::bail!("cpu-quota must be between 1 and 100, not {cpu_quota}");
+            }
+        }
+        if let Some(io_weight) = self.io_weight {
+            if !(1..=10000).contains(&io_weight) {
+                anyhow::bail!("io-weight must be between 1 and 10000, not {io_weight}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the configuration; this may only be done once, before any
+/// components are created.
+pub(crate) fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        panic!("Configuration already initialized");
+    }
+}
+
+/// Return the current configuration.
+pub(crate) fn get() -> &'static Config {
+    CONFIG.get_or_init(Default::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let path = td.path().join("config.json");
+        let config = Config::load_from(&path)?;
+        assert!(!config.esp_private_mount);
+        assert_eq!(config.esp_mount, None);
+
+        std::fs::write(
+            &path,
+            r#"{"esp-mount": "efi/system", "efi-vendor": "centos"}"#,
+        )?;
+        let config = Config::load_from(&path)?;
+        assert_eq!(config.esp_mount.as_deref(), Some("efi/system"));
+        assert_eq!(config.efi_vendor.as_deref(), Some("centos"));
+        assert_eq!(config.efi_boot_label, None);
+        assert_eq!(config.lock_timeout, None);
+
+        std::fs::write(&path, r#"{"efi-boot-label": "Fedora Linux"}"#)?;
+        assert_eq!(
+            Config::load_from(&path)?.efi_boot_label.as_deref(),
+            Some("Fedora Linux")
+        );
+
+        std::fs::write(&path, r#"{"lock-timeout": 30}"#)?;
+        assert_eq!(Config::load_from(&path)?.lock_timeout, Some(30));
+        assert!(!Config::load_from(&path)?.update_on_deploy);
+
+        std::fs::write(&path, r#"{"update-on-deploy": true}"#)?;
+        assert!(Config::load_from(&path)?.update_on_deploy);
+        assert!(!Config::load_from(&path)?.ab_esp);
+
+        std::fs::write(&path, r#"{"ab-esp": true}"#)?;
+        assert!(Config::load_from(&path)?.ab_esp);
+        assert!(Config::load_from(&path)?.maintenance_windows.is_empty());
+
+        std::fs::write(&path, r#"{"maintenance-windows": ["* 2-3 * * sat"]}"#)?;
+        assert_eq!(
+            Config::load_from(&path)?.maintenance_windows,
+            ["* 2-3 * * sat"]
+        );
+        std::fs::write(&path, r#"{"maintenance-windows": ["* 2-3 * *"]}"#)?;
+        assert!(Config::load_from(&path).is_err());
+
+        std::fs::write(&path, r#"{"retries": 0, "retry-delay-ms": 50}"#)?;
+        let config = Config::load_from(&path)?;
+        assert_eq!(config.retries, Some(0));
+        assert_eq!(config.retry_delay_ms, Some(50));
+
+        std::fs::write(&path, r#"{"no-nvram": true}"#)?;
+        assert!(Config::load_from(&path)?.no_nvram);
+        assert!(!Config::load_from(&path)?.ofw_nvram);
+
+        std::fs::write(&path, r#"{"force-label-match": true}"#)?;
+        assert!(Config::load_from(&path)?.force_label_match);
+
+        std::fs::write(&path, r#"{"ofw-nvram": true}"#)?;
+        assert!(Config::load_from(&path)?.ofw_nvram);
+
+        std::fs::write(&path, r#"{"grub-config-backups": 2}"#)?;
+        assert_eq!(Config::load_from(&path)?.grub_config_backups, Some(2));
+
+        std::fs::write(&path, r#"{"payload-size-warning-mib": 200}"#)?;
+        assert_eq!(
+            Config::load_from(&path)?.payload_size_warning_mib,
+            Some(200)
+        );
+
+        std::fs::write(
+            &path,
+            r#"{"audit-log": "/var/log/bootupd-audit.jsonl", "audit-hmac-key": "/etc/bootupd/audit-key"}"#,
+        )?;
+        let config = Config::load_from(&path)?;
+        assert_eq!(
+            config.audit_log.as_deref(),
+            Some("/var/log/bootupd-audit.jsonl")
+        );
+        assert_eq!(
+            config.audit_hmac_key.as_deref(),
+            Some("/etc/bootupd/audit-key")
+        );
+
+        std::fs::write(&path, r#"{"verify-direct-io": true}"#)?;
+        assert!(Config::load_from(&path)?.verify_direct_io);
+
+        std::fs::write(&path, r#"{"esp-fsck": true}"#)?;
+        assert!(Config::load_from(&path)?.esp_fsck);
+
+        std::fs::write(&path, r#"{"hook-failure": "warn"}"#)?;
+        assert_eq!(
+            Config::load_from(&path)?.hook_failure,
+            Some(crate::hooks::HookFailure::Warn)
+        );
+
+        std::fs::write(
+            &path,
+            r#"{"timeout": 600, "cpu-quota": 50, "io-weight": 10}"#,
+        )?;
+        let config = Config::load_from(&path)?;
+        assert_eq!(config.timeout, Some(600));
+        assert_eq!(config.cpu_quota, Some(50));
+        assert_eq!(config.io_weight, Some(10));
+        for invalid in [
+            r#"{"cpu-quota": 0}"#,
+            r#"{"cpu-quota": 101}"#,
+            r#"{"io-weight": 0}"#,
+            r#"{"io-weight": 10001}"#,
+        ] {
+            std::fs::write(&path, invalid)?;
+            assert!(Config::load_from(&path).is_err(), "{invalid}");
+        }
+        std::fs::write(&path, r#"{"cpu-quota": 100, "io-weight": 10000}"#)?;
+        Config::load_from(&path)?;
+
+        std::fs::write(&path, r#"{"unknown-key": true}"#)?;
+        assert!(Config::load_from(&path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! The UEFI forbidden signature database (`dbx`), used to check that we
+//! don't install boot images which the firmware would refuse to load.
+//!
+//! Only revocations by SHA-256 Authenticode digest are considered, not
+//! revoked certificates.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+/// The `dbx` variable, under `EFI_IMAGE_SECURITY_DATABASE_GUID`.
+const DBX_VAR_PATH: &str = "/sys/firmware/efi/efivars/dbx-d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+/// Where `dbxtool` ships pending `dbx` updates, relative to the root.
+const DBX_UPDATES_DIR: &str = "usr/share/dbxtool";
+/// `EFI_CERT_SHA256_GUID` (c1c41626-504c-4092-aca9-41f936934328) as laid
+/// out in memory.
+const EFI_CERT_SHA256_GUID: [u8; 16] = [
+    0x26, 0x16, 0xc4, 0xc1, 0x4c, 0x50, 0x92, 0x40, 0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43, 0x28,
+];
+
+/// A set of revoked Authenticode digests.
+pub(crate) type Revocations = HashSet<[u8; 32]>;
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32> {
+    let b = data
+        .get(off..off + 4)
+        .with_context(|| format!("Truncated signature list at {off:#x}"))?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Parse a sequence of `EFI_SIGNATURE_LIST`s, keeping the SHA-256 digests.
+fn parse_signature_lists(mut data: &[u8]) -> Result<Revocations> {
+    let mut r = HashSet::new();
+    while !data.is_empty() {
+        let list_size = read_u32(data, 16)? as usize;
+        let header_size = read_u32(data, 20)? as usize;
+        let sig_size = read_u32(data, 24)? as usize;
+        if list_size < 28 {
+            bail!("Invalid signature list size {list_size}");
+        }
+        let list = data.get(..list_size).context("Truncated signature list")?;
+        if data[..16] == EFI_CERT_SHA256_GUID {
+            // Each signature is an owner GUID followed by the digest
+            if sig_size != 16 + 32 {
+                bail!("Invalid SHA-256 signature size {sig_size}");
+            }
+            let sigs = list
+                .get(28 + header_size..)
+                .context("Invalid signature list header size")?;
+            for sig in sigs.chunks_exact(sig_size) {
+                r.insert(sig[16..].try_into()?);
+            }
+        }
+        data = &data[list_size..];
+    }
+    Ok(r)
+}
+
+/// Parse an authenticated variable update (as shipped by `dbxtool`): an
+/// `EFI_TIME` and a `WIN_CERTIFICATE`, followed by signature lists.
+fn parse_authenticated_update(data: &[u8]) -> Result<Revocations> {
+    let cert_len = read_u32(data, 16)? as usize;
+    let lists = data
+        .get(16 + cert_len..)
+        .context("Truncated authenticated variable")?;
+    parse_signature_lists(lists)
+}
+
+/// Read the current `dbx`; empty if it isn't set or this isn't a UEFI
+/// system.
+pub(crate) fn read_current() -> Result<Revocations> {
+    let data = match std::fs::read(DBX_VAR_PATH) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).context("Reading dbx"),
+    };
+    // Skip the variable attributes
+    let lists = data.get(4..).context("Truncated dbx")?;
+    parse_signature_lists(lists).context("Parsing dbx")
+}
+
+/// Read the `dbx` updates shipped in the root `sysroot`.
+pub(crate) fn read_pending(sysroot: &Path) -> Result<Revocations> {
+    let dir = sysroot.join(DBX_UPDATES_DIR);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {dir:?}")),
+    };
+    let mut r = HashSet::new();
+    for e in entries {
+        let path = e?.path();
+        if path.extension().is_some_and(|e| e == "bin") {
+            let data = std::fs::read(&path)?;
+            r.extend(
+                parse_authenticated_update(&data).with_context(|| format!("Parsing {path:?}"))?,
+            );
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature_list(guid: [u8; 16], digests: &[[u8; 32]]) -> Vec<u8> {
+        let mut l = guid.to_vec();
+        let size = 28 + digests.len() * 48;
+        l.extend((size as u32).to_le_bytes());
+        l.extend(0u32.to_le_bytes());
+        l.extend(48u32.to_le_bytes());
+        for d in digests {
+            l.extend([0xaa; 16]);
+            l.extend(d);
+        }
+        l
+    }
+
+    #[test]
+    fn test_parse_signature_lists() -> Result<()> {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        let mut data = signature_list(EFI_CERT_SHA256_GUID, &[a]);
+        // e.g. EFI_CERT_X509_GUID; the "digest" here is really a certificate
+        data.extend(signature_list([0x11; 16], &[[3u8; 32]]));
+        data.extend(signature_list(EFI_CERT_SHA256_GUID, &[b]));
+        let expected: Revocations = [a, b].into_iter().collect();
+        assert_eq!(parse_signature_lists(&data)?, expected);
+
+        let mut update = vec![0u8; 16];
+        let cert = [0x55u8; 24];
+        update.extend((cert.len() as u32).to_le_bytes());
+        update.extend(&cert[4..]);
+        update.extend(&data);
+        assert_eq!(parse_authenticated_update(&update)?, expected);
+
+        assert!(parse_signature_lists(&data[..data.len() - 1]).is_err());
+        Ok(())
+    }
+}
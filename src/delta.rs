@@ -0,0 +1,229 @@
+//! Binary deltas for large payload files (e.g. UKIs or FIT images), so
+//! that updating them on slow media doesn't require writing them in full.
+//!
+//! Images may ship deltas next to the payload of a component, in
+//! `usr/lib/bootupd/updates/<component>.deltas/`.  The delta which turns
+//! a file with the SHA-512 `<digest>` into the payload file `<path>` is
+//! `<path>.<first 16 hex digits of digest>` with the suffix `.zst`
+//! (created with `zstd --patch-from`) or `.xd3` (created with `xdelta3`).
+//! When applying an update, a changed file whose current content has a
+//! delta is patched instead of copied; the result is checked against the
+//! payload file, and if anything goes wrong the file is copied after all.
+
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use openat_ext::OpenatDirExt;
+
+use crate::filetree::FileMetadata;
+use crate::util::CommandRunExt;
+
+/// Suffix of the directory holding the deltas for a payload directory.
+const DELTAS_SUFFIX: &str = ".deltas";
+
+/// Number of hex digits of the base digest in the name of a delta.
+const DIGEST_PREFIX_LEN: usize = 16;
+
+/// Supported delta formats, by file name suffix.
+const FORMATS: &[(&str, Format)] = &[(".zst", Format::Zstd), (".xd3", Format::Xdelta3)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zstd,
+    Xdelta3,
+}
+
+impl Format {
+    /// The command writing `out` by applying `delta` to `base`.
+    fn command(self, base: &Path, delta: &Path, out: &Path) -> Command {
+        match self {
+            Format::Zstd => {
+                let mut patch_from = std::ffi::OsString::from("--patch-from=");
+                patch_from.push(base);
+                let mut c = Command::new("zstd");
+                // Allow the large windows needed for big files
+                c.args(["-d", "-q", "-f", "--long=31"])
+                    .arg(patch_from)
+                    .arg(delta)
+                    .arg("-o")
+                    .arg(out);
+                c
+            }
+            Format::Xdelta3 => {
+                let mut c = Command::new("xdelta3");
+                c.args(["-d", "-f", "-s"]).arg(base).arg(delta).arg(out);
+                c
+            }
+        }
+    }
+}
+
+/// The path of `name` in `dir`, usable by tools; our descriptors aren't
+/// inherited by them, so this is resolved here.
+fn fd_path(dir: &openat::Dir, name: &Utf8Path) -> Result<PathBuf> {
+    let dirpath = std::fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd()))?;
+    Ok(dirpath.join(name))
+}
+
+/// The name of the delta for `path` from content with the digest `base`,
+/// without the format suffix.
+fn delta_name(path: &str, base: &FileMetadata) -> String {
+    let hex = base.sha512.0.trim_start_matches("sha512:");
+    let prefix = &hex[..hex.len().min(DIGEST_PREFIX_LEN)];
+    format!("{path}.{prefix}")
+}
+
+/// The deltas shipped for a payload directory.
+pub(crate) struct Deltas {
+    dir: openat::Dir,
+}
+
+impl Deltas {
+    /// The deltas for the payload in `srcdir`, if there are any.
+    pub(crate) fn for_payload(srcdir: &openat::Dir) -> Result<Option<Self>> {
+        let src = std::fs::read_link(format!("/proc/self/fd/{}", srcdir.as_raw_fd()))
+            .context("Finding path of payload")?;
+        let Some(name) = src.file_name() else {
+            return Ok(None);
+        };
+        let mut name = name.to_owned();
+        name.push(DELTAS_SUFFIX);
+        let path = src.with_file_name(name);
+        if !path.is_dir() {
+            return Ok(None);
+        }
+        log::debug!("Using deltas in {}", path.display());
+        let dir = openat::Dir::open(&path).with_context(|| format!("Opening {path:?}"))?;
+        Ok(Some(Self { dir }))
+    }
+
+    /// The delta for `path` from `base` and its format, if any.
+    fn find(&self, path: &str, base: &FileMetadata) -> Result<Option<(String, Format)>> {
+        let name = delta_name(path, base);
+        for (suffix, format) in FORMATS {
+            let name = format!("{name}{suffix}");
+            if self.dir.exists(&name)? {
+                return Ok(Some((name, *format)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write the payload file `path` in `srcdir` to `tmp` in `destdir` by
+    /// patching its current content `base`, also in `destdir`, if there
+    /// is a delta from it.  Returns whether it did so.
+    pub(crate) fn apply(
+        &self,
+        srcdir: &openat::Dir,
+        path: &str,
+        destdir: &openat::Dir,
+        base: &Utf8Path,
+        tmp: &Utf8Path,
+    ) -> Result<bool> {
+        if !destdir.exists(base.as_std_path())? {
+            return Ok(false);
+        }
+        let current = FileMetadata::new_from_path(destdir, base.as_str())?;
+        let Some((delta, format)) = self.find(path, &current)? else {
+            return Ok(false);
+        };
+        let expected = FileMetadata::new_from_path(srcdir, path)?;
+        let patch = || -> Result<()> {
+            format
+                .command(
+                    &fd_path(destdir, base)?,
+                    &fd_path(&self.dir, Utf8Path::new(&delta))?,
+                    &fd_path(destdir, tmp)?,
+                )
+                .run()?;
+            let patched = FileMetadata::new_from_path(destdir, tmp.as_str())?;
+            if patched != expected {
+                bail!("Got {} instead of {}", patched.sha512, expected.sha512);
+            }
+            Ok(())
+        };
+        if let Err(e) = patch() {
+            destdir.remove_file_optional(tmp.as_std_path())?;
+            return Err(e).with_context(|| format!("Applying {delta}"));
+        }
+        log::debug!("Patched {path} with {delta}");
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deltas() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let updates = td.path().join("updates");
+        std::fs::create_dir_all(updates.join("EFI/fedora"))?;
+        std::fs::write(updates.join("EFI/fedora/grubx64.efi"), "grub2")?;
+        let esp = td.path().join("esp");
+        std::fs::create_dir_all(esp.join("fedora"))?;
+        std::fs::write(esp.join("fedora/grubx64.efi"), "grub")?;
+        let srcdir = openat::Dir::open(&updates.join("EFI"))?;
+        let destdir = openat::Dir::open(&esp)?;
+
+        assert!(Deltas::for_payload(&srcdir)?.is_none());
+        std::fs::create_dir_all(updates.join("EFI.deltas/fedora"))?;
+        let deltas = Deltas::for_payload(&srcdir)?.unwrap();
+
+        let base = FileMetadata::new_from_path(&destdir, "fedora/grubx64.efi")?;
+        let name = delta_name("fedora/grubx64.efi", &base);
+        let hex = base.sha512.0.strip_prefix("sha512:").unwrap();
+        assert_eq!(name, format!("fedora/grubx64.efi.{}", &hex[..16]));
+
+        // Without a matching delta, the file is left to be copied
+        let path = Utf8Path::new("fedora/grubx64.efi");
+        let tmp = Utf8Path::new("fedora/grubx64.efi.tmp");
+        assert!(!deltas.apply(&srcdir, path.as_str(), &destdir, path, tmp)?);
+        std::fs::write(updates.join("EFI.deltas/fedora/grubx64.efi.0123.zst"), "")?;
+        assert!(!deltas.apply(&srcdir, path.as_str(), &destdir, path, tmp)?);
+
+        // A broken delta fails, and the partially written file is removed
+        let xd3 = updates.join(format!("EFI.deltas/{name}.xd3"));
+        std::fs::write(&xd3, "")?;
+        assert_eq!(
+            deltas.find(path.as_str(), &base)?,
+            Some((format!("{name}.xd3"), Format::Xdelta3))
+        );
+        std::fs::write(esp.join(tmp), "partial")?;
+        assert!(deltas
+            .apply(&srcdir, path.as_str(), &destdir, path, tmp)
+            .is_err());
+        assert!(!esp.join(tmp).exists());
+        std::fs::remove_file(&xd3)?;
+
+        // So does one which doesn't produce the payload file
+        let zst = updates.join(format!("EFI.deltas/{name}.zst"));
+        let make_delta = |target: &Path| -> Result<()> {
+            let mut patch_from = std::ffi::OsString::from("--patch-from=");
+            patch_from.push(esp.join(path));
+            Command::new("zstd")
+                .args(["-q", "-f"])
+                .arg(patch_from)
+                .arg(target)
+                .arg("-o")
+                .arg(&zst)
+                .run()
+        };
+        std::fs::write(td.path().join("other"), "grub3")?;
+        make_delta(&td.path().join("other"))?;
+        let e = deltas
+            .apply(&srcdir, path.as_str(), &destdir, path, tmp)
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("instead of"));
+        assert!(!esp.join(tmp).exists());
+
+        make_delta(&updates.join("EFI/fedora/grubx64.efi"))?;
+        assert!(deltas.apply(&srcdir, path.as_str(), &destdir, path, tmp)?);
+        assert_eq!(std::fs::read_to_string(esp.join(tmp))?, "grub2");
+        Ok(())
+    }
+}
@@ -0,0 +1,325 @@
+//! Device Tree blobs, for boards whose firmware (e.g. U-Boot) loads the
+//! DTB for the kernel from disk.
+//!
+//! Packages ship DTBs in `usr/lib/efi/devicetree/<pkg>/<version>/`; the
+//! newest version of each package makes up the update payload.  These are
+//! installed to `dtb/` on the mounted ESP (see `esp-mount`) if it has one,
+//! or otherwise to `/boot/dtb`, which is typically a link to
+//! `/boot/dtb-<version>`.
+//!
+//! On aarch64 most systems use ACPI instead, so there this component is
+//! only used when requested, e.g. `bootupctl backend install --component
+//! DeviceTree`.  On riscv64 it is used by default, but skipped for images
+//! which ship no DTBs.
+
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use openat_ext::OpenatDirExt;
+
+use crate::component::*;
+use crate::filetree::{self, FileTree};
+use crate::model::*;
+use crate::packagesystem;
+use crate::util::CommandRunExt;
+
+/// The name of this component.
+pub(crate) const NAME: &str = "DeviceTree";
+
+/// Where packages ship DTBs, relative to the root.
+const DT_SRCDIR: &str = "usr/lib/efi/devicetree";
+
+/// The installed DTB directory under `root`: `dtb/` on the ESP mounted
+/// there if it exists, else `boot/dtb`.
+fn dtb_dir(root: &Path) -> Result<PathBuf> {
+    if let Some(esp) = crate::esp::find_mounted_esp(root)? {
+        let esp_dtb = esp.join("dtb");
+        if esp_dtb.exists() {
+            return Ok(esp_dtb);
+        }
+    }
+    Ok(root.join("boot/dtb"))
+}
+
+/// Find the newest version of each package in `srcdir`.
+fn newest_versions(srcdir: &Path) -> Result<Vec<PathBuf>> {
+    let mut r = Vec::new();
+    for pkg in std::fs::read_dir(srcdir).with_context(|| format!("Reading {srcdir:?}"))? {
+        let pkg = pkg?;
+        let mut versions = Vec::new();
+        for ver in std::fs::read_dir(pkg.path())? {
+            let ver = ver?;
+            if !ver.file_type()?.is_dir() {
+                continue;
+            }
+            let Ok(name) = ver.file_name().into_string() else {
+                continue;
+            };
+            versions.push(name);
+        }
+        let newest = versions
+            .into_iter()
+            .max_by(|a, b| packagesystem::compare_versions(a, b));
+        match newest {
+            Some(v) => r.push(pkg.path().join(v)),
+            None => log::debug!("No versions found in {:?}", pkg.path()),
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+#[derive(Default)]
+pub(crate) struct DeviceTree {}
+
+impl DeviceTree {
+    /// Whether `sysroot` ships DTBs, from which an update payload can be
+    /// generated.
+    pub(crate) fn present_in(sysroot_path: &str) -> bool {
+        let srcdir = Path::new(sysroot_path).join(DT_SRCDIR);
+        srcdir.exists() && newest_versions(&srcdir).is_ok_and(|v| !v.is_empty())
+    }
+
+    /// The root of `sysroot`.
+    fn root_path(sysroot: &openat::Dir) -> Result<PathBuf> {
+        let fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
+        Ok(std::fs::read_link(fd)?)
+    }
+
+    /// Update the DTBs in `current` under `dest_root` to the payload in
+    /// `payload_root`.
+    fn update(
+        &self,
+        payload_root: &openat::Dir,
+        dest_root: &openat::Dir,
+        current: &FileTree,
+    ) -> Result<FileTree> {
+        let updated = payload_root
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let updatef = crate::signature::verified_payload_tree(payload_root, self.name(), &updated)
+            .context("reading update dir")?;
+        let diff = current.diff(&updatef)?;
+        let dir = dtb_dir(&Self::root_path(dest_root)?)?;
+        let destdir = openat::Dir::open(&dir).with_context(|| format!("Opening {dir:?}"))?;
+        filetree::apply_diff(&updated, &destdir, &diff, None)?;
+        Ok(updatef)
+    }
+}
+
+impl Component for DeviceTree {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        let dir = dtb_dir(Path::new("/"))?;
+        if !dir.exists() {
+            log::trace!("No {dir:?} found");
+            return Ok(None);
+        }
+        query_adopt_state(&[dir.to_string_lossy().into_owned()])
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        updatemeta: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            bail!("Failed to find installed DTBs")
+        };
+        // Take over only the DTBs that the update ships
+        let updated = sysroot.sub_dir(&component_updatedirname(self))?;
+        let updatef = crate::signature::verified_payload_tree(sysroot, self.name(), &updated)?;
+        let dir = dtb_dir(&Self::root_path(sysroot)?)?;
+        let mut current = FileTree::new_from_dir(&openat::Dir::open(&dir)?)?;
+        current
+            .children
+            .retain(|k, _| updatef.children.contains_key(k));
+        log::debug!("Adopting {} DTBs", current.children.len());
+        let filetree = self.update(sysroot, sysroot, &current)?;
+        Ok(InstalledContent {
+            meta: updatemeta.clone(),
+            filetree: Some(filetree),
+            adopted_from: Some(meta.version),
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        _device: &str,
+        _update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let meta = require_component_update(src_root, self)?;
+        let srcdir = src_root.sub_dir(&component_updatedirname(self))?;
+        let tree = FileTree::new_from_dir(&srcdir)?;
+        let dir = dtb_dir(Path::new(dest_root))?;
+        std::fs::create_dir_all(&dir)?;
+        let destdir = openat::Dir::open(&dir).with_context(|| format!("Opening {dir:?}"))?;
+        let diff = FileTree::default().diff(&tree)?;
+        filetree::apply_diff(&srcdir, &destdir, &diff, None)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(tree),
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn install_destination(&self, dest_root: &str, _device: &str) -> Result<String> {
+        Ok(dtb_dir(Path::new(dest_root))?.display().to_string())
+    }
+
+    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+        let srcdir = Path::new(sysroot_path).join(DT_SRCDIR);
+        let versions = newest_versions(&srcdir)?;
+        if versions.is_empty() {
+            bail!("Failed to find any DTBs in {srcdir:?}");
+        }
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest)?;
+        for v in versions.iter() {
+            log::debug!("Using {v:?}");
+            Command::new("cp")
+                .arg("-a")
+                .arg(v.join("."))
+                .arg(&dest)
+                .run()?;
+        }
+        let owned = versions
+            .iter()
+            .map(|v| Path::new("/").join(v.strip_prefix(sysroot_path).unwrap_or(v)));
+        let meta = packagesystem::query_files(sysroot_path, owned)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        payload_root: &openat::Dir,
+        dest_root: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let updatemeta = self.query_update(payload_root)?.expect("update available");
+        let filetree = self.update(payload_root, dest_root, currentf)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(filetree),
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn validate(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        fast: bool,
+    ) -> Result<ValidationResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let dir = openat::Dir::open(&dtb_dir(&Self::root_path(sysroot)?)?)?;
+        let diff = currentf.validate_in(&dir, fast)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(format!("Changed: {}", f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(format!("Removed: {}", f));
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn diff_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Option<Vec<filetree::FileChange>>> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let Some(updated) = sysroot.sub_dir_optional(&component_updatedirname(self))? else {
+            return Ok(Some(Vec::new()));
+        };
+        let updatef = crate::treecache::payload_tree(sysroot, self, &updated)
+            .context("reading update dir")?;
+        let diff = currentf.diff(&updatef)?;
+        Ok(Some(diff.describe(currentf, &updatef)))
+    }
+
+    fn uninstall(&self, sysroot: &openat::Dir, current: &InstalledContent) -> Result<()> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let dir = openat::Dir::open(&dtb_dir(&Self::root_path(sysroot)?)?)?;
+        filetree::remove_tree(&dir, currentf).context("removing files")
+    }
+
+    fn get_efi_vendor(&self, _sysroot: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_newest_versions() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        for d in [
+            "dtb-rpi/2023.10/broadcom",
+            "dtb-rpi/2024.01/broadcom",
+            "dtb-rpi/2024.01~rc1/broadcom",
+            "dtb-rockchip/6.8.11/rockchip",
+            "dtb-rockchip/6.8.5/rockchip",
+        ] {
+            std::fs::create_dir_all(td.path().join(d))?;
+        }
+        assert_eq!(
+            newest_versions(td.path())?,
+            [
+                td.path().join("dtb-rockchip/6.8.11"),
+                td.path().join("dtb-rpi/2024.01")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dtb_dir() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        // Not the ESP, unless a FAT filesystem is mounted there
+        std::fs::create_dir_all(td.path().join("boot/efi/dtb"))?;
+        assert_eq!(dtb_dir(td.path())?, td.path().join("boot/dtb"));
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+//! Disk images attached read-only, for `bootupctl --image`, e.g. to check
+//! the bootloader of a freshly built image in CI before publishing it.
+//!
+//! qcow2 images are attached with `qemu-nbd`, anything else is assumed to
+//! be raw and attached as a loop device.  The ESP and the filesystem
+//! holding `bootupd-state.json` (a separate `/boot` partition, or the root
+//! filesystem) are then mounted read-only in a temporary directory, and
+//! everything is detached again when we're done.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use rustix::mount::{MountFlags, UnmountFlags};
+use serde::Deserialize;
+
+use crate::model::SavedState;
+use crate::util::{cmd_output, CommandRunExt};
+
+const ESP_TYPE_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+
+/// The magic at the start of qcow2 images.
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+
+/// Filesystems which may hold `/boot`.
+const BOOT_FSTYPES: &[&str] = &["ext4", "xfs", "btrfs"];
+
+/// How an image is attached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Attach {
+    Nbd,
+    Loop,
+}
+
+/// How to attach the image starting with `header`.
+fn attach_method(header: &[u8]) -> Attach {
+    if header.starts_with(QCOW2_MAGIC) {
+        Attach::Nbd
+    } else {
+        Attach::Loop
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LsblkOutput {
+    blockdevices: Vec<LsblkPartition>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LsblkPartition {
+    path: String,
+    #[serde(rename = "type")]
+    devtype: String,
+    parttype: Option<String>,
+    partlabel: Option<String>,
+    fstype: Option<String>,
+}
+
+/// The ESP, and the candidates for the filesystem holding the state: the
+/// partition labeled `boot` first, as on Fedora CoreOS.
+fn classify(partitions: &[LsblkPartition]) -> (Option<&LsblkPartition>, Vec<&LsblkPartition>) {
+    let parts = partitions.iter().filter(|p| p.devtype == "part");
+    let esp = parts.clone().find(|p| {
+        p.parttype
+            .as_deref()
+            .is_some_and(|t| t.eq_ignore_ascii_case(ESP_TYPE_GUID))
+    });
+    let mut candidates = parts
+        .filter(|p| {
+            p.fstype
+                .as_deref()
+                .is_some_and(|t| BOOT_FSTYPES.contains(&t))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|p| p.partlabel.as_deref() != Some("boot"));
+    (esp, candidates)
+}
+
+/// Mount `source` read-only on `target`.
+fn mount_ro(source: &str, target: &Path, fstype: &str) -> Result<()> {
+    std::fs::create_dir_all(target)?;
+    rustix::mount::mount(source, target, fstype, MountFlags::RDONLY, "")
+        .with_context(|| format!("Mounting {source} on {target:?}"))
+}
+
+/// A free `/dev/nbd*` device, loading the module if needed.
+fn free_nbd_device() -> Result<String> {
+    if !Path::new("/sys/block/nbd0").exists() {
+        Command::new("modprobe").arg("nbd").run()?;
+    }
+    for i in 0.. {
+        let sys = PathBuf::from(format!("/sys/block/nbd{i}"));
+        if !sys.exists() {
+            break;
+        }
+        // Connected devices have a pid
+        if !sys.join("pid").exists() {
+            return Ok(format!("/dev/nbd{i}"));
+        }
+    }
+    bail!("No free nbd device")
+}
+
+/// A disk image attached read-only, with its boot filesystems mounted.
+#[derive(Debug)]
+pub(crate) struct DiskImage {
+    attach: Attach,
+    device: String,
+    tempdir: tempfile::TempDir,
+    mounts: Vec<PathBuf>,
+    esp: Option<String>,
+}
+
+impl DiskImage {
+    /// Attach the image at `path`, and mount its ESP and `/boot`.
+    #[context("Attaching disk image {}", path.display())]
+    pub(crate) fn attach(path: &Path) -> Result<Self> {
+        let mut header = [0u8; 4];
+        std::io::Read::read_exact(&mut std::fs::File::open(path)?, &mut header)?;
+        let attach = attach_method(&header);
+        let device = match attach {
+            Attach::Nbd => {
+                let device = free_nbd_device()?;
+                Command::new("qemu-nbd")
+                    .args(["--read-only", "--format=qcow2"])
+                    .arg(format!("--connect={device}"))
+                    .arg(path)
+                    .run()?;
+                device
+            }
+            Attach::Loop => cmd_output(
+                Command::new("losetup")
+                    .args(["--find", "--show", "--read-only", "--partscan"])
+                    .arg(path),
+            )?
+            .trim()
+            .to_string(),
+        };
+        log::debug!("Attached {path:?} as {device}");
+        let mut image = Self {
+            attach,
+            device,
+            tempdir: tempfile::tempdir()?,
+            mounts: Vec::new(),
+            esp: None,
+        };
+        image.mount()?;
+        Ok(image)
+    }
+
+    /// Mount the ESP and the filesystem holding the state.
+    fn mount(&mut self) -> Result<()> {
+        crate::mount::enter_private_namespace()?;
+        Command::new("udevadm").arg("settle").run()?;
+        let o = cmd_output(Command::new("lsblk").args([
+            "--json",
+            "--list",
+            "--output",
+            "PATH,TYPE,PARTTYPE,PARTLABEL,FSTYPE",
+            &self.device,
+        ]))?;
+        let o: LsblkOutput = serde_json::from_str(&o).context("Parsing lsblk output")?;
+        let (esp, candidates) = classify(&o.blockdevices);
+        if let Some(esp) = esp {
+            let target = self.esp_path();
+            mount_ro(&esp.path, &target, "vfat")?;
+            self.mounts.push(target);
+            self.esp = Some(esp.path.clone());
+        }
+        let root = self.root();
+        for p in candidates {
+            let fstype = p.fstype.as_deref().unwrap_or_default();
+            // A separate /boot, or a root filesystem with /boot in it
+            for target in [root.join("boot"), root.clone()] {
+                mount_ro(&p.path, &target, fstype)?;
+                if root
+                    .join(SavedState::STATEFILE_DIR)
+                    .join(SavedState::STATEFILE_NAME)
+                    .exists()
+                {
+                    log::debug!("Found the bootupd state on {}", p.path);
+                    self.mounts.push(target);
+                    return Ok(());
+                }
+                rustix::mount::unmount(&target, UnmountFlags::empty())?;
+            }
+        }
+        bail!("No bootupd state found in {}", self.device)
+    }
+
+    /// Where the filesystem holding `boot/bootupd-state.json` is mounted.
+    pub(crate) fn root(&self) -> PathBuf {
+        self.tempdir.path().join("root")
+    }
+
+    /// Where the ESP is mounted, if the image has one.
+    pub(crate) fn esp(&self) -> Option<PathBuf> {
+        self.esp.as_ref().map(|_| self.esp_path())
+    }
+
+    /// The ESP partition, e.g. `/dev/loop0p2`.
+    pub(crate) fn esp_device(&self) -> Option<&str> {
+        self.esp.as_deref()
+    }
+
+    fn esp_path(&self) -> PathBuf {
+        self.tempdir.path().join("esp")
+    }
+}
+
+impl Drop for DiskImage {
+    fn drop(&mut self) {
+        for target in self.mounts.iter().rev() {
+            if let Err(e) = rustix::mount::unmount(target, UnmountFlags::empty()) {
+                log::warn!("Failed to unmount {target:?}: {e}");
+            }
+        }
+        let r = match self.attach {
+            Attach::Nbd => Command::new("qemu-nbd")
+                .args(["--disconnect", &self.device])
+                .run(),
+            Attach::Loop => Command::new("losetup")
+                .args(["--detach", &self.device])
+                .run(),
+        };
+        if let Err(e) = r {
+            log::warn!("Failed to detach {}: {e:#}", self.device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attach_method() {
+        assert_eq!(attach_method(b"QFI\xfb\0\0\0\x03"), Attach::Nbd);
+        assert_eq!(attach_method(&[0u8; 8]), Attach::Loop);
+    }
+
+    #[test]
+    fn test_classify() -> Result<()> {
+        let data = r#"{"blockdevices": [
+            {"path": "/dev/loop0", "type": "loop", "parttype": null, "partlabel": null, "fstype": null},
+            {"path": "/dev/loop0p1", "type": "part", "parttype": "21686148-6449-6e6f-744e-656564454649", "partlabel": "BIOS-BOOT", "fstype": null},
+            {"path": "/dev/loop0p2", "type": "part", "parttype": "c12a7328-f81f-11d2-ba4b-00a0c93ec93b", "partlabel": "EFI-SYSTEM", "fstype": "vfat"},
+            {"path": "/dev/loop0p4", "type": "part", "parttype": "0fc63daf-8483-4772-8e79-3d69d8477de4", "partlabel": "root", "fstype": "xfs"},
+            {"path": "/dev/loop0p3", "type": "part", "parttype": "0fc63daf-8483-4772-8e79-3d69d8477de4", "partlabel": "boot", "fstype": "ext4"}
+        ]}"#;
+        let o: LsblkOutput = serde_json::from_str(data)?;
+        let (esp, candidates) = classify(&o.blockdevices);
+        assert_eq!(esp.map(|p| p.path.as_str()), Some("/dev/loop0p2"));
+        let candidates = candidates
+            .iter()
+            .map(|p| p.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(candidates, ["/dev/loop0p3", "/dev/loop0p4"]);
+        Ok(())
+    }
+}
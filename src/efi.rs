@@ -4,7 +4,9 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io::Read;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -19,14 +21,22 @@ use rustix::fd::BorrowedFd;
 use walkdir::WalkDir;
 use widestring::U16CString;
 
+use crate::errors::ErrorKind;
+use crate::esp::{esp_mounts, find_mounted_esp};
 use crate::filetree;
 use crate::model::*;
 use crate::ostreeutil;
+use crate::payloadversion::PayloadVersion;
 use crate::util::{self, CommandRunExt};
 use crate::{component::*, packagesystem};
 
-/// Well-known paths to the ESP that may have been mounted external to us.
-pub(crate) const ESP_MOUNTS: &[&str] = &["boot/efi", "efi", "boot"];
+/// Where packages install the files for the ESP, relative to the root;
+/// this is where the rpm database has the files of the payload, wherever
+/// the ESP is mounted on the system it is built from.
+const PACKAGED_EFI_DIR: &str = "boot/efi/EFI";
+
+/// Parent directory for private ESP mountpoints.
+const PRIVATE_ESP_MOUNT_DIR: &str = "/run/bootupd";
 
 /// The binary to change EFI boot ordering
 const EFIBOOTMGR: &str = "efibootmgr";
@@ -36,6 +46,195 @@ pub(crate) const SHIM: &str = "shimaa64.efi";
 #[cfg(target_arch = "x86_64")]
 pub(crate) const SHIM: &str = "shimx64.efi";
 
+/// The removable media path, relative to `EFI/`, used by firmware when no
+/// NVRAM entry works
+#[cfg(target_arch = "aarch64")]
+const FALLBACK: &str = "BOOT/BOOTAA64.EFI";
+#[cfg(target_arch = "x86_64")]
+const FALLBACK: &str = "BOOT/BOOTX64.EFI";
+
+/// Read by shim's fallback to recreate NVRAM entries
+#[cfg(target_arch = "aarch64")]
+const BOOTCSV: &str = "BOOTAA64.CSV";
+#[cfg(target_arch = "x86_64")]
+const BOOTCSV: &str = "BOOTX64.CSV";
+
+/// 32-bit x86 shim, which some images ship next to [`SHIM`] for 64-bit
+/// CPUs with 32-bit firmware (e.g. some tablets)
+#[cfg(target_arch = "x86_64")]
+const SHIM_IA32: &str = "shimia32.efi";
+
+/// The shims for each firmware architecture we support, native first.
+#[cfg(target_arch = "aarch64")]
+const SHIMS: &[&str] = &[SHIM];
+#[cfg(target_arch = "x86_64")]
+const SHIMS: &[&str] = &[SHIM, SHIM_IA32];
+
+/// Contains the bitness of the firmware, `32` or `64`.
+#[cfg(target_arch = "x86_64")]
+const FW_PLATFORM_SIZE: &str = "/sys/firmware/efi/fw_platform_size";
+
+/// The files on the ESP which depend on the firmware architecture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FirmwareFiles {
+    shim: &'static str,
+    fallback: &'static str,
+    bootcsv: &'static str,
+}
+
+const NATIVE_FILES: FirmwareFiles = FirmwareFiles {
+    shim: SHIM,
+    fallback: FALLBACK,
+    bootcsv: BOOTCSV,
+};
+
+#[cfg(target_arch = "x86_64")]
+const IA32_FILES: FirmwareFiles = FirmwareFiles {
+    shim: SHIM_IA32,
+    fallback: "BOOT/BOOTIA32.EFI",
+    bootcsv: "BOOTIA32.CSV",
+};
+
+/// The files used by the running firmware, given the contents of
+/// `fw_platform_size` if known.
+#[cfg(target_arch = "x86_64")]
+fn firmware_files_for(platform_size: Option<&str>) -> FirmwareFiles {
+    match platform_size.map(str::trim) {
+        Some("32") => IA32_FILES,
+        _ => NATIVE_FILES,
+    }
+}
+
+/// The files used by the running firmware, which on x86_64 may be 32-bit
+/// even though the CPU isn't.
+fn firmware_files() -> FirmwareFiles {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let size = std::fs::read_to_string(FW_PLATFORM_SIZE).ok();
+        firmware_files_for(size.as_deref())
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    NATIVE_FILES
+}
+
+/// Read by older versions of shim's fallback, for any architecture
+const BOOT_CSV: &str = "BOOT.CSV";
+
+/// The shim which the fallback CSV `name` in a vendor directory should
+/// point to, if it's one we manage.
+fn fallback_csv_shim(name: &str) -> Option<&'static str> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if name.eq_ignore_ascii_case(IA32_FILES.bootcsv) {
+            return Some(SHIM_IA32);
+        }
+    }
+    (name.eq_ignore_ascii_case(BOOTCSV) || name.eq_ignore_ascii_case(BOOT_CSV)).then_some(SHIM)
+}
+
+/// A fallback CSV making shim's fallback create a boot entry named `label`
+/// for `shim`: UCS-2 with a byte order mark, one
+/// `loader,label,options,description` line per entry.
+fn fallback_csv(shim: &str, label: &str) -> Vec<u8> {
+    let line = format!("{shim},{label},,This is the boot entry for {label}\n");
+    let mut r = vec![0xff, 0xfe];
+    r.extend(line.encode_utf16().flat_map(u16::to_le_bytes));
+    r
+}
+
+/// The loader and label of each entry in the fallback CSV `data`.
+fn parse_fallback_csv(data: &[u8]) -> Result<Vec<(String, String)>> {
+    let data = data.strip_prefix(&[0xff, 0xfe]).unwrap_or(data);
+    if data.len() % 2 != 0 {
+        bail!("Not UCS-2 encoded (odd length)");
+    }
+    let chars = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+    let text = String::from_utf16(&chars).context("Not UCS-2 encoded")?;
+    let mut r = Vec::new();
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let mut fields = line.split(',');
+        match (fields.next(), fields.next()) {
+            (Some(loader), Some(label)) if !loader.is_empty() && !label.is_empty() => {
+                r.push((loader.to_string(), label.to_string()))
+            }
+            _ => bail!("Invalid entry: {line:?}"),
+        }
+    }
+    Ok(r)
+}
+
+/// The name of boot entries we create: `efi-boot-label` if set, otherwise
+/// the name of the OS installed in `root`.
+fn boot_label(root: &Path) -> Result<String> {
+    if let Some(label) = crate::config::get().efi_boot_label.as_ref() {
+        return Ok(label.clone());
+    }
+    let sysroot = Dir::open_ambient_dir(root, cap_std::ambient_authority())?;
+    get_product_name(&sysroot)
+}
+
+/// Rewrite the fallback CSVs of `tree`, installed to `efidir`, which don't
+/// point to the shim next to them with the boot label of the OS in `root`,
+/// so that shim's fallback recreates the right boot entry (e.g. after a
+/// firmware reset cleared NVRAM).  Updates the metadata in `tree` to match.
+#[context("Regenerating fallback CSVs")]
+fn regenerate_fallback_csvs(
+    root: &Path,
+    efidir: &openat::Dir,
+    tree: &mut filetree::FileTree,
+) -> Result<()> {
+    match boot_label(root) {
+        Ok(label) => write_fallback_csvs(efidir, tree, &label),
+        Err(e) => {
+            log::warn!("Not regenerating fallback CSVs: {e:#}");
+            Ok(())
+        }
+    }
+}
+
+/// [`regenerate_fallback_csvs`] with the boot label `label`.
+fn write_fallback_csvs(
+    efidir: &openat::Dir,
+    tree: &mut filetree::FileTree,
+    label: &str,
+) -> Result<()> {
+    let csvs = tree
+        .children
+        .keys()
+        .filter_map(|path| {
+            let (vendor, name) = path.split_once('/')?;
+            let shim = fallback_csv_shim(name)?;
+            let shim_path = format!("{vendor}/{shim}");
+            tree.children
+                .contains_key(&shim_path)
+                .then(|| (path.clone(), shim))
+        })
+        .collect::<Vec<_>>();
+    let mut audit = crate::audit::Recorder::new(efidir)?;
+    for (path, shim) in csvs {
+        let mut current = Vec::new();
+        efidir.open_file(path.as_str())?.read_to_end(&mut current)?;
+        let expected = [(shim.to_string(), label.to_string())];
+        if parse_fallback_csv(&current).is_ok_and(|entries| entries == expected) {
+            continue;
+        }
+        log::info!("Regenerating EFI/{path} for {label}");
+        if let Some(audit) = audit.as_mut() {
+            audit.before(efidir, &path)?;
+        }
+        efidir.write_file_contents(path.as_str(), 0o644, fallback_csv(shim, label))?;
+        let meta = filetree::FileMetadata::new_from_path(efidir, &path)?;
+        tree.children.insert(path, meta);
+    }
+    if let Some(audit) = audit {
+        audit.commit(efidir)?;
+    }
+    Ok(())
+}
+
 /// The ESP partition label on Fedora CoreOS derivatives
 pub(crate) const COREOS_ESP_PART_LABEL: &str = "EFI-SYSTEM";
 pub(crate) const ANACONDA_ESP_PART_LABEL: &str = "EFI\\x20System\\x20Partition";
@@ -43,6 +242,26 @@ pub(crate) const ANACONDA_ESP_PART_LABEL: &str = "EFI\\x20System\\x20Partition";
 /// Systemd boot loader info EFI variable names
 const LOADER_INFO_VAR_STR: &str = "LoaderInfo-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
 const STUB_INFO_VAR_STR: &str = "StubInfo-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+/// The Secure Boot state, under `EFI_GLOBAL_VARIABLE`
+const SECURE_BOOT_VAR_STR: &str = "SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+/// Where the kernel exposes SMBIOS information
+const DMI_ID_DIR: &str = "/sys/class/dmi/id";
+
+/// Where the kernel exposes EFI variables.
+const EFIVARS: &str = "/sys/firmware/efi/efivars";
+/// Where `extend-payload` stores supplemental files for the ESP, as
+/// `<package>/<version>/`, relative to the root.
+const FIRMWARE_DIR: &str = "usr/lib/efi/firmware";
+
+/// Whether efivarfs is mounted read-only, in which case no variables can
+/// be written.
+pub(crate) fn efivars_readonly() -> Result<bool> {
+    match rustix::fs::statvfs(EFIVARS) {
+        Ok(st) => Ok(st.f_flag.contains(rustix::fs::StatVfsMountFlags::RDONLY)),
+        Err(rustix::io::Errno::NOENT) => Ok(true),
+        Err(e) => Err(e).with_context(|| format!("Querying {EFIVARS}")),
+    }
+}
 
 /// Return `true` if the system is booted via EFI
 pub(crate) fn is_efi_booted() -> Result<bool> {
@@ -51,112 +270,1200 @@ pub(crate) fn is_efi_booted() -> Result<bool> {
         .map_err(Into::into)
 }
 
-#[derive(Default)]
-pub(crate) struct Efi {
-    mountpoint: RefCell<Option<PathBuf>>,
-}
+/// Where the ESP is mounted, and whether by us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EspMount {
+    path: PathBuf,
+    /// Set if we mounted it, so that it's unmounted again when we're done;
+    /// mounts which were already there (e.g. by the admin) are left alone.
+    owned: bool,
+    /// Set if it was mounted read-only and we remounted it writable, so
+    /// that it's made read-only again.
+    remounted: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct Efi {
+    mountpoint: RefCell<Option<EspMount>>,
+    /// Other ESPs (e.g. on mirrored disks) which we mounted privately
+    extra_mountpoints: RefCell<Vec<PathBuf>>,
+    /// Set by the paths writing to the ESPs (update, install, adopt), which
+    /// are the only ones checking and possibly repairing their filesystems
+    writing: Cell<bool>,
+    /// The ESP devices whose filesystems were checked
+    fat_checked: RefCell<Vec<PathBuf>>,
+    /// Problems with the filesystems of the ESPs, found when checking them
+    fat_problems: RefCell<Vec<String>>,
+}
+
+/// The device mounted at `mnt`, with symlinks resolved.
+fn mounted_device(mnt: &Path) -> Result<PathBuf> {
+    let dir = openat::Dir::open(mnt)?;
+    let source = crate::filesystem::inspect_filesystem(&dir, ".")?.source;
+    std::fs::canonicalize(&source).with_context(|| format!("Resolving {source}"))
+}
+
+/// Mount the ESP on `device` at a fresh directory under `/run/bootupd`.
+fn mount_private(device: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(PRIVATE_ESP_MOUNT_DIR)
+        .with_context(|| format!("Creating {PRIVATE_ESP_MOUNT_DIR}"))?;
+    let mnt = tempfile::Builder::new()
+        .prefix("esp.")
+        .tempdir_in(PRIVATE_ESP_MOUNT_DIR)?;
+    crate::mount::enter_private_namespace()?;
+    crate::retry::retry("Mounting ESP", || {
+        crate::mount::mount_vfat(device, mnt.path())
+    })?;
+    let mnt = mnt.into_path();
+    log::debug!("Mounted {device:?} at {mnt:?}");
+    Ok(mnt)
+}
+
+/// Unmount an ESP we mounted, removing the mountpoint if it was private.
+fn unmount_esp(mount: &Path) -> Result<()> {
+    crate::mount::unmount(mount)?;
+    log::trace!("Unmounted {mount:?}");
+    if mount.starts_with(PRIVATE_ESP_MOUNT_DIR) {
+        std::fs::remove_dir(mount)
+            .with_context(|| format!("Removing private mountpoint {mount:?}"))?;
+    }
+    Ok(())
+}
+
+/// The changes to make to each ESP for an update, computed once up front.
+#[derive(Debug)]
+pub(crate) struct UpdatePlan {
+    /// Diffs to apply, per vendor directory; vendors without changes are omitted
+    pub(crate) diffs: Vec<(String, filetree::FileTreeDiff)>,
+    /// The per-vendor trees after the update
+    pub(crate) vendors: BTreeMap<String, filetree::FileTree>,
+    /// Vendor directories which the update doesn't ship; these are left in place
+    pub(crate) retained: Vec<String>,
+}
+
+/// Compute the changes to get from the `current` vendor trees to `update`.
+pub(crate) fn plan_update(
+    mut vendors: BTreeMap<String, filetree::FileTree>,
+    update: &filetree::FileTree,
+) -> Result<UpdatePlan> {
+    let update_vendors = update.split_toplevel();
+    let retained = vendors
+        .keys()
+        .filter(|v| !update_vendors.contains_key(*v))
+        .cloned()
+        .collect();
+    let empty = filetree::FileTree::default();
+    let mut diffs = Vec::new();
+    for (vendor, updatetree) in update_vendors {
+        let diff = vendors
+            .get(&vendor)
+            .unwrap_or(&empty)
+            .diff_fat(&updatetree)?;
+        if !diff.is_empty() {
+            diffs.push((vendor.clone(), diff));
+        }
+        vendors.insert(vendor, updatetree);
+    }
+    Ok(UpdatePlan {
+        diffs,
+        vendors,
+        retained,
+    })
+}
+
+/// Apply the per-vendor diffs to the ESP mounted at `esp`.
+pub(crate) fn apply_diffs_to_esp(
+    updated: &openat::Dir,
+    esp: &Path,
+    diffs: &[(String, filetree::FileTreeDiff)],
+    show_progress: bool,
+) -> Result<()> {
+    let efidir = esp.join("EFI");
+    let destdir =
+        openat::Dir::open(&efidir).with_context(|| format!("opening {}", efidir.display()))?;
+    validate_esp(&destdir)?;
+    for (vendor, diff) in diffs {
+        log::trace!("applying diff for {vendor} to {esp:?}: {diff}");
+        let label = format!("Updating {}", efidir.join(vendor).display());
+        let opts = filetree::ApplyUpdateOptions {
+            progress: show_progress.then(|| filetree::terminal_progress(label)),
+            fat: true,
+            component: Some("EFI".into()),
+            ..Default::default()
+        };
+        filetree::apply_diff(updated, &destdir, diff, Some(&opts))
+            .with_context(|| format!("applying filesystem changes for {vendor} to {esp:?}"))?;
+    }
+    Ok(())
+}
+
+/// Compare the files on each of the ESPs `dirs`, labelled by name, with
+/// those on the first one; returns the differences, relative to `EFI/`.
+fn compare_esp_dirs(dirs: &[(String, openat::Dir)]) -> Result<Vec<String>> {
+    let Some(((primary, primary_dir), others)) = dirs.split_first() else {
+        return Ok(Vec::new());
+    };
+    let primary_tree = filetree::FileTree::new_from_dir(primary_dir)
+        .with_context(|| format!("reading {primary}"))?;
+    let mut errs = Vec::new();
+    for (name, dir) in others {
+        let tree =
+            filetree::FileTree::new_from_dir(dir).with_context(|| format!("reading {name}"))?;
+        let diff = primary_tree.diff_fat(&tree)?;
+        for f in diff.changes.iter() {
+            errs.push(format!("Differs on {name}: {f}"));
+        }
+        for f in diff.removals.iter() {
+            errs.push(format!("Missing on {name}: {f}"));
+        }
+        for f in diff.additions.iter() {
+            errs.push(format!("Only on {name}: {f}"));
+        }
+    }
+    Ok(errs)
+}
+
+/// Copy the files of `tree` from the first of the ESPs `dirs` which has
+/// all of them unmodified onto the others; returns the indices of those
+/// which were changed.  Files which aren't part of `tree` are left alone.
+fn resync_esp_dirs(tree: &filetree::FileTree, dirs: &[openat::Dir]) -> Result<Vec<usize>> {
+    let diffs = dirs
+        .iter()
+        .map(|d| tree.relative_diff_to(d))
+        .collect::<Result<Vec<_>>>()?;
+    let Some(src) = diffs.iter().position(|d| d.is_empty()) else {
+        bail!("No ESP has the installed files unmodified");
+    };
+    let mut r = Vec::new();
+    for (i, diff) in diffs.into_iter().enumerate() {
+        if diff.is_empty() {
+            continue;
+        }
+        // Changed and missing files are both copied over
+        let diff = filetree::FileTreeDiff {
+            additions: diff.removals,
+            removals: Default::default(),
+            changes: diff.changes,
+        };
+        log::debug!("Resyncing ESP {i} from {src}: {diff}");
+        let opts = filetree::ApplyUpdateOptions {
+            fat: true,
+            component: Some("EFI".into()),
+            ..Default::default()
+        };
+        filetree::apply_diff(&dirs[src], &dirs[i], &diff, Some(&opts))?;
+        r.push(i);
+    }
+    Ok(r)
+}
+
+/// The Authenticode digest of the EFI binary `path` in `dir`.
+fn authenticode_digest(dir: &openat::Dir, path: &str) -> Result<[u8; 32]> {
+    let mut buf = Vec::new();
+    std::io::Read::read_to_end(&mut dir.open_file(path)?, &mut buf)?;
+    crate::authenticode::sha256(&buf).with_context(|| format!("Hashing {path}"))
+}
+
+/// The EFI binaries in `tree` (relative to `dir`) whose digests are in
+/// `revoked`.
+fn revoked_images(
+    dir: &openat::Dir,
+    tree: &filetree::FileTree,
+    revoked: &crate::dbx::Revocations,
+) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    if revoked.is_empty() {
+        return Ok(r);
+    }
+    for path in tree.children.keys() {
+        if !path.to_ascii_lowercase().ends_with(".efi") {
+            continue;
+        }
+        match authenticode_digest(dir, path) {
+            Ok(d) if revoked.contains(&d) => r.push(path.clone()),
+            Ok(_) => {}
+            // Not every file named .efi needs to be a PE image
+            Err(e) => log::debug!("{e:#}"),
+        }
+    }
+    Ok(r)
+}
+
+/// Whether `dir` contains any `*.conf` files.
+fn has_conf_files(dir: &Path) -> Result<bool> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Reading {dir:?}")),
+    };
+    for e in entries {
+        if e?.path().extension().is_some_and(|e| e == "conf") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Check each link of the boot chain for the vendor directories `vendors`
+/// on the ESP `esp`: that the firmware finds shim via an NVRAM entry (given
+/// the output of `efibootmgr -v`, if known) or the fallback path, that
+/// shim has a GRUB config next to it, and that `boot` has a GRUB config
+/// and entries to boot.  Returns a description of each broken link.
+fn check_boot_chain(
+    esp: &openat::Dir,
+    boot: &Path,
+    vendors: &[&str],
+    nvram: Option<&str>,
+) -> Result<Vec<String>> {
+    let mut errs = Vec::new();
+    let mut err = |msg: String| errs.push(format!("Boot chain: {msg}"));
+    let FirmwareFiles {
+        shim: shim_name,
+        fallback,
+        bootcsv,
+    } = firmware_files();
+
+    let mut shims = Vec::new();
+    for v in vendors {
+        let shim = format!("{v}/{shim_name}");
+        if esp.exists(shim.as_str())? {
+            shims.push(*v);
+        } else {
+            err(format!("EFI/{shim} is missing"));
+        }
+    }
+
+    // Firmware to shim
+    let has_fallback = esp.exists(fallback)?;
+    let mut has_csv = false;
+    for v in shims.iter() {
+        let csv = format!("{v}/{bootcsv}");
+        let Some(mut f) = esp.open_file_optional(csv.as_str())? else {
+            continue;
+        };
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        has_csv = true;
+        match parse_fallback_csv(&data) {
+            Ok(entries) if entries.is_empty() => err(format!("EFI/{csv} has no entries")),
+            Ok(entries) => {
+                for (loader, _) in entries {
+                    if !esp.exists(format!("{v}/{loader}").as_str())? {
+                        err(format!("EFI/{csv} refers to missing {loader}"));
+                    }
+                }
+            }
+            Err(e) => err(format!("EFI/{csv}: {e}")),
+        }
+    }
+    if has_fallback {
+        if !has_csv {
+            err(format!(
+                "EFI/{fallback} exists, but no vendor directory has {bootcsv}"
+            ));
+        }
+    }
+    if let Some(nvram) = nvram {
+        let nvram = nvram.to_ascii_lowercase();
+        let found = shims.iter().any(|v| {
+            let loader = format!("\\efi\\{v}\\{shim_name}").to_ascii_lowercase();
+            nvram.contains(&loader)
+        });
+        if !found && !has_fallback {
+            err(format!(
+                "No NVRAM boot entry loads {shim_name}, and EFI/{fallback} is missing"
+            ));
+        }
+    }
+
+    // shim to GRUB config
+    for v in shims.iter() {
+        if !esp.exists(format!("{v}/grub.cfg").as_str())? {
+            err(format!("EFI/{v}/grub.cfg is missing"));
+        }
+    }
+
+    // GRUB config to /boot
+    if !boot.join("grub2/grub.cfg").exists() {
+        err(format!("{}/grub2/grub.cfg is missing", boot.display()));
+    }
+    if !has_conf_files(&boot.join("loader/entries"))? {
+        err(format!(
+            "No boot loader entries in {}/loader/entries",
+            boot.display()
+        ));
+    }
+    Ok(errs)
+}
+
+/// The output of `efibootmgr -v`, if we can read EFI variables.
+fn read_nvram_entries() -> Option<String> {
+    if !is_efi_booted().unwrap_or(false) {
+        return None;
+    }
+    match Command::new(EFIBOOTMGR).arg("-v").output() {
+        Ok(o) if o.status.success() => Some(String::from_utf8_lossy(&o.stdout).into_owned()),
+        Ok(o) => {
+            log::debug!("{EFIBOOTMGR} -v failed: {}", o.status);
+            None
+        }
+        Err(e) => {
+            log::debug!("Running {EFIBOOTMGR}: {e}");
+            None
+        }
+    }
+}
+
+/// Refuse to install EFI binaries which the firmware would refuse to load.
+fn check_dbx(updated: &openat::Dir, updatef: &filetree::FileTree) -> Result<()> {
+    let revoked = revoked_images(updated, updatef, &crate::dbx::read_current()?)?;
+    if !revoked.is_empty() {
+        bail!(
+            "Update contains binaries revoked by the UEFI dbx: {}",
+            revoked.join(" ")
+        );
+    }
+    Ok(())
+}
+
+impl Efi {
+    fn esp_path(&self) -> Result<PathBuf> {
+        self.ensure_mounted_esp(Path::new("/"))
+            .map(|v| v.join("EFI"))
+    }
+
+    fn open_esp_optional(&self) -> Result<Option<openat::Dir>> {
+        if !is_efi_booted()? && self.get_esp_device().is_none() {
+            log::debug!("Skip EFI");
+            return Ok(None);
+        }
+        let sysroot = openat::Dir::open("/")?;
+        let esp = sysroot.sub_dir_optional(&self.esp_path()?)?;
+        Ok(esp)
+    }
+
+    /// The `EFI/` directory of the ESP.
+    pub(crate) fn open_esp(&self) -> Result<openat::Dir> {
+        self.ensure_mounted_esp(Path::new("/"))?;
+        let sysroot = openat::Dir::open("/")?;
+        let esp = sysroot.sub_dir(&self.esp_path()?)?;
+        Ok(esp)
+    }
+
+    fn get_esp_device(&self) -> Option<PathBuf> {
+        let esp_devices = [COREOS_ESP_PART_LABEL, ANACONDA_ESP_PART_LABEL]
+            .into_iter()
+            .map(|p| Path::new("/dev/disk/by-partlabel/").join(p));
+        let mut esp_device = None;
+        for path in esp_devices {
+            if path.exists() {
+                esp_device = Some(path);
+                break;
+            }
+        }
+        return esp_device;
+    }
+
+    /// Generate the update payload in `sysroot_path` from the shim and GRUB
+    /// packages in `rpmdir`, which aren't installed in it.
+    #[context("Generating EFI payload from packages in {}", rpmdir.display())]
+    pub(crate) fn generate_update_metadata_from_rpms(
+        &self,
+        sysroot_path: &str,
+        rpmdir: &Path,
+    ) -> Result<ContentMetadata> {
+        let dest_efidir = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest_efidir)?;
+        let mut rpms = std::fs::read_dir(rpmdir)?
+            .map(|e| Ok(e?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        rpms.retain(|p| p.extension().is_some_and(|e| e == "rpm"));
+        rpms.sort();
+        let mut pkgs = BTreeMap::new();
+        for path in rpms {
+            let rpm = crate::rpmfile::RpmFile::open(&path)?;
+            let files = rpm.extract(PACKAGED_EFI_DIR, &dest_efidir)?;
+            if files.is_empty() {
+                log::debug!("No EFI files in {}", rpm.package.nevra);
+                continue;
+            }
+            log::debug!("Extracted {} files from {}", files.len(), rpm.package.nevra);
+            pkgs.insert(rpm.package.nevra, rpm.package.buildtime);
+        }
+        let meta = packagesystem::package_metadata(pkgs)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    /// The EFI binaries which an update of `current` would replace, along
+    /// with the Authenticode digests of the installed and updated versions.
+    pub(crate) fn image_changes(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<crate::tpm::ImageDigests>> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let updatef = crate::treecache::payload_tree(sysroot, self, &updated)
+            .context("reading update dir")?;
+        let esp = self.open_esp()?;
+        let mut r = Vec::new();
+        for (path, meta) in updatef.children.iter() {
+            if !path.to_ascii_lowercase().ends_with(".efi") {
+                continue;
+            }
+            match currentf.children.get(path) {
+                Some(installed) if installed != meta => {}
+                _ => continue,
+            }
+            r.push(crate::tpm::ImageDigests {
+                path: path.clone(),
+                current: authenticode_digest(&esp, path)?,
+                update: authenticode_digest(&updated, path)?,
+            });
+        }
+        Ok(r)
+    }
+
+    /// Compare the installed shim and GRUB images of `current` with those
+    /// measured into the TPM event log during this boot.
+    pub(crate) fn validate_measurements(
+        &self,
+        current: &InstalledContent,
+    ) -> Result<ValidationResult> {
+        if !crate::tpm::available() {
+            return Ok(ValidationResult::Skip);
+        }
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let esp = self.open_esp()?;
+        let boot_time = crate::tpm::boot_time()?;
+        let mut images = Vec::new();
+        for path in currentf.children.keys() {
+            let name = path.rsplit('/').next().unwrap_or(path).to_ascii_lowercase();
+            if !name.ends_with(".efi")
+                || !["shim", "grub", "boot"].iter().any(|p| name.starts_with(p))
+            {
+                continue;
+            }
+            // Missing images are reported by the file validation
+            let Some(meta) = esp.metadata_optional(path)? else {
+                continue;
+            };
+            images.push(crate::tpm::InstalledImage {
+                path: path.clone(),
+                digest: authenticode_digest(&esp, path)?,
+                updated_since_boot: meta.stat().st_mtime > boot_time,
+            });
+        }
+        let measured = crate::tpm::measured_images()?;
+        let errs = crate::tpm::check_measurements(&images, &measured);
+        if errs.is_empty() {
+            Ok(ValidationResult::Valid)
+        } else {
+            Ok(ValidationResult::Errors(errs))
+        }
+    }
+
+    /// Check the boot chain of `current`, from the firmware to the boot
+    /// loader entries in `boot/` under `sysroot`.
+    pub(crate) fn validate_boot_chain(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<ValidationResult> {
+        if !is_efi_booted()? && self.get_esp_device().is_none() {
+            return Ok(ValidationResult::Skip);
+        }
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let root = std::fs::read_link(format!("/proc/self/fd/{}", sysroot.as_raw_fd()))?;
+        self.ensure_mounted_esp(&root)?;
+        let efidir = self.open_esp()?;
+        let mut vendors = currentf
+            .children
+            .keys()
+            .filter_map(|k| SHIMS.iter().find_map(|s| k.strip_suffix(&format!("/{s}"))))
+            .collect::<Vec<_>>();
+        // Both shims may be in the same vendor directory
+        vendors.dedup();
+        let nvram = read_nvram_entries();
+        let errs = check_boot_chain(&efidir, &root.join("boot"), &vendors, nvram.as_deref())?;
+        if errs.is_empty() {
+            Ok(ValidationResult::Valid)
+        } else {
+            Ok(ValidationResult::Errors(errs))
+        }
+    }
+
+    pub(crate) fn ensure_mounted_esp(&self, root: &Path) -> Result<PathBuf> {
+        let _span = tracing::info_span!("mount", ?root).entered();
+        let mut mountpoint = self.mountpoint.borrow_mut();
+        if let Some(mountpoint) = mountpoint.as_ref() {
+            return Ok(mountpoint.path.clone());
+        }
+        crate::try_fail_point!("efi::mount");
+        if crate::config::get().esp_private_mount && root == Path::new("/") {
+            crate::notify::progress("mount-esp", Some(self.name()), &[]);
+            let mnt = self.mount_esp_private(root)?;
+            *mountpoint = Some(EspMount {
+                path: mnt.clone(),
+                owned: true,
+                remounted: false,
+            });
+            return Ok(mnt);
+        }
+        if let Some(mnt) = find_mounted_esp(root)? {
+            let remounted = util::is_readonly_mount(&mnt)?;
+            util::ensure_writable_mount(&mnt).context(ErrorKind::EspReadOnly)?;
+            // Recorded right away, so that the mount is restored even if
+            // the check fails
+            *mountpoint = Some(EspMount {
+                path: mnt.clone(),
+                owned: false,
+                remounted,
+            });
+            self.check_fat(&mounted_device(&mnt)?)?;
+            log::debug!("Reusing existing {mnt:?}");
+            return Ok(mnt);
+        }
+
+        let esp_device = self.get_esp_device().ok_or(ErrorKind::EspNotFound)?;
+        for mnt in esp_mounts() {
+            let mnt = root.join(mnt);
+            if !mnt.exists() {
+                continue;
+            }
+            self.check_fat(&esp_device)?;
+            crate::notify::progress("mount-esp", Some(self.name()), &[]);
+            crate::mount::enter_private_namespace()?;
+            crate::retry::retry("Mounting ESP", || {
+                crate::mount::mount_vfat(&esp_device, &mnt)
+            })?;
+            log::debug!("Mounted at {mnt:?}");
+            *mountpoint = Some(EspMount {
+                path: mnt.clone(),
+                owned: true,
+                remounted: false,
+            });
+            return Ok(mnt);
+        }
+        bail!("No mountpoint for the ESP found in {}", root.display())
+    }
+
+    /// If the ESP is mounted at one of the well-known paths, return its source device.
+    fn find_mounted_esp_source(&self, root: &Path) -> Result<Option<PathBuf>> {
+        for mnt in esp_mounts() {
+            let mnt = root.join(mnt);
+            if !mnt.exists() {
+                continue;
+            }
+            let st =
+                rustix::fs::statfs(&mnt).with_context(|| format!("statfs failed for {mnt:?}"))?;
+            if st.f_type != libc::MSDOS_SUPER_MAGIC {
+                continue;
+            }
+            let dir = openat::Dir::open(&mnt)?;
+            let fsinfo = crate::filesystem::inspect_filesystem(&dir, ".")?;
+            return Ok(Some(fsinfo.source.into()));
+        }
+        Ok(None)
+    }
+
+    /// Mount the ESP at a fresh directory under `/run/bootupd`, leaving
+    /// the well-known (admin-visible) mountpoints untouched.
+    #[context("Mounting ESP privately")]
+    fn mount_esp_private(&self, root: &Path) -> Result<PathBuf> {
+        let esp_device = match self.find_mounted_esp_source(root)? {
+            Some(device) => device,
+            None => self.get_esp_device().ok_or(ErrorKind::EspNotFound)?,
+        };
+        self.check_fat(&esp_device)?;
+        mount_private(&esp_device)
+    }
+
+    /// Check the filesystem of the ESP on `device` before writing to it,
+    /// recording any problem for [`Efi::ensure_consistent_esps`]; nothing is
+    /// done on the read-only paths.
+    fn check_fat(&self, device: &Path) -> Result<()> {
+        if !self.writing.get() {
+            return Ok(());
+        }
+        let device =
+            std::fs::canonicalize(device).with_context(|| format!("Resolving {device:?}"))?;
+        if self.fat_checked.borrow().contains(&device) {
+            return Ok(());
+        }
+        self.fat_checked.borrow_mut().push(device.clone());
+        if let Some(problem) = crate::fatcheck::check(&device)? {
+            log::warn!("{problem}");
+            self.fat_problems.borrow_mut().push(problem);
+        }
+        Ok(())
+    }
+
+    /// Refuse to write to the ESPs if problems are found with their
+    /// filesystems, unless forced.  Those mounted before we started writing
+    /// are checked now.
+    fn ensure_consistent_esps(&self) -> Result<()> {
+        self.writing.set(true);
+        let primary = self.mountpoint.borrow().as_ref().map(|m| m.path.clone());
+        let mounted = primary
+            .into_iter()
+            .chain(self.extra_mountpoints.borrow().iter().cloned())
+            .collect::<Vec<_>>();
+        for mnt in mounted {
+            self.check_fat(&mounted_device(&mnt)?)?;
+        }
+        let problems = self.fat_problems.borrow();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if crate::fatcheck::forced() {
+            log::warn!("Writing to the ESP despite filesystem problems");
+            return Ok(());
+        }
+        Err(ErrorKind::EspDirty).with_context(|| {
+            format!(
+                "{}; repair it with fsck.fat, or set esp-fsck, or use --force",
+                problems.join("; ")
+            )
+        })
+    }
+
+    /// Ensure all ESPs on the disks backing `/boot` are mounted, returning
+    /// their mountpoints; the first is the one from `ensure_mounted_esp()`,
+    /// and any others are mounted privately.
+    #[context("Mounting all ESPs")]
+    pub(crate) fn ensure_mounted_esps(&self) -> Result<Vec<PathBuf>> {
+        let primary = self.ensure_mounted_esp(Path::new("/"))?;
+        let devices = match crate::blockdev::find_colocated_esps("/") {
+            Ok(devices) => devices,
+            Err(e) => {
+                log::debug!("Not looking for other ESPs: {e:#}");
+                return Ok(vec![primary]);
+            }
+        };
+        let mut extra = self.extra_mountpoints.borrow_mut();
+        if devices.len() > 1 && extra.is_empty() {
+            let primary_dev = mounted_device(&primary)?;
+            for device in devices {
+                let device = std::fs::canonicalize(&device)
+                    .with_context(|| format!("Resolving {device}"))?;
+                if device != primary_dev {
+                    self.check_fat(&device)?;
+                    extra.push(mount_private(&device)?);
+                }
+            }
+        }
+        Ok(std::iter::once(primary)
+            .chain(extra.iter().cloned())
+            .collect())
+    }
+
+    /// Open `EFI/` on each ESP, labelled by its path.
+    fn open_all_esps(&self) -> Result<Vec<(String, openat::Dir)>> {
+        self.ensure_mounted_esps()?
+            .into_iter()
+            .map(|esp| {
+                let efidir = esp.join("EFI");
+                let d = openat::Dir::open(&efidir)
+                    .with_context(|| format!("opening {}", efidir.display()))?;
+                validate_esp(&d)?;
+                Ok((efidir.display().to_string(), d))
+            })
+            .collect()
+    }
+
+    /// Compare the content of all ESPs (e.g. on mirrored disks) with each
+    /// other, rather than with the saved state.
+    pub(crate) fn compare_esps(&self) -> Result<Vec<String>> {
+        compare_esp_dirs(&self.open_all_esps()?)
+    }
+
+    /// Copy the installed files in `tree` from an ESP which has them
+    /// unmodified, preferably the primary one, onto any others where they
+    /// are modified or missing; returns the paths of those ESPs.
+    #[context("Resyncing ESPs")]
+    pub(crate) fn resync_esps(&self, tree: &filetree::FileTree) -> Result<Vec<String>> {
+        let (names, dirs): (Vec<_>, Vec<_>) = self.open_all_esps()?.into_iter().unzip();
+        self.ensure_consistent_esps()?;
+        let changed = resync_esp_dirs(tree, &dirs)?;
+        Ok(changed.into_iter().map(|i| names[i].clone()).collect())
+    }
+
+    /// Update the ESPs mounted at `esps` from `current` to the payload in
+    /// `sysroot`; the metadata is recorded from the first one.
+    fn update_esps(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        esps: &[PathBuf],
+    ) -> Result<InstalledContent> {
+        self.ensure_consistent_esps()?;
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let updatef = crate::signature::verified_payload_tree(sysroot, self.name(), &updated)
+            .context("reading update dir")?;
+        check_dbx(&updated, &updatef)?;
+        // Each vendor directory is updated independently; content for vendors
+        // which the update no longer ships (e.g. after a rebase to a different
+        // OS) is left in place.
+        let vendors = current
+            .vendors
+            .clone()
+            .unwrap_or_else(|| currentf.split_toplevel());
+        let plan = plan_update(vendors, &updatef)?;
+        for vendor in plan.retained.iter() {
+            log::info!("Retaining EFI/{vendor} which is not part of the update");
+        }
+        // All ESPs (e.g. on mirrored disks) are updated in parallel; only
+        // show progress for one of them to keep the output readable.
+        std::thread::scope(|s| -> Result<()> {
+            let updated = &updated;
+            let diffs = &plan.diffs;
+            let handles = esps
+                .iter()
+                .enumerate()
+                .map(|(i, esp)| s.spawn(move || apply_diffs_to_esp(updated, esp, diffs, i == 0)))
+                .collect::<Vec<_>>();
+            for h in handles {
+                h.join()
+                    .map_err(|_| anyhow::anyhow!("ESP update thread panicked"))??;
+            }
+            Ok(())
+        })?;
+        let adopted_from = None;
+        // The CSVs are the same on all ESPs, but validation only checks the
+        // primary one
+        let mut vendors = plan.vendors;
+        for (i, esp) in esps.iter().enumerate() {
+            let efidir = openat::Dir::open(&esp.join("EFI"))?;
+            for tree in vendors.values_mut() {
+                let mut regenerated = tree.clone();
+                regenerate_fallback_csvs(Path::new("/"), &efidir, &mut regenerated)?;
+                if i == 0 {
+                    *tree = regenerated;
+                }
+            }
+        }
+        let mut filetree = filetree::FileTree::merge(vendors.values());
+        filetree.record_mtimes(&openat::Dir::open(&esps[0].join("EFI"))?);
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(filetree),
+            adopted_from,
+            vendors: Some(vendors),
+            partitions: None,
+            warnings: current.warnings.clone(),
+        })
+    }
+
+    /// The PARTUUID of each ESP in `esps`, mounted at the given paths.
+    fn esp_partuuids(esps: &[PathBuf]) -> Result<Vec<String>> {
+        esps.iter()
+            .map(|esp| {
+                let dev = mounted_device(esp)?;
+                let dev = dev.to_string_lossy();
+                crate::blockdev::get_esp_info(&dev)?
+                    .partuuid
+                    .ok_or_else(|| anyhow::anyhow!("ESP {dev} has no PARTUUID"))
+            })
+            .collect()
+    }
+
+    /// Remove the stale boot entries (see [`stale_boot_entries`]), checking
+    /// the files on the ESPs of this system; with `dry_run`, only find them.
+    #[context("Removing stale EFI boot entries")]
+    pub(crate) fn gc_boot_entries(&self, dry_run: bool) -> Result<Vec<StaleBootEntry>> {
+        if !is_efi_booted()? {
+            bail!("Not booted via EFI");
+        }
+        if !dry_run {
+            if crate::config::get().no_nvram {
+                bail!("Removing EFI boot entries, but no-nvram is set");
+            }
+            if efivars_readonly()? {
+                return Err(ErrorKind::NvramWriteFailed)
+                    .with_context(|| format!("{EFIVARS} is read-only"));
+            }
+        }
+        let esps = self.ensure_mounted_esps()?;
+        let partuuids = Self::esp_partuuids(&esps)?;
+        let nvram = util::cmd_output(Command::new(EFIBOOTMGR).arg("-v"))?;
+        let stale = stale_boot_entries(&nvram, |partuuid, file| {
+            let i = partuuids
+                .iter()
+                .position(|p| p.eq_ignore_ascii_case(partuuid))?;
+            let file = file.trim_start_matches('\\').replace('\\', "/");
+            Some(esps[i].join(file).exists())
+        });
+        if dry_run {
+            return Ok(stale);
+        }
+        for entry in stale.iter() {
+            log::info!(
+                "Deleting Boot{} {:?}: {}",
+                entry.id,
+                entry.name,
+                entry.reason
+            );
+            delete_boot_entry(&entry.id)?;
+        }
+        Ok(stale)
+    }
+
+    /// With exactly two ESPs, update only the one the system didn't boot
+    /// from, and make the firmware boot from it first.  The other one is
+    /// updated by [`Efi::confirm_ab_update`] once the system booted from
+    /// the updated ESP, or the update undone by [`Efi::rollback_ab_update`].
+    #[context("Updating the inactive ESP")]
+    pub(crate) fn run_update_ab(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<UnconfirmedEsp> {
+        if crate::config::get().no_nvram {
+            bail!("A/B ESP updates need to change the boot order, but no-nvram is set");
+        }
+        let esps = self.ensure_mounted_esps()?;
+        if esps.len() != 2 {
+            bail!("A/B ESP updates need two ESPs, found {}", esps.len());
+        }
+        let partuuids = Self::esp_partuuids(&esps)?;
+        let nvram = util::cmd_output(Command::new(EFIBOOTMGR).arg("-v"))?;
+        let Some(booted) = booted_partuuid(&nvram) else {
+            bail!("Can't tell which ESP the system booted from");
+        };
+        let Some(active) = partuuids
+            .iter()
+            .position(|p| p.eq_ignore_ascii_case(&booted))
+        else {
+            bail!("Booted from {booted}, which is neither of the ESPs {partuuids:?}");
+        };
+        let inactive = 1 - active;
+        log::info!(
+            "Updating the ESP {} (booted from {})",
+            partuuids[inactive],
+            partuuids[active]
+        );
+        let update = self.update_esps(sysroot, current, &esps[inactive..=inactive])?;
+        set_first_boot_entry(&nvram, &partuuids[inactive])?;
+        Ok(UnconfirmedEsp {
+            partuuid: partuuids[inactive].clone(),
+            other: partuuids[active].clone(),
+            update,
+        })
+    }
+
+    /// Finish the A/B update `unconfirmed` from `installed`, after booting
+    /// from the updated ESP: copy the update onto the other ESP.
+    #[context("Updating the other ESP")]
+    pub(crate) fn confirm_ab_update(
+        &self,
+        unconfirmed: &UnconfirmedEsp,
+        installed: &InstalledContent,
+    ) -> Result<()> {
+        let nvram = util::cmd_output(Command::new(EFIBOOTMGR).arg("-v"))?;
+        let booted = booted_partuuid(&nvram);
+        if !booted
+            .as_deref()
+            .is_some_and(|b| b.eq_ignore_ascii_case(&unconfirmed.partuuid))
+        {
+            bail!(
+                "The system didn't boot from the updated ESP {} (booted from {}); \
+                 run `bootupctl rollback` to undo the update",
+                unconfirmed.partuuid,
+                booted.as_deref().unwrap_or("an unknown device")
+            );
+        }
+        self.copy_between_esps(
+            &unconfirmed.partuuid,
+            &unconfirmed.other,
+            installed,
+            &unconfirmed.update,
+        )
+    }
 
-impl Efi {
-    fn esp_path(&self) -> Result<PathBuf> {
-        self.ensure_mounted_esp(Path::new("/"))
-            .map(|v| v.join("EFI"))
+    /// Undo the A/B update `unconfirmed`: copy the `installed` content
+    /// back from the other ESP onto the updated one, and make the firmware
+    /// boot from the other one first again.
+    #[context("Rolling back the updated ESP")]
+    pub(crate) fn rollback_ab_update(
+        &self,
+        unconfirmed: &UnconfirmedEsp,
+        installed: &InstalledContent,
+    ) -> Result<()> {
+        self.copy_between_esps(
+            &unconfirmed.other,
+            &unconfirmed.partuuid,
+            &unconfirmed.update,
+            installed,
+        )?;
+        let nvram = util::cmd_output(Command::new(EFIBOOTMGR).arg("-v"))?;
+        set_first_boot_entry(&nvram, &unconfirmed.other)
     }
 
-    fn open_esp_optional(&self) -> Result<Option<openat::Dir>> {
-        if !is_efi_booted()? && self.get_esp_device().is_none() {
-            log::debug!("Skip EFI");
-            return Ok(None);
-        }
-        let sysroot = openat::Dir::open("/")?;
-        let esp = sysroot.sub_dir_optional(&self.esp_path()?)?;
-        Ok(esp)
+    /// Update the ESP `dest` from the content `from` to `to`, copying the
+    /// files from the ESP `src` which has `to`; both are PARTUUIDs.
+    fn copy_between_esps(
+        &self,
+        src: &str,
+        dest: &str,
+        from: &InstalledContent,
+        to: &InstalledContent,
+    ) -> Result<()> {
+        self.ensure_consistent_esps()?;
+        let fromf = from
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for EFI content found!"))?;
+        let tof = to
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for EFI content found!"))?;
+        let vendors = from
+            .vendors
+            .clone()
+            .unwrap_or_else(|| fromf.split_toplevel());
+        let plan = plan_update(vendors, tof)?;
+        let src = openat::Dir::open(&self.find_esp(src)?.join("EFI"))?;
+        apply_diffs_to_esp(&src, &self.find_esp(dest)?, &plan.diffs, true)
     }
 
-    fn open_esp(&self) -> Result<openat::Dir> {
-        self.ensure_mounted_esp(Path::new("/"))?;
-        let sysroot = openat::Dir::open("/")?;
-        let esp = sysroot.sub_dir(&self.esp_path()?)?;
-        Ok(esp)
+    /// The mountpoint of the ESP with PARTUUID `partuuid`.
+    fn find_esp(&self, partuuid: &str) -> Result<PathBuf> {
+        let esps = self.ensure_mounted_esps()?;
+        let partuuids = Self::esp_partuuids(&esps)?;
+        partuuids
+            .iter()
+            .position(|p| p.eq_ignore_ascii_case(partuuid))
+            .map(|i| esps[i].clone())
+            .ok_or_else(|| anyhow::anyhow!("ESP {partuuid} not found"))
     }
 
-    fn get_esp_device(&self) -> Option<PathBuf> {
-        let esp_devices = [COREOS_ESP_PART_LABEL, ANACONDA_ESP_PART_LABEL]
-            .into_iter()
-            .map(|p| Path::new("/dev/disk/by-partlabel/").join(p));
-        let mut esp_device = None;
-        for path in esp_devices {
-            if path.exists() {
-                esp_device = Some(path);
-                break;
-            }
+    /// Validate the ESPs during the A/B update `unconfirmed`: the updated
+    /// one against the update, and the other one against `installed`.
+    pub(crate) fn validate_ab(
+        &self,
+        unconfirmed: &UnconfirmedEsp,
+        installed: &InstalledContent,
+        fast: bool,
+    ) -> Result<ValidationResult> {
+        let mut errs = Vec::new();
+        for (partuuid, content) in [
+            (&unconfirmed.partuuid, &unconfirmed.update),
+            (&unconfirmed.other, installed),
+        ] {
+            let tree = content
+                .filetree
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("No filetree for EFI content found!"))?;
+            let efidir = openat::Dir::open(&self.find_esp(partuuid)?.join("EFI"))?;
+            errs.extend(
+                self.validate_files(&efidir, tree, fast)?
+                    .into_iter()
+                    .map(|e| format!("ESP {partuuid}: {e}")),
+            );
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
         }
-        return esp_device;
     }
 
-    pub(crate) fn ensure_mounted_esp(&self, root: &Path) -> Result<PathBuf> {
-        let mut mountpoint = self.mountpoint.borrow_mut();
-        if let Some(mountpoint) = mountpoint.as_deref() {
-            return Ok(mountpoint.to_owned());
+    /// Check that the files of `tree` in `efidir` are unmodified, and that
+    /// no interrupted update left files behind.
+    fn validate_files(
+        &self,
+        efidir: &openat::Dir,
+        tree: &filetree::FileTree,
+        fast: bool,
+    ) -> Result<Vec<String>> {
+        let diff = tree.validate_in(efidir, fast)?;
+        let provenance = crate::provenance::load(&openat::Dir::open("/")?, self.name())?;
+        // Which package the expected file came from, if known
+        let origin = |f: &str| {
+            tree.children
+                .get(f)
+                .and_then(|m| crate::provenance::package_for(&provenance, &m.sha512))
+                .map(|p| format!(" (from {p})"))
+                .unwrap_or_default()
+        };
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(format!("Changed: {}{}", f, origin(f)));
         }
-        for &mnt in ESP_MOUNTS {
-            let mnt = root.join(mnt);
-            if !mnt.exists() {
-                continue;
-            }
-            let st =
-                rustix::fs::statfs(&mnt).with_context(|| format!("statfs failed for {mnt:?}"))?;
-            if st.f_type != libc::MSDOS_SUPER_MAGIC {
-                continue;
-            }
-            util::ensure_writable_mount(&mnt)?;
-            log::debug!("Reusing existing {mnt:?}");
-            return Ok(mnt);
+        for f in diff.removals.iter() {
+            errs.push(format!("Removed: {}{}", f, origin(f)));
+        }
+        assert_eq!(diff.additions.len(), 0);
+        for p in filetree::leftover_tmp(efidir)? {
+            errs.push(format!("Left over from an interrupted update: {p}"));
         }
+        Ok(errs)
+    }
 
-        let esp_device = self
-            .get_esp_device()
-            .ok_or_else(|| anyhow::anyhow!("Failed to find ESP device"))?;
-        for &mnt in ESP_MOUNTS.iter() {
-            let mnt = root.join(mnt);
-            if !mnt.exists() {
-                continue;
+    /// Copy the payload in `src_root` to the ESP mounted at `esp`, for the
+    /// OS installed in `dest_root`, and with `update_firmware` create a boot
+    /// entry for it on `device`, replacing existing ones if `clear` is set.
+    /// Returns the files installed, and any warnings.
+    fn install_to_esp(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &Path,
+        esp: &Path,
+        device: &str,
+        update_firmware: bool,
+        clear: bool,
+    ) -> Result<(filetree::FileTree, Vec<String>)> {
+        let srcdir_name = component_updatedirname(self);
+        let mut ft = crate::filetree::FileTree::new_from_dir(&src_root.sub_dir(&srcdir_name)?)?;
+        let destd = &openat::Dir::open(esp)
+            .with_context(|| format!("opening dest dir {}", esp.display()))?;
+        validate_esp(destd)?;
+        self.ensure_consistent_esps()?;
+
+        let mut audit = crate::audit::Recorder::new(destd)?;
+        if let Some(audit) = audit.as_mut() {
+            for path in ft.children.keys() {
+                audit.before(destd, &format!("EFI/{path}"))?;
+            }
+        }
+        // TODO - add some sort of API that allows directly setting the working
+        // directory to a file descriptor.
+        std::process::Command::new("cp")
+            .args(["-rp", "--reflink=auto"])
+            .arg(&srcdir_name)
+            .arg(esp)
+            .current_dir(format!("/proc/self/fd/{}", src_root.as_raw_fd()))
+            .run()?;
+        if let Some(audit) = audit {
+            audit.commit(destd)?;
+        }
+        let efidir = destd.sub_dir("EFI")?;
+        regenerate_fallback_csvs(dest_root, &efidir, &mut ft)?;
+        let mut warnings = Vec::new();
+        if update_firmware {
+            for vendordir in self.get_efi_vendors(&src_root)? {
+                warnings.extend(self.update_firmware(dest_root, device, destd, &vendordir, clear)?);
             }
-            std::process::Command::new("mount")
-                .arg(&esp_device)
-                .arg(&mnt)
-                .run()
-                .with_context(|| format!("Failed to mount {:?}", esp_device))?;
-            log::debug!("Mounted at {mnt:?}");
-            *mountpoint = Some(mnt);
-            break;
         }
-        Ok(mountpoint.as_deref().unwrap().to_owned())
+        ft.record_mtimes(&efidir);
+        Ok((ft, warnings))
     }
 
     fn unmount(&self) -> Result<()> {
-        if let Some(mount) = self.mountpoint.borrow_mut().take() {
-            Command::new("umount")
-                .arg(&mount)
-                .run()
-                .with_context(|| format!("Failed to unmount {mount:?}"))?;
-            log::trace!("Unmounted");
+        // Try all of them, even if one fails
+        let mut r = Ok(());
+        for mount in self.extra_mountpoints.borrow_mut().drain(..) {
+            r = r.and(unmount_esp(&mount));
         }
-        Ok(())
+        match self.mountpoint.borrow_mut().take() {
+            Some(mount) if mount.owned => r = r.and(unmount_esp(&mount.path)),
+            Some(mount) if mount.remounted => {
+                r = r.and(crate::mount::remount_ro(&mount.path).map_err(Into::into));
+            }
+            Some(mount) => log::trace!("Leaving {:?} mounted", mount.path),
+            None => {}
+        }
+        r
     }
 
+    /// Create the EFI boot entry for `vendordir`, unless `no-nvram` is set.
+    /// Unless `require-nvram` is set, failing to write EFI variables (which
+    /// some platforms don't allow) is returned as a warning rather than an
+    /// error.
     #[context("Updating EFI firmware variables")]
-    fn update_firmware(&self, device: &str, espdir: &openat::Dir, vendordir: &str) -> Result<()> {
+    fn update_firmware(
+        &self,
+        root: &Path,
+        device: &str,
+        espdir: &openat::Dir,
+        vendordir: &str,
+        clear: bool,
+    ) -> Result<Option<String>> {
+        let _span = tracing::info_span!("firmware", device, clear).entered();
+        if crate::config::get().no_nvram {
+            log::info!("Not modifying EFI variables (no-nvram is set)");
+            let fallback = firmware_files().fallback;
+            if !espdir.exists(format!("EFI/{fallback}").as_str())? {
+                let msg = format!("no-nvram is set, but EFI/{fallback} is missing");
+                log::warn!("{msg}");
+                return Ok(Some(msg));
+            }
+            return Ok(None);
+        }
         if !is_efi_booted()? {
             log::debug!("Not booted via EFI, skipping firmware update");
-            return Ok(());
+            return Ok(None);
+        }
+        let require_nvram = crate::config::get().require_nvram;
+        if efivars_readonly()? {
+            if require_nvram {
+                return Err(ErrorKind::NvramWriteFailed)
+                    .with_context(|| format!("{EFIVARS} is read-only"));
+            }
+            let msg = format!("{EFIVARS} is read-only; no EFI boot entry was created");
+            log::warn!("{msg}");
+            return Ok(Some(msg));
         }
-        let sysroot = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
-        let product_name = get_product_name(&sysroot)?;
-        log::debug!("Get product name: {product_name}");
+        let product_name = boot_label(root)?;
+        log::debug!("Boot entry label: {product_name}");
         assert!(product_name.len() > 0);
-        // clear all the boot entries that match the target name
-        clear_efi_target(&product_name)?;
-        create_efi_boot_entry(device, espdir, vendordir, &product_name)
+        let r = (|| {
+            crate::try_fail_point!("efi::boot-entries");
+            let source = crate::filesystem::inspect_filesystem(espdir, ".")?.source;
+            // Without it, the entries of ours are only told apart by the
+            // vendor directory
+            let partuuid = match crate::blockdev::get_esp_info(&source) {
+                Ok(info) => info.partuuid,
+                Err(e) => {
+                    log::debug!("Failed to get the PARTUUID of {source}: {e:#}");
+                    None
+                }
+            };
+            // clear the boot entries of ours that match the target name,
+            // unless we already created one for another disk
+            if clear {
+                clear_efi_target(&product_name, vendordir, partuuid.as_deref())?;
+            }
+            crate::try_fail_point!("efi::create-boot-entry");
+            create_efi_boot_entry(
+                device,
+                espdir,
+                vendordir,
+                &product_name,
+                partuuid.as_deref(),
+            )
+        })();
+        match r {
+            Ok(()) => Ok(None),
+            // Only the variables not being writable is tolerated, like a
+            // read-only efivarfs above; e.g. a wrong device is an error
+            Err(e) if !require_nvram && NvramReadOnly::is_cause_of(&e) => {
+                let msg = format!("Failed to create EFI boot entry: {e:#}");
+                log::warn!("{msg}");
+                Ok(Some(msg))
+            }
+            Err(e) => Err(e.context(ErrorKind::NvramWriteFailed)),
+        }
     }
 }
 
@@ -166,11 +1473,17 @@ fn get_product_name(sysroot: &Dir) -> Result<String> {
     if sysroot.exists(release_path) {
         let content = sysroot.read_to_string(release_path)?;
         let re = regex::Regex::new(r" *release.*").unwrap();
-        return Ok(re.replace_all(&content, "").to_string());
+        return Ok(re.replace_all(content.trim(), "").to_string());
+    }
+    // Read the os-release of the root, not necessarily the host's
+    for path in ["etc/os-release", "usr/lib/os-release"] {
+        if sysroot.exists(path) {
+            let content = sysroot.read_to_string(path)?;
+            let release: OsRelease = content.lines().map(String::from).collect();
+            return Ok(release.name);
+        }
     }
-    // Read /etc/os-release
-    let release: OsRelease = OsRelease::new()?;
-    Ok(release.name)
+    bail!("No os-release found")
 }
 
 /// Convert a nul-terminated UTF-16 byte array to a String.
@@ -186,7 +1499,7 @@ fn string_from_utf16_bytes(slice: &[u8]) -> String {
 
 /// Read a nul-terminated UTF-16 string from an EFI variable.
 fn read_efi_var_utf16_string(name: &str) -> Option<String> {
-    let efivars = Path::new("/sys/firmware/efi/efivars");
+    let efivars = Path::new(EFIVARS);
     if !efivars.exists() {
         log::trace!("No efivars mount at {:?}", efivars);
         return None;
@@ -212,8 +1525,48 @@ fn read_efi_var_utf16_string(name: &str) -> Option<String> {
     }
 }
 
+/// Read the `SecureBoot` EFI variable, if it exists.
+fn get_secure_boot() -> Option<bool> {
+    let path = Path::new(EFIVARS).join(SECURE_BOOT_VAR_STR);
+    match std::fs::read(&path) {
+        // Skip the variable attributes
+        Ok(buf) => buf.get(4).map(|&v| v == 1),
+        Err(e) => {
+            log::debug!("Failed reading {path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Read a firmware property from SMBIOS, if available.
+fn read_dmi(name: &str) -> Option<String> {
+    let path = Path::new(DMI_ID_DIR).join(name);
+    let v = std::fs::read_to_string(&path)
+        .map_err(|e| log::debug!("Failed reading {path:?}: {e}"))
+        .ok()?;
+    let v = v.trim();
+    (!v.is_empty()).then(|| v.to_string())
+}
+
+/// Gather information about how the running system was booted.
+pub(crate) fn boot_info() -> Result<BootInfo> {
+    let efi = is_efi_booted()?;
+    Ok(BootInfo {
+        method: if efi {
+            BootMethod::Efi
+        } else {
+            BootMethod::Bios
+        },
+        secure_boot: efi.then(get_secure_boot).flatten(),
+        firmware_vendor: read_dmi("bios_vendor"),
+        firmware_version: read_dmi("bios_version"),
+        loader_info: efi.then(get_loader_info).flatten(),
+        stub_info: efi.then(get_stub_info).flatten(),
+    })
+}
+
 /// Read the LoaderInfo EFI variable if it exists.
-fn get_loader_info() -> Option<String> {
+pub(crate) fn get_loader_info() -> Option<String> {
     read_efi_var_utf16_string(LOADER_INFO_VAR_STR)
 }
 
@@ -237,24 +1590,121 @@ fn skip_systemd_bootloaders() -> bool {
     false
 }
 
+/// The supplemental payloads in `sysroot_path`, by package and then
+/// oldest version first.
+fn firmware_versions(sysroot_path: &str) -> Result<Vec<PayloadVersion>> {
+    let dir = Path::new(sysroot_path).join(FIRMWARE_DIR);
+    let pkgs = match std::fs::read_dir(&dir) {
+        Ok(p) => p,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {dir:?}")),
+    };
+    let mut r = Vec::new();
+    for pkg in pkgs {
+        let pkg = pkg?;
+        let name = pkg.file_name().to_string_lossy().into_owned();
+        if name == crate::objectstore::OBJECTS_DIR {
+            continue;
+        }
+        for v in std::fs::read_dir(pkg.path())? {
+            let v = v?.file_name().to_string_lossy().into_owned();
+            match PayloadVersion::new(&name, &v) {
+                Ok(v) => r.push(v),
+                Err(e) => log::warn!("Skipping {:?}: {e}", pkg.path().join(&v)),
+            }
+        }
+    }
+    r.sort_by(|a, b| {
+        a.package
+            .cmp(&b.package)
+            .then_with(|| packagesystem::compare_versions(&a.version, &b.version))
+    });
+    Ok(r)
+}
+
+/// The newest version of each supplemental payload in `sysroot_path`.
+fn firmware_payloads(sysroot_path: &str) -> Result<Vec<PayloadVersion>> {
+    let mut r: Vec<PayloadVersion> = Vec::new();
+    for v in firmware_versions(sysroot_path)? {
+        if r.last().is_some_and(|l| l.package == v.package) {
+            r.pop();
+        }
+        r.push(v);
+    }
+    Ok(r)
+}
+
+/// The timestamp of the supplemental payload in `src`: `epoch` (i.e.
+/// `SOURCE_DATE_EPOCH`) if set, else the newest modification time of its
+/// files, which `cp -a` keeps from the package they were taken from.
+/// Either is the same when the image is rebuilt, unlike the time the
+/// payload was staged.
+fn firmware_payload_timestamp(
+    src: &Path,
+    epoch: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<chrono::DateTime<chrono::Utc>> {
+    if let Some(epoch) = epoch {
+        return Ok(epoch);
+    }
+    let mut r = None;
+    for entry in WalkDir::new(src) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let mtime: chrono::DateTime<chrono::Utc> = entry.metadata()?.modified()?.into();
+        r = r.max(Some(mtime));
+    }
+    r.with_context(|| format!("No files in {src:?}"))
+}
+
+/// Copy the supplemental payloads in `sysroot_path` to `dest_efidir`, as
+/// they update along with the rest, and merge them into `meta`.
+fn add_firmware_payloads(
+    sysroot_path: &str,
+    dest_efidir: &Path,
+    meta: &mut ContentMetadata,
+    epoch: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<()> {
+    for v in firmware_payloads(sysroot_path)? {
+        let src = Path::new(sysroot_path)
+            .join(FIRMWARE_DIR)
+            .join(&v.package)
+            .join(&v.version);
+        log::debug!("Adding {src:?}");
+        Command::new("cp")
+            .arg("-a")
+            .arg(src.join("."))
+            .arg(dest_efidir)
+            .run()?;
+        meta.timestamp = meta.timestamp.max(firmware_payload_timestamp(&src, epoch)?);
+        meta.version.push_str(&format!(",{v}"));
+        meta.firmware
+            .get_or_insert_with(Default::default)
+            .insert(v.package, v.version);
+    }
+    Ok(())
+}
+
 impl Component for Efi {
     fn name(&self) -> &'static str {
         "EFI"
     }
 
     fn query_adopt(&self) -> Result<Option<Adoptable>> {
-        let esp = self.open_esp_optional()?;
-        if esp.is_none() {
+        let Some(esp) = self.open_esp_optional()? else {
             log::trace!("No ESP detected");
             return Ok(None);
         };
 
         // Don't adopt if the system is booted with systemd-boot or
-        // systemd-stub since those will be managed with bootctl.
+        // systemd-stub since those will be managed with bootctl, or
+        // explicitly adopted as the SystemdBoot component.
         if skip_systemd_bootloaders() {
             return Ok(None);
         }
-        crate::component::query_adopt_state()
+        let package_files = packaged_esp_files(&esp, &self.esp_path()?)?;
+        crate::component::query_adopt_state(&package_files)
     }
 
     /// Given an adoptable system and an update, perform the update.
@@ -267,20 +1717,37 @@ impl Component for Efi {
             anyhow::bail!("Failed to find adoptable system")
         };
 
+        self.writing.set(true);
         let esp = self.open_esp()?;
         validate_esp(&esp)?;
+        self.ensure_consistent_esps()?;
         let updated = sysroot
             .sub_dir(&component_updatedirname(self))
             .context("opening update dir")?;
-        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        let mut updatef = crate::signature::verified_payload_tree(sysroot, self.name(), &updated)
+            .context("reading update dir")?;
+        check_dbx(&updated, &updatef)?;
         // For adoption, we should only touch files that we know about.
         let diff = updatef.relative_diff_to(&esp)?;
         log::trace!("applying adoption diff: {}", &diff);
-        filetree::apply_diff(&updated, &esp, &diff, None).context("applying filesystem changes")?;
+        let opts = filetree::ApplyUpdateOptions {
+            progress: Some(filetree::terminal_progress("Adopting EFI")),
+            fat: true,
+            component: Some(self.name().into()),
+            ..Default::default()
+        };
+        filetree::apply_diff(&updated, &esp, &diff, Some(&opts))
+            .context("applying filesystem changes")?;
+        regenerate_fallback_csvs(Path::new("/"), &esp, &mut updatef)?;
+        let vendors = updatef.split_toplevel();
+        updatef.record_mtimes(&esp);
         Ok(InstalledContent {
             meta: updatemeta.clone(),
             filetree: Some(updatef),
             adopted_from: Some(meta.version),
+            vendors: Some(vendors),
+            partitions: None,
+            warnings: None,
         })
     }
 
@@ -292,65 +1759,91 @@ impl Component for Efi {
         device: &str,
         update_firmware: bool,
     ) -> Result<InstalledContent> {
-        let Some(meta) = get_component_update(src_root, self)? else {
-            anyhow::bail!("No update metadata for component {} found", self.name());
-        };
+        let meta = require_component_update(src_root, self)?;
         log::debug!("Found metadata {}", meta.version);
-        let srcdir_name = component_updatedirname(self);
-        let ft = crate::filetree::FileTree::new_from_dir(&src_root.sub_dir(&srcdir_name)?)?;
+        self.writing.set(true);
         let destdir = &self.ensure_mounted_esp(Path::new(dest_root))?;
-
-        let destd = &openat::Dir::open(destdir)
-            .with_context(|| format!("opening dest dir {}", destdir.display()))?;
-        validate_esp(destd)?;
-
-        // TODO - add some sort of API that allows directly setting the working
-        // directory to a file descriptor.
-        std::process::Command::new("cp")
-            .args(["-rp", "--reflink=auto"])
-            .arg(&srcdir_name)
-            .arg(destdir)
-            .current_dir(format!("/proc/self/fd/{}", src_root.as_raw_fd()))
-            .run()?;
-        if update_firmware {
-            if let Some(vendordir) = self.get_efi_vendor(&src_root)? {
-                self.update_firmware(device, destd, &vendordir)?
-            }
-        }
+        let (ft, warnings) = self.install_to_esp(
+            src_root,
+            Path::new(dest_root),
+            destdir,
+            device,
+            update_firmware,
+            true,
+        )?;
+        let vendors = ft.split_toplevel();
         Ok(InstalledContent {
             meta,
             filetree: Some(ft),
             adopted_from: None,
+            vendors: Some(vendors),
+            partitions: None,
+            warnings: (!warnings.is_empty()).then_some(warnings),
         })
     }
 
+    fn install_to_devices(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        devices: &[String],
+        update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let first = devices.first().map_or("", String::as_str);
+        let mut r = self.install(src_root, dest_root, first, update_firmware)?;
+        let others = devices.get(1..).unwrap_or_default();
+        if others.is_empty() {
+            return Ok(r);
+        }
+        // The ESP under `dest_root` is usually on the first device; the
+        // others are mounted privately, and unmounted on drop.
+        let primary = self.ensure_mounted_esp(Path::new(dest_root))?;
+        let primary_dev = mounted_device(&primary)?;
+        let mut warnings = r.warnings.take().unwrap_or_default();
+        for device in others {
+            let esp = crate::blockdev::get_esp_partition(device)?
+                .ok_or_else(|| anyhow::anyhow!("No ESP found on {device}"))?;
+            let esp = std::fs::canonicalize(&esp).with_context(|| format!("Resolving {esp}"))?;
+            if esp == primary_dev {
+                log::debug!("Already installed to the ESP on {device}");
+                continue;
+            }
+            self.check_fat(&esp)?;
+            let mnt = mount_private(&esp)?;
+            self.extra_mountpoints.borrow_mut().push(mnt.clone());
+            let (_, w) = self
+                .install_to_esp(
+                    src_root,
+                    Path::new(dest_root),
+                    &mnt,
+                    device,
+                    update_firmware,
+                    false,
+                )
+                .with_context(|| format!("Installing to the ESP on {device}"))?;
+            warnings.extend(w);
+        }
+        r.warnings = (!warnings.is_empty()).then_some(warnings);
+        Ok(r)
+    }
+
     fn run_update(
         &self,
-        sysroot: &openat::Dir,
+        payload_root: &openat::Dir,
+        _dest_root: &openat::Dir,
         current: &InstalledContent,
     ) -> Result<InstalledContent> {
-        let currentf = current
-            .filetree
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
-        let updatemeta = self.query_update(sysroot)?.expect("update available");
-        let updated = sysroot
-            .sub_dir(&component_updatedirname(self))
-            .context("opening update dir")?;
-        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
-        let diff = currentf.diff(&updatef)?;
-        self.ensure_mounted_esp(Path::new("/"))?;
-        let destdir = self.open_esp().context("opening EFI dir")?;
-        validate_esp(&destdir)?;
-        log::trace!("applying diff: {}", &diff);
-        filetree::apply_diff(&updated, &destdir, &diff, None)
-            .context("applying filesystem changes")?;
-        let adopted_from = None;
-        Ok(InstalledContent {
-            meta: updatemeta,
-            filetree: Some(updatef),
-            adopted_from,
-        })
+        // The ESPs are always those of the running system
+        self.writing.set(true);
+        self.update_esps(payload_root, current, &self.ensure_mounted_esps()?)
+    }
+
+    fn install_destination(&self, dest_root: &str, _device: &str) -> Result<String> {
+        if let Some(mnt) = find_mounted_esp(Path::new(dest_root))? {
+            return Ok(mnt.display().to_string());
+        }
+        let esp_device = self.get_esp_device().ok_or(ErrorKind::EspNotFound)?;
+        Ok(esp_device.display().to_string())
     }
 
     fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
@@ -382,16 +1875,158 @@ impl Component for Efi {
             f
         });
 
-        let meta = packagesystem::query_files(sysroot_path, files)?;
+        let mut meta = packagesystem::query_files(sysroot_path, files)?;
+
+        // Hardlink identical files in the supplemental payloads
+        let store = Path::new(sysroot_path).join(FIRMWARE_DIR);
+        if store.exists() {
+            let saved = crate::objectstore::dedup(&store)?;
+            if saved > 0 {
+                log::info!("Deduplicated {saved} bytes in {FIRMWARE_DIR}");
+            }
+            crate::objectstore::prune(&store)?;
+        }
+
+        let epoch = packagesystem::source_date_epoch()?;
+        add_firmware_payloads(sysroot_path, &dest_efidir, &mut meta, epoch)?;
         write_update_metadata(sysroot_path, self, &meta)?;
         Ok(meta)
     }
 
+    fn extend_payload(
+        &self,
+        sysroot_path: &str,
+        src: &Path,
+        pkg_name: Option<&str>,
+        version: Option<&str>,
+        keep: usize,
+    ) -> Result<ExtendedPayload> {
+        if !src.exists() {
+            bail!("Failed to find {src:?}");
+        }
+        let v = PayloadVersion::resolve(pkg_name, version, || {
+            let meta = packagesystem::query_files(sysroot_path, [src])
+                .context("Finding package; use --pkg-name and --version if there is none")?;
+            if meta.version.contains(',') {
+                bail!(
+                    "{src:?} is owned by several packages ({}); use --pkg-name and --version",
+                    meta.version
+                );
+            }
+            meta.version.parse()
+        })?;
+
+        let pkgdir = Path::new(sysroot_path).join(FIRMWARE_DIR).join(&v.package);
+        let dest = pkgdir.join(&v.version);
+        // Replace the version if it was added before
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest)?;
+        }
+        std::fs::create_dir_all(&dest)?;
+        Command::new("cp").arg("-a").arg(src).arg(&dest).run()?;
+
+        // Keep the newest other versions, up to `keep` in total
+        let others = firmware_versions(sysroot_path)?
+            .into_iter()
+            .filter(|o| o.package == v.package && o.version != v.version)
+            .collect::<Vec<_>>();
+        let n = others.len().saturating_sub(keep.saturating_sub(1));
+        let mut removed = Vec::new();
+        for o in others.into_iter().take(n) {
+            log::debug!("Removing {o}");
+            std::fs::remove_dir_all(pkgdir.join(&o.version))?;
+            removed.push(o.version);
+        }
+
+        let mut files = WalkDir::new(&dest)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| {
+                e.path()
+                    .strip_prefix(&dest)
+                    .map(|p| p.to_string_lossy().into_owned())
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        files.sort();
+        Ok(ExtendedPayload {
+            component: self.name().to_string(),
+            path: format!("{FIRMWARE_DIR}/{}/{}", v.package, v.version),
+            package: v.package,
+            version: v.version,
+            files,
+            removed,
+        })
+    }
+
+    fn firmware_versions(&self, sysroot_path: &str) -> Result<Vec<PayloadVersion>> {
+        firmware_versions(sysroot_path)
+    }
+
+    fn payload_source(&self, path: &str) -> Option<PathBuf> {
+        // Same as the paths queried in generate_update_metadata
+        Some(Path::new("/").join(PACKAGED_EFI_DIR).join(path))
+    }
+
     fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
         get_component_update(sysroot, self)
     }
 
-    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+    fn diff_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Option<Vec<filetree::FileChange>>> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let Some(updated) = sysroot.sub_dir_optional(&component_updatedirname(self))? else {
+            return Ok(Some(Vec::new()));
+        };
+        let updatef = crate::treecache::payload_tree(sysroot, self, &updated)
+            .context("reading update dir")?;
+        let vendors = current
+            .vendors
+            .clone()
+            .unwrap_or_else(|| currentf.split_toplevel());
+        let plan = plan_update(vendors, &updatef)?;
+        let changes = plan
+            .diffs
+            .iter()
+            .flat_map(|(_, diff)| diff.describe(currentf, &updatef))
+            .collect();
+        Ok(Some(changes))
+    }
+
+    fn status_warnings(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        let Some(currentf) = current.filetree.as_ref() else {
+            return Ok(Vec::new());
+        };
+        let pending = crate::dbx::read_pending(&sysroot.recover_path()?)?;
+        if pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        let Some(esp) = self.open_esp_optional()? else {
+            return Ok(Vec::new());
+        };
+        let r = revoked_images(&esp, currentf, &pending)?
+            .into_iter()
+            .map(|p| format!("{p} would be revoked by the pending dbx update; update first"))
+            .collect();
+        Ok(r)
+    }
+
+    fn validate(
+        &self,
+        _sysroot: &openat::Dir,
+        current: &InstalledContent,
+        fast: bool,
+    ) -> Result<ValidationResult> {
         if !is_efi_booted()? && self.get_esp_device().is_none() {
             return Ok(ValidationResult::Skip);
         }
@@ -401,15 +2036,7 @@ impl Component for Efi {
             .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
         self.ensure_mounted_esp(Path::new("/"))?;
         let efidir = self.open_esp()?;
-        let diff = currentf.relative_diff_to(&efidir)?;
-        let mut errs = Vec::new();
-        for f in diff.changes.iter() {
-            errs.push(format!("Changed: {}", f));
-        }
-        for f in diff.removals.iter() {
-            errs.push(format!("Removed: {}", f));
-        }
-        assert_eq!(diff.additions.len(), 0);
+        let errs = self.validate_files(&efidir, currentf, fast)?;
         if !errs.is_empty() {
             Ok(ValidationResult::Errors(errs))
         } else {
@@ -417,26 +2044,58 @@ impl Component for Efi {
         }
     }
 
-    fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>> {
+    fn uninstall(&self, _sysroot: &openat::Dir, current: &InstalledContent) -> Result<()> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        self.ensure_mounted_esp(Path::new("/"))?;
+        let destdir = self.open_esp().context("opening EFI dir")?;
+        validate_esp(&destdir)?;
+        filetree::remove_tree(&destdir, currentf).context("removing files")?;
+        Ok(())
+    }
+
+    fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>> {
+        let mut vendors = self.get_efi_vendors(sysroot)?;
+        // Callers wanting a single vendor can't handle more than one shim
+        if vendors.len() > 1 {
+            anyhow::bail!(
+                "Found shims in multiple vendor directories in the image; use --efi-vendor to pick one"
+            );
+        }
+        Ok(vendors.pop())
+    }
+
+    fn get_efi_vendors(&self, sysroot: &openat::Dir) -> Result<Vec<String>> {
         let updated = sysroot
             .sub_dir(&component_updatedirname(self))
             .context("opening update dir")?;
-        let shim_files = find_file_recursive(updated.recover_path()?, SHIM)?;
-
-        // Does not support multiple shim for efi
-        if shim_files.len() > 1 {
-            anyhow::bail!("Found multiple {SHIM} in the image");
-        }
-        if let Some(p) = shim_files.first() {
-            let p = p
-                .parent()
-                .unwrap()
-                .file_name()
-                .ok_or_else(|| anyhow::anyhow!("No file name found"))?;
-            Ok(Some(p.to_string_lossy().into_owned()))
-        } else {
-            anyhow::bail!("Failed to find {SHIM} in the image")
+        if let Some(vendor) = crate::config::get().efi_vendor.as_deref() {
+            if !updated.exists(vendor)? {
+                anyhow::bail!("Configured EFI vendor directory {vendor} not found in the image");
+            }
+            return Ok(vec![vendor.to_string()]);
         }
+        let mut shim_files = Vec::new();
+        for shim in SHIMS {
+            shim_files.extend(find_file_recursive(updated.recover_path()?, shim)?);
+        }
+        if shim_files.is_empty() {
+            anyhow::bail!("Failed to find {} in the image", SHIMS.join(" or "))
+        }
+        let mut vendors = shim_files
+            .iter()
+            .map(|p| {
+                p.parent()
+                    .and_then(|p| p.file_name())
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .ok_or_else(|| anyhow::anyhow!("No file name found"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        vendors.sort();
+        vendors.dedup();
+        Ok(vendors)
     }
 }
 
@@ -447,7 +2106,22 @@ impl Drop for Efi {
     }
 }
 
-fn validate_esp(dir: &openat::Dir) -> Result<()> {
+/// The shim and GRUB binaries in the `EFI` directory `esp` of the ESP, as
+/// paths under `efidir`, where it is mounted, for the rpm database.
+fn packaged_esp_files(esp: &openat::Dir, efidir: &Path) -> Result<Vec<String>> {
+    let mut r: Vec<_> = util::filenames(esp)?
+        .into_iter()
+        .filter(|f| {
+            let name = f.rsplit('/').next().unwrap_or(f);
+            SHIMS.contains(&name) || (name.starts_with("grub") && name.ends_with(".efi"))
+        })
+        .map(|f| efidir.join(f.trim_start_matches('/')).display().to_string())
+        .collect();
+    r.sort();
+    Ok(r)
+}
+
+pub(crate) fn validate_esp(dir: &openat::Dir) -> Result<()> {
     let dir = unsafe { BorrowedFd::borrow_raw(dir.as_raw_fd()) };
     let stat = rustix::fs::fstatfs(&dir)?;
     if stat.f_type != libc::MSDOS_SUPER_MAGIC {
@@ -459,6 +2133,179 @@ fn validate_esp(dir: &openat::Dir) -> Result<()> {
     Ok(())
 }
 
+/// The value of `key` (e.g. `BootCurrent`) in the output of `efibootmgr`.
+fn efibootmgr_value<'a>(output: &'a str, key: &str) -> Option<&'a str> {
+    output.lines().find_map(|l| {
+        let (k, v) = l.split_once(':')?;
+        (k == key).then(|| v.trim())
+    })
+}
+
+/// Where a boot entry loads from, parsed from the textual device path
+/// `efibootmgr -v` shows, e.g.
+/// `HD(1,GPT,94ff4025-...,0x800,0x12c000)/\EFI\fedora\shimx64.efi`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DevicePath {
+    /// The PARTUUID of the partition, lowercase; for MBR partitions, as
+    /// Linux forms it from the disk signature and partition number, e.g.
+    /// `1234abcd-01`
+    partuuid: Option<String>,
+    /// The file on it, e.g. `\EFI\fedora\shimx64.efi`
+    file: Option<String>,
+}
+
+impl DevicePath {
+    /// Parse the device path `s`; nodes other than the partition and the
+    /// file, and anything after the path (the optional data), are ignored.
+    fn parse(s: &str) -> Self {
+        let mut r = DevicePath::default();
+        let mut depth = 0;
+        let mut start = 0;
+        let mut nodes = Vec::new();
+        for (i, c) in s.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                '/' if depth == 0 => {
+                    nodes.push(&s[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+            // The optional data directly follows the last node
+            if depth == 0 && c == ')' && !s[i + 1..].starts_with('/') {
+                nodes.push(&s[start..=i]);
+                start = s.len();
+                break;
+            }
+        }
+        if start < s.len() {
+            // A file path node without `File()`, up to the optional data
+            let rest = &s[start..];
+            nodes.push(rest.split(char::is_whitespace).next().unwrap_or_default());
+        }
+        for node in nodes {
+            if let Some(hd) = node.strip_prefix("HD(") {
+                let mut fields = hd.trim_end_matches(')').split(',');
+                r.partuuid = match (fields.next(), fields.next(), fields.next()) {
+                    (Some(_), Some("GPT"), Some(partuuid)) => Some(partuuid.to_ascii_lowercase()),
+                    (Some(n), Some("MBR"), Some(sig)) => mbr_partuuid(n, sig),
+                    _ => None,
+                };
+            } else if let Some(f) = node
+                .strip_prefix("File(")
+                .map(|f| f.trim_end_matches(')'))
+                .or_else(|| node.starts_with('\\').then_some(node))
+            {
+                // Paths may be split across several nodes
+                r.file.get_or_insert_with(String::new).push_str(f);
+            }
+        }
+        r
+    }
+
+    /// Whether the file is in the vendor directory `vendordir` of the ESP.
+    fn in_vendor_dir(&self, vendordir: &str) -> bool {
+        let prefix = format!("\\efi\\{vendordir}\\").to_ascii_lowercase();
+        self.file
+            .as_deref()
+            .is_some_and(|f| f.to_ascii_lowercase().starts_with(&prefix))
+    }
+
+    /// Whether the file is in the vendor directory `vendordir` of the ESP
+    /// with PARTUUID `partuuid`, i.e. an entry we created for this ESP.
+    /// Without the PARTUUID of the ESP, any ESP matches.
+    fn is_ours(&self, vendordir: &str, partuuid: Option<&str>) -> bool {
+        let same_esp = match partuuid {
+            Some(partuuid) => self
+                .partuuid
+                .as_deref()
+                .is_some_and(|p| p.eq_ignore_ascii_case(partuuid)),
+            None => true,
+        };
+        same_esp && self.in_vendor_dir(vendordir)
+    }
+}
+
+/// The PARTUUID Linux gives the MBR partition number `n` (decimal) on the
+/// disk with signature `sig` (e.g. `0x1234abcd`).
+fn mbr_partuuid(n: &str, sig: &str) -> Option<String> {
+    let n: u32 = n.parse().ok()?;
+    let sig = u32::from_str_radix(sig.trim_start_matches("0x"), 16).ok()?;
+    Some(format!("{sig:08x}-{n:02x}"))
+}
+
+/// The device path of each boot entry in the output of `efibootmgr -v`, by
+/// boot number.
+fn boot_entry_device_paths(output: &str) -> BTreeMap<String, DevicePath> {
+    let mut r = BTreeMap::new();
+    for line in output.lines().filter_map(|line| line.strip_prefix("Boot")) {
+        let Some((id, rest)) = line.split_once(|c: char| c == '*' || c.is_whitespace()) else {
+            continue;
+        };
+        if id.len() != 4 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+        // The label is separated from the device path by a tab
+        let Some((_, path)) = rest.split_once('\t') else {
+            continue;
+        };
+        r.insert(id.to_string(), DevicePath::parse(path.trim()));
+    }
+    r
+}
+
+/// The PARTUUID of the GPT partition which each boot entry in the output of
+/// `efibootmgr -v` loads from, by boot number.
+fn boot_entry_partuuids(output: &str) -> BTreeMap<String, String> {
+    boot_entry_device_paths(output)
+        .into_iter()
+        .filter_map(|(id, p)| Some((id, p.partuuid?)))
+        .collect()
+}
+
+/// The PARTUUID of the ESP the firmware booted from, given the output of
+/// `efibootmgr -v`.
+fn booted_partuuid(output: &str) -> Option<String> {
+    let current = efibootmgr_value(output, "BootCurrent")?;
+    boot_entry_partuuids(output).remove(current)
+}
+
+/// The boot order `order` with `first` moved to the front.
+fn boot_order_with_first(order: &str, first: &str) -> String {
+    std::iter::once(first)
+        .chain(
+            order
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty() && *id != first),
+        )
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Make the firmware try the boot entry loading from the ESP `partuuid`
+/// first, given the output of `efibootmgr -v`.
+#[context("Updating BootOrder")]
+fn set_first_boot_entry(nvram: &str, partuuid: &str) -> Result<()> {
+    let partuuid = partuuid.to_ascii_lowercase();
+    let Some(id) = boot_entry_partuuids(nvram)
+        .into_iter()
+        .find_map(|(id, p)| (p == partuuid).then_some(id))
+    else {
+        bail!("No EFI boot entry loads from the ESP {partuuid}");
+    };
+    let order = efibootmgr_value(nvram, "BootOrder").unwrap_or_default();
+    let order = boot_order_with_first(order, &id);
+    log::info!("Setting BootOrder to {order}");
+    crate::retry::retry("Setting BootOrder", || {
+        Command::new(EFIBOOTMGR)
+            .args(["--bootorder", order.as_str()])
+            .run()
+            .with_context(|| format!("Failed to invoke {EFIBOOTMGR}"))
+    })
+}
+
 #[derive(Debug, PartialEq)]
 struct BootEntry {
     id: String,
@@ -487,24 +2334,193 @@ fn parse_boot_entries(output: &str) -> Vec<BootEntry> {
     entries
 }
 
-#[context("Clearing EFI boot entries that match target {target}")]
-pub(crate) fn clear_efi_target(target: &str) -> Result<()> {
-    let target = target.to_lowercase();
-    let output = Command::new(EFIBOOTMGR).output()?;
+/// The output of `efibootmgr -v`, listing the boot entries.
+fn list_boot_entries() -> Result<String> {
+    let output = Command::new(EFIBOOTMGR).arg("-v").output()?;
     if !output.status.success() {
-        anyhow::bail!("Failed to invoke {EFIBOOTMGR}")
+        bail!("Failed to invoke {EFIBOOTMGR}")
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Delete the boot entry `id`.  A failed attempt may still have deleted
+/// it, so before retrying we check whether it's still there.
+fn delete_boot_entry(id: &str) -> Result<()> {
+    let mut attempted = false;
+    crate::retry::retry("Deleting EFI boot entry", || {
+        if std::mem::replace(&mut attempted, true)
+            && !parse_boot_entries(&list_boot_entries()?)
+                .iter()
+                .any(|e| e.id == id)
+        {
+            log::debug!("Boot{id} was deleted by the failed attempt");
+            return Ok(());
+        }
+        efibootmgr_write(&["-b", id, "-B"])
+    })
+}
+
+/// Whether the boot entries in the output of `efibootmgr -v` include one
+/// labeled `label` which loads `loader` from the ESP `partuuid`.
+fn has_boot_entry(nvram: &str, label: &str, partuuid: Option<&str>, loader: &str) -> bool {
+    let paths = boot_entry_device_paths(nvram);
+    parse_boot_entries(nvram).iter().any(|e| {
+        e.name == label
+            && paths.get(&e.id).is_some_and(|p| {
+                p.file
+                    .as_deref()
+                    .is_some_and(|f| f.eq_ignore_ascii_case(loader))
+                    && match partuuid {
+                        Some(partuuid) => p
+                            .partuuid
+                            .as_deref()
+                            .is_some_and(|u| u.eq_ignore_ascii_case(partuuid)),
+                        None => true,
+                    }
+            })
+    })
+}
+
+/// A boot entry removed by `bootupctl bootorder gc`, and why.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct StaleBootEntry {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) reason: String,
+}
+
+/// The stale boot entries in the output of `efibootmgr -v`: those loading
+/// the same file from the same partition as one before them in BootOrder,
+/// and those loading a file which `exists` says is missing.  `exists` gets
+/// the PARTUUID and the file, and returns `None` if it can't tell, e.g. for
+/// partitions on other disks.  Entries which don't load a file from a
+/// partition, like the firmware's own, are never stale.
+fn stale_boot_entries(
+    output: &str,
+    exists: impl Fn(&str, &str) -> Option<bool>,
+) -> Vec<StaleBootEntry> {
+    let paths = boot_entry_device_paths(output);
+    let mut entries = parse_boot_entries(output);
+    // Entries missing from BootOrder go last
+    let order = efibootmgr_value(output, "BootOrder")
+        .unwrap_or_default()
+        .split(',')
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| order.iter().position(|&o| o == e.id).unwrap_or(order.len()));
+    let mut seen = BTreeMap::new();
+    let mut r = Vec::new();
+    for entry in entries {
+        let Some(DevicePath {
+            partuuid: Some(partuuid),
+            file: Some(file),
+        }) = paths.get(&entry.id)
+        else {
+            continue;
+        };
+        let key = (partuuid.clone(), file.to_ascii_lowercase());
+        let reason = if let Some(kept) = seen.get(&key) {
+            format!("duplicate of Boot{kept}")
+        } else if exists(partuuid, file) == Some(false) {
+            format!("{file} doesn't exist on the ESP {partuuid}")
+        } else {
+            seen.insert(key, entry.id);
+            continue;
+        };
+        r.push(StaleBootEntry {
+            id: entry.id,
+            name: entry.name,
+            reason,
+        });
+    }
+    r
+}
+
+/// The messages efibootmgr reports (`strerror()`) when efivarfs refuses
+/// to write a variable: `EROFS`, and `EPERM` or `EACCES` for variables
+/// which are immutable or protected by the firmware.
+const NVRAM_READONLY_ERRORS: &[&str] = &[
+    "Read-only file system",
+    "Operation not permitted",
+    "Permission denied",
+];
+
+/// efibootmgr failing as the EFI variables can't be written on this
+/// system at all, unlike other failures such as a wrong device.
+#[derive(Debug)]
+struct NvramReadOnly(String);
+
+impl NvramReadOnly {
+    fn is_cause_of(e: &anyhow::Error) -> bool {
+        e.chain().any(|e| e.is::<Self>())
+    }
+}
+
+impl std::fmt::Display for NvramReadOnly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{EFIBOOTMGR}: {}", self.0)
+    }
+}
+
+impl std::error::Error for NvramReadOnly {}
+
+/// Run efibootmgr with `args` to modify the EFI variables.
+fn efibootmgr_write(args: &[&str]) -> Result<()> {
+    let o = Command::new(EFIBOOTMGR)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to invoke {EFIBOOTMGR}"))?;
+    if o.status.success() {
+        return Ok(());
+    }
+    Err(efibootmgr_error(
+        &o.status,
+        &String::from_utf8_lossy(&o.stderr),
+    ))
+}
+
+/// The error for efibootmgr exiting with `status`, having printed `stderr`.
+fn efibootmgr_error(status: &std::process::ExitStatus, stderr: &str) -> anyhow::Error {
+    let stderr = stderr.trim();
+    if NVRAM_READONLY_ERRORS.iter().any(|m| stderr.contains(m)) {
+        return NvramReadOnly(stderr.to_string()).into();
     }
+    anyhow::anyhow!("Failed to invoke {EFIBOOTMGR} ({status}): {stderr}")
+}
 
-    let output = String::from_utf8(output.stdout)?;
+/// Delete the boot entries labeled `target` which load a file from the
+/// vendor directory `vendordir` on the ESP with PARTUUID `partuuid` (if
+/// known); those of other systems which happen to have the same label,
+/// e.g. another install of the same distribution on a second disk, are
+/// kept unless `force-label-match` is set.
+#[context("Clearing EFI boot entries that match target {target}")]
+pub(crate) fn clear_efi_target(
+    target: &str,
+    vendordir: &str,
+    partuuid: Option<&str>,
+) -> Result<()> {
+    let target = target.to_lowercase();
+    let output = crate::retry::retry("Listing EFI boot entries", list_boot_entries)?;
     let boot_entries = parse_boot_entries(&output);
+    let paths = boot_entry_device_paths(&output);
+    let force_label_match = crate::config::get().force_label_match;
     for entry in boot_entries {
-        if entry.name.to_lowercase() == target {
-            log::debug!("Deleting matched target {:?}", entry);
-            Command::new(EFIBOOTMGR)
-                .args(["-b", entry.id.as_str(), "-B"])
-                .run()
-                .with_context(|| format!("Failed to invoke {EFIBOOTMGR}"))?;
+        if entry.name.to_lowercase() != target {
+            continue;
         }
+        let path = paths.get(&entry.id);
+        if !force_label_match && !path.is_some_and(|p| p.is_ours(vendordir, partuuid)) {
+            log::info!(
+                "Keeping EFI boot entry Boot{} {:?} which loads {} from {}",
+                entry.id,
+                entry.name,
+                path.and_then(|p| p.file.as_deref()).unwrap_or("no file"),
+                path.and_then(|p| p.partuuid.as_deref())
+                    .unwrap_or("another device")
+            );
+            continue;
+        }
+        log::debug!("Deleting matched target {:?}", entry);
+        delete_boot_entry(&entry.id)?;
     }
 
     anyhow::Ok(())
@@ -516,9 +2532,9 @@ pub(crate) fn create_efi_boot_entry(
     espdir: &openat::Dir,
     vendordir: &str,
     target: &str,
+    partuuid: Option<&str>,
 ) -> Result<()> {
-    let fsinfo = crate::filesystem::inspect_filesystem(espdir, ".")?;
-    let source = fsinfo.source;
+    let source = crate::filesystem::inspect_filesystem(espdir, ".")?.source;
     let devname = source
         .rsplit_once('/')
         .ok_or_else(|| anyhow::anyhow!("Failed to parse {source}"))?
@@ -526,14 +2542,24 @@ pub(crate) fn create_efi_boot_entry(
     let partition_path = format!("/sys/class/block/{devname}/partition");
     let partition_number = std::fs::read_to_string(&partition_path)
         .with_context(|| format!("Failed to read {partition_path}"))?;
-    let shim = format!("{vendordir}/{SHIM}");
-    if espdir.exists(&shim)? {
-        anyhow::bail!("Failed to find {SHIM}");
+    // The firmware can only load a shim of its own architecture
+    let shim = firmware_files().shim;
+    if !espdir.exists(format!("EFI/{vendordir}/{shim}").as_str())? {
+        anyhow::bail!("Failed to find EFI/{vendordir}/{shim}");
     }
-    let loader = format!("\\EFI\\{}\\{SHIM}", vendordir);
+    let loader = format!("\\EFI\\{}\\{shim}", vendordir);
     log::debug!("Creating new EFI boot entry using '{target}'");
-    let st = Command::new(EFIBOOTMGR)
-        .args([
+    let mut attempted = false;
+    crate::retry::retry("Creating EFI boot entry", || {
+        // A failed attempt may still have written the entry, e.g. if only
+        // updating BootOrder failed; retrying blindly would add another
+        if std::mem::replace(&mut attempted, true)
+            && has_boot_entry(&list_boot_entries()?, target, partuuid, &loader)
+        {
+            log::debug!("The EFI boot entry was created by the failed attempt");
+            return Ok(());
+        }
+        efibootmgr_write(&[
             "--create",
             "--disk",
             device,
@@ -544,11 +2570,7 @@ pub(crate) fn create_efi_boot_entry(
             "--label",
             target,
         ])
-        .status()?;
-    if !st.success() {
-        anyhow::bail!("Failed to invoke {EFIBOOTMGR}")
-    }
-    anyhow::Ok(())
+    })
 }
 
 #[context("Find target file recursively")]
@@ -575,6 +2597,415 @@ mod tests {
     use cap_std_ext::dirext::CapStdExtDirExt;
 
     use super::*;
+    use crate::testutils::write_tree;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_check_boot_chain() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let esp = td.path().join("EFI");
+        let boot = td.path().join("boot");
+        for d in [esp.join("fedora"), esp.join("BOOT"), boot.join("grub2")] {
+            std::fs::create_dir_all(d)?;
+        }
+        std::fs::write(esp.join("fedora/shimx64.efi"), "shim")?;
+        let espd = openat::Dir::open(&esp)?;
+        let nvram = "Boot0001* Fedora\tHD(1,GPT,...)/File(\\EFI\\FEDORA\\SHIMX64.EFI)\n";
+        let errs = check_boot_chain(&espd, &boot, &["fedora"], Some(nvram))?;
+        assert_eq!(
+            errs,
+            [
+                "Boot chain: EFI/fedora/grub.cfg is missing".to_string(),
+                format!("Boot chain: {}/grub2/grub.cfg is missing", boot.display()),
+                format!(
+                    "Boot chain: No boot loader entries in {}/loader/entries",
+                    boot.display()
+                ),
+            ]
+        );
+
+        std::fs::write(esp.join("fedora/grub.cfg"), "")?;
+        std::fs::write(boot.join("grub2/grub.cfg"), "")?;
+        std::fs::create_dir_all(boot.join("loader/entries"))?;
+        std::fs::write(boot.join("loader/entries/fedora.conf"), "")?;
+        assert!(check_boot_chain(&espd, &boot, &["fedora"], Some(nvram))?.is_empty());
+        // Without a matching NVRAM entry or the fallback path
+        assert_eq!(
+            check_boot_chain(&espd, &boot, &["fedora"], Some("BootOrder: 0001"))?,
+            ["Boot chain: No NVRAM boot entry loads shimx64.efi, and EFI/BOOT/BOOTX64.EFI is missing"]
+        );
+        // The fallback path needs a CSV
+        std::fs::write(esp.join("BOOT/BOOTX64.EFI"), "shim")?;
+        assert_eq!(
+            check_boot_chain(&espd, &boot, &["fedora"], Some("BootOrder: 0001"))?,
+            ["Boot chain: EFI/BOOT/BOOTX64.EFI exists, but no vendor directory has BOOTX64.CSV"]
+        );
+        std::fs::write(esp.join("fedora/BOOTX64.CSV"), "")?;
+        assert_eq!(
+            check_boot_chain(&espd, &boot, &["fedora"], None)?,
+            ["Boot chain: EFI/fedora/BOOTX64.CSV has no entries"]
+        );
+        std::fs::write(
+            esp.join("fedora/BOOTX64.CSV"),
+            fallback_csv("grubx64.efi", "Fedora"),
+        )?;
+        assert_eq!(
+            check_boot_chain(&espd, &boot, &["fedora"], None)?,
+            ["Boot chain: EFI/fedora/BOOTX64.CSV refers to missing grubx64.efi"]
+        );
+        std::fs::write(
+            esp.join("fedora/BOOTX64.CSV"),
+            fallback_csv("shimx64.efi", "Fedora"),
+        )?;
+        assert!(check_boot_chain(&espd, &boot, &["fedora"], None)?.is_empty());
+        // A vendor directory without shim
+        assert_eq!(
+            check_boot_chain(&espd, &boot, &["fedora", "centos"], None)?,
+            ["Boot chain: EFI/centos/shimx64.efi is missing"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_update() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let current = write_tree(
+            &td.path().join("current"),
+            &[
+                ("BOOT/BOOTX64.EFI", "boot v1"),
+                ("fedora/shimx64.efi", "shim v1"),
+                ("fedora/grubx64.efi", "grub v1"),
+                ("fedora/mmx64.efi", "mm v1"),
+                ("centos/shimx64.efi", "shim v1"),
+            ],
+        )?;
+        let current = filetree::FileTree::new_from_dir(&current)?;
+        let update = write_tree(
+            &td.path().join("update"),
+            &[
+                ("BOOT/BOOTX64.EFI", "boot v2"),
+                ("fedora/shimx64.efi", "shim v1"),
+                ("fedora/grubx64.efi", "grub v1"),
+                ("rhel/shimx64.efi", "shim v1"),
+            ],
+        )?;
+        let update = filetree::FileTree::new_from_dir(&update)?;
+        let plan = plan_update(current.split_toplevel(), &update)?;
+        let diffs: BTreeMap<_, _> = plan.diffs.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        // Unchanged content is skipped entirely
+        assert_eq!(
+            diffs.keys().copied().collect::<Vec<_>>(),
+            ["BOOT", "fedora", "rhel"]
+        );
+        // Updated
+        let boot = diffs["BOOT"];
+        assert!(boot.changes.contains("BOOT/BOOTX64.EFI"));
+        assert_eq!((boot.additions.len(), boot.removals.len()), (0, 0));
+        // Removed
+        let fedora = diffs["fedora"];
+        assert!(fedora.removals.contains("fedora/mmx64.efi"));
+        assert_eq!((fedora.additions.len(), fedora.changes.len()), (0, 0));
+        // Added
+        let rhel = diffs["rhel"];
+        assert!(rhel.additions.contains("rhel/shimx64.efi"));
+        assert_eq!((rhel.changes.len(), rhel.removals.len()), (0, 0));
+        // Vendors no longer shipped are kept as they were
+        assert_eq!(plan.retained, ["centos"]);
+        assert_eq!(
+            plan.vendors.keys().collect::<Vec<_>>(),
+            ["BOOT", "centos", "fedora", "rhel"]
+        );
+        assert_eq!(plan.vendors["centos"], current.split_toplevel()["centos"]);
+        assert_eq!(plan.vendors["rhel"], update.split_toplevel()["rhel"]);
+
+        // And nothing to do if already up to date
+        let plan = plan_update(plan.vendors, &update)?;
+        assert!(plan.diffs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resync_esps() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let installed = [
+            ("fedora/shimx64.efi", "shim v2"),
+            ("fedora/grubx64.efi", "grub v2"),
+        ];
+        // The second ESP missed part of the last update
+        let esps = [
+            &installed[..],
+            &[
+                ("fedora/shimx64.efi", "shim v1"),
+                ("fedora/grubx64.efi", "grub v2"),
+                ("fedora/extra.efi", "extra"),
+            ],
+            &[("fedora/shimx64.efi", "shim v2")],
+        ];
+        let mut dirs = Vec::new();
+        for (i, files) in esps.iter().enumerate() {
+            let esp = write_tree(&td.path().join(format!("esp{i}")), files)?;
+            dirs.push((format!("esp{i}"), esp));
+        }
+        assert_eq!(
+            compare_esp_dirs(&dirs)?,
+            [
+                "Differs on esp1: fedora/shimx64.efi",
+                "Only on esp1: fedora/extra.efi",
+                "Missing on esp2: fedora/grubx64.efi",
+            ]
+        );
+
+        let tree = filetree::FileTree::new_from_dir(&write_tree(
+            &td.path().join("installed"),
+            &installed,
+        )?)?;
+        let (_, dirs): (Vec<_>, Vec<_>) = dirs.into_iter().unzip();
+        assert_eq!(resync_esp_dirs(&tree, &dirs)?, [1, 2]);
+        for i in 1..3 {
+            let esp = td.path().join(format!("esp{i}"));
+            assert_eq!(
+                std::fs::read_to_string(esp.join("fedora/shimx64.efi"))?,
+                "shim v2"
+            );
+            assert_eq!(
+                std::fs::read_to_string(esp.join("fedora/grubx64.efi"))?,
+                "grub v2"
+            );
+        }
+        // Files we don't manage are left alone
+        assert!(td.path().join("esp1/fedora/extra.efi").exists());
+        assert!(resync_esp_dirs(&tree, &dirs)?.is_empty());
+
+        // Nothing to copy from
+        std::fs::write(td.path().join("esp0/fedora/shimx64.efi"), "corrupt")?;
+        std::fs::write(td.path().join("esp1/fedora/shimx64.efi"), "corrupt")?;
+        std::fs::remove_file(td.path().join("esp2/fedora/shimx64.efi"))?;
+        assert!(resync_esp_dirs(&tree, &dirs).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_packaged_esp_files() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let esp = write_tree(
+            td.path(),
+            &[
+                (format!("fedora/{SHIM}"), ""),
+                ("fedora/grubx64.efi".to_string(), ""),
+                ("fedora/grub.cfg".to_string(), ""),
+                ("fedora/mmx64.efi".to_string(), ""),
+                ("BOOT/fbx64.efi".to_string(), ""),
+            ],
+        )?;
+        let files = packaged_esp_files(&esp, Path::new("/boot/efi/EFI"))?;
+        assert_eq!(
+            files,
+            [
+                "/boot/efi/EFI/fedora/grubx64.efi".to_string(),
+                format!("/boot/efi/EFI/fedora/{SHIM}"),
+            ]
+        );
+        // E.g. with `esp-mount` set to `efi`
+        let files = packaged_esp_files(&esp, Path::new("/efi/EFI"))?;
+        assert_eq!(files[0], "/efi/EFI/fedora/grubx64.efi");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_fallback_csv() -> Result<()> {
+        let csv = fallback_csv("shimx64.efi", "Fedora Linux");
+        assert_eq!(&csv[..4], &[0xff, 0xfe, b's', 0]);
+        assert_eq!(
+            parse_fallback_csv(&csv)?,
+            [("shimx64.efi".to_string(), "Fedora Linux".to_string())]
+        );
+        // As shipped by distributions, without a BOM and with CRLF
+        let utf16 = |s: &str| {
+            s.encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>()
+        };
+        let entries = parse_fallback_csv(&utf16("shimx64.efi,Red Hat,,desc\r\n\r\n"))?;
+        assert_eq!(
+            entries,
+            [("shimx64.efi".to_string(), "Red Hat".to_string())]
+        );
+        assert!(parse_fallback_csv(&utf16("shimx64.efi\n")).is_err());
+        assert!(parse_fallback_csv(b"shimx64.efi,x\n").is_err());
+        assert!(parse_fallback_csv(&[])?.is_empty());
+
+        let td = tempfile::tempdir()?;
+        let stock = fallback_csv("shimx64.efi", "Fedora");
+        let efidir = write_tree(
+            td.path(),
+            &[
+                ("fedora/shimx64.efi", &b"shim"[..]),
+                ("fedora/shimia32.efi", &b"shim32"[..]),
+                ("fedora/BOOTX64.CSV", &stock[..]),
+                ("fedora/BOOTIA32.CSV", &stock[..]),
+                ("fedora/BOOT.CSV", &stock[..]),
+                ("other/BOOTX64.CSV", &stock[..]),
+            ],
+        )?;
+        let mut tree = filetree::FileTree::new_from_dir(&efidir)?;
+        let orig = tree.clone();
+        write_fallback_csvs(&efidir, &mut tree, "Fedora")?;
+        // BOOTIA32.CSV points to the wrong shim
+        let read = |p: &str| std::fs::read(td.path().join(p));
+        assert_eq!(
+            read("fedora/BOOTIA32.CSV")?,
+            fallback_csv("shimia32.efi", "Fedora")
+        );
+        assert_ne!(tree, orig);
+        assert_eq!(tree, filetree::FileTree::new_from_dir(&efidir)?);
+
+        write_fallback_csvs(&efidir, &mut tree, "Fedora CoreOS")?;
+        for p in ["fedora/BOOTX64.CSV", "fedora/BOOT.CSV"] {
+            assert_eq!(read(p)?, fallback_csv("shimx64.efi", "Fedora CoreOS"));
+        }
+        // No shim next to it
+        assert_eq!(read("other/BOOTX64.CSV")?, stock);
+        assert_eq!(tree, filetree::FileTree::new_from_dir(&efidir)?);
+
+        // Unchanged CSVs aren't rewritten; the label is that of the OS in
+        // the root given
+        use std::os::unix::fs::MetadataExt;
+        let csvs = ["fedora/BOOTX64.CSV", "fedora/BOOTIA32.CSV"];
+        let inodes = || csvs.map(|p| td.path().join(p).metadata().unwrap().ino());
+        let before = inodes();
+        let root = tempfile::tempdir()?;
+        write_tree(
+            root.path(),
+            &[("etc/system-release", "Fedora CoreOS release 41\n")],
+        )?;
+        regenerate_fallback_csvs(root.path(), &efidir, &mut tree)?;
+        assert_eq!(inodes(), before);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_multiarch_shims() -> Result<()> {
+        assert_eq!(firmware_files_for(Some("32\n")), IA32_FILES);
+        assert_eq!(firmware_files_for(Some("64\n")), NATIVE_FILES);
+        assert_eq!(firmware_files_for(None), NATIVE_FILES);
+
+        let td = tempfile::tempdir()?;
+        let updates = td.path().join("usr/lib/bootupd/updates/EFI");
+        let updatesd = write_tree(
+            &updates,
+            &[
+                ("fedora/shimx64.efi", ""),
+                ("fedora/shimia32.efi", ""),
+                ("legacy/shimia32.efi", ""),
+            ],
+        )?;
+        let sysroot = openat::Dir::open(td.path())?;
+        let vendors = Efi::default().get_efi_vendors(&sysroot)?;
+        assert_eq!(vendors, ["fedora", "legacy"]);
+
+        let files = packaged_esp_files(&updatesd, Path::new("/boot/efi/EFI"))?;
+        assert_eq!(
+            files,
+            [
+                "/boot/efi/EFI/fedora/shimia32.efi",
+                "/boot/efi/EFI/fedora/shimx64.efi",
+                "/boot/efi/EFI/legacy/shimia32.efi",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_extend_payload() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path().to_str().unwrap();
+        let src = td.path().join("src/fwupd");
+        std::fs::create_dir_all(&src)?;
+        std::fs::write(src.join("fwupdx64.efi"), "fw")?;
+
+        let efi = Efi::default();
+        let r = efi.extend_payload(sysroot, &src, Some("fwupd-efi"), Some("1.10-1"), 1)?;
+        assert_eq!(r.path, "usr/lib/efi/firmware/fwupd-efi/1.10-1");
+        assert_eq!(r.files, ["fwupd/fwupdx64.efi"]);
+        let r = efi.extend_payload(sysroot, &src, Some("fwupd-efi"), Some("1.11-1"), 1)?;
+        assert_eq!(r.removed, ["1.10-1"]);
+        assert_eq!(
+            firmware_payloads(sysroot)?,
+            [PayloadVersion::new("fwupd-efi", "1.11-1")?]
+        );
+
+        // Older versions are kept, and the newest is used
+        for ver in ["1.9-1", "1.12~rc1-1"] {
+            efi.extend_payload(sysroot, &src, Some("fwupd-efi"), Some(ver), 3)?;
+        }
+        efi.extend_payload(sysroot, &src, Some("uboot"), Some("2024.10"), 3)?;
+        let versions = |pkg: &str| -> Result<Vec<String>> {
+            Ok(firmware_versions(sysroot)?
+                .into_iter()
+                .filter(|v| v.package == pkg)
+                .map(|v| v.version)
+                .collect())
+        };
+        assert_eq!(versions("fwupd-efi")?, ["1.9-1", "1.11-1", "1.12~rc1-1"]);
+        assert_eq!(
+            firmware_payloads(sysroot)?,
+            [
+                PayloadVersion::new("fwupd-efi", "1.12~rc1-1")?,
+                PayloadVersion::new("uboot", "2024.10")?
+            ]
+        );
+        let r = efi.extend_payload(sysroot, &src, Some("fwupd-efi"), Some("1.12-1"), 2)?;
+        assert_eq!(r.removed, ["1.9-1", "1.11-1"]);
+        assert_eq!(versions("fwupd-efi")?, ["1.12~rc1-1", "1.12-1"]);
+
+        assert!(efi
+            .extend_payload(sysroot, &src, Some("../x"), Some("1"), 1)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_metadata_reproducible() -> Result<()> {
+        let buildtime = chrono::DateTime::from_timestamp(1657222566, 0).unwrap();
+        let shipped = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1700000000);
+        let efi = Efi::default();
+        // Build the same payload twice, as when rebuilding an image
+        let generate = |epoch| -> Result<(ContentMetadata, Vec<u8>)> {
+            let root = crate::testutils::SyntheticSysroot::new()?;
+            let sysroot = root.path().to_str().unwrap();
+            let src = root.path().join("src/fwupd");
+            std::fs::create_dir_all(&src)?;
+            let f = std::fs::File::create(src.join("fwupdx64.efi"))?;
+            f.set_modified(shipped)?;
+            efi.extend_payload(sysroot, &src, Some("fwupd-efi"), Some("1.10-1"), 1)?;
+            let dest = component_updatedir(sysroot, &efi);
+            std::fs::create_dir_all(&dest)?;
+            let pkgs = BTreeMap::from([("shim-x64-15.6-2.x86_64", buildtime)]);
+            let mut meta = packagesystem::package_metadata(pkgs)?;
+            add_firmware_payloads(sysroot, &dest, &mut meta, epoch)?;
+            write_update_metadata(sysroot, &efi, &meta)?;
+            let json = std::fs::read(root.path().join(BOOTUPD_UPDATES_DIR).join("EFI.json"))?;
+            Ok((meta, json))
+        };
+
+        let (meta, json) = generate(None)?;
+        assert_eq!(
+            meta.timestamp,
+            chrono::DateTime::<chrono::Utc>::from(shipped)
+        );
+        assert_eq!(meta.version, "shim-x64-15.6-2.x86_64,fwupd-efi-1.10-1");
+        // Staged at a different time, but bit-identical
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert_eq!(generate(None)?.1, json);
+
+        let epoch = chrono::DateTime::from_timestamp(1710000000, 0).unwrap();
+        let (meta, json) = generate(Some(epoch))?;
+        assert_eq!(meta.timestamp, epoch);
+        assert_eq!(generate(Some(epoch))?.1, json);
+        Ok(())
+    }
 
     #[test]
     fn test_parse_boot_entries() -> Result<()> {
@@ -647,6 +3078,190 @@ Boot0003* test";
         );
         Ok(())
     }
+
+    #[test]
+    fn test_has_boot_entry() {
+        let output = r"
+BootCurrent: 0003
+BootOrder: 0003,0000
+Boot0000* UiApp	FvVol(7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1)/FvFile(462caa21-7614-4503-836e-8ab6f4662331)
+Boot0003* Fedora	HD(2,GPT,94ff4025-5276-4bec-adea-e98da271b64c,0x1000,0x3f800)/\EFI\fedora\shimx64.efi";
+        let uuid = "94FF4025-5276-4BEC-ADEA-E98DA271B64C";
+        let loader = r"\EFI\fedora\shimx64.efi";
+        let uuid = Some(uuid);
+        assert!(has_boot_entry(output, "Fedora", uuid, loader));
+        assert!(has_boot_entry(
+            output,
+            "Fedora",
+            uuid,
+            &loader.to_uppercase()
+        ));
+        assert!(!has_boot_entry(output, "CentOS", uuid, loader));
+        assert!(!has_boot_entry(
+            output,
+            "Fedora",
+            uuid,
+            r"\EFI\fedora\grubx64.efi"
+        ));
+        let other = Some("5b7c1d5a-3d0e-4c3d-8a7e-6f1b0b1e2c3d");
+        assert!(!has_boot_entry(output, "Fedora", other, loader));
+        assert!(has_boot_entry(output, "Fedora", None, loader));
+    }
+
+    #[test]
+    fn test_efibootmgr_error() {
+        use std::os::unix::process::ExitStatusExt;
+        let status = std::process::ExitStatus::from_raw(1 << 8);
+        for (stderr, readonly) in [
+            (
+                "Could not prepare Boot variable: Read-only file system\n",
+                true,
+            ),
+            ("Could not set variable: Operation not permitted\n", true),
+            ("efibootmgr: Could not open disk /dev/nope\n", false),
+            ("", false),
+        ] {
+            let e = efibootmgr_error(&status, stderr).context("Adding new EFI boot entry");
+            assert_eq!(NvramReadOnly::is_cause_of(&e), readonly, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn test_device_path() {
+        let output = r"
+BootCurrent: 0003
+BootOrder: 0003,0004,0005,0000
+Boot0000* UiApp	FvVol(7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1)/FvFile(462caa21-7614-4503-836e-8ab6f4662331)
+Boot0003* Fedora	HD(2,GPT,94FF4025-5276-4bec-adea-e98da271b64c,0x1000,0x3f800)/\EFI\fedora\shimx64.efi
+Boot0004* Windows Boot Manager	HD(1,GPT,0cf5ae6a-2c4a-4b41-9b39-3fd0ae6a7e1b,0x800,0x32000)/File(\EFI\Microsoft\Boot\bootmgfw.efi)WINDOWS.........
+Boot0005* Fedora	HD(1,GPT,5b7c1d5a-3d0e-4c3d-8a7e-6f1b0b1e2c3d,0x800,0x12c000)/\EFI\centos\shimx64.efi";
+        let paths = boot_entry_device_paths(output);
+        assert_eq!(paths.len(), 4);
+        assert_eq!(paths["0000"], DevicePath::default());
+        assert_eq!(
+            paths["0003"],
+            DevicePath {
+                partuuid: Some("94ff4025-5276-4bec-adea-e98da271b64c".into()),
+                file: Some("\\EFI\\fedora\\shimx64.efi".into()),
+            }
+        );
+        assert_eq!(
+            paths["0004"].file.as_deref(),
+            Some("\\EFI\\Microsoft\\Boot\\bootmgfw.efi")
+        );
+        assert!(paths["0003"].in_vendor_dir("fedora"));
+        assert!(paths["0003"].is_ours("fedora", Some("94ff4025-5276-4BEC-adea-e98da271b64c")));
+        // Our vendor directory on another disk
+        assert!(!paths["0003"].is_ours("fedora", Some("5b7c1d5a-3d0e-4c3d-8a7e-6f1b0b1e2c3d")));
+        // Without the PARTUUID of our ESP
+        assert!(paths["0003"].is_ours("fedora", None));
+        assert!(!paths["0005"].is_ours("fedora", None));
+        assert!(!paths["0005"].in_vendor_dir("fedora"));
+        assert!(!paths["0004"].in_vendor_dir("fedora"));
+        assert!(!paths["0000"].in_vendor_dir("fedora"));
+        // A path split across nodes
+        assert_eq!(
+            DevicePath::parse(r"HD(1,MBR,0x1,0x800,0x1000)/File(\EFI)/File(\BOOT\BOOTX64.EFI)"),
+            DevicePath {
+                partuuid: Some("00000001-01".into()),
+                file: Some(r"\EFI\BOOT\BOOTX64.EFI".into()),
+            }
+        );
+        // MBR partitions have the PARTUUID Linux forms for them
+        let path =
+            DevicePath::parse(r"HD(2,MBR,0x1234ABCD,0x800,0x12c000)/\EFI\fedora\shimx64.efi");
+        assert_eq!(path.partuuid.as_deref(), Some("1234abcd-02"));
+        assert!(path.is_ours("fedora", Some("1234ABCD-02")));
+        assert!(!path.is_ours("fedora", Some("1234abcd-01")));
+    }
+
+    #[test]
+    fn test_unmount_reused_esp() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efi = Efi::default();
+        *efi.mountpoint.borrow_mut() = Some(EspMount {
+            path: td.path().to_owned(),
+            owned: false,
+            remounted: false,
+        });
+        assert_eq!(efi.ensure_mounted_esp(Path::new("/"))?, td.path());
+        // Not mounted by us, so left alone; unmounting it would fail here
+        efi.unmount()?;
+        assert!(efi.mountpoint.borrow().is_none());
+
+        *efi.mountpoint.borrow_mut() = Some(EspMount {
+            path: td.path().to_owned(),
+            owned: true,
+            remounted: false,
+        });
+        assert!(efi.unmount().is_err());
+        assert!(efi.mountpoint.borrow().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_boot_entries() {
+        let output = r"
+BootCurrent: 0005
+BootOrder: 0005,0003,0000
+Boot0000* UiApp	FvVol(7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1)/FvFile(462caa21-7614-4503-836e-8ab6f4662331)
+Boot0003* Fedora	HD(2,GPT,94ff4025-5276-4bec-adea-e98da271b64c,0x1000,0x3f800)/\EFI\fedora\shimx64.efi
+Boot0004* Fedora	HD(2,GPT,94ff4025-5276-4bec-adea-e98da271b64c,0x1000,0x3f800)/\EFI\centos\shimx64.efi
+Boot0005* Fedora	HD(2,GPT,94FF4025-5276-4bec-adea-e98da271b64c,0x1000,0x3f800)/\EFI\FEDORA\shimx64.efi
+Boot0006* Other	HD(1,GPT,5b7c1d5a-3d0e-4c3d-8a7e-6f1b0b1e2c3d,0x800,0x12c000)/\EFI\other\shimx64.efi";
+        let stale = stale_boot_entries(output, |partuuid, file| {
+            (partuuid == "94ff4025-5276-4bec-adea-e98da271b64c").then_some(!file.contains("centos"))
+        });
+        assert_eq!(
+            stale,
+            [
+                StaleBootEntry {
+                    id: "0003".into(),
+                    name: "Fedora".into(),
+                    reason: "duplicate of Boot0005".into(),
+                },
+                StaleBootEntry {
+                    id: "0004".into(),
+                    name: "Fedora".into(),
+                    reason: "\\EFI\\centos\\shimx64.efi doesn't exist on the ESP 94ff4025-5276-4bec-adea-e98da271b64c".into(),
+                },
+            ]
+        );
+        assert_eq!(stale_boot_entries(output, |_, _| None).len(), 1);
+    }
+
+    #[test]
+    fn test_booted_partuuid() {
+        let output = r"
+BootCurrent: 0004
+Timeout: 0 seconds
+BootOrder: 0003,0004,0000
+Boot0000* UiApp	FvVol(7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1)/FvFile(462caa21-7614-4503-836e-8ab6f4662331)
+Boot0003* Fedora	HD(2,GPT,94FF4025-5276-4BEC-ADEA-E98DA271B64C,0x1000,0x3f800)/\EFI\fedora\shimx64.efi
+Boot0004* Fedora	HD(2,GPT,0a1b2c3d-5276-4bec-adea-e98da271b64c,0x1000,0x3f800)/\EFI\fedora\shimx64.efi";
+        assert_eq!(
+            efibootmgr_value(output, "BootOrder"),
+            Some("0003,0004,0000")
+        );
+        assert_eq!(
+            booted_partuuid(output).as_deref(),
+            Some("0a1b2c3d-5276-4bec-adea-e98da271b64c")
+        );
+        let partuuids = boot_entry_partuuids(output);
+        assert_eq!(partuuids.len(), 2);
+        assert_eq!(partuuids["0003"], "94ff4025-5276-4bec-adea-e98da271b64c");
+        // Booted from an entry which isn't on a disk
+        let output = output.replace("BootCurrent: 0004", "BootCurrent: 0000");
+        assert_eq!(booted_partuuid(&output), None);
+
+        assert_eq!(
+            boot_order_with_first("0003,0004,0000", "0004"),
+            "0004,0003,0000"
+        );
+        assert_eq!(boot_order_with_first("0003,0000", "0004"), "0004,0003,0000");
+        assert_eq!(boot_order_with_first("", "0004"), "0004");
+    }
+
     #[cfg(test)]
     fn fixture() -> Result<cap_std_ext::cap_tempfile::TempDir> {
         let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
@@ -675,9 +3290,18 @@ Boot0003* test";
             assert_eq!("Red Hat Enterprise Linux CoreOS", name);
         }
         {
-            tmpd.remove_file("etc/system-release")?;
+            tmpd.atomic_write("etc/system-release", "Fedora release 41 (Forty One)\n")?;
             let name = get_product_name(&tmpd)?;
-            assert!(name.len() > 0);
+            assert_eq!("Fedora", name);
+        }
+        {
+            tmpd.remove_file("etc/system-release")?;
+            assert!(get_product_name(&tmpd).is_err());
+            tmpd.create_dir_all("usr/lib")?;
+            tmpd.atomic_write("usr/lib/os-release", "NAME=\"Fedora Linux\"\nID=fedora\n")?;
+            assert_eq!(get_product_name(&tmpd)?, "Fedora Linux");
+            tmpd.atomic_write("etc/os-release", "NAME=\"CentOS Stream\"\n")?;
+            assert_eq!(get_product_name(&tmpd)?, "CentOS Stream");
         }
         Ok(())
     }
@@ -0,0 +1,352 @@
+//! eMMC hardware boot partitions (`/dev/mmcblk<N>boot0` and `boot1`),
+//! from which the SoC of many embedded boards loads the bootloader.
+//!
+//! Images ship the content in `usr/lib/bootupd/emmc-boot/`, as `boot0`
+//! and/or `boot1`, each written to the corresponding boot partition of the
+//! eMMC holding `/boot`.  The kernel keeps these partitions read-only via
+//! `force_ro`, which we clear while writing them.  Like `DeviceTree` on
+//! aarch64 this is only used when requested, e.g. `bootupctl backend
+//! install --component EmmcBoot`.
+//!
+//! The installed files are tracked by the name of the boot partition, with
+//! the size and digest of the image written to it; the rest of the
+//! partition is left alone.
+
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
+
+use crate::component::*;
+use crate::filetree::{FileMetadata, FileTree};
+use crate::model::*;
+use crate::packagesystem;
+use crate::sha512string::SHA512String;
+
+/// The name of this component.
+pub(crate) const NAME: &str = "EmmcBoot";
+
+/// Where images ship the boot partition content, relative to the root.
+const EMMC_SRCDIR: &str = "usr/lib/bootupd/emmc-boot";
+
+/// The hardware boot partitions of an eMMC.
+const BOOT_PARTITIONS: &[&str] = &["boot0", "boot1"];
+
+/// The eMMC device name of `disk`, e.g. `mmcblk0` for `/dev/mmcblk0`.
+fn emmc_name(disk: &str) -> Result<String> {
+    let path = std::fs::canonicalize(disk).with_context(|| format!("Resolving {disk}"))?;
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let is_emmc = name
+        .strip_prefix("mmcblk")
+        .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+    if !is_emmc {
+        bail!("{disk} is not an eMMC device");
+    }
+    Ok(name.to_string())
+}
+
+/// The payload file for the installed boot partition `name`, e.g. `boot1`
+/// for `mmcblk0boot1`.
+fn payload_name(name: &str) -> Option<&'static str> {
+    BOOT_PARTITIONS.iter().copied().find(|p| {
+        name.strip_suffix(p)
+            .is_some_and(|d| d.starts_with("mmcblk"))
+    })
+}
+
+/// The device node of the boot partition `name`.
+fn device_path(name: &str) -> PathBuf {
+    Path::new("/dev").join(name)
+}
+
+/// The digest of the first `size` bytes of `device`, i.e. where an image
+/// of that size was written.
+fn hash_prefix(device: &Path, size: u64) -> Result<SHA512String> {
+    let f = std::fs::File::open(device).with_context(|| format!("Opening {device:?}"))?;
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    let n = std::io::copy(&mut f.take(size), &mut hasher)?;
+    if n != size {
+        bail!("{device:?} is smaller than {size} bytes");
+    }
+    Ok(SHA512String::from_hasher(&mut hasher))
+}
+
+/// Write `image`, described by `meta`, to the start of `device`, unless
+/// it's already there.
+fn write_image(mut image: impl Read, meta: &FileMetadata, device: &Path) -> Result<bool> {
+    if hash_prefix(device, meta.size).is_ok_and(|d| d == meta.sha512) {
+        log::debug!("{device:?} is up to date");
+        return Ok(false);
+    }
+    let mut dest = std::fs::OpenOptions::new()
+        .write(true)
+        .open(device)
+        .with_context(|| format!("Opening {device:?}"))?;
+    let capacity = dest.seek(std::io::SeekFrom::End(0))?;
+    if capacity < meta.size {
+        bail!("The image doesn't fit in {device:?} ({capacity} bytes)");
+    }
+    dest.rewind()?;
+    std::io::copy(&mut image, &mut dest)?;
+    dest.flush()?;
+    dest.sync_all()?;
+    Ok(true)
+}
+
+/// Run `f` with the boot partition `name` writable, restoring its
+/// `force_ro` flag afterwards.
+#[context("Writing eMMC boot partition {name}")]
+fn with_writable<T>(name: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let force_ro = Path::new("/sys/block").join(name).join("force_ro");
+    let orig =
+        std::fs::read_to_string(&force_ro).with_context(|| format!("Reading {force_ro:?}"))?;
+    let orig = orig.trim();
+    if orig != "0" {
+        std::fs::write(&force_ro, "0").with_context(|| format!("Writing {force_ro:?}"))?;
+    }
+    let r = f();
+    if orig != "0" {
+        if let Err(e) = std::fs::write(&force_ro, orig) {
+            log::warn!("Failed to restore {force_ro:?}: {e}");
+        }
+    }
+    r
+}
+
+#[derive(Default)]
+pub(crate) struct EmmcBoot {}
+
+impl EmmcBoot {
+    /// Whether `sysroot` ships boot partition images, from which an update
+    /// payload can be generated.
+    pub(crate) fn present_in(sysroot_path: &str) -> bool {
+        Path::new(sysroot_path).join(EMMC_SRCDIR).exists()
+    }
+
+    /// Write the payload files in `sysroot` to the boot partitions in
+    /// `wanted`, given as (partition, payload file); returns the installed
+    /// tree.
+    fn write_partitions(
+        &self,
+        sysroot: &openat::Dir,
+        wanted: &[(String, &str)],
+    ) -> Result<FileTree> {
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let payload = crate::signature::verified_payload_tree(sysroot, self.name(), &updated)
+            .context("reading update dir")?;
+        let mut r = FileTree::default();
+        for (name, file) in wanted {
+            let Some(meta) = payload.children.get(*file) else {
+                continue;
+            };
+            let device = device_path(name);
+            if !device.exists() {
+                bail!("{} does not exist", device.display());
+            }
+            let written = with_writable(name, || {
+                write_image(updated.open_file(*file)?, meta, &device)
+            })?;
+            if written {
+                log::info!("Wrote {file} to {}", device.display());
+            }
+            r.children.insert(name.clone(), meta.clone());
+        }
+        Ok(r)
+    }
+}
+
+impl Component for EmmcBoot {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        // There's no telling what wrote the boot partitions
+        Ok(None)
+    }
+
+    fn adopt_update(
+        &self,
+        _sysroot: &openat::Dir,
+        _updatemeta: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        bail!("{NAME} can't be adopted")
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        _dest_root: &str,
+        device: &str,
+        _update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let meta = require_component_update(src_root, self)?;
+        if device.is_empty() {
+            bail!("{NAME} requires a device");
+        }
+        let emmc = emmc_name(device)?;
+        let wanted = BOOT_PARTITIONS
+            .iter()
+            .map(|p| (format!("{emmc}{p}"), *p))
+            .collect::<Vec<_>>();
+        let filetree = self.write_partitions(src_root, &wanted)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(filetree),
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn install_destination(&self, _dest_root: &str, device: &str) -> Result<String> {
+        let emmc = emmc_name(device)?;
+        let devices = BOOT_PARTITIONS
+            .iter()
+            .map(|p| device_path(&format!("{emmc}{p}")).display().to_string())
+            .collect::<Vec<_>>();
+        Ok(devices.join(", "))
+    }
+
+    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+        let src = Path::new(sysroot_path).join(EMMC_SRCDIR);
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest)?;
+        let mut files = Vec::new();
+        for p in BOOT_PARTITIONS {
+            let path = src.join(p);
+            if !path.exists() {
+                continue;
+            }
+            std::fs::copy(&path, dest.join(p)).with_context(|| format!("Copying {path:?}"))?;
+            files.push(path);
+        }
+        if files.is_empty() {
+            bail!("No boot partition images found in {}", src.display());
+        }
+        let meta = packagesystem::query_files(sysroot_path, &files)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn payload_source(&self, path: &str) -> Option<PathBuf> {
+        Some(Path::new("/").join(EMMC_SRCDIR).join(path))
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        payload_root: &openat::Dir,
+        _dest_root: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let updatemeta = self.query_update(payload_root)?.expect("update available");
+        let wanted = currentf
+            .children
+            .keys()
+            .filter_map(|name| Some((name.clone(), payload_name(name)?)))
+            .collect::<Vec<_>>();
+        let filetree = self.write_partitions(payload_root, &wanted)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(filetree),
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn validate(
+        &self,
+        _sysroot: &openat::Dir,
+        current: &InstalledContent,
+        _fast: bool,
+    ) -> Result<ValidationResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let mut errs = Vec::new();
+        for (name, meta) in currentf.children.iter() {
+            let device = device_path(name);
+            if !device.try_exists()? {
+                errs.push(format!("Removed: {}", device.display()));
+                continue;
+            }
+            if hash_prefix(&device, meta.size)? != meta.sha512 {
+                errs.push(format!("Changed: {}", device.display()));
+            }
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn uninstall(&self, _sysroot: &openat::Dir, _current: &InstalledContent) -> Result<()> {
+        // Erasing the boot partitions would leave the board unbootable
+        log::info!("Leaving the eMMC boot partitions in place");
+        Ok(())
+    }
+
+    fn get_efi_vendor(&self, _sysroot: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_name() {
+        assert_eq!(payload_name("mmcblk0boot0"), Some("boot0"));
+        assert_eq!(payload_name("mmcblk12boot1"), Some("boot1"));
+        assert_eq!(payload_name("mmcblk0"), None);
+        assert_eq!(payload_name("boot0"), None);
+        assert_eq!(payload_name("mmcblk0boot2"), None);
+    }
+
+    #[test]
+    fn test_write_image() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let image = td.path().join("boot0");
+        std::fs::write(&image, "u-boot")?;
+        let device = td.path().join("mmcblk0boot0");
+        std::fs::write(&device, vec![0u8; 64])?;
+        let meta = FileMetadata::new_from_path(&openat::Dir::open(td.path())?, "boot0")?;
+
+        assert!(hash_prefix(&device, meta.size)? != meta.sha512);
+        let open = || std::fs::File::open(&image);
+        assert!(write_image(open()?, &meta, &device)?);
+        assert_eq!(hash_prefix(&device, meta.size)?, meta.sha512);
+        // The rest of the partition is left alone
+        let data = std::fs::read(&device)?;
+        assert_eq!(data.len(), 64);
+        assert_eq!(&data[..6], b"u-boot");
+        assert!(!write_image(open()?, &meta, &device)?);
+
+        let small = td.path().join("small");
+        std::fs::write(&small, "u")?;
+        assert!(write_image(open()?, &meta, &small).is_err());
+        assert!(hash_prefix(&small, meta.size).is_err());
+        Ok(())
+    }
+}
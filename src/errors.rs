@@ -0,0 +1,141 @@
+//! Classes of failures, so that tools can act on why a command failed
+//! without parsing error messages.
+//!
+//! Like [`Outcome`], an [`ErrorKind`] is usually the root of the chain of
+//! an error, with the details added as context on top, e.g.
+//! `Err(ErrorKind::PayloadMissing).context("No update metadata for EFI")`;
+//! it can also be added as context to an error from elsewhere.
+//! With structured output (e.g. `--json`), `bootupctl` prints a failure as
+//! an [`ErrorReport`] on standard output.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::exitcode::Outcome;
+
+/// Why a command failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// No ESP was found on the disks backing `/boot`
+    EspNotFound,
+    /// The ESP is mounted read-only and couldn't be remounted
+    EspReadOnly,
+    /// The filesystem of the ESP is marked dirty or had errors
+    EspDirty,
+    /// `/boot` can't be made writable, e.g. because it's part of a
+    /// read-only image
+    BootReadOnly,
+    /// An EFI boot entry couldn't be written, with `require-nvram` set
+    NvramWriteFailed,
+    /// The OS doesn't ship an update payload for the component
+    PayloadMissing,
+    /// The saved state (and its backup) can't be parsed or verified
+    StateCorrupt,
+    /// Another process holds the bootupd lock
+    LockHeld,
+    /// Validation found modified or missing files
+    ValidationFailed,
+    /// No components are supported on this platform
+    UnsupportedPlatform,
+    /// Any other failure
+    Other,
+}
+
+impl ErrorKind {
+    /// The class of `e`, from the outermost [`ErrorKind`] (whether the
+    /// root error or context) or [`Outcome`] in its chain.
+    pub fn of(e: &anyhow::Error) -> Self {
+        if let Some(kind) = e.downcast_ref::<ErrorKind>() {
+            return *kind;
+        }
+        match Outcome::of(e) {
+            Some(Outcome::LockContention) => ErrorKind::LockHeld,
+            Some(Outcome::ValidationFailed) => ErrorKind::ValidationFailed,
+            Some(Outcome::UnsupportedPlatform) => ErrorKind::UnsupportedPlatform,
+            Some(Outcome::UpdatesAvailable) | None => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ErrorKind::EspNotFound => "Failed to find ESP device",
+            ErrorKind::EspReadOnly => "The ESP is read-only",
+            ErrorKind::EspDirty => "The ESP filesystem is inconsistent",
+            ErrorKind::BootReadOnly => "/boot is read-only",
+            ErrorKind::NvramWriteFailed => "Failed to write EFI variables",
+            ErrorKind::PayloadMissing => "No update payload found",
+            ErrorKind::StateCorrupt => "Invalid saved state",
+            ErrorKind::LockHeld => "Another process holds the bootupd lock",
+            ErrorKind::ValidationFailed => "Caught validation errors",
+            ErrorKind::UnsupportedPlatform => "No components available for this platform",
+            ErrorKind::Other => "Failed",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for ErrorKind {}
+
+/// A failed command, as printed with structured output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ErrorReport {
+    /// The full error message
+    pub error: String,
+    pub kind: ErrorKind,
+    /// The exit code of the process
+    pub exit_code: i32,
+}
+
+impl ErrorReport {
+    pub(crate) fn new(e: &anyhow::Error) -> Self {
+        Self {
+            error: format!("{e:#}"),
+            kind: ErrorKind::of(e),
+            exit_code: Outcome::of(e)
+                .map(Outcome::code)
+                .unwrap_or(crate::exitcode::FAILURE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_kind() {
+        let r: anyhow::Result<()> = Err(ErrorKind::PayloadMissing.into());
+        let e = r.context("No update metadata for EFI").unwrap_err();
+        let report = ErrorReport::new(&e.context("Updating EFI"));
+        assert_eq!(report.kind, ErrorKind::PayloadMissing);
+        assert_eq!(
+            report.error,
+            "Updating EFI: No update metadata for EFI: No update payload found"
+        );
+        assert_eq!(report.exit_code, 1);
+        assert_eq!(
+            serde_json::to_value(&report).unwrap()["kind"],
+            "payload-missing"
+        );
+
+        let r: anyhow::Result<()> = Err(Outcome::LockContention.into());
+        let e = r.context("Failed to acquire write lock").unwrap_err();
+        let report = ErrorReport::new(&e);
+        assert_eq!(report.kind, ErrorKind::LockHeld);
+        assert_eq!(report.exit_code, 4);
+
+        // As context on top of another error
+        let e = anyhow::anyhow!("efibootmgr failed")
+            .context(ErrorKind::NvramWriteFailed)
+            .context("Installing EFI");
+        assert_eq!(ErrorKind::of(&e), ErrorKind::NvramWriteFailed);
+
+        assert_eq!(ErrorKind::of(&anyhow::anyhow!("oops")), ErrorKind::Other);
+    }
+}
@@ -0,0 +1,33 @@
+//! Finding the ESP among the places where the OS mounts it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Well-known paths to the ESP that may have been mounted external to us.
+const ESP_MOUNTS: &[&str] = &["boot/efi", "efi", "boot"];
+
+/// Return the candidate ESP mountpoints (relative to the root), honoring
+/// any configured override.
+pub(crate) fn esp_mounts() -> Vec<&'static str> {
+    match crate::config::get().esp_mount.as_deref() {
+        Some(mnt) => vec![mnt.trim_start_matches('/')],
+        None => ESP_MOUNTS.to_vec(),
+    }
+}
+
+/// Return the first of the candidate ESP mountpoints under `root` which
+/// has a FAT filesystem mounted.
+pub(crate) fn find_mounted_esp(root: &Path) -> Result<Option<PathBuf>> {
+    for mnt in esp_mounts() {
+        let mnt = root.join(mnt);
+        if !mnt.exists() {
+            continue;
+        }
+        let st = rustix::fs::statfs(&mnt).with_context(|| format!("statfs failed for {mnt:?}"))?;
+        if st.f_type == libc::MSDOS_SUPER_MAGIC {
+            return Ok(Some(mnt));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,93 @@
+//! Process exit codes, so that scripts can act on the outcome of a
+//! command without parsing error messages.
+//!
+//! | Code | Meaning                                                      |
+//! |------|--------------------------------------------------------------|
+//! | 0    | Success                                                      |
+//! | 1    | Any other error                                              |
+//! | 2    | Updates or a migration are available (only with `--check`)   |
+//! | 3    | Validation found modified or missing files                   |
+//! | 4    | Another process held the bootupd lock past `lock-timeout`    |
+//! | 5    | No components are supported on this platform                 |
+
+use std::fmt;
+
+/// Exit code for errors without a more specific code.
+pub(crate) const FAILURE: i32 = 1;
+
+/// An outcome with its own exit code; `run_cli` looks for this anywhere
+/// in the chain of an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    UpdatesAvailable,
+    ValidationFailed,
+    LockContention,
+    UnsupportedPlatform,
+}
+
+impl Outcome {
+    /// The process exit code.
+    pub(crate) fn code(self) -> i32 {
+        match self {
+            Outcome::UpdatesAvailable => 2,
+            Outcome::ValidationFailed => 3,
+            Outcome::LockContention => 4,
+            Outcome::UnsupportedPlatform => 5,
+        }
+    }
+
+    /// Whether this should be reported as an error.
+    pub(crate) fn is_error(self) -> bool {
+        !matches!(self, Outcome::UpdatesAvailable)
+    }
+
+    /// Find the outcome in the chain of `e`, if any.
+    pub(crate) fn of(e: &anyhow::Error) -> Option<Self> {
+        e.chain().find_map(|e| e.downcast_ref::<Outcome>()).copied()
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Outcome::UpdatesAvailable => "Updates available",
+            Outcome::ValidationFailed => "Caught validation errors",
+            Outcome::LockContention => "Another process holds the bootupd lock",
+            Outcome::UnsupportedPlatform => "No components available for this platform",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for Outcome {}
+
+/// Map the result of a command to an exit code, printing any error.
+pub(crate) fn from_result(r: anyhow::Result<()>) -> i32 {
+    let e = match r {
+        Ok(()) => return libc::EXIT_SUCCESS,
+        Err(e) => e,
+    };
+    let outcome = Outcome::of(&e);
+    if outcome.map(Outcome::is_error).unwrap_or(true) {
+        // Use the alternative formatter to get everything on a single line... it reads better.
+        eprintln!("error: {:#}", e);
+    }
+    outcome.map(Outcome::code).unwrap_or(FAILURE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(from_result(Ok(())), 0);
+        assert_eq!(from_result(Err(anyhow::anyhow!("oops"))), FAILURE);
+        let r: anyhow::Result<()> = Err(Outcome::ValidationFailed.into());
+        assert_eq!(from_result(r.context("Validating EFI")), 3);
+        let r: anyhow::Result<()> = Err(Outcome::LockContention.into());
+        assert_eq!(from_result(r.context("Failed to acquire write lock")), 4);
+        assert_eq!(from_result(Err(Outcome::UpdatesAvailable.into())), 2);
+    }
+}
@@ -0,0 +1,434 @@
+//! extlinux (syslinux), for appliance images which boot from an ext4
+//! `/boot` rather than with GRUB.
+//!
+//! `extlinux` itself writes `ldlinux.sys` (whose location is recorded in
+//! the volume boot record, so it can't be replaced as a plain file) and
+//! `ldlinux.c32`; we track the COM32 modules referenced from
+//! `extlinux.conf`, such as `menu.c32`, which must match its version.
+//! `extlinux.conf` itself belongs to the OS (e.g. it is written by
+//! kernel-install) and is never modified by us.
+//! Like `SystemdBoot`, this is never part of the default set of components
+//! and must be requested explicitly, e.g. `bootupctl backend install
+//! --component Extlinux`.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use openat_ext::OpenatDirExt;
+
+use crate::component::*;
+use crate::filetree::{self, FileTree, FileTreeDiff};
+use crate::model::*;
+use crate::packagesystem;
+
+/// The name of this component.
+pub(crate) const NAME: &str = "Extlinux";
+
+/// The extlinux installer, relative to the root.
+const EXTLINUX_BIN: &str = "usr/sbin/extlinux";
+/// Where syslinux ships its modules and MBR boot code, relative to the root.
+const SYSLINUX_DATADIR: &str = "usr/share/syslinux";
+/// The installed extlinux directory, relative to the root.
+const EXTLINUX_DIR: &str = "boot/extlinux";
+/// Written by `extlinux --install` and `extlinux --update` rather than by us.
+const LDLINUX: &[&str] = &["ldlinux.sys", "ldlinux.c32"];
+
+fn is_module(name: &str) -> bool {
+    name.ends_with(".c32") && !LDLINUX.contains(&name)
+}
+
+/// Keep only the modules in `tree`.
+fn modules_of(tree: FileTree) -> FileTree {
+    let children = tree
+        .children
+        .into_iter()
+        .filter(|(k, _)| is_module(k))
+        .collect();
+    FileTree { children }
+}
+
+/// The modules named in `extlinux.conf`, e.g. via `UI menu.c32` or
+/// `COM32 chain.c32`.
+fn parse_config_modules(config: &str) -> Vec<String> {
+    let mut r: Vec<_> = config
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.starts_with('#'))
+        .flat_map(|l| l.split_whitespace())
+        .filter_map(|w| Path::new(w).file_name()?.to_str())
+        .filter(|w| is_module(&w.to_ascii_lowercase()))
+        .map(ToOwned::to_owned)
+        .collect();
+    r.sort();
+    r.dedup();
+    r
+}
+
+/// Apply `diff` from `updated` to the modules in `dir`, first copying the
+/// modules it changes or removes to `backup` for [`restore_modules`].
+fn apply_modules(
+    updated: &openat::Dir,
+    dir: &Path,
+    diff: &FileTreeDiff,
+    backup: &Path,
+) -> Result<()> {
+    for name in diff.changes.iter().chain(diff.removals.iter()) {
+        std::fs::copy(dir.join(name), backup.join(name))
+            .with_context(|| format!("Backing up {name}"))?;
+    }
+    let destdir = openat::Dir::open(dir).with_context(|| format!("Opening {dir:?}"))?;
+    filetree::apply_diff(updated, &destdir, diff, None)
+}
+
+/// Undo [`apply_modules`], putting back the previous modules from
+/// `backup`, which must be on the same filesystem as `dir`.
+fn restore_modules(dir: &Path, diff: &FileTreeDiff, backup: &Path) -> Result<()> {
+    for name in diff.additions.iter() {
+        match std::fs::remove_file(dir.join(name)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Removing {name}")),
+        }
+    }
+    for name in diff.changes.iter().chain(diff.removals.iter()) {
+        let src = backup.join(name);
+        if src.exists() {
+            std::fs::rename(&src, dir.join(name)).with_context(|| format!("Restoring {name}"))?;
+        }
+    }
+    filetree::syncfs(&openat::Dir::open(dir)?)
+}
+
+/// Run `extlinux` with `args`, printing its error output on failure.
+fn run_extlinux(args: &[&str], dir: &Path) -> Result<()> {
+    let mut cmd = Command::new(Path::new("/").join(EXTLINUX_BIN));
+    cmd.args(args).arg(dir);
+    let out = cmd.output()?;
+    if !out.status.success() {
+        std::io::stderr().write_all(&out.stderr)?;
+        bail!("Failed to run {:?}", cmd);
+    }
+    Ok(())
+}
+
+/// Write the syslinux MBR boot code to `device`, which chain-loads the
+/// partition holding `/boot`.
+fn write_mbr(device: &str) -> Result<()> {
+    let out = Command::new("blkid")
+        .args(["-o", "value", "-s", "PTTYPE", device])
+        .output()?;
+    let pttype = String::from_utf8(out.stdout)?;
+    let mbr = match pttype.trim() {
+        "gpt" => "gptmbr.bin",
+        "dos" => "mbr.bin",
+        o => bail!("Unsupported partition table type {o:?} on {device}"),
+    };
+    let code = std::fs::read(Path::new("/").join(SYSLINUX_DATADIR).join(mbr))?;
+    // Only the boot code; the rest of the sector holds the partition table.
+    let code = code.get(..440).unwrap_or(&code);
+    let mut f = std::fs::OpenOptions::new().write(true).open(device)?;
+    f.write_all(code)?;
+    f.sync_all()?;
+    Ok(())
+}
+
+#[derive(Default)]
+pub(crate) struct Extlinux {}
+
+impl Extlinux {
+    /// Whether `sysroot` ships extlinux, from which an update payload can
+    /// be generated.
+    pub(crate) fn present_in(sysroot_path: &str) -> bool {
+        Path::new(sysroot_path).join(EXTLINUX_BIN).exists()
+    }
+
+    /// Update the modules in `current` in `boot/extlinux` under `dest_root`
+    /// to the payload, adding those `extlinux.conf` newly refers to, and
+    /// then `ldlinux.sys` itself.
+    fn update(
+        &self,
+        sysroot: &openat::Dir,
+        dest_root: &Path,
+        current: &FileTree,
+    ) -> Result<FileTree> {
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let updatef = crate::signature::verified_payload_tree(sysroot, self.name(), &updated)
+            .context("reading update dir")?;
+        let dir = dest_root.join(EXTLINUX_DIR);
+        let wanted = match std::fs::read_to_string(dir.join("extlinux.conf")) {
+            Ok(config) => parse_config_modules(&config),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).context("Reading extlinux.conf"),
+        };
+        // Only update the modules we already have or the config needs; the
+        // payload ships all of them.
+        let children: BTreeMap<_, _> = updatef
+            .children
+            .into_iter()
+            .filter(|(k, _)| current.children.contains_key(k) || wanted.contains(k))
+            .collect();
+        let updatef = FileTree { children };
+        let diff = current.diff(&updatef)?;
+
+        // The modules must match ldlinux.sys, so if either writing them or
+        // `extlinux --update` fails, the previous modules are put back for
+        // the old ldlinux.sys.  They are kept next to the extlinux
+        // directory, where they're on the same filesystem but not taken
+        // for modules.
+        let backup = tempfile::Builder::new()
+            .prefix(".bootupd-extlinux-")
+            .tempdir_in(dest_root.join("boot"))
+            .context("Creating backup directory")?;
+        let r = apply_modules(&updated, &dir, &diff, backup.path())
+            .and_then(|()| run_extlinux(&["--update"], &dir));
+        if let Err(e) = r {
+            if let Err(re) = restore_modules(&dir, &diff, backup.path()) {
+                log::warn!("Failed to restore the previous modules: {re:#}");
+            }
+            return Err(e);
+        }
+        Ok(updatef)
+    }
+
+    /// The root of `sysroot`.
+    fn root_path(sysroot: &openat::Dir) -> Result<std::path::PathBuf> {
+        let fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
+        Ok(std::fs::read_link(fd)?)
+    }
+}
+
+impl Component for Extlinux {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        if crate::efi::is_efi_booted()? {
+            return Ok(None);
+        }
+        if !Path::new("/")
+            .join(EXTLINUX_DIR)
+            .join("ldlinux.sys")
+            .exists()
+        {
+            log::trace!("No ldlinux.sys found");
+            return Ok(None);
+        }
+        query_adopt_state(&[format!("/{EXTLINUX_BIN}")])
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        updatemeta: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            bail!("Failed to find extlinux")
+        };
+        let dest_root = Self::root_path(sysroot)?;
+        let dir = openat::Dir::open(&dest_root.join(EXTLINUX_DIR))?;
+        let current = modules_of(FileTree::new_from_dir(&dir)?);
+        log::debug!("Adopting {} modules", current.children.len());
+        let filetree = self.update(sysroot, &dest_root, &current)?;
+        Ok(InstalledContent {
+            meta: updatemeta.clone(),
+            filetree: Some(filetree),
+            adopted_from: Some(meta.version),
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        device: &str,
+        _update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let meta = require_component_update(src_root, self)?;
+        let dir = Path::new(dest_root).join(EXTLINUX_DIR);
+        std::fs::create_dir_all(&dir)?;
+        run_extlinux(&["--install"], &dir)?;
+        if !device.is_empty() {
+            write_mbr(device).with_context(|| format!("Writing MBR to {device}"))?;
+        }
+
+        // Install the modules named in the config if there is one, or
+        // else all of them.
+        let srcdir = src_root.sub_dir(&component_updatedirname(self))?;
+        let mut tree = FileTree::new_from_dir(&srcdir)?;
+        if let Ok(config) = std::fs::read_to_string(dir.join("extlinux.conf")) {
+            let wanted = parse_config_modules(&config);
+            tree.children.retain(|k, _| wanted.contains(k));
+        }
+        let destdir = openat::Dir::open(&dir)?;
+        let diff = FileTree::default().diff(&tree)?;
+        filetree::apply_diff(&srcdir, &destdir, &diff, None)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(tree),
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn install_destination(&self, dest_root: &str, device: &str) -> Result<String> {
+        let dir = Path::new(dest_root).join(EXTLINUX_DIR);
+        if device.is_empty() {
+            Ok(dir.display().to_string())
+        } else {
+            Ok(format!("{} (MBR of {device})", dir.display()))
+        }
+    }
+
+    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+        let src = Path::new(sysroot_path).join(SYSLINUX_DATADIR);
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest)?;
+        for entry in std::fs::read_dir(&src).with_context(|| format!("Reading {src:?}"))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.to_str().is_some_and(is_module) {
+                std::fs::copy(entry.path(), dest.join(&name))
+                    .with_context(|| format!("Copying {name:?}"))?;
+            }
+        }
+        let extlinux = Path::new(sysroot_path).join(EXTLINUX_BIN);
+        let meta = packagesystem::query_files(sysroot_path, [&extlinux])?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        payload_root: &openat::Dir,
+        dest_root: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let updatemeta = self.query_update(payload_root)?.expect("update available");
+        let dest_root = Self::root_path(dest_root)?;
+        let filetree = self.update(payload_root, &dest_root, currentf)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(filetree),
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn validate(
+        &self,
+        _sysroot: &openat::Dir,
+        current: &InstalledContent,
+        fast: bool,
+    ) -> Result<ValidationResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let dir = openat::Dir::open(&Path::new("/").join(EXTLINUX_DIR))?;
+        let mut errs = Vec::new();
+        if !dir.exists("ldlinux.sys")? {
+            errs.push("Removed: ldlinux.sys".to_string());
+        }
+        let diff = currentf.validate_in(&dir, fast)?;
+        for f in diff.changes.iter() {
+            errs.push(format!("Changed: {}", f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(format!("Removed: {}", f));
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn uninstall(&self, _sysroot: &openat::Dir, current: &InstalledContent) -> Result<()> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let dir = openat::Dir::open(&Path::new("/").join(EXTLINUX_DIR))?;
+        // ldlinux.sys is left alone; the volume boot record refers to it.
+        filetree::remove_tree(&dir, currentf).context("removing files")
+    }
+
+    fn get_efi_vendor(&self, _sysroot: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_modules() {
+        let config = "\
+# menu.c32 is commented out here
+UI menu.c32
+DEFAULT linux
+LABEL linux
+  KERNEL /vmlinuz
+  APPEND root=/dev/sda2
+LABEL other
+  COM32 /boot/extlinux/chain.c32
+  APPEND hd1
+LABEL again
+  COM32 chain.c32
+";
+        assert_eq!(parse_config_modules(config), ["chain.c32", "menu.c32"]);
+        assert!(!is_module("ldlinux.c32"));
+        assert!(!is_module("extlinux.conf"));
+    }
+
+    #[test]
+    fn test_restore_modules() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let p = td.path();
+        crate::testutils::write_tree(
+            p,
+            &[
+                ("extlinux/menu.c32", "menu v1"),
+                ("extlinux/chain.c32", "chain v1"),
+                ("update/menu.c32", "menu v2"),
+                ("update/vesamenu.c32", "vesamenu v2"),
+            ],
+        )?;
+        std::fs::create_dir(p.join("backup"))?;
+        let dir = p.join("extlinux");
+        let current = FileTree::new_from_dir(&openat::Dir::open(&dir)?)?;
+        let updated = openat::Dir::open(&p.join("update"))?;
+        let diff = current.diff(&FileTree::new_from_dir(&updated)?)?;
+        apply_modules(&updated, &dir, &diff, &p.join("backup"))?;
+        assert_eq!(std::fs::read_to_string(dir.join("menu.c32"))?, "menu v2");
+        assert!(dir.join("vesamenu.c32").exists());
+        assert!(!dir.join("chain.c32").exists());
+
+        restore_modules(&dir, &diff, &p.join("backup"))?;
+        assert_eq!(FileTree::new_from_dir(&openat::Dir::open(&dir)?)?, current);
+        Ok(())
+    }
+}
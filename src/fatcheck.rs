@@ -0,0 +1,248 @@
+//! Checks that the FAT filesystem of an ESP is consistent before we write
+//! to it, since writing onto a corrupted FAT can make things much worse.
+//!
+//! When mounting a FAT filesystem read-write, the kernel sets a "dirty"
+//! bit in its boot sector, which it clears again on a clean unmount; if
+//! the bit is already set when we are about to mount an ESP, it wasn't
+//! unmounted cleanly (e.g. after a power loss).  With `esp-fsck` set we
+//! then run `fsck.fat -a` on it first.  For an ESP which is already
+//! mounted, the bit is set by that mount, so instead we look for errors
+//! the kernel logged about it since it was mounted; errors from an earlier
+//! mount during the same boot may since have been repaired.  Updates
+//! refuse to write to an ESP with such problems unless `--force` is given.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{bail, Context, Result};
+
+/// Set by `--force`, to write to ESPs even if they look inconsistent.
+static FORCE: AtomicBool = AtomicBool::new(false);
+
+/// Size of the FAT boot sector.
+const BOOT_SECTOR_SIZE: usize = 512;
+
+/// The kernel's "dirty" bit in the `state` field of the boot sector.
+const FAT_STATE_DIRTY: u8 = 0x01;
+
+/// Parts of kernel messages about a FAT filesystem which indicate that it
+/// is (or was) inconsistent.
+const KERNEL_ERRORS: &[&str] = &["not properly unmounted", "error", "corrupt", "read-only"];
+
+/// How long before systemd noticed a mount its kernel messages may have
+/// been logged, in microseconds; e.g. the kernel warns about a filesystem
+/// which was not properly unmounted while mounting it.
+const MOUNT_LOG_MARGIN_USEC: u64 = 1_000_000;
+
+/// Write to ESPs even if they look inconsistent.
+pub(crate) fn set_force() {
+    FORCE.store(true, Ordering::SeqCst);
+}
+
+/// Whether writing to inconsistent ESPs was allowed with `--force`.
+pub(crate) fn forced() -> bool {
+    FORCE.load(Ordering::SeqCst)
+}
+
+/// Whether the FAT boot sector `bs` has the dirty bit set.
+fn boot_sector_dirty(bs: &[u8; BOOT_SECTOR_SIZE]) -> Result<bool> {
+    if bs[510..] != [0x55, 0xAA] {
+        bail!("Not a FAT boot sector");
+    }
+    // FAT32 has no 16-bit FAT size, and a longer BPB before the state
+    let fat_size16 = u16::from_le_bytes([bs[22], bs[23]]);
+    let state = if fat_size16 == 0 { bs[0x41] } else { bs[0x25] };
+    Ok(state & FAT_STATE_DIRTY != 0)
+}
+
+/// Whether the FAT filesystem on `device` has the dirty bit set.
+fn is_dirty(device: &Path) -> Result<bool> {
+    let mut f = std::fs::File::open(device).with_context(|| format!("Opening {device:?}"))?;
+    let mut bs = [0u8; BOOT_SECTOR_SIZE];
+    f.read_exact(&mut bs)
+        .with_context(|| format!("Reading boot sector of {device:?}"))?;
+    boot_sector_dirty(&bs).with_context(|| format!("Checking {device:?}"))
+}
+
+/// Where `device` is mounted, if anywhere.
+fn mountpoint(device: &Path) -> Result<Option<PathBuf>> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts")?;
+    Ok(mounts
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split_ascii_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+        .filter(|(source, _)| source.starts_with('/'))
+        .find(|(source, _)| std::fs::canonicalize(source).is_ok_and(|s| s == device))
+        .map(|(_, target)| PathBuf::from(target)))
+}
+
+/// When the filesystem at `mountpoint` was mounted, in microseconds since
+/// boot, as tracked by systemd for its mount unit; `None` if unknown.
+fn mounted_at(mountpoint: &Path) -> Result<Option<u64>> {
+    let out = crate::util::cmd_output(
+        Command::new("systemctl")
+            .args([
+                "show",
+                "--value",
+                "--property=InactiveExitTimestampMonotonic",
+                "--",
+            ])
+            .arg(mountpoint),
+    )?;
+    let usec: u64 = out
+        .trim()
+        .parse()
+        .with_context(|| format!("Parsing mount timestamp {out:?}"))?;
+    Ok((usec > 0).then_some(usec))
+}
+
+/// Run `fsck.fat -a` on the unmounted `device`.
+fn repair(device: &Path) -> Result<()> {
+    crate::notify::progress(
+        "check-esp",
+        Some("EFI"),
+        &[("device", device.display().to_string())],
+    );
+    let st = Command::new("fsck.fat")
+        .args(["-a", "-w"])
+        .arg(device)
+        .status()
+        .context("Running fsck.fat")?;
+    // 1 means errors were found and corrected
+    match st.code() {
+        Some(0) => {}
+        Some(1) => log::warn!("fsck.fat corrected errors on {device:?}"),
+        _ => bail!("fsck.fat failed on {device:?}: {st}"),
+    }
+    Ok(())
+}
+
+/// Check `device` before we mount it, repairing it if `esp-fsck` is set;
+/// returns the problem found, if any.
+fn check_unmounted(device: &Path) -> Result<Option<String>> {
+    if !is_dirty(device)? {
+        return Ok(None);
+    }
+    if crate::config::get().esp_fsck {
+        repair(device)?;
+        if !is_dirty(device)? {
+            return Ok(None);
+        }
+    }
+    Ok(Some(format!(
+        "{} was not properly unmounted",
+        device.display()
+    )))
+}
+
+/// The kernel messages in `log`, as written by `journalctl -o
+/// short-monotonic`, about errors on the FAT filesystem on the block
+/// device `name` logged `since` microseconds after boot or later.
+fn kernel_errors<'a>(log: &'a str, name: &str, since: u64) -> Vec<&'a str> {
+    let prefix = format!("FAT-fs ({name}): ");
+    log.lines()
+        .filter_map(|l| {
+            let (ts, rest) = l.strip_prefix('[')?.split_once(']')?;
+            let ts = (ts.trim().parse::<f64>().ok()? * 1e6) as u64;
+            // After the hostname
+            let (_, msg) = rest.split_once(" kernel: ")?;
+            let lower = msg.strip_prefix(&prefix)?.to_ascii_lowercase();
+            (ts >= since && KERNEL_ERRORS.iter().any(|e| lower.contains(e))).then_some(msg)
+        })
+        .collect()
+}
+
+/// Check `device`, mounted at `mountpoint`, for errors logged by the kernel
+/// since it was mounted; returns the problem found, if any.
+fn check_mounted(device: &Path, mountpoint: &Path) -> Result<Option<String>> {
+    let Some(name) = device.file_name().and_then(|n| n.to_str()) else {
+        return Ok(None);
+    };
+    let since = match mounted_at(mountpoint) {
+        Ok(Some(t)) => t.saturating_sub(MOUNT_LOG_MARGIN_USEC),
+        Ok(None) => 0,
+        Err(e) => {
+            log::debug!("Checking all kernel messages about {name} in this boot: {e:#}");
+            0
+        }
+    };
+    let log = match crate::util::cmd_output(Command::new("journalctl").args([
+        "-k",
+        "-b",
+        "-q",
+        "-o",
+        "short-monotonic",
+    ])) {
+        Ok(log) => log,
+        Err(e) => {
+            log::debug!("Not checking kernel messages about {name}: {e:#}");
+            return Ok(None);
+        }
+    };
+    let errors = kernel_errors(&log, name, since);
+    let Some(last) = errors.last() else {
+        return Ok(None);
+    };
+    Ok(Some(format!("{}: {last}", device.display())))
+}
+
+/// Check the FAT filesystem on the ESP `device`, which we are about to
+/// mount or use; returns the problem found, if any.
+pub(crate) fn check(device: &Path) -> Result<Option<String>> {
+    let device = std::fs::canonicalize(device).with_context(|| format!("Resolving {device:?}"))?;
+    match mountpoint(&device)? {
+        Some(mountpoint) => check_mounted(&device, &mountpoint),
+        None => check_unmounted(&device),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_sector_dirty() -> Result<()> {
+        let mut bs = [0u8; BOOT_SECTOR_SIZE];
+        assert!(boot_sector_dirty(&bs).is_err());
+        bs[510] = 0x55;
+        bs[511] = 0xAA;
+        // FAT32
+        assert!(!boot_sector_dirty(&bs)?);
+        bs[0x41] = FAT_STATE_DIRTY;
+        assert!(boot_sector_dirty(&bs)?);
+        // FAT16
+        bs[22] = 0x20;
+        assert!(!boot_sector_dirty(&bs)?);
+        bs[0x25] = FAT_STATE_DIRTY;
+        assert!(boot_sector_dirty(&bs)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kernel_errors() {
+        let log = "\
+[    3.521337] host kernel: FAT-fs (vda2): Volume was not properly unmounted. Some data may be corrupt. Please run fsck.
+[    3.521400] host kernel: FAT-fs (vda2): utf8 is not a recommended IO charset for FAT filesystems
+[    4.100000] host kernel: FAT-fs (vda3): error, fat_get_cluster: invalid cluster chain (i_pos 0)
+[    4.200000] host kernel: EXT4-fs (vda4): mounted filesystem with ordered data mode
+[   60.000000] host kernel: FAT-fs (vda3): Volume was not properly unmounted. Some data may be corrupt. Please run fsck.
+";
+        assert_eq!(
+            kernel_errors(log, "vda2", 0),
+            ["FAT-fs (vda2): Volume was not properly unmounted. Some data may be corrupt. Please run fsck."]
+        );
+        assert_eq!(kernel_errors(log, "vda3", 0).len(), 2);
+        assert!(kernel_errors(log, "vda4", 0).is_empty());
+        assert!(kernel_errors(log, "vda", 0).is_empty());
+        // Errors from before the current mount, which fsck may have fixed
+        assert!(kernel_errors(log, "vda2", 10_000_000).is_empty());
+        assert_eq!(
+            kernel_errors(log, "vda3", 10_000_000),
+            ["FAT-fs (vda3): Volume was not properly unmounted. Some data may be corrupt. Please run fsck."]
+        );
+    }
+}
@@ -27,12 +27,12 @@ pub(crate) struct Findmnt {
 pub(crate) fn inspect_filesystem(root: &openat::Dir, path: &str) -> Result<Filesystem> {
     let rootfd = unsafe { BorrowedFd::borrow_raw(root.as_raw_fd()) };
     // SAFETY: This is unsafe just for the pre_exec, when we port to cap-std we can use cap-std-ext
-    let o: Findmnt = unsafe {
+    let o: Findmnt = crate::retry::retry("Running findmnt", || unsafe {
         Command::new("findmnt")
             .args(["-J", "-v", "--output=SOURCE,FSTYPE,OPTIONS,UUID", path])
             .pre_exec(move || rustix::process::fchdir(rootfd).map_err(Into::into))
-            .run_and_parse_json()?
-    };
+            .run_and_parse_json()
+    })?;
     o.filesystems
         .into_iter()
         .next()
@@ -4,59 +4,145 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use anyhow::{bail, Context, Result};
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use camino::{Utf8Path, Utf8PathBuf};
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use openat_ext::OpenatDirExt;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use openssl::hash::{Hasher, MessageDigest};
 use rustix::fd::BorrowedFd;
 use serde::{Deserialize, Serialize};
 #[allow(unused_imports)]
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Display;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use std::os::unix::io::AsRawFd;
 use std::os::unix::process::CommandExt;
 use std::process::Command;
 
 /// The prefix we apply to our temporary files.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) const TMP_PREFIX: &str = ".btmp.";
+/// Working area in the destination directory (e.g. `EFI/` on the ESP)
+/// where updated content is written before being swapped into place;
+/// anything in it is left over from an interrupted operation.  The name
+/// is reserved in all directories we manage.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) const WORK_DIR: &str = ".bootupd-tmp";
 // This module doesn't handle modes right now, because
 // we're only targeting FAT filesystems for UEFI.
 // In FAT there are no unix permission bits, usually
 // they're set by mount options.
 // See also https://github.com/coreos/fedora-coreos-config/commit/8863c2b34095a2ae5eae6fbbd121768a5f592091
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 const DEFAULT_FILE_MODE: u32 = 0o700;
 
+/// Files are hashed through a buffer of at most this size, so that large
+/// payload files such as UKIs or FIT images aren't read into memory.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Alignment of buffers for `O_DIRECT` reads, a multiple of the logical
+/// block size of any disk.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const DIRECT_IO_ALIGN: usize = 4096;
+
 use crate::sha512string::SHA512String;
 
 /// Metadata for a single file
-#[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct FileMetadata {
+pub struct FileMetadata {
     /// File size in bytes
-    pub(crate) size: u64,
+    pub size: u64,
     /// Content checksum; chose SHA-512 because there are not a lot of files here
     /// and it's ok if the checksum is large.
-    pub(crate) sha512: SHA512String,
+    pub sha512: SHA512String,
+    /// Modification time on the ESP in nanoseconds since the epoch, recorded
+    /// after writing the file so that `validate --fast` can skip hashing
+    /// files which weren't touched since.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+}
+
+// The mtime is only a hint for validation; files are the same if their
+// content is.
+impl PartialEq for FileMetadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.size == other.size && self.sha512 == other.sha512
+    }
+}
+
+impl std::hash::Hash for FileMetadata {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.size.hash(state);
+        self.sha512.hash(state);
+    }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+/// The files in a directory, as recorded for installed components and in
+/// payload manifests.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct FileTree {
-    pub(crate) children: BTreeMap<String, FileMetadata>,
+pub struct FileTree {
+    /// Regular files, keyed by their path relative to the directory;
+    /// directories are implied by these
+    pub children: BTreeMap<String, FileMetadata>,
 }
 
+/// The paths which differ between two [`FileTree`]s; these are sorted, so
+/// that the serialized form is stable.
 #[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct FileTreeDiff {
-    pub(crate) additions: HashSet<String>,
-    pub(crate) removals: HashSet<String>,
-    pub(crate) changes: HashSet<String>,
+#[serde(rename_all = "kebab-case")]
+pub struct FileTreeDiff {
+    /// Files only in the updated tree
+    pub additions: BTreeSet<String>,
+    /// Files only in the current tree
+    pub removals: BTreeSet<String>,
+    /// Files in both trees with different content
+    pub changes: BTreeSet<String>,
 }
 
 impl Display for FileTreeDiff {
@@ -71,6 +157,84 @@ impl Display for FileTreeDiff {
     }
 }
 
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+impl FileTreeDiff {
+    /// Whether the trees have the same files.
+    pub fn is_empty(&self) -> bool {
+        self.additions.is_empty() && self.removals.is_empty() && self.changes.is_empty()
+    }
+
+    /// List the changes in path order, with the metadata of each file from
+    /// the trees this diff was computed from.
+    pub fn describe(&self, current: &FileTree, updated: &FileTree) -> Vec<FileChange> {
+        let kinds = [
+            (&self.additions, FileChangeKind::Added),
+            (&self.changes, FileChangeKind::Changed),
+            (&self.removals, FileChangeKind::Removed),
+        ];
+        let mut r: Vec<_> = kinds
+            .into_iter()
+            .flat_map(|(paths, kind)| {
+                paths.iter().map(move |path| FileChange {
+                    path: path.clone(),
+                    kind,
+                    current: current.get_ignore_case(path).cloned(),
+                    update: updated.get_ignore_case(path).cloned(),
+                })
+            })
+            .collect();
+        r.sort_by(|a, b| a.path.cmp(&b.path));
+        r
+    }
+}
+
+/// How a file is changed by an update.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileChangeKind {
+    Added,
+    Changed,
+    Removed,
+}
+
+/// A single file changed by an update.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileChange {
+    pub path: String,
+    pub kind: FileChangeKind,
+    /// The file as installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<FileMetadata>,
+    /// The file in the update
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update: Option<FileMetadata>,
+}
+
+impl Display for FileChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let marker = match self.kind {
+            FileChangeKind::Added => 'A',
+            FileChangeKind::Changed => 'M',
+            FileChangeKind::Removed => 'D',
+        };
+        write!(f, "{marker} {}", self.path)?;
+        match (self.current.as_ref(), self.update.as_ref()) {
+            (Some(cur), Some(upd)) => write!(
+                f,
+                " ({} -> {} bytes, {} -> {})",
+                cur.size, upd.size, cur.sha512, upd.sha512
+            ),
+            (Some(m), None) | (None, Some(m)) => write!(f, " ({} bytes, {})", m.size, m.sha512),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
 #[cfg(test)]
 impl FileTreeDiff {
     pub(crate) fn count(&self) -> usize {
@@ -79,27 +243,49 @@ impl FileTreeDiff {
 }
 
 impl FileMetadata {
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    pub(crate) fn new_from_path<P: openat::AsPath>(
-        dir: &openat::Dir,
-        name: P,
-    ) -> Result<FileMetadata> {
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn new_from_path(dir: &openat::Dir, name: &str) -> Result<FileMetadata> {
+        use std::io::Read;
+        let start = std::time::Instant::now();
         let mut r = dir.open_file(name)?;
         let meta = r.metadata()?;
         let mut hasher =
             Hasher::new(MessageDigest::sha512()).expect("openssl sha512 hasher creation failed");
-        let _ = std::io::copy(&mut r, &mut hasher)?;
+        let mut buf = vec![0; (meta.len() as usize).clamp(4096, HASH_BUFFER_SIZE)];
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).with_context(|| format!("reading {name}")),
+            };
+            hasher.update(&buf[..n])?;
+        }
         let digest = SHA512String::from_hasher(&mut hasher);
+        log::debug!(
+            "Hashed {name} ({} bytes) in {:?}",
+            meta.len(),
+            start.elapsed()
+        );
         Ok(FileMetadata {
             size: meta.len(),
             sha512: digest,
+            mtime: None,
         })
     }
 }
 
 impl FileTree {
     // Internal helper to generate a sub-tree
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
     fn unsorted_from_dir(dir: &openat::Dir) -> Result<HashMap<String, FileMetadata>> {
         let mut ret = HashMap::new();
         for entry in dir.list_dir(".")? {
@@ -110,6 +296,10 @@ impl FileTree {
             if name.starts_with(TMP_PREFIX) {
                 bail!("File {} contains our temporary prefix!", name);
             }
+            // Not content, and cleaned up by the next operation
+            if name == WORK_DIR {
+                continue;
+            }
             match dir.get_file_type(&entry)? {
                 openat::SimpleType::File => {
                     let meta = FileMetadata::new_from_path(dir, name)?;
@@ -136,20 +326,120 @@ impl FileTree {
     }
 
     /// Create a FileTree from the target directory.
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
     pub(crate) fn new_from_dir(dir: &openat::Dir) -> Result<Self> {
+        let span = tracing::info_span!("hash", files = tracing::field::Empty).entered();
         let mut children = BTreeMap::new();
         for (k, v) in Self::unsorted_from_dir(dir)?.drain() {
             children.insert(k, v);
         }
+        span.record("files", children.len());
 
         Ok(Self { children })
     }
 
+    /// The total size of the files in this tree, in bytes.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn total_size(&self) -> u64 {
+        self.children.values().map(|m| m.size).sum()
+    }
+
+    /// Split into subtrees keyed by the first path component (on the ESP,
+    /// the vendor directory).  Paths in each subtree are unchanged.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn split_toplevel(&self) -> BTreeMap<String, FileTree> {
+        let mut r: BTreeMap<String, FileTree> = BTreeMap::new();
+        for (k, v) in self.children.iter() {
+            let top = k.split_once('/').map(|(top, _)| top).unwrap_or("");
+            r.entry(top.to_string())
+                .or_default()
+                .children
+                .insert(k.clone(), v.clone());
+        }
+        r
+    }
+
+    /// Combine multiple trees, e.g. as produced by `split_toplevel`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn merge<'a>(trees: impl IntoIterator<Item = &'a FileTree>) -> FileTree {
+        let mut children = BTreeMap::new();
+        for t in trees {
+            children.extend(t.children.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        FileTree { children }
+    }
+
+    /// The metadata of `path`, matching names the way FAT does.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn get_ignore_case(&self, path: &str) -> Option<&FileMetadata> {
+        self.children.get(path).or_else(|| {
+            self.children
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(path))
+                .map(|(_, v)| v)
+        })
+    }
+
+    /// Map the name of each file as the filesystem compares them, i.e.
+    /// ignoring case with `fat`, to the name itself; fails if two names
+    /// would refer to the same file on FAT.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn name_keys(&self, fat: bool) -> Result<HashMap<String, &String>> {
+        let mut r = HashMap::new();
+        for k in self.children.keys() {
+            let key = if fat { fat_key(k) } else { k.clone() };
+            if let Some(other) = r.insert(key, k) {
+                bail!("Files {other} and {k} are the same on FAT");
+            }
+        }
+        Ok(r)
+    }
+
     /// Determine the changes *from* self to the updated tree
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    pub(crate) fn diff(&self, updated: &Self) -> Result<FileTreeDiff> {
-        self.diff_impl(updated, true)
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub fn diff(&self, updated: &Self) -> Result<FileTreeDiff> {
+        let _span = tracing::info_span!("diff").entered();
+        self.diff_impl(updated, true, false)
+    }
+
+    /// Like [`Self::diff`], for trees on a FAT filesystem such as the ESP,
+    /// where names which only differ in case are the same file.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn diff_fat(&self, updated: &Self) -> Result<FileTreeDiff> {
+        let _span = tracing::info_span!("diff").entered();
+        self.diff_impl(updated, true, true)
     }
 
     /// Determine any changes only using the files tracked in self as
@@ -157,34 +447,43 @@ impl FileTree {
     /// files and not count them as additions.
     #[cfg(test)]
     pub(crate) fn changes(&self, current: &Self) -> Result<FileTreeDiff> {
-        self.diff_impl(current, false)
+        self.diff_impl(current, false, false)
     }
 
     /// The inverse of `changes` - determine if there are any files
     /// changed or added in `current` compared to self.
     #[cfg(test)]
     pub(crate) fn updates(&self, current: &Self) -> Result<FileTreeDiff> {
-        current.diff_impl(self, false)
+        current.diff_impl(self, false, false)
     }
 
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    fn diff_impl(&self, updated: &Self, check_additions: bool) -> Result<FileTreeDiff> {
-        let mut additions = HashSet::new();
-        let mut removals = HashSet::new();
-        let mut changes = HashSet::new();
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn diff_impl(&self, updated: &Self, check_additions: bool, fat: bool) -> Result<FileTreeDiff> {
+        let mut additions = BTreeSet::new();
+        let mut removals = BTreeSet::new();
+        let mut changes = BTreeSet::new();
 
+        // Names which only differ in case are the same file on FAT; changes
+        // use the name in the update, which is where the content comes from.
+        let key = |k: &String| if fat { fat_key(k) } else { k.clone() };
+        let updated_keys = updated.name_keys(fat)?;
         for (k, v1) in self.children.iter() {
-            if let Some(v2) = updated.children.get(k) {
-                if v1 != v2 {
-                    changes.insert(k.clone());
+            if let Some(&k2) = updated_keys.get(&key(k)) {
+                if v1 != &updated.children[k2] {
+                    changes.insert(k2.clone());
                 }
             } else {
                 removals.insert(k.clone());
             }
         }
         if check_additions {
+            let keys = self.name_keys(fat)?;
             for k in updated.children.keys() {
-                if self.children.contains_key(k) {
+                if keys.contains_key(&key(k)) {
                     continue;
                 }
                 additions.insert(k.clone());
@@ -199,10 +498,36 @@ impl FileTree {
 
     /// Create a diff from a target directory.  This will ignore
     /// any files or directories that are not part of the original tree.
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
     pub(crate) fn relative_diff_to(&self, dir: &openat::Dir) -> Result<FileTreeDiff> {
-        let mut removals = HashSet::new();
-        let mut changes = HashSet::new();
+        self.relative_diff_impl(dir, false)
+    }
+
+    /// Check the files of this tree in `dir`, as for [`Self::relative_diff_to`].
+    /// With `fast`, files whose size and mtime match those recorded by
+    /// [`Self::record_mtimes`] are assumed unchanged rather than hashed.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn validate_in(&self, dir: &openat::Dir, fast: bool) -> Result<FileTreeDiff> {
+        let _span = tracing::info_span!("diff", files = self.children.len(), fast).entered();
+        self.relative_diff_impl(dir, fast)
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn relative_diff_impl(&self, dir: &openat::Dir, fast: bool) -> Result<FileTreeDiff> {
+        let mut removals = BTreeSet::new();
+        let mut changes = BTreeSet::new();
 
         for (path, info) in self.children.iter() {
             assert!(!path.starts_with('/'));
@@ -210,6 +535,12 @@ impl FileTree {
             if let Some(meta) = dir.metadata_optional(path)? {
                 match meta.simple_type() {
                     openat::SimpleType::File => {
+                        if fast
+                            && info.mtime == Some(stat_mtime(&meta))
+                            && info.size == meta.stat().st_size as u64
+                        {
+                            continue;
+                        }
                         let target_info = FileMetadata::new_from_path(dir, path)?;
                         if info != &target_info {
                             changes.insert(path.clone());
@@ -225,56 +556,182 @@ impl FileTree {
             }
         }
         Ok(FileTreeDiff {
-            additions: HashSet::new(),
+            additions: BTreeSet::new(),
             removals,
             changes,
         })
     }
+
+    /// Record the modification time of each file as found in `dir`, just
+    /// after writing it there.  Files which can't be found are left without
+    /// one, and will always be hashed.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn record_mtimes(&mut self, dir: &openat::Dir) {
+        for (path, info) in self.children.iter_mut() {
+            info.mtime = match dir.metadata_optional(path.as_str()) {
+                Ok(m) => m.map(|m| stat_mtime(&m)),
+                Err(e) => {
+                    log::debug!("Not recording mtime of {path}: {e}");
+                    None
+                }
+            };
+        }
+    }
 }
 
-// Recursively remove all files/dirs in the directory that start with our TMP_PREFIX
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-fn cleanup_tmp(dir: &openat::Dir) -> Result<()> {
-    for entry in dir.list_dir(".")? {
-        let entry = entry?;
-        let Some(name) = entry.file_name().to_str() else {
-            // Skip invalid UTF-8 for now, we will barf on it later though.
-            continue;
-        };
+/// The modification time of a file, as recorded in [`FileMetadata::mtime`].
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn stat_mtime(meta: &openat::Metadata) -> i64 {
+    let st = meta.stat();
+    st.st_mtime * 1_000_000_000 + st.st_mtime_nsec
+}
 
-        match dir.get_file_type(&entry)? {
-            openat::SimpleType::Dir => {
-                if name.starts_with(TMP_PREFIX) {
-                    dir.remove_all(name)?;
-                    continue;
-                } else {
-                    let child = dir.sub_dir(name)?;
-                    cleanup_tmp(&child)?;
-                }
+/// Paths in `dir` left over by an interrupted operation: the working area,
+/// and files or directories with our [`TMP_PREFIX`], which older versions
+/// wrote next to the content being replaced.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn leftover_tmp(dir: &openat::Dir) -> Result<Vec<Utf8PathBuf>> {
+    fn walk(dir: &openat::Dir, prefix: &Utf8Path, r: &mut Vec<Utf8PathBuf>) -> Result<()> {
+        for entry in dir.list_dir(".")? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str() else {
+                // Skip invalid UTF-8 for now, we will barf on it later though.
+                continue;
+            };
+            let path = prefix.join(name);
+            if name == WORK_DIR || name.starts_with(TMP_PREFIX) {
+                r.push(path);
+            } else if matches!(dir.get_file_type(&entry)?, openat::SimpleType::Dir) {
+                walk(&dir.sub_dir(name)?, &path, r)?;
             }
-            openat::SimpleType::File => {
-                if name.starts_with(TMP_PREFIX) {
-                    dir.remove_file(name)?;
-                }
+        }
+        Ok(())
+    }
+    let mut r = Vec::new();
+    walk(dir, Utf8Path::new(""), &mut r)?;
+    r.sort();
+    Ok(r)
+}
+
+/// Remove everything left over by an interrupted operation; see
+/// [`leftover_tmp`].
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn cleanup_tmp(dir: &openat::Dir) -> Result<()> {
+    for path in leftover_tmp(dir)? {
+        log::debug!("Removing leftover {path}");
+        let path = path.as_std_path();
+        match dir.metadata(path)?.simple_type() {
+            openat::SimpleType::Dir => {
+                dir.remove_all(path)?;
             }
-            _ => {}
+            _ => dir.remove_file(path)?,
         }
     }
     Ok(())
 }
 
+/// Progress of applying a diff; only new and changed files are counted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) struct ApplyProgress {
+    pub(crate) files_done: u64,
+    pub(crate) files_total: u64,
+    pub(crate) bytes_done: u64,
+    pub(crate) bytes_total: u64,
+}
+
+/// Callback for progress of applying a diff.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) type ProgressFn = std::sync::Arc<dyn Fn(&ApplyProgress) + Send + Sync>;
+
 #[derive(Default, Clone)]
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) struct ApplyUpdateOptions {
     pub(crate) skip_removals: bool,
     pub(crate) skip_sync: bool,
+    /// The destination is FAT, where a file may exist under a name which
+    /// only differs in case, e.g. as created by firmware
+    pub(crate) fat: bool,
+    /// Called before copying the first file, and after each one
+    pub(crate) progress: Option<ProgressFn>,
+    /// The component updated, for the progress reported to systemd
+    pub(crate) component: Option<String>,
+}
+
+/// Report progress on stderr, overwriting the line each time if it is a
+/// terminal and otherwise only printing a summary at the end.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn terminal_progress(label: impl Into<String>) -> ProgressFn {
+    use std::io::IsTerminal;
+    let label = label.into();
+    let is_terminal = std::io::stderr().is_terminal();
+    std::sync::Arc::new(move |p: &ApplyProgress| {
+        if p.files_total == 0 {
+            return;
+        }
+        let mib = |n: u64| n as f64 / (1024.0 * 1024.0);
+        let line = format!(
+            "{label}: {}/{} files, {:.1}/{:.1} MiB",
+            p.files_done,
+            p.files_total,
+            mib(p.bytes_done),
+            mib(p.bytes_total)
+        );
+        let done = p.files_done == p.files_total;
+        if is_terminal {
+            eprint!("\r{line}");
+            if done {
+                eprintln!();
+            }
+        } else if done {
+            eprintln!("{line}");
+        } else {
+            log::debug!("{line}");
+        }
+    })
 }
 
 // syncfs() is a Linux-specific system call, which doesn't seem
 // to be bound in nix today.  I found https://github.com/XuShaohua/nc
 // but that's a nontrivial dependency with not a lot of code review.
 // Let's just fork off a helper process for now.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) fn syncfs(d: &openat::Dir) -> Result<()> {
     use rustix::fs::{Mode, OFlags};
     let d = unsafe { BorrowedFd::borrow_raw(d.as_raw_fd()) };
@@ -284,7 +741,11 @@ pub(crate) fn syncfs(d: &openat::Dir) -> Result<()> {
 }
 
 /// Copy from src to dst at root dir
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 fn copy_dir(root: &openat::Dir, src: &str, dst: &str) -> Result<()> {
     use bootc_utils::CommandRunExt;
 
@@ -301,39 +762,224 @@ fn copy_dir(root: &openat::Dir, src: &str, dst: &str) -> Result<()> {
     Ok(())
 }
 
-/// Get first sub dir and tmp sub dir for the path
-/// "fedora/foo/bar" -> ("fedora", ".btmp.fedora")
-/// "foo" -> ("foo", ".btmp.foo")
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+/// The name of `path` as FAT compares names, i.e. ignoring ASCII case
+/// (non-ASCII names are case-sensitive with the default `utf8` option).
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn fat_key(path: &str) -> String {
+    path.to_ascii_uppercase()
+}
+
+/// `path` in `dir`, with each component which exists under a name that
+/// differs only in case replaced by that name, so that e.g. an update to
+/// `BOOT/bootx64.efi` keeps the `BOOT/BOOTX64.EFI` created by firmware.
+/// Components which don't exist are kept as they are.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn on_disk_case(dir: &openat::Dir, path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let mut r = Utf8PathBuf::new();
+    let mut parent_exists = true;
+    for c in path.iter() {
+        let mut found = None;
+        if parent_exists {
+            let parent = if r.as_str().is_empty() {
+                Utf8Path::new(".")
+            } else {
+                r.as_path()
+            };
+            let entries = match dir.list_dir(parent.as_std_path()) {
+                Ok(e) => e,
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT | libc::ENOTDIR)) => {
+                    parent_exists = false;
+                    r.push(c);
+                    continue;
+                }
+                Err(e) => return Err(e).with_context(|| format!("listing {parent}")),
+            };
+            for e in entries {
+                let e = e?;
+                let Some(name) = e.file_name().to_str() else {
+                    continue;
+                };
+                if name == c {
+                    found = Some(name.to_string());
+                    break;
+                } else if name.eq_ignore_ascii_case(c) {
+                    found = Some(name.to_string());
+                }
+            }
+            parent_exists = found.is_some();
+        }
+        r.push(found.as_deref().unwrap_or(c));
+    }
+    Ok(r)
+}
+
+/// Hash `name` in `dir` as read from the storage rather than the page
+/// cache, using `O_DIRECT` if the filesystem supports it.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn hash_uncached(dir: &openat::Dir, name: &str) -> Result<FileMetadata> {
+    use rustix::fs::{Mode, OFlags};
+    use rustix::io::Errno;
+    let dirfd = unsafe { BorrowedFd::borrow_raw(dir.as_raw_fd()) };
+    let oflags = OFlags::RDONLY | OFlags::CLOEXEC | OFlags::DIRECT;
+    let fd = match rustix::fs::openat(dirfd, name, oflags, Mode::empty()) {
+        Ok(fd) => fd,
+        Err(Errno::INVAL) => {
+            // Not supported; evict the file from the cache instead
+            let f = dir.open_file(name)?;
+            unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+            return FileMetadata::new_from_path(dir, name);
+        }
+        Err(e) => return Err(e).with_context(|| format!("opening {name}")),
+    };
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE + DIRECT_IO_ALIGN];
+    let offset = buf.as_ptr().align_offset(DIRECT_IO_ALIGN);
+    let buf = &mut buf[offset..offset + HASH_BUFFER_SIZE];
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    let mut size = 0;
+    loop {
+        let n = match rustix::io::read(&fd, &mut *buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(Errno::INTR) => continue,
+            Err(e) => return Err(e).with_context(|| format!("reading {name}")),
+        };
+        hasher.update(&buf[..n])?;
+        size += n as u64;
+    }
+    Ok(FileMetadata {
+        size,
+        sha512: SHA512String::from_hasher(&mut hasher),
+        mtime: None,
+    })
+}
+
+/// Read back the files written to `destdir`, given as (path in `srcdir`,
+/// path written in `destdir`), and check that they match the source, to
+/// catch storage which silently corrupts writes.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn verify_written(
+    srcdir: &openat::Dir,
+    destdir: &openat::Dir,
+    written: &[(&str, Utf8PathBuf)],
+) -> Result<()> {
+    let uncached = crate::config::get().verify_direct_io;
+    for (src, dest) in written {
+        let expected = FileMetadata::new_from_path(srcdir, src)?;
+        let found = if uncached {
+            hash_uncached(destdir, dest.as_str())?
+        } else {
+            FileMetadata::new_from_path(destdir, dest.as_str())?
+        };
+        if found != expected {
+            bail!(
+                "Verifying {src} after writing it: read back {} ({} bytes) instead of {} ({} bytes); the storage may be failing",
+                found.sha512,
+                found.size,
+                expected.sha512,
+                expected.size
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Get first sub dir and its location in the working area for the path
+/// "fedora/foo/bar" -> ("fedora", ".bootupd-tmp/fedora")
+/// "foo" -> ("foo", ".bootupd-tmp/foo")
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 fn get_first_dir(path: &Utf8Path) -> Result<(&Utf8Path, String)> {
     let first = path
         .iter()
         .next()
         .ok_or_else(|| anyhow::anyhow!("Invalid path: {path}"))?;
-    let mut tmp = first.to_owned();
-    tmp.insert_str(0, TMP_PREFIX);
-    Ok((first.into(), tmp))
+    Ok((first.into(), format!("{WORK_DIR}/{first}")))
 }
 
 /// Given two directories, apply a diff generated from srcdir to destdir
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) fn apply_diff(
     srcdir: &openat::Dir,
     destdir: &openat::Dir,
     diff: &FileTreeDiff,
     opts: Option<&ApplyUpdateOptions>,
 ) -> Result<()> {
+    let files = diff.changes.len() + diff.additions.len();
+    let _span = tracing::info_span!("apply", files).entered();
     let default_opts = ApplyUpdateOptions {
         ..Default::default()
     };
     let opts = opts.unwrap_or(&default_opts);
+    // The path of a file in destdir
+    let dest_path = |pathstr: &str| {
+        if opts.fat {
+            on_disk_case(destdir, Utf8Path::new(pathstr))
+        } else {
+            Ok(Utf8PathBuf::from(pathstr))
+        }
+    };
     cleanup_tmp(destdir).context("cleaning up temporary files")?;
+    destdir.ensure_dir_all(WORK_DIR, DEFAULT_FILE_MODE)?;
+
+    let mut progress = ApplyProgress::default();
+    for pathstr in diff.changes.iter().chain(diff.additions.iter()) {
+        progress.files_total += 1;
+        if opts.progress.is_some() {
+            progress.bytes_total += srcdir.metadata(pathstr.as_str())?.len();
+        }
+    }
+    if let Some(cb) = opts.progress.as_ref() {
+        cb(&progress);
+    }
+
+    let mut audit = crate::audit::Recorder::new(destdir)?;
+    if let Some(audit) = audit.as_mut() {
+        let removals = diff.removals.iter().filter(|_| !opts.skip_removals);
+        let written = diff.changes.iter().chain(diff.additions.iter());
+        for pathstr in written.chain(removals) {
+            let path = dest_path(pathstr)?;
+            audit.before(destdir, path.as_str())?;
+        }
+    }
+
+    let deltas = match crate::delta::Deltas::for_payload(srcdir) {
+        Ok(d) => d,
+        Err(e) => {
+            log::debug!("Not using deltas: {e:#}");
+            None
+        }
+    };
 
     let mut updates = HashMap::new();
+    let mut written = Vec::new();
     // Handle removals in temp dir, or remove directly if file not in dir
     if !opts.skip_removals {
         for pathstr in diff.removals.iter() {
-            let path = Utf8Path::new(pathstr);
+            let path = dest_path(pathstr)?;
+            let path = path.as_path();
             let (first_dir, first_dir_tmp) = get_first_dir(path)?;
             let path_tmp;
             if first_dir != path {
@@ -341,7 +987,7 @@ pub(crate) fn apply_diff(
                 // copy to temp dir and remember
                 if !destdir.exists(&first_dir_tmp)? {
                     copy_dir(destdir, first_dir.as_str(), &first_dir_tmp)?;
-                    updates.insert(first_dir, first_dir_tmp);
+                    updates.insert(first_dir.to_path_buf(), first_dir_tmp);
                 }
             } else {
                 path_tmp = path.to_path_buf();
@@ -354,14 +1000,16 @@ pub(crate) fn apply_diff(
     // Write changed or new files to temp dir or temp file
     for pathstr in diff.changes.iter().chain(diff.additions.iter()) {
         let path = Utf8Path::new(pathstr);
-        let (first_dir, first_dir_tmp) = get_first_dir(path)?;
+        // Write to the existing file if it differs only in case
+        let dest = dest_path(pathstr)?;
+        let (first_dir, first_dir_tmp) = get_first_dir(&dest)?;
         let mut path_tmp = Utf8PathBuf::from(&first_dir_tmp);
-        if first_dir != path {
+        if first_dir != dest.as_path() {
             if !destdir.exists(&first_dir_tmp)? && destdir.exists(first_dir.as_std_path())? {
                 // copy to temp dir if not exists
                 copy_dir(destdir, first_dir.as_str(), &first_dir_tmp)?;
             }
-            path_tmp = path_tmp.join(path.strip_prefix(&first_dir)?);
+            path_tmp = path_tmp.join(dest.strip_prefix(first_dir)?);
             // ensure new additions dir exists
             if let Some(parent) = path_tmp.parent() {
                 destdir.ensure_dir_all(parent.as_std_path(), DEFAULT_FILE_MODE)?;
@@ -371,12 +1019,43 @@ pub(crate) fn apply_diff(
                 .remove_file_optional(path_tmp.as_std_path())
                 .with_context(|| format!("removing {path_tmp} before copying"))?;
         }
-        updates.insert(first_dir, first_dir_tmp);
-        srcdir
-            .copy_file_at(path.as_std_path(), destdir, path_tmp.as_std_path())
-            .with_context(|| format!("copying {:?} to {:?}", path, path_tmp))?;
+        updates.insert(first_dir.to_path_buf(), first_dir_tmp);
+        let patched = match deltas.as_ref() {
+            Some(deltas) if diff.changes.contains(pathstr) => deltas
+                .apply(srcdir, pathstr, destdir, &dest, &path_tmp)
+                .unwrap_or_else(|e| {
+                    log::warn!("Copying {pathstr} in full: {e:#}");
+                    false
+                }),
+            _ => false,
+        };
+        if !patched {
+            srcdir
+                .copy_file_at(path.as_std_path(), destdir, path_tmp.as_std_path())
+                .with_context(|| format!("copying {:?} to {:?}", path, path_tmp))?;
+        }
+        written.push((pathstr.as_str(), path_tmp));
+        progress.files_done += 1;
+        crate::notify::progress(
+            "apply",
+            opts.component.as_deref(),
+            &[(
+                "files",
+                format!("{}/{}", progress.files_done, progress.files_total),
+            )],
+        );
+        if let Some(cb) = opts.progress.as_ref() {
+            progress.bytes_done += srcdir.metadata(path.as_std_path())?.len();
+            cb(&progress);
+        }
     }
 
+    // Before anything is swapped into place, so that the old content is
+    // left alone if this fails
+    crate::notify::progress("verify", opts.component.as_deref(), &[]);
+    verify_written(srcdir, destdir, &written)?;
+
+    crate::try_fail_point!("update::apply-diff");
     // do local exchange or rename
     for (dst, tmp) in updates.iter() {
         let dst = dst.as_std_path();
@@ -394,14 +1073,16 @@ pub(crate) fn apply_diff(
     }
     // Ensure all of the updates & changes are written persistently to disk
     if !opts.skip_sync {
+        crate::notify::progress("sync", opts.component.as_deref(), &[]);
         syncfs(destdir)?;
     }
 
-    // finally remove the temp dir
-    for (_, tmp) in updates.iter() {
-        log::trace!("cleanup: {}", tmp);
-        destdir.remove_all(tmp).context("clean up temp")?;
+    if let Some(audit) = audit {
+        audit.commit(destdir)?;
     }
+
+    // finally remove the working area, which now holds the old content
+    destdir.remove_all(WORK_DIR).context("clean up temp")?;
     // A second full filesystem sync to narrow any races rather than
     // waiting for writeback to kick in.
     if !opts.skip_sync {
@@ -410,6 +1091,47 @@ pub(crate) fn apply_diff(
     Ok(())
 }
 
+/// Remove all files in `tree` from `destdir`, along with any directories
+/// left empty as a result.  Files not in the tree are left untouched.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn remove_tree(destdir: &openat::Dir, tree: &FileTree) -> Result<()> {
+    cleanup_tmp(destdir).context("cleaning up temporary files")?;
+    let mut audit = crate::audit::Recorder::new(destdir)?;
+    let mut dirs = BTreeSet::new();
+    for pathstr in tree.children.keys() {
+        let path = Utf8Path::new(pathstr);
+        if let Some(audit) = audit.as_mut() {
+            audit.before(destdir, pathstr)?;
+        }
+        destdir
+            .remove_file_optional(path.as_std_path())
+            .with_context(|| format!("removing {path}"))?;
+        dirs.extend(
+            path.ancestors()
+                .skip(1)
+                .filter(|p| !p.as_str().is_empty())
+                .map(|p| p.to_owned()),
+        );
+    }
+    // Reverse order visits children before their parents
+    for dir in dirs.iter().rev() {
+        match destdir.remove_dir(dir.as_std_path()) {
+            Ok(()) => log::trace!("removed empty dir {dir}"),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTEMPTY | libc::ENOENT)) => {}
+            Err(e) => return Err(e).with_context(|| format!("removing {dir}")),
+        }
+    }
+    syncfs(destdir)?;
+    if let Some(audit) = audit {
+        audit.commit(destdir)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -579,18 +1301,279 @@ mod tests {
         assert!(!a.join(relp).join("shim.x64").exists());
         Ok(())
     }
+    #[test]
+    fn test_split_toplevel() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        for d in ["fedora", "centos", "BOOT"] {
+            fs::create_dir_all(p.join(d))?;
+            fs::write(p.join(d).join("shimx64.efi"), format!("{d} shim"))?;
+        }
+        fs::write(p.join("fedora/grub.cfg"), "grub config")?;
+        let d = openat::Dir::open(p)?;
+        let t = FileTree::new_from_dir(&d)?;
+        let split = t.split_toplevel();
+        assert_eq!(
+            split.keys().collect::<Vec<_>>(),
+            ["BOOT", "centos", "fedora"]
+        );
+        let fedora = split.get("fedora").unwrap();
+        assert_eq!(fedora.children.len(), 2);
+        assert!(fedora.children.contains_key("fedora/grub.cfg"));
+        assert_eq!(FileTree::merge(split.values()), t);
+        Ok(())
+    }
+    #[test]
+    fn test_describe_diff() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        for (d, files) in [
+            (
+                "a",
+                &[("fedora/shim.efi", "v1"), ("fedora/mm.efi", "v1")][..],
+            ),
+            (
+                "b",
+                &[("fedora/shim.efi", "v02"), ("BOOT/boot.efi", "v1")][..],
+            ),
+        ] {
+            crate::testutils::write_tree(&p.join(d), files)?;
+        }
+        let ta = FileTree::new_from_dir(&openat::Dir::open(&p.join("a"))?)?;
+        let tb = FileTree::new_from_dir(&openat::Dir::open(&p.join("b"))?)?;
+        let changes = ta.diff(&tb)?.describe(&ta, &tb);
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| {
+                (
+                    c.path.as_str(),
+                    c.kind,
+                    c.current.is_some(),
+                    c.update.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("BOOT/boot.efi", FileChangeKind::Added, false, true),
+                ("fedora/mm.efi", FileChangeKind::Removed, true, false),
+                ("fedora/shim.efi", FileChangeKind::Changed, true, true),
+            ]
+        );
+        let shim = changes[2].to_string();
+        assert!(shim.starts_with("M fedora/shim.efi (2 -> 3 bytes, sha512:"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_tree() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("fedora/fw"))?;
+        fs::write(p.join("fedora/shimx64.efi"), "shim")?;
+        fs::write(p.join("fedora/fw/firmware.bin"), "firmware")?;
+        let d = openat::Dir::open(p)?;
+        let t = FileTree::new_from_dir(&d)?;
+        fs::create_dir_all(p.join("centos"))?;
+        fs::write(p.join("centos/shimx64.efi"), "shim")?;
+        fs::write(p.join("fedora/user.cfg"), "not ours")?;
+        remove_tree(&d, &t)?;
+        assert!(!d.exists("fedora/shimx64.efi")?);
+        assert!(!d.exists("fedora/fw")?);
+        assert!(d.exists("fedora/user.cfg")?);
+        assert!(d.exists("centos/shimx64.efi")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fat_case() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        // As created by firmware, and as shipped in the update
+        crate::testutils::write_tree(
+            p,
+            &[
+                ("esp/BOOT/BOOTX64.EFI", "shim v1"),
+                ("esp/BOOT/FBX64.EFI", "fallback"),
+                ("update/BOOT/bootx64.efi", "shim v2"),
+                ("update/BOOT/fbx64.efi", "fallback"),
+            ],
+        )?;
+        let esp = openat::Dir::open(&p.join("esp"))?;
+        let update = openat::Dir::open(&p.join("update"))?;
+        let tesp = FileTree::new_from_dir(&esp)?;
+        let tupdate = FileTree::new_from_dir(&update)?;
+        // Elsewhere, the names are different files
+        let diff = tesp.diff(&tupdate)?;
+        assert_eq!(diff.additions.len(), 2);
+        assert_eq!(diff.removals.len(), 2);
+        let diff = tesp.diff_fat(&tupdate)?;
+        assert!(diff.additions.is_empty());
+        assert!(diff.removals.is_empty());
+        assert_eq!(
+            diff.changes,
+            BTreeSet::from(["BOOT/bootx64.efi".to_string()])
+        );
+        // The installed file is found under the name it has on the ESP
+        let changes = diff.describe(&tesp, &tupdate);
+        let installed = &tesp.children["BOOT/BOOTX64.EFI"];
+        let shipped = &tupdate.children["BOOT/bootx64.efi"];
+        assert_eq!(
+            changes,
+            [FileChange {
+                path: "BOOT/bootx64.efi".into(),
+                kind: FileChangeKind::Changed,
+                current: Some(installed.clone()),
+                update: Some(shipped.clone()),
+            }]
+        );
+        assert_eq!(
+            changes[0].to_string(),
+            format!(
+                "M BOOT/bootx64.efi (7 -> 7 bytes, {} -> {})",
+                installed.sha512, shipped.sha512
+            )
+        );
+
+        let opts = ApplyUpdateOptions {
+            skip_sync: true,
+            fat: true,
+            ..Default::default()
+        };
+        apply_diff(&update, &esp, &diff, Some(&opts))?;
+        assert_eq!(
+            fs::read_to_string(p.join("esp/BOOT/BOOTX64.EFI"))?,
+            "shim v2"
+        );
+        assert!(!p.join("esp/BOOT/bootx64.efi").exists());
+        assert!(leftover_tmp(&esp)?.is_empty());
+
+        let mut colliding = tupdate.clone();
+        colliding.children.insert(
+            "boot/BOOTX64.EFI".into(),
+            tupdate.children["BOOT/bootx64.efi"].clone(),
+        );
+        let e = tesp.diff_fat(&colliding).unwrap_err();
+        assert!(format!("{e:#}").contains("are the same on FAT"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_serialize() -> Result<()> {
+        let meta = |sha512: &str| FileMetadata {
+            size: 1,
+            sha512: SHA512String(sha512.into()),
+            mtime: None,
+        };
+        let tree = |files: &[(&str, &str)]| FileTree {
+            children: files
+                .iter()
+                .map(|(k, v)| (k.to_string(), meta(v)))
+                .collect(),
+        };
+        let a = tree(&[("z", "sha512:aa"), ("b/c", "sha512:bb"), ("a", "sha512:cc")]);
+        let b = tree(&[("y", "sha512:aa"), ("b/c", "sha512:dd"), ("a", "sha512:ee")]);
+        let diff = a.diff(&b)?;
+        assert_eq!(
+            serde_json::to_string(&diff)?,
+            r#"{"additions":["y"],"removals":["z"],"changes":["a","b/c"]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&a)?,
+            r#"{"children":{"a":{"size":1,"sha512":"sha512:cc"},"b/c":{"size":1,"sha512":"sha512:bb"},"z":{"size":1,"sha512":"sha512:aa"}}}"#
+        );
+        let changes = diff.describe(&a, &b);
+        assert_eq!(
+            changes
+                .iter()
+                .map(|c| (c.kind, c.path.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (FileChangeKind::Changed, "a"),
+                (FileChangeKind::Changed, "b/c"),
+                (FileChangeKind::Added, "y"),
+                (FileChangeKind::Removed, "z")
+            ]
+        );
+        assert_eq!(changes[3].to_string(), "D z (1 bytes, sha512:aa)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_large_file() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let d = openat::Dir::open(tmpd.path())?;
+        // Spans several buffers, ending with a partial one
+        let data = (0..3 * HASH_BUFFER_SIZE + 17)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(tmpd.path().join("uki.efi"), &data)?;
+        let meta = FileMetadata::new_from_path(&d, "uki.efi")?;
+        let expected = openssl::hash::hash(MessageDigest::sha512(), &data)?;
+        assert_eq!(meta.size, data.len() as u64);
+        assert_eq!(meta.sha512.0, format!("sha512:{}", hex::encode(expected)));
+        let t = FileTree::new_from_dir(&d)?;
+        assert_eq!(t.total_size(), data.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_fast() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir(p.join("fedora"))?;
+        fs::write(p.join("fedora/shimx64.efi"), "shim")?;
+        fs::write(p.join("fedora/grubx64.efi"), "grub")?;
+        let d = openat::Dir::open(p)?;
+        let mut t = FileTree::new_from_dir(&d)?;
+        t.record_mtimes(&d);
+        assert!(t.children.values().all(|m| m.mtime.is_some()));
+        // Only the content matters for comparisons
+        assert_eq!(t, FileTree::new_from_dir(&d)?);
+        assert!(t.validate_in(&d, true)?.is_empty());
+
+        // Corrupt a file in place, keeping its size and mtime; only full
+        // validation notices
+        let shim = p.join("fedora/shimx64.efi");
+        let mtime = fs::metadata(&shim)?.modified()?;
+        fs::write(&shim, "SHIM")?;
+        fs::File::options()
+            .write(true)
+            .open(&shim)?
+            .set_modified(mtime)?;
+        assert!(t.validate_in(&d, true)?.is_empty());
+        assert_eq!(
+            t.validate_in(&d, false)?.changes,
+            BTreeSet::from(["fedora/shimx64.efi".to_string()])
+        );
+
+        fs::write(p.join("fedora/grubx64.efi"), "grub2")?;
+        fs::remove_file(&shim)?;
+        let diff = t.validate_in(&d, true)?;
+        assert_eq!(
+            diff.changes,
+            BTreeSet::from(["fedora/grubx64.efi".to_string()])
+        );
+        assert_eq!(
+            diff.removals,
+            BTreeSet::from(["fedora/shimx64.efi".to_string()])
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_get_first_dir() -> Result<()> {
         // test path
         let path = Utf8Path::new("foo/subdir/bar");
         let (tp, tp_tmp) = get_first_dir(path)?;
         assert_eq!(tp, Utf8Path::new("foo"));
-        assert_eq!(tp_tmp, ".btmp.foo");
+        assert_eq!(tp_tmp, ".bootupd-tmp/foo");
         // test file
         let path = Utf8Path::new("testfile");
         let (tp, tp_tmp) = get_first_dir(path)?;
         assert_eq!(tp, Utf8Path::new("testfile"));
-        assert_eq!(tp_tmp, ".btmp.testfile");
+        assert_eq!(tp_tmp, ".bootupd-tmp/testfile");
         Ok(())
     }
     #[test]
@@ -616,11 +1599,95 @@ mod tests {
         assert!(dp.exists("a/.btmp.a")?);
         assert!(dp.exists(".btmp.b/b")?);
         assert!(dp.exists(".btmp.b/foo")?);
+        std::fs::create_dir_all(p.join(".bootupd-tmp/fedora"))?;
+        std::fs::create_dir_all(p.join("a/.bootupd-tmp"))?;
+        assert_eq!(
+            leftover_tmp(&dp)?,
+            [
+                ".bootupd-tmp",
+                ".btmp.b",
+                "a/.bootupd-tmp",
+                "a/.btmp.a",
+                "a/.btmp.foo"
+            ]
+        );
         cleanup_tmp(&dp)?;
         assert!(!dp.exists("a/.btmp.a")?);
         assert!(dp.exists("a/foo")?);
         assert!(!dp.exists("a/.btmp.foo")?);
         assert!(!dp.exists(".btmp.b")?);
+        assert!(!dp.exists(".bootupd-tmp")?);
+        assert!(!dp.exists("a/.bootupd-tmp")?);
+        assert!(leftover_tmp(&dp)?.is_empty());
+
+        // The working area isn't content
+        std::fs::create_dir_all(p.join(".bootupd-tmp"))?;
+        std::fs::write(p.join(".bootupd-tmp/foo"), "old")?;
+        let t = FileTree::new_from_dir(&dp)?;
+        assert_eq!(t.children.keys().collect::<Vec<_>>(), ["a/foo"]);
+        Ok(())
+    }
+    #[test]
+    fn test_apply_progress() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        let a = crate::testutils::write_tree(
+            &p.join("a"),
+            &[("fedora/shim", "shim"), ("fedora/grub", "grub2")],
+        )?;
+        fs::create_dir(p.join("b"))?;
+        let b = openat::Dir::open(&p.join("b"))?;
+        let diff = run_diff(&b, &a)?;
+        let reports = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let opts = ApplyUpdateOptions {
+            progress: Some({
+                let reports = reports.clone();
+                std::sync::Arc::new(move |p: &ApplyProgress| reports.lock().unwrap().push(*p))
+            }),
+            ..Default::default()
+        };
+        apply_diff(&a, &b, &diff, Some(&opts))?;
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].files_done, 0);
+        assert_eq!(
+            *reports.last().unwrap(),
+            ApplyProgress {
+                files_done: 2,
+                files_total: 2,
+                bytes_done: 9,
+                bytes_total: 9
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_written() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let src = td.path().join("src");
+        let dest = td.path().join("dest");
+        std::fs::create_dir_all(src.join("fedora"))?;
+        std::fs::create_dir_all(dest.join("fedora"))?;
+        // Larger than one read, and not a multiple of the alignment
+        let data = (0..HASH_BUFFER_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(src.join("fedora/grubx64.efi"), &data)?;
+        std::fs::write(dest.join("fedora/grubx64.efi"), &data)?;
+        let srcdir = openat::Dir::open(&src)?;
+        let destdir = openat::Dir::open(&dest)?;
+
+        let expected = FileMetadata::new_from_path(&srcdir, "fedora/grubx64.efi")?;
+        assert_eq!(hash_uncached(&destdir, "fedora/grubx64.efi")?, expected);
+        let written = [(
+            "fedora/grubx64.efi",
+            Utf8PathBuf::from("fedora/grubx64.efi"),
+        )];
+        verify_written(&srcdir, &destdir, &written)?;
+
+        std::fs::write(dest.join("fedora/grubx64.efi"), &data[1..])?;
+        assert!(verify_written(&srcdir, &destdir, &written).is_err());
         Ok(())
     }
     // Waiting on https://github.com/rust-lang/rust/pull/125692
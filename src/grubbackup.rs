@@ -0,0 +1,245 @@
+//! Backups of the GRUB config taken when migrating to a static config, so
+//! that the migration can be rolled back.
+//!
+//! Each backup is a copy of the config as `grub.cfg.backup-<timestamp>`,
+//! next to a `.json` file recording the state needed to restore it.  Older
+//! versions only wrote a single `grub.cfg.backup`, which is listed too.
+//! That one and the oldest timestamped backup hold the config from before
+//! the migration, so they are never removed.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+/// Prefix of backup file names.
+const PREFIX: &str = "grub.cfg.backup";
+/// Timestamp format in backup file names; sorts chronologically.
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+/// Backups kept, unless configured otherwise.
+const DEFAULT_KEEP: usize = 5;
+
+/// The state of the system when a backup was taken.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BackupMeta {
+    /// Target of `grub.cfg` if it was a symlink, e.g. to the config
+    /// generated by ostree
+    pub(crate) symlink: Option<PathBuf>,
+    /// Value of `sysroot.bootloader` in the ostree repo config, if set
+    pub(crate) bootloader: Option<String>,
+    /// The customizations of the config which were moved to `user.cfg`,
+    /// as appended to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) user_cfg: Option<String>,
+    /// Likewise for the menu entries moved to `custom.cfg`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) custom_cfg: Option<String>,
+}
+
+/// A saved GRUB config.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Backup {
+    /// File name in the GRUB config directory
+    pub(crate) name: String,
+    /// When it was taken; `None` for the legacy backup
+    pub(crate) timestamp: Option<NaiveDateTime>,
+}
+
+fn meta_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{name}.json"))
+}
+
+/// List the backups in the GRUB config directory `dir`, oldest first.
+pub(crate) fn list(dir: &Path) -> Result<Vec<Backup>> {
+    let mut r = Vec::new();
+    for e in std::fs::read_dir(dir).with_context(|| format!("Reading {dir:?}"))? {
+        let name = e?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name == PREFIX {
+            r.push(Backup {
+                name: name.to_string(),
+                timestamp: None,
+            });
+        } else if let Some(ts) = name.strip_prefix(PREFIX).and_then(|s| s.strip_prefix('-')) {
+            // Skips the metadata files too
+            if let Ok(timestamp) = NaiveDateTime::parse_from_str(ts, TIMESTAMP_FORMAT) {
+                r.push(Backup {
+                    name: name.to_string(),
+                    timestamp: Some(timestamp),
+                });
+            }
+        }
+    }
+    r.sort_by_key(|b| b.timestamp);
+    Ok(r)
+}
+
+/// Remove all but the newest `keep` backups, besides the legacy and the
+/// oldest one.
+fn rotate(dir: &Path, keep: usize) -> Result<()> {
+    let backups = list(dir)?;
+    let rotated = backups
+        .iter()
+        .filter(|b| b.timestamp.is_some())
+        .skip(1)
+        .collect::<Vec<_>>();
+    let n = rotated.len().saturating_sub(keep);
+    for b in &rotated[..n] {
+        log::debug!("Removing old GRUB config backup {}", b.name);
+        std::fs::remove_file(dir.join(&b.name))?;
+        let meta = meta_path(dir, &b.name);
+        if meta.exists() {
+            std::fs::remove_file(meta)?;
+        }
+    }
+    Ok(())
+}
+
+/// Save a copy of `config` in the GRUB config directory `dir`, then
+/// remove the oldest backups beyond the configured number (see [`rotate`]);
+/// returns the name of the backup.
+#[context("Backing up GRUB config {}", config.display())]
+pub(crate) fn backup(
+    dir: &Path,
+    config: &Path,
+    meta: &BackupMeta,
+    now: DateTime<Utc>,
+) -> Result<String> {
+    let name = format!("{PREFIX}-{}", now.format(TIMESTAMP_FORMAT));
+    let path = dir.join(&name);
+    if path.exists() {
+        bail!("Backup {name} already exists");
+    }
+    std::fs::copy(config, &path)?;
+    std::fs::write(meta_path(dir, &name), serde_json::to_vec(meta)?)?;
+    let keep = crate::config::get()
+        .grub_config_backups
+        .unwrap_or(DEFAULT_KEEP);
+    // Always keep the backup we just took
+    rotate(dir, keep.max(1))?;
+    Ok(name)
+}
+
+/// Restore the backup `name` (by default the newest) in the GRUB config
+/// directory `dir`, returning the state recorded with it.  If `grub.cfg`
+/// was a symlink and its target still exists, the symlink is restored,
+/// otherwise the saved copy.
+#[context("Restoring GRUB config backup")]
+pub(crate) fn restore(dir: &Path, name: Option<&str>) -> Result<(String, BackupMeta)> {
+    let name = match name {
+        Some(n) => {
+            if !list(dir)?.iter().any(|b| b.name == n) {
+                bail!("No such backup: {n}");
+            }
+            n.to_string()
+        }
+        None => match list(dir)?.pop() {
+            Some(b) => b.name,
+            None => bail!("No GRUB config backups in {}", dir.display()),
+        },
+    };
+    let meta = match std::fs::read(meta_path(dir, &name)) {
+        Ok(m) => serde_json::from_slice(&m).with_context(|| format!("Parsing {name}.json"))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BackupMeta::default(),
+        Err(e) => return Err(e.into()),
+    };
+    // Replace grub.cfg atomically
+    let tmp = dir.join("grub.cfg.restore");
+    let _ = std::fs::remove_file(&tmp);
+    match meta.symlink.as_ref() {
+        Some(target) if dir.join(target).exists() => {
+            std::os::unix::fs::symlink(target, &tmp)?;
+        }
+        _ => {
+            std::fs::copy(dir.join(&name), &tmp)?;
+        }
+    }
+    std::fs::rename(&tmp, dir.join("grub.cfg"))?;
+    Ok((name, meta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_restore() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = td.path();
+        std::fs::write(dir.join("grub.cfg.backup"), "legacy")?;
+        std::fs::write(dir.join("ostree.cfg"), "ostree")?;
+        std::fs::write(dir.join("grub.cfg"), "static")?;
+        let t0 = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+
+        let names = (0..DEFAULT_KEEP as i64 + 2)
+            .map(|i| {
+                let meta = BackupMeta {
+                    symlink: (i == 0).then(|| "ostree.cfg".into()),
+                    bootloader: Some(format!("grub2-{i}")),
+                    user_cfg: None,
+                    custom_cfg: None,
+                };
+                backup(
+                    dir,
+                    &dir.join("grub.cfg"),
+                    &meta,
+                    t0 + chrono::Duration::hours(i),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(names[0], "grub.cfg.backup-20240102-030405");
+        let last = t0 + chrono::Duration::hours(DEFAULT_KEEP as i64 + 1);
+        assert!(backup(dir, &dir.join("grub.cfg"), &Default::default(), last).is_err());
+
+        // The legacy and the oldest backups are from before the migration,
+        // so the second oldest was removed
+        let backups = list(dir)?;
+        assert_eq!(backups.len(), DEFAULT_KEEP + 2);
+        assert_eq!(backups[0].name, "grub.cfg.backup");
+        assert_eq!(
+            backups[1..].iter().map(|b| &b.name).collect::<Vec<_>>(),
+            std::iter::once(&names[0])
+                .chain(&names[2..])
+                .collect::<Vec<_>>()
+        );
+        assert!(!meta_path(dir, "grub.cfg.backup-20240102-040405").exists());
+
+        std::fs::write(dir.join("grub.cfg"), "broken")?;
+        let (name, meta) = restore(dir, None)?;
+        assert_eq!(&name, names.last().unwrap());
+        assert_eq!(meta.bootloader.as_deref(), Some("grub2-6"));
+        assert_eq!(std::fs::read_to_string(dir.join("grub.cfg"))?, "static");
+
+        assert!(restore(dir, Some("grub.cfg.backup-20240102-040405")).is_err());
+        restore(dir, Some("grub.cfg.backup"))?;
+        assert_eq!(std::fs::read_to_string(dir.join("grub.cfg"))?, "legacy");
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_symlink() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = td.path();
+        std::fs::write(dir.join("ostree.cfg"), "ostree")?;
+        std::fs::write(dir.join("grub.cfg"), "ostree")?;
+        let meta = BackupMeta {
+            symlink: Some("ostree.cfg".into()),
+            bootloader: None,
+            user_cfg: Some("set timeout=30\n".into()),
+            custom_cfg: Some("menuentry 'Rescue' {\n}\n".into()),
+        };
+        let name = backup(dir, &dir.join("grub.cfg"), &meta, Utc::now())?;
+        std::fs::write(dir.join("grub.cfg"), "static")?;
+        assert_eq!(restore(dir, Some(&name))?, (name, meta));
+        assert_eq!(
+            std::fs::read_link(dir.join("grub.cfg"))?,
+            Path::new("ostree.cfg")
+        );
+        Ok(())
+    }
+}
@@ -10,11 +10,16 @@ const GRUB2DIR: &str = "grub2";
 const CONFIGDIR: &str = "/usr/lib/bootupd/grub2-static";
 const DROPINDIR: &str = "configs.d";
 
+/// Label `name` in `/boot` with its default SELinux label.
+fn label(bootdir: &openat::Dir, name: &str) {
+    crate::label::set_default_label(bootdir, name, &Path::new("/boot").join(name));
+}
+
 /// Install the static GRUB config files.
 #[context("Installing static GRUB configs")]
 pub(crate) fn install(
     target_root: &openat::Dir,
-    installed_efi_vendor: Option<&str>,
+    installed_efi_vendors: &[String],
     write_uuid: bool,
 ) -> Result<()> {
     let bootdir = &target_root.sub_dir("boot").context("Opening /boot")?;
@@ -27,7 +32,9 @@ pub(crate) fn install(
 
     if !bootdir.exists(GRUB2DIR)? {
         bootdir.create_dir(GRUB2DIR, 0o700)?;
+        label(bootdir, GRUB2DIR);
     }
+    let mut audit = crate::audit::Recorder::new(bootdir)?;
 
     let mut config = std::fs::read_to_string(Path::new(CONFIGDIR).join("grub-static-pre.cfg"))?;
 
@@ -48,9 +55,13 @@ pub(crate) fn install(
             continue;
         }
         writeln!(config, "source $prefix/{name}")?;
+        if let Some(audit) = audit.as_mut() {
+            audit.before(bootdir, &format!("{GRUB2DIR}/{name}"))?;
+        }
         dropindir
             .copy_file_at(name, bootdir, format!("{GRUB2DIR}/{name}"))
             .with_context(|| format!("Copying {name}"))?;
+        label(bootdir, &format!("{GRUB2DIR}/{name}"));
         println!("Installed {name}");
     }
 
@@ -59,9 +70,13 @@ pub(crate) fn install(
         config.push_str(post.as_str());
     }
 
+    if let Some(audit) = audit.as_mut() {
+        audit.before(bootdir, &format!("{GRUB2DIR}/grub.cfg"))?;
+    }
     bootdir
         .write_file_contents(format!("{GRUB2DIR}/grub.cfg"), 0o644, config.as_bytes())
         .context("Copying grub-static.cfg")?;
+    label(bootdir, &format!("{GRUB2DIR}/grub.cfg"));
     println!("Installed: grub.cfg");
 
     let uuid_path = if write_uuid {
@@ -72,36 +87,56 @@ pub(crate) fn install(
             .ok_or_else(|| anyhow::anyhow!("Failed to find UUID for boot"))?;
         let grub2_uuid_contents = format!("set BOOT_UUID=\"{bootfs_uuid}\"\n");
         let uuid_path = format!("{GRUB2DIR}/bootuuid.cfg");
+        if let Some(audit) = audit.as_mut() {
+            audit.before(bootdir, &uuid_path)?;
+        }
         bootdir
             .write_file_contents(&uuid_path, 0o644, grub2_uuid_contents)
             .context("Writing bootuuid.cfg")?;
+        label(bootdir, &uuid_path);
         Some(uuid_path)
     } else {
         None
     };
+    if let Some(audit) = audit {
+        audit.commit(bootdir)?;
+    }
 
-    if let Some(vendordir) = installed_efi_vendor {
+    let dest_efidir = target_root
+        .sub_dir_optional("boot/efi/EFI")
+        .context("Opening /boot/efi/EFI")?;
+    let mut efi_audit = match dest_efidir.as_ref() {
+        Some(efidir) => crate::audit::Recorder::new(efidir)?,
+        None => None,
+    };
+    for vendordir in installed_efi_vendors {
         log::debug!("vendordir={:?}", &vendordir);
         let vendor = PathBuf::from(vendordir);
         let target = &vendor.join("grub.cfg");
-        let dest_efidir = target_root
-            .sub_dir_optional("boot/efi/EFI")
-            .context("Opening /boot/efi/EFI")?;
-        if let Some(efidir) = dest_efidir {
+        if let Some(efidir) = dest_efidir.as_ref() {
+            if let Some(audit) = efi_audit.as_mut() {
+                audit.before(efidir, &format!("{vendordir}/grub.cfg"))?;
+                if uuid_path.is_some() {
+                    audit.before(efidir, &format!("{vendordir}/bootuuid.cfg"))?;
+                }
+            }
             efidir
                 .copy_file(&Path::new(CONFIGDIR).join("grub-static-efi.cfg"), target)
                 .context("Copying static EFI")?;
             println!("Installed: {target:?}");
-            if let Some(uuid_path) = uuid_path {
+            if let Some(uuid_path) = uuid_path.as_deref() {
                 // SAFETY: we always have a filename
-                let filename = Path::new(&uuid_path).file_name().unwrap();
+                let filename = Path::new(uuid_path).file_name().unwrap();
                 let target = &vendor.join(filename);
                 bootdir
-                    .copy_file_at(uuid_path, &efidir, target)
+                    .copy_file_at(uuid_path, efidir, target)
                     .context("Writing bootuuid.cfg to efi dir")?;
             }
         }
     }
+    if let (Some(audit), Some(efidir)) = (efi_audit, dest_efidir.as_ref()) {
+        audit.commit(efidir)?;
+    }
 
     Ok(())
 }
@@ -120,7 +155,7 @@ mod tests {
         std::fs::create_dir_all(tdp.join("boot/grub2"))?;
         std::fs::create_dir_all(tdp.join("boot/efi/EFI/BOOT"))?;
         std::fs::create_dir_all(tdp.join("boot/efi/EFI/fedora"))?;
-        install(&td, Some("fedora"), false).unwrap();
+        install(&td, &["fedora".to_string()], false).unwrap();
 
         assert!(td.exists("boot/grub2/grub.cfg")?);
         assert!(td.exists("boot/efi/EFI/fedora/grub.cfg")?);
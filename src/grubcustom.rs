@@ -0,0 +1,378 @@
+//! Local customizations of a GRUB config generated by `grub2-mkconfig`,
+//! carried over when migrating to a static config.
+//!
+//! The generated config consists of one section per script in
+//! `/etc/grub.d`, between `### BEGIN /etc/grub.d/<script> ###` and
+//! `### END ... ###` lines.  We consider local to the system: sections of
+//! scripts not shipped by GRUB itself, the entries added to `40_custom`,
+//! and anything outside of the sections, i.e. edits of the config itself
+//! (e.g. `set timeout=...`).  The entries `os-prober` found for other
+//! systems on the machine (e.g. Windows, or another Linux install) are
+//! kept too, since the static config doesn't run it.  These are moved to
+//! drop-ins, so that they survive the config being replaced: the menu
+//! entries to `custom.cfg`, which is sourced after the generated entries
+//! (by GRUB's `41_custom` section, or the static config), so that numeric
+//! `GRUB_DEFAULT`/`saved_entry` indexes still refer to the same entries;
+//! the rest to `user.cfg`, which both the static config and Fedora's
+//! `01_users` section source before them.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use openat_ext::OpenatDirExt;
+
+/// The section of the entries generated by ostree.
+const OSTREE_SECTION: &str = "/etc/grub.d/15_ostree";
+
+/// The section holding the entries added by the admin.
+const CUSTOM_SECTION: &str = "/etc/grub.d/40_custom";
+
+/// The section holding the entries of other systems.
+const OS_PROBER_SECTION: &str = "/etc/grub.d/30_os-prober";
+
+/// Sections of the scripts shipped with GRUB, which are regenerated.
+const STANDARD_SECTIONS: &[&str] = &[
+    "/etc/grub.d/00_header",
+    "/etc/grub.d/00_tuned",
+    "/etc/grub.d/01_users",
+    "/etc/grub.d/08_fallback_counting",
+    "/etc/grub.d/10_linux",
+    "/etc/grub.d/10_reset_boot_success",
+    "/etc/grub.d/12_menu_auto_hide",
+    "/etc/grub.d/14_menu_show_once",
+    OSTREE_SECTION,
+    "/etc/grub.d/20_linux_xen",
+    "/etc/grub.d/20_ppc_terminfo",
+    OS_PROBER_SECTION,
+    "/etc/grub.d/30_uefi-firmware",
+    "/etc/grub.d/35_fwupd",
+    CUSTOM_SECTION,
+    "/etc/grub.d/41_custom",
+];
+
+/// The drop-in customizations other than menu entries are moved to.
+pub(crate) const USER_CFG: &str = "user.cfg";
+
+/// The drop-in menu entries are moved to.
+pub(crate) const CUSTOM_CFG: &str = "custom.cfg";
+
+/// Sources `user.cfg`, for configs which don't already.
+const SOURCE_USER_CFG: &str = "\
+if [ -f $prefix/user.cfg ]; then
+  source $prefix/user.cfg
+fi
+";
+
+/// Sources `custom.cfg`, for configs which don't already.
+const SOURCE_CUSTOM_CFG: &str = "\
+if [ -f $prefix/custom.cfg ]; then
+  source $prefix/custom.cfg
+fi
+";
+
+/// Lines of a GRUB config which are local to the system.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Customization {
+    /// The section they were found in, or `grub.cfg` if outside of any
+    pub(crate) source: String,
+    pub(crate) lines: Vec<String>,
+}
+
+impl Customization {
+    /// The drop-in these lines are moved to.
+    pub(crate) fn drop_in(&self) -> &'static str {
+        let entries = self.lines.iter().any(|l| {
+            let l = l.trim_start();
+            l.starts_with("menuentry ") || l.starts_with("submenu ")
+        });
+        if entries {
+            CUSTOM_CFG
+        } else {
+            USER_CFG
+        }
+    }
+}
+
+/// A GRUB config split into the generated parts and local customizations.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SplitConfig {
+    /// The config without the ostree entries and the customizations
+    pub(crate) config: String,
+    pub(crate) customizations: Vec<Customization>,
+}
+
+/// Add `line` to the customization of `source`, which is the last one
+/// while the lines of a section are read.
+fn push_custom(customizations: &mut Vec<Customization>, source: &str, line: &str) {
+    match customizations.last_mut() {
+        Some(c) if c.source == source => c.lines.push(line.to_string()),
+        _ => customizations.push(Customization {
+            source: source.to_string(),
+            lines: vec![line.to_string()],
+        }),
+    }
+}
+
+/// Split the generated GRUB `config`, dropping the ostree entries.
+pub(crate) fn split(config: &str) -> SplitConfig {
+    let mut r = String::new();
+    let mut customizations = Vec::new();
+    let mut section: Option<&str> = None;
+    for line in config.lines() {
+        if let Some(name) = line
+            .strip_prefix("### BEGIN ")
+            .and_then(|l| l.strip_suffix(" ###"))
+        {
+            section = Some(name);
+        } else if line.starts_with("### END ") {
+            section = None;
+        } else {
+            let code = !line.trim().is_empty() && !line.trim_start().starts_with('#');
+            match section {
+                Some(OSTREE_SECTION) => continue,
+                Some(s @ (CUSTOM_SECTION | OS_PROBER_SECTION)) if code => {
+                    push_custom(&mut customizations, s, line);
+                    continue;
+                }
+                Some(s) if !STANDARD_SECTIONS.contains(&s) => {
+                    push_custom(&mut customizations, s, line);
+                    continue;
+                }
+                None if code => {
+                    push_custom(&mut customizations, "grub.cfg", line);
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        r.push_str(line);
+        r.push('\n');
+    }
+    // Appended, so that the entries come after the generated ones
+    for (name, source) in [(USER_CFG, SOURCE_USER_CFG), (CUSTOM_CFG, SOURCE_CUSTOM_CFG)] {
+        if customizations.iter().any(|c| c.drop_in() == name) && !r.contains(name) {
+            r.push_str(source);
+        }
+    }
+    SplitConfig {
+        config: r,
+        customizations,
+    }
+}
+
+/// The titles of the menu entries in `customizations`, e.g. to report
+/// which entries of other systems are kept.
+pub(crate) fn menuentry_titles(customizations: &[Customization]) -> Vec<&str> {
+    customizations
+        .iter()
+        .flat_map(|c| c.lines.iter())
+        .filter_map(|l| l.trim_start().strip_prefix("menuentry "))
+        .filter_map(|l| {
+            let quote = l.chars().next().filter(|&c| c == '\'' || c == '"')?;
+            l[1..].split(quote).next()
+        })
+        .collect()
+}
+
+/// The block appended to the drop-in `name` for the `customizations`
+/// which are moved there, if any.
+pub(crate) fn drop_in_block(customizations: &[Customization], name: &str) -> Option<String> {
+    let mut r = String::from("# Carried over by `bootupctl migrate-static-grub-config`\n");
+    let mut any = false;
+    for c in customizations.iter().filter(|c| c.drop_in() == name) {
+        r.push_str(&format!("# From {}\n", c.source));
+        for l in c.lines.iter() {
+            r.push_str(l);
+            r.push('\n');
+        }
+        any = true;
+    }
+    any.then_some(r)
+}
+
+/// The contents of the drop-in `name` in the GRUB config directory `dir`,
+/// if any.
+fn read_drop_in(dir: &openat::Dir, name: &str) -> Result<Option<String>> {
+    let Some(mut f) = dir.open_file_optional(name)? else {
+        return Ok(None);
+    };
+    let mut contents = String::new();
+    f.read_to_string(&mut contents)
+        .with_context(|| format!("Reading {name}"))?;
+    Ok(Some(contents))
+}
+
+/// Append `block` to the drop-in `name` in the GRUB config directory
+/// `dir`, unless it's already there, e.g. when migrating again.
+pub(crate) fn append_drop_in(dir: &openat::Dir, name: &str, block: &str) -> Result<()> {
+    let mut contents = read_drop_in(dir, name)?.unwrap_or_default();
+    if contents.contains(block) {
+        return Ok(());
+    }
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(block);
+    dir.write_file_contents(name, 0o600, contents)
+        .with_context(|| format!("Writing {name}"))?;
+    Ok(())
+}
+
+/// Remove `block` from the drop-in `name` in the GRUB config directory
+/// `dir`, removing the file if nothing else is left.
+pub(crate) fn remove_from_drop_in(dir: &openat::Dir, name: &str, block: &str) -> Result<()> {
+    let Some(contents) = read_drop_in(dir, name)? else {
+        return Ok(());
+    };
+    let rest = contents.replacen(block, "", 1);
+    if rest.trim().is_empty() {
+        dir.remove_file_optional(name)?;
+    } else if rest != contents {
+        dir.write_file_contents(name, 0o600, rest)
+            .with_context(|| format!("Writing {name}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENERATED: &str = "\
+#
+# DO NOT EDIT THIS FILE
+#
+### BEGIN /etc/grub.d/00_header ###
+set timeout=5
+### END /etc/grub.d/00_header ###
+set timeout=30
+### BEGIN /etc/grub.d/15_ostree ###
+menuentry 'Fedora CoreOS 41 (ostree:0)' {
+}
+### END /etc/grub.d/15_ostree ###
+### BEGIN /etc/grub.d/30_os-prober ###
+menuentry 'Windows Boot Manager (on /dev/nvme0n1p1)' --class windows $menuentry_id_option 'osprober-efi-0CF5-AE6A' {
+	chainloader /efi/Microsoft/Boot/bootmgfw.efi
+}
+### END /etc/grub.d/30_os-prober ###
+### BEGIN /etc/grub.d/40_custom ###
+# This file provides an easy way to add custom menu entries.
+menuentry 'Rescue' {
+  linux /rescue/vmlinuz
+}
+### END /etc/grub.d/40_custom ###
+### BEGIN /etc/grub.d/42_site ###
+set site=lab
+### END /etc/grub.d/42_site ###
+";
+
+    #[test]
+    fn test_split() {
+        let split = split(GENERATED);
+        assert_eq!(
+            split.config,
+            "\
+#
+# DO NOT EDIT THIS FILE
+#
+### BEGIN /etc/grub.d/00_header ###
+set timeout=5
+### END /etc/grub.d/00_header ###
+### BEGIN /etc/grub.d/15_ostree ###
+### END /etc/grub.d/15_ostree ###
+### BEGIN /etc/grub.d/30_os-prober ###
+### END /etc/grub.d/30_os-prober ###
+### BEGIN /etc/grub.d/40_custom ###
+# This file provides an easy way to add custom menu entries.
+### END /etc/grub.d/40_custom ###
+### BEGIN /etc/grub.d/42_site ###
+### END /etc/grub.d/42_site ###
+"
+            .to_string()
+                + SOURCE_USER_CFG
+                + SOURCE_CUSTOM_CFG
+        );
+        let sources = split
+            .customizations
+            .iter()
+            .map(|c| (c.source.as_str(), c.drop_in()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                ("grub.cfg", USER_CFG),
+                (OS_PROBER_SECTION, CUSTOM_CFG),
+                (CUSTOM_SECTION, CUSTOM_CFG),
+                ("/etc/grub.d/42_site", USER_CFG)
+            ]
+        );
+        assert_eq!(split.customizations[2].lines.len(), 3);
+        assert_eq!(
+            menuentry_titles(&split.customizations),
+            ["Windows Boot Manager (on /dev/nvme0n1p1)", "Rescue"]
+        );
+        assert_eq!(
+            drop_in_block(&split.customizations, USER_CFG).unwrap(),
+            "\
+# Carried over by `bootupctl migrate-static-grub-config`
+# From grub.cfg
+set timeout=30
+# From /etc/grub.d/42_site
+set site=lab
+"
+        );
+
+        // The entries are sourced after the generated ones
+        let generated = format!(
+            "\
+### BEGIN /etc/grub.d/01_users ###
+{SOURCE_USER_CFG}### END /etc/grub.d/01_users ###
+### BEGIN /etc/grub.d/10_linux ###
+insmod blscfg
+blscfg
+### END /etc/grub.d/10_linux ###
+{}",
+            &GENERATED[GENERATED.find("### BEGIN /etc/grub.d/30").unwrap()..]
+        );
+        let config = super::split(&generated).config;
+        assert_eq!(config.matches(USER_CFG).count(), 2);
+        assert!(config.find("blscfg").unwrap() < config.find(CUSTOM_CFG).unwrap());
+        // Also with GRUB's own `41_custom` section
+        let generated = format!(
+            "{generated}\
+### BEGIN /etc/grub.d/41_custom ###
+{SOURCE_CUSTOM_CFG}### END /etc/grub.d/41_custom ###
+"
+        );
+        let config = super::split(&generated).config;
+        assert_eq!(config.matches(CUSTOM_CFG).count(), 2);
+
+        // A config without customizations is only stripped
+        let plain =
+            "### BEGIN /etc/grub.d/10_linux ###\ninsmod blscfg\n### END /etc/grub.d/10_linux ###\n";
+        let split = super::split(plain);
+        assert_eq!(split.config, plain);
+        assert!(split.customizations.is_empty());
+    }
+
+    #[test]
+    fn test_drop_ins() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = openat::Dir::open(td.path())?;
+        let block = drop_in_block(&split(GENERATED).customizations, USER_CFG).unwrap();
+        dir.write_file_contents(USER_CFG, 0o600, "GRUB2_PASSWORD=hash")?;
+        append_drop_in(&dir, USER_CFG, &block)?;
+        append_drop_in(&dir, USER_CFG, &block)?;
+        let contents = dir.read_to_string(USER_CFG)?;
+        assert_eq!(contents, format!("GRUB2_PASSWORD=hash\n{block}"));
+        remove_from_drop_in(&dir, USER_CFG, &block)?;
+        assert_eq!(dir.read_to_string(USER_CFG)?, "GRUB2_PASSWORD=hash\n");
+
+        let block = drop_in_block(&split(GENERATED).customizations, CUSTOM_CFG).unwrap();
+        append_drop_in(&dir, CUSTOM_CFG, &block)?;
+        assert_eq!(dir.read_to_string(CUSTOM_CFG)?, block);
+        remove_from_drop_in(&dir, CUSTOM_CFG, &block)?;
+        assert!(!dir.exists(CUSTOM_CFG)?);
+        Ok(())
+    }
+}
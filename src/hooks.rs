@@ -0,0 +1,294 @@
+//! Site-specific scripts run around component updates, e.g. to quiesce
+//! monitoring, snapshot a VM or file a change ticket.
+//!
+//! The executables in `/etc/bootupd/hooks.d/pre-update/` are run before
+//! the files of a component are written (on update, adoption, reinstall,
+//! A/B confirmation and rollback, resync of the ESPs and uninstall), and
+//! those in `/etc/bootupd/hooks.d/post-update/` afterwards, whether that
+//! succeeded or not; each in the order of their names, skipping hidden and
+//! non-executable files.  They get the environment variables:
+//!
+//! - `BOOTUPD_HOOK`: `pre-update` or `post-update`
+//! - `BOOTUPD_OPERATION`: `update`, `adopt`, `reinstall`, `confirm`,
+//!   `rollback`, `resync` or `uninstall`
+//! - `BOOTUPD_COMPONENT`: the name of the component, e.g. `EFI`
+//! - `BOOTUPD_FROM_VERSION` and `BOOTUPD_TO_VERSION`: the installed
+//!   version and the one being written; the latter is unset on uninstall
+//! - `BOOTUPD_RESULT`: for `post-update`, `success` or `failure`
+//!
+//! With the `hook-failure` policy `abort` (the default), a failing
+//! `pre-update` hook stops the update, and a failing `post-update` hook
+//! fails the command, although the update itself is kept; with `warn`,
+//! failures are only logged.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::model::ContentMetadata;
+use crate::util::CommandRunExt;
+
+/// Where hooks are found, in a subdirectory per [`Stage`].
+const HOOKS_DIR: &str = "/etc/bootupd/hooks.d";
+
+/// What to do when a hook fails.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum HookFailure {
+    /// Stop the update, or fail the command after it
+    #[default]
+    Abort,
+    /// Log the failure and carry on
+    Warn,
+}
+
+/// When hooks are run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    PreUpdate,
+    PostUpdate,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::PreUpdate => "pre-update",
+            Stage::PostUpdate => "post-update",
+        }
+    }
+}
+
+/// What is done to the component which hooks are run around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Update,
+    Adopt,
+    Reinstall,
+    Confirm,
+    Rollback,
+    Resync,
+    Uninstall,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Update => "update",
+            Operation::Adopt => "adopt",
+            Operation::Reinstall => "reinstall",
+            Operation::Confirm => "confirm",
+            Operation::Rollback => "rollback",
+            Operation::Resync => "resync",
+            Operation::Uninstall => "uninstall",
+        }
+    }
+}
+
+/// The change to a component which hooks are run around.
+pub(crate) struct HookUpdate<'a> {
+    pub(crate) operation: Operation,
+    pub(crate) component: &'a str,
+    pub(crate) from: &'a ContentMetadata,
+    /// `None` when uninstalling
+    pub(crate) to: Option<&'a ContentMetadata>,
+}
+
+/// The hooks in `dir`, in the order to run them.
+fn find_hooks(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {dir:?}")),
+    };
+    let mut hooks = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let meta = std::fs::metadata(&path).with_context(|| format!("Querying {path:?}"))?;
+        if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+            log::debug!("Skipping non-executable {path:?}");
+            continue;
+        }
+        hooks.push(path);
+    }
+    hooks.sort();
+    Ok(hooks)
+}
+
+/// Run the hooks for `stage` in `dir`, applying the failure `policy`.
+fn run_in(
+    dir: &Path,
+    stage: Stage,
+    update: &HookUpdate,
+    result: Option<bool>,
+    policy: HookFailure,
+) -> Result<()> {
+    for hook in find_hooks(&dir.join(stage.name()))? {
+        log::debug!("Running {} hook {hook:?}", stage.name());
+        let mut c = Command::new(&hook);
+        c.env("BOOTUPD_HOOK", stage.name())
+            .env("BOOTUPD_OPERATION", update.operation.name())
+            .env("BOOTUPD_COMPONENT", update.component)
+            .env("BOOTUPD_FROM_VERSION", &update.from.version)
+            .env_remove("BOOTUPD_TO_VERSION");
+        if let Some(to) = update.to {
+            c.env("BOOTUPD_TO_VERSION", &to.version);
+        }
+        if let Some(success) = result {
+            c.env(
+                "BOOTUPD_RESULT",
+                if success { "success" } else { "failure" },
+            );
+        }
+        if let Err(e) = c.run() {
+            match policy {
+                HookFailure::Abort => {
+                    bail!("{} hook {hook:?} failed: {e:#}", stage.name())
+                }
+                HookFailure::Warn => log::warn!("{} hook {hook:?} failed: {e:#}", stage.name()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the hooks before `update`.
+fn pre_update(update: &HookUpdate) -> Result<()> {
+    let policy = crate::config::get().hook_failure.unwrap_or_default();
+    run_in(Path::new(HOOKS_DIR), Stage::PreUpdate, update, None, policy)
+}
+
+/// Run the hooks after `update`, which succeeded or not.
+fn post_update(update: &HookUpdate, success: bool) -> Result<()> {
+    let policy = crate::config::get().hook_failure.unwrap_or_default();
+    run_in(
+        Path::new(HOOKS_DIR),
+        Stage::PostUpdate,
+        update,
+        Some(success),
+        policy,
+    )
+}
+
+/// Run `f`, which writes the component of `update`, between its hooks;
+/// the `post-update` hooks also run if anything after the `pre-update`
+/// ones fails.
+pub(crate) fn run_around<T>(update: &HookUpdate, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    pre_update(update)?;
+    match f() {
+        Ok(r) => {
+            post_update(update, true)?;
+            Ok(r)
+        }
+        Err(e) => {
+            if let Err(hook_err) = post_update(update, false) {
+                log::warn!("{hook_err:#}");
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hooks() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let out = td.path().join("out");
+        let pre = td.path().join("pre-update");
+        std::fs::create_dir_all(&pre)?;
+        let write_hook = |name: &str, script: &str, mode: u32| -> Result<()> {
+            let path = pre.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{script}\n"))?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            Ok(())
+        };
+        let log = format!("echo \"$0 $BOOTUPD_HOOK $BOOTUPD_OPERATION $BOOTUPD_COMPONENT $BOOTUPD_FROM_VERSION $BOOTUPD_TO_VERSION\" >> {out:?}");
+        write_hook("20-second", &log, 0o755)?;
+        write_hook("10-first", &log, 0o755)?;
+        write_hook("30-disabled", "exit 1", 0o644)?;
+        write_hook(".hidden", "exit 1", 0o755)?;
+
+        let meta = |version: &str| ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: version.into(),
+            firmware: None,
+        };
+        let (from, to) = (meta("grub2-1"), meta("grub2-2"));
+        let update = HookUpdate {
+            operation: Operation::Update,
+            component: "EFI",
+            from: &from,
+            to: Some(&to),
+        };
+        run_in(
+            td.path(),
+            Stage::PreUpdate,
+            &update,
+            None,
+            HookFailure::Abort,
+        )?;
+        let lines = std::fs::read_to_string(&out)?;
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("10-first pre-update update EFI grub2-1 grub2-2"));
+        assert!(lines[1].ends_with("20-second pre-update update EFI grub2-1 grub2-2"));
+
+        // Without a new version, on uninstall
+        std::fs::remove_file(&out)?;
+        let uninstall = HookUpdate {
+            operation: Operation::Uninstall,
+            to: None,
+            ..update
+        };
+        run_in(
+            td.path(),
+            Stage::PreUpdate,
+            &uninstall,
+            None,
+            HookFailure::Abort,
+        )?;
+        let lines = std::fs::read_to_string(&out)?;
+        assert!(lines
+            .lines()
+            .all(|l| l.ends_with("pre-update uninstall EFI grub2-1 ")));
+
+        // No post-update hooks
+        run_in(
+            td.path(),
+            Stage::PostUpdate,
+            &update,
+            Some(true),
+            HookFailure::Abort,
+        )?;
+
+        write_hook("15-failing", "exit 3", 0o755)?;
+        std::fs::remove_file(&out)?;
+        assert!(run_in(
+            td.path(),
+            Stage::PreUpdate,
+            &update,
+            None,
+            HookFailure::Abort
+        )
+        .is_err());
+        // The hooks after the failing one aren't run
+        assert_eq!(std::fs::read_to_string(&out)?.lines().count(), 1);
+        run_in(
+            td.path(),
+            Stage::PreUpdate,
+            &update,
+            None,
+            HookFailure::Warn,
+        )?;
+        Ok(())
+    }
+}
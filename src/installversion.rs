@@ -0,0 +1,203 @@
+//! The version the system was installed as, used for the components which
+//! were installed without bootupd and can be adopted.
+//!
+//! The sources are tried in turn:
+//!
+//! - the aleph file written when building Fedora CoreOS (and derivatives)
+//! - the install record `bootc install` writes into the physical root
+//! - Anaconda's logs, on ostree-based systems it installed (e.g. from an
+//!   `ostreecontainer` kickstart); on package-based systems the rpm
+//!   database tells the installed versions instead.  Anaconda doesn't
+//!   record the version it installed, so this is only a guess
+//! - the origin of the booted ostree deployment, i.e. the container image
+//!   or ref it tracks
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use serde::Deserialize;
+
+/// The install record of `bootc install`, in the physical root.
+const BOOTC_ALEPH_PATH: &str = "sysroot/.bootc-aleph.json";
+/// Written by Anaconda during the installation.
+const ANACONDA_LOG_PATH: &str = "var/log/anaconda/anaconda.log";
+/// Holds the deployments on ostree-based systems.
+const OSTREE_DEPLOY_PATH: &str = "ostree/deploy";
+
+/// The version the system was installed as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InstallVersion {
+    /// Where the version comes from, e.g. `bootc`
+    pub(crate) source: &'static str,
+    pub(crate) version: String,
+    /// When the system was installed, or as close as can be told
+    pub(crate) timestamp: DateTime<Utc>,
+    /// Whether the version is known to be the one installed, rather than
+    /// guessed from the current system
+    pub(crate) confident: bool,
+}
+
+/// Finds the version the system at a root was installed as, if it can.
+type Provider = fn(&Path) -> Result<Option<InstallVersion>>;
+
+/// The sources of the install version, in order of preference.
+const PROVIDERS: &[Provider] = &[coreos_aleph, bootc_aleph, anaconda, ostree_origin];
+
+/// The version the system at `root` was installed as, from the first
+/// source which knows it.
+pub(crate) fn get_install_version(root: &Path) -> Result<Option<InstallVersion>> {
+    for provider in PROVIDERS {
+        if let Some(v) = provider(root)? {
+            log::trace!("Install version: {v:?}");
+            return Ok(Some(v));
+        }
+    }
+    log::trace!("No install version found");
+    Ok(None)
+}
+
+/// The modification time of `path`.
+fn mtime(path: &Path) -> Result<DateTime<Utc>> {
+    let meta = std::fs::metadata(path).with_context(|| format!("Querying {path:?}"))?;
+    Ok(meta.modified()?.into())
+}
+
+fn coreos_aleph(root: &Path) -> Result<Option<InstallVersion>> {
+    Ok(
+        crate::coreos::get_aleph_version(root)?.map(|a| InstallVersion {
+            source: "coreos-aleph",
+            version: a.aleph.version,
+            timestamp: a.ts,
+            confident: true,
+        }),
+    )
+}
+
+/// The part of the bootc install record we use.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct BootcAleph {
+    /// The image reference installed from
+    #[serde(default)]
+    image: Option<String>,
+    /// The `org.opencontainers.image.version` label of the image
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+fn bootc_aleph(root: &Path) -> Result<Option<InstallVersion>> {
+    let path = root.join(BOOTC_ALEPH_PATH);
+    let data = match std::fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading {path:?}")),
+    };
+    let aleph: BootcAleph =
+        serde_json::from_str(&data).with_context(|| format!("Parsing {path:?}"))?;
+    let Some(version) = aleph.version.or(aleph.image) else {
+        return Ok(None);
+    };
+    let timestamp = match aleph.timestamp {
+        Some(ts) => ts,
+        None => mtime(&path)?,
+    };
+    Ok(Some(InstallVersion {
+        source: "bootc",
+        version,
+        timestamp,
+        confident: true,
+    }))
+}
+
+fn anaconda(root: &Path) -> Result<Option<InstallVersion>> {
+    let log = root.join(ANACONDA_LOG_PATH);
+    if !root.join(OSTREE_DEPLOY_PATH).exists() || !log.exists() {
+        return Ok(None);
+    }
+    // The current os-release, which is only the installed version as long
+    // as the system wasn't upgraded since
+    let release = os_release::OsRelease::new_from(root.join("etc/os-release"))
+        .context("Reading os-release")?;
+    Ok(Some(InstallVersion {
+        source: "anaconda",
+        version: format!("{}-{}", release.id, release.version_id),
+        timestamp: mtime(&log)?,
+        confident: false,
+    }))
+}
+
+fn ostree_origin(root: &Path) -> Result<Option<InstallVersion>> {
+    let deploy = root.join(OSTREE_DEPLOY_PATH);
+    if !deploy.exists() {
+        return Ok(None);
+    }
+    // The deployment is only known on the booted system
+    let source = if root == Path::new("/") {
+        std::fs::read_to_string("/proc/cmdline")
+            .map_err(anyhow::Error::from)
+            .and_then(|cmdline| {
+                crate::ostreeutil::find_booted_deployment(Path::new("/sysroot"), &cmdline)
+            })
+            .and_then(|d| crate::ostreeutil::deployment_source(&d))
+            .unwrap_or_else(|e| {
+                log::debug!("Failed to read the booted deployment's origin: {e:#}");
+                None
+            })
+    } else {
+        None
+    };
+    let btime = deploy.metadata()?.created()?;
+    Ok(Some(InstallVersion {
+        source: "ostree",
+        version: source.unwrap_or_else(|| "unknown".to_string()),
+        timestamp: btime.into(),
+        confident: true,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Waiting on https://github.com/rust-lang/rust/pull/125692
+    #[cfg(not(target_env = "musl"))]
+    #[test]
+    fn test_install_version() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        assert_eq!(get_install_version(root)?, None);
+
+        // ostree systems are always recognized
+        std::fs::create_dir_all(root.join(OSTREE_DEPLOY_PATH))?;
+        let v = get_install_version(root)?.expect("version");
+        assert_eq!((v.source, v.version.as_str()), ("ostree", "unknown"));
+
+        std::fs::create_dir_all(root.join("var/log/anaconda"))?;
+        std::fs::write(root.join(ANACONDA_LOG_PATH), "")?;
+        std::fs::create_dir_all(root.join("etc"))?;
+        std::fs::write(
+            root.join("etc/os-release"),
+            "NAME=\"Fedora Linux\"\nID=fedora\nVERSION_ID=41\n",
+        )?;
+        let v = get_install_version(root)?.expect("version");
+        assert_eq!((v.source, v.version.as_str()), ("anaconda", "fedora-41"));
+        assert!(!v.confident);
+
+        std::fs::create_dir_all(root.join("sysroot"))?;
+        std::fs::write(
+            root.join(BOOTC_ALEPH_PATH),
+            r#"{"image": "quay.io/fedora/fedora-bootc:41", "kernel": "6.11.4-301.fc41.x86_64", "timestamp": "2024-10-29T12:00:00Z"}"#,
+        )?;
+        let v = get_install_version(root)?.expect("version");
+        assert_eq!(
+            (v.source, v.version.as_str()),
+            ("bootc", "quay.io/fedora/fedora-bootc:41")
+        );
+        assert!(v.confident);
+        assert_eq!(v.timestamp.to_rfc3339(), "2024-10-29T12:00:00+00:00");
+        Ok(())
+    }
+}
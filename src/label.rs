@@ -0,0 +1,77 @@
+//! SELinux labels for the files we write into `/boot` ourselves (rather
+//! than via rpm, which labels them), so that e.g. a static GRUB config
+//! doesn't inherit the label of the directory it was created in, which a
+//! later relabel or audit would flag.
+//!
+//! With the `selinux` cargo feature the policy is queried via libselinux,
+//! otherwise via the `matchpathcon` and `chcon` tools.
+
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use fn_error_context::context;
+
+/// Mounted when SELinux is enabled.
+const SELINUXFS: &str = "/sys/fs/selinux";
+
+/// The path of `name` in `dir`, usable by tools.
+fn fd_path(dir: &openat::Dir, name: &str) -> PathBuf {
+    Path::new(&format!("/proc/self/fd/{}", dir.as_raw_fd())).join(name)
+}
+
+#[cfg(feature = "selinux")]
+#[context("Labeling {name} as {}", path.display())]
+fn relabel(dir: &openat::Dir, name: &str, path: &Path) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let target = fd_path(dir, name);
+    let mode = std::fs::symlink_metadata(&target)?.mode() & libc::S_IFMT;
+    let key = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let labeler = selinux::label::Labeler::<selinux::label::back_end::File>::new(&[], false)?;
+    let context = labeler.look_up(&key, mode as libc::c_int)?;
+    context.set_for_path(&target, false, false)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "selinux"))]
+#[context("Labeling {name} as {}", path.display())]
+fn relabel(dir: &openat::Dir, name: &str, path: &Path) -> Result<()> {
+    use crate::util::CommandRunExt;
+    use anyhow::Context;
+
+    let context = crate::util::cmd_output(
+        std::process::Command::new("matchpathcon")
+            .arg("-n")
+            .arg(path),
+    )?;
+    let context = context.trim();
+    if context.is_empty() {
+        anyhow::bail!("No label for {}", path.display());
+    }
+    std::process::Command::new("chcon")
+        .args(["--no-dereference", context])
+        .arg(fd_path(dir, name))
+        .run()
+        .context("Running chcon")
+}
+
+/// Whether SELinux is enabled.
+pub(crate) fn enabled() -> bool {
+    Path::new(SELINUXFS).join("enforce").exists()
+}
+
+/// Give `name` in `dir` the label the policy assigns to `path`, i.e. where
+/// it ends up on the booted system (e.g. `/boot/grub2/grub.cfg`).  Does
+/// nothing if SELinux is disabled, and failures are only logged, since the
+/// file works regardless of its label.
+pub(crate) fn set_default_label(dir: &openat::Dir, name: &str, path: &Path) {
+    if !enabled() {
+        log::trace!("SELinux is disabled; not labeling {name}");
+        return;
+    }
+    if let Err(e) = relabel(dir, name, path) {
+        log::warn!("{e:#}");
+    }
+}
@@ -0,0 +1,209 @@
+/*!
+**Boot**loader **upd**ater.
+
+This is an early prototype hidden/not-yet-standardized mechanism
+which just updates EFI for now (x86_64/aarch64 only).
+
+But in the future will hopefully gain some independence from
+ostree and also support e.g. updating the MBR etc.
+
+This crate is also usable as a library, for tools which want to query
+or drive updates directly rather than parsing `bootupctl status --json`;
+see [`status`], [`update`], [`adopt_and_update`] and [`validate`].  These
+operate on the booted system, and need to run as root.  [`file_tree`] and
+[`FileTree::diff`] compare arbitrary directories, e.g. to verify images.
+
+Refs:
+ * <https://github.com/coreos/fedora-coreos-tracker/issues/510>
+!*/
+
+#![deny(unused_must_use)]
+// The style lints are more annoying than useful
+#![allow(clippy::style)]
+
+mod audit;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod authenticode;
+mod backend;
+#[cfg(any(target_arch = "x86_64", target_arch = "powerpc64"))]
+mod bios;
+mod blockdev;
+mod bls;
+mod bootupd;
+mod chaos;
+mod cli;
+mod component;
+mod config;
+mod coreos;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod dbx;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod delta;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+mod devicetree;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod diskimage;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod efi;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+mod emmcboot;
+mod errors;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod esp;
+mod exitcode;
+#[cfg(all(feature = "extlinux", target_arch = "x86_64"))]
+mod extlinux;
+mod failpoints;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod fatcheck;
+mod filesystem;
+mod filetree;
+mod grubbackup;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+mod grubconfigs;
+mod grubcustom;
+mod hooks;
+mod installversion;
+mod label;
+mod maintenance;
+mod model;
+mod model_legacy;
+mod mount;
+mod notice;
+mod notify;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod objectstore;
+mod ostreeutil;
+mod packagesystem;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod payloadversion;
+mod plugin;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod provenance;
+mod retry;
+#[cfg(feature = "rpmdb")]
+mod rpmdb;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod rpmfile;
+#[cfg(any(feature = "rpmdb", target_arch = "x86_64", target_arch = "aarch64"))]
+mod rpmheader;
+mod sha512string;
+mod signature;
+mod sourceimage;
+mod syncpolicy;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod systemdboot;
+#[cfg(any(test, feature = "testutils"))]
+pub mod testutils;
+#[cfg(not(any(test, feature = "testutils")))]
+#[allow(dead_code)]
+mod testutils;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod tpm;
+mod trace;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod treecache;
+mod util;
+
+use clap::crate_name;
+
+pub use crate::bootupd::ComponentUpdateResult;
+pub use crate::component::ValidationResult;
+pub use crate::errors::{ErrorKind, ErrorReport};
+pub use crate::filetree::{FileChange, FileChangeKind, FileMetadata, FileTree, FileTreeDiff};
+pub use crate::model::{
+    Adoptable, BootInfo, BootMethod, BootPartition, BootPartitionKind, ComponentStatus,
+    ComponentUpdatable, ContentMetadata, DeferredUpdate, DeploymentInfo, DiskInfo, DiskTopology,
+    EspInfo, FirmwareStatus, LastUpdate, MountTopology, Pin, PlatformInfo, ScrubResult, Status,
+};
+pub use crate::sha512string::SHA512String;
+
+/// Query the installed components, and any available updates.
+pub fn status() -> anyhow::Result<Status> {
+    bootupd::status()
+}
+
+/// Update the named component (see [`Status::components`]) to the version
+/// shipped in the booted OS, if it is newer.
+pub fn update(component: &str) -> anyhow::Result<ComponentUpdateResult> {
+    bootupd::update(component)
+}
+
+/// Start managing the named component (see [`Status::adoptable`]),
+/// updating it in the process.
+pub fn adopt_and_update(component: &str) -> anyhow::Result<ContentMetadata> {
+    bootupd::adopt_and_update(component)
+}
+
+/// Check that the files of the named component are unmodified.
+pub fn validate(component: &str) -> anyhow::Result<ValidationResult> {
+    bootupd::validate(component, false)
+}
+
+/// Read the files in `dir`, hashing each of them.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub fn file_tree(dir: &std::path::Path) -> anyhow::Result<FileTree> {
+    bootupd::file_tree(dir)
+}
+
+/// CLI logic, for both `bootupd` and `bootupctl`; returns the exit code,
+/// which is one of those documented in the README.
+#[doc(hidden)]
+pub fn run_cli() -> i32 {
+    // Parse command-line options.
+    let args: Vec<_> = std::env::args().collect();
+    let cli_opts = cli::MultiCall::from_args(args);
+
+    // Setup logging.
+    env_logger::Builder::from_default_env()
+        .format_timestamp(None)
+        .format_module_path(false)
+        .filter(Some(crate_name!()), cli_opts.loglevel())
+        .init();
+
+    log::trace!("executing cli");
+
+    // Dispatch CLI subcommand.
+    let format = cli_opts.output_format();
+    let r = cli_opts
+        .trace_opts()
+        .init()
+        .and_then(|_guard| cli_opts.run());
+    if let Err(e) = r.as_ref() {
+        // Validation failures are part of the output of `validate` itself
+        let reported = match exitcode::Outcome::of(e) {
+            Some(o) => !o.is_error() || o == exitcode::Outcome::ValidationFailed,
+            None => false,
+        };
+        if !reported && format != cli::OutputFormat::Human {
+            if let Err(e) = format.print(&ErrorReport::new(e)) {
+                log::warn!("Failed to print error: {e:#}");
+            }
+        }
+    }
+    exitcode::from_result(r)
+}
@@ -0,0 +1,211 @@
+//! Maintenance windows, outside of which `bootupctl update` defers updates
+//! rather than writing the bootloader, for fleets which only want that to
+//! happen at planned times.
+//!
+//! Each window is a cron expression with the usual five fields (minute,
+//! hour, day of month, month, day of week); the system is in the window
+//! during every minute the expression matches, in local time.  For example
+//! `* 2-3 * * Sat,Sun` is 02:00 to 03:59 on weekends.  As in cron, if both
+//! the day of month and the day of week are restricted, matching either is
+//! enough.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::prelude::*;
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// The values matched by one field, as a bitmask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    mask: u64,
+    /// Whether the field is `*`, which matters for the day fields
+    any: bool,
+}
+
+impl Field {
+    fn matches(&self, v: u32) -> bool {
+        self.mask & (1 << v) != 0
+    }
+}
+
+/// Parse a value of a field ranging from `min` to `max`, which may also be
+/// given as one of `names` (starting at `min`).
+fn parse_value(s: &str, min: u32, max: u32, names: &[&str]) -> Result<u32> {
+    let lower = s.to_ascii_lowercase();
+    if let Some(i) = names.iter().position(|n| *n == lower) {
+        return Ok(min + i as u32);
+    }
+    let v: u32 = s.parse().map_err(|_| anyhow!("Invalid value {s:?}"))?;
+    if v < min || v > max {
+        bail!("{v} is not within {min}-{max}");
+    }
+    Ok(v)
+}
+
+/// Parse one field, e.g. `1-5`, `*/15` or `sat,sun`.
+fn parse_field(s: &str, min: u32, max: u32, names: &[&str]) -> Result<Field> {
+    let mut mask = 0;
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((r, step)) => {
+                let step: u32 = step.parse().map_err(|_| anyhow!("Invalid step {step:?}"))?;
+                if step == 0 {
+                    bail!("Invalid step 0");
+                }
+                (r, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            r => match r.split_once('-') {
+                Some((a, b)) => (
+                    parse_value(a, min, max, names)?,
+                    parse_value(b, min, max, names)?,
+                ),
+                None => {
+                    let v = parse_value(r, min, max, names)?;
+                    (v, if step > 1 { max } else { v })
+                }
+            },
+        };
+        if start > end {
+            bail!("Invalid range {range:?}");
+        }
+        for v in (start..=end).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+    Ok(Field {
+        mask,
+        any: s == "*",
+    })
+}
+
+/// A maintenance window, given as a cron expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Window {
+    minute: Field,
+    hour: Field,
+    day: Field,
+    month: Field,
+    weekday: Field,
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields = s.split_ascii_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("Invalid maintenance window {s:?}: expected 5 fields");
+        };
+        let parse = || -> Result<Self> {
+            let mut weekday = parse_field(weekday, 0, 7, WEEKDAYS)?;
+            // Both 0 and 7 are Sunday
+            if weekday.matches(7) {
+                weekday.mask |= 1;
+            }
+            Ok(Self {
+                minute: parse_field(minute, 0, 59, &[])?,
+                hour: parse_field(hour, 0, 23, &[])?,
+                day: parse_field(day, 1, 31, &[])?,
+                month: parse_field(month, 1, 12, MONTHS)?,
+                weekday,
+            })
+        };
+        parse().with_context(|| format!("Invalid maintenance window {s:?}"))
+    }
+}
+
+impl Window {
+    /// Whether `t` is within the window.
+    pub(crate) fn contains(&self, t: &NaiveDateTime) -> bool {
+        let day = self.day.matches(t.day());
+        let weekday = self.weekday.matches(t.weekday().num_days_from_sunday());
+        let day_matches = match (self.day.any, self.weekday.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minute.matches(t.minute())
+            && self.hour.matches(t.hour())
+            && self.month.matches(t.month())
+            && day_matches
+    }
+}
+
+/// Whether updates are allowed now: either no maintenance windows are
+/// configured, or the current local time is in one of them.
+pub(crate) fn in_window() -> Result<bool> {
+    let windows = &crate::config::get().maintenance_windows;
+    if windows.is_empty() {
+        return Ok(true);
+    }
+    let now = Local::now().naive_local();
+    for w in windows {
+        if w.parse::<Window>()?.contains(&now) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_window() -> Result<()> {
+        // 2024-06-01 is a Saturday
+        let w: Window = "* 2-3 * * Sat,Sun".parse()?;
+        assert!(w.contains(&at("2024-06-01 02:00")));
+        assert!(w.contains(&at("2024-06-02 03:59")));
+        assert!(!w.contains(&at("2024-06-01 04:00")));
+        assert!(!w.contains(&at("2024-06-03 02:30")));
+
+        let w: Window = "*/15 22 * * 1-5".parse()?;
+        assert!(w.contains(&at("2024-06-03 22:45")));
+        assert!(!w.contains(&at("2024-06-03 22:46")));
+        assert!(!w.contains(&at("2024-06-01 22:00")));
+
+        // Sunday as 7
+        let w: Window = "* * * * 7".parse()?;
+        assert!(w.contains(&at("2024-06-02 12:00")));
+
+        // Day of month or day of week
+        let w: Window = "* * 1 * mon".parse()?;
+        assert!(w.contains(&at("2024-06-01 12:00")));
+        assert!(w.contains(&at("2024-06-03 12:00")));
+        assert!(!w.contains(&at("2024-06-04 12:00")));
+        let w: Window = "* * 1 jan *".parse()?;
+        assert!(w.contains(&at("2025-01-01 00:00")));
+        assert!(!w.contains(&at("2024-06-01 00:00")));
+
+        for s in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "* 4-2 * * *",
+            "*/0 * * * *",
+            "* * * * fri-",
+            "* * * * funday",
+        ] {
+            assert!(s.parse::<Window>().is_err(), "{s:?}");
+        }
+        Ok(())
+    }
+}
@@ -13,11 +13,15 @@ pub(crate) const BOOTUPD_UPDATES_DIR: &str = "usr/lib/bootupd/updates";
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct ContentMetadata {
+pub struct ContentMetadata {
     /// The timestamp, which is used to determine update availability
-    pub(crate) timestamp: DateTime<Utc>,
+    pub timestamp: DateTime<Utc>,
     /// Human readable version number, like ostree it is not ever parsed, just displayed
-    pub(crate) version: String,
+    pub version: String,
+    /// Versions of the supplemental payloads included, e.g. firmware added
+    /// with `extend-payload`, keyed by package name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<BTreeMap<String, String>>,
 }
 
 impl ContentMetadata {
@@ -39,25 +43,232 @@ pub(crate) struct InstalledContent {
     pub(crate) filetree: Option<crate::filetree::FileTree>,
     /// The version this was originally adopted from
     pub(crate) adopted_from: Option<ContentMetadata>,
+    /// For content split into vendor directories (i.e. on the ESP), the files of
+    /// each top-level directory, tracked independently.  Directories which are
+    /// no longer shipped in an update are retained.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) vendors: Option<BTreeMap<String, crate::filetree::FileTree>>,
+    /// Partitions written directly rather than as files, i.e. the PReP
+    /// partition on ppc64le.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) partitions: Option<Vec<PartitionContent>>,
+    /// Problems found while installing which didn't fail the
+    /// installation, e.g. not being able to write EFI variables.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) warnings: Option<Vec<String>>,
+}
+
+/// The content of a partition which a component writes directly.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PartitionContent {
+    pub(crate) partuuid: String,
+    /// Size of the partition in bytes
+    pub(crate) size: u64,
+    /// Checksum of the whole partition
+    pub(crate) sha512: crate::sha512string::SHA512String,
 }
 
 /// Will be serialized into /boot/bootupd-state.json
-#[derive(Serialize, Deserialize, Default, Debug)]
+///
+/// Unknown fields are ignored, so that state written by a newer bootupd
+/// can still be read after a downgrade.
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-#[serde(deny_unknown_fields)]
 pub(crate) struct SavedState {
+    /// Version of the serialized format; absent up to format version 1,
+    /// see [`SavedState::disk_format_version`].
+    #[serde(default)]
+    pub(crate) format_version: u32,
     /// Maps a component name to its currently installed version
     pub(crate) installed: BTreeMap<String, InstalledContent>,
     /// Maps a component name to an in progress update
     pub(crate) pending: Option<BTreeMap<String, ContentMetadata>>,
     /// If static bootloader configs are enabled, this contains the version
     pub(crate) static_configs: Option<ContentMetadata>,
+    /// The disks the bootloader was installed to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) devices: Option<Vec<DeviceIdentity>>,
+    /// An A/B update of the ESPs which is waiting for `bootupctl confirm`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) unconfirmed_esp: Option<UnconfirmedEsp>,
+    /// The last time a component was updated, and whether the system
+    /// booted successfully since
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_update: Option<LastUpdate>,
+    /// The result of the last `bootupctl scrub`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_scrub: Option<ScrubResult>,
+    /// Components held at their current version, or kept from being adopted
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) pinned: BTreeMap<String, Pin>,
+    /// Updates which weren't applied outside of the maintenance windows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) deferred: Option<DeferredUpdate>,
+}
+
+/// Updates deferred until the next maintenance window.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeferredUpdate {
+    /// When `bootupctl update` last deferred them
+    pub timestamp: DateTime<Utc>,
+    /// The components which have updates available
+    pub components: Vec<String>,
+}
+
+/// Why a component is excluded from updates and adoption.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Pin {
+    /// When the component was pinned
+    pub timestamp: DateTime<Utc>,
+    /// The reason given to `bootupctl pin`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl std::fmt::Display for Pin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "since {}", self.timestamp.to_rfc3339())?;
+        if let Some(reason) = self.reason.as_deref() {
+            write!(f, ": {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of reading back all installed files, e.g. to find bit-rot
+/// on SD cards early.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ScrubResult {
+    /// When the scrub finished
+    pub timestamp: DateTime<Utc>,
+    /// Modified or missing files found; empty if all were intact
+    pub errors: Vec<String>,
+}
+
+/// The last bootloader update, until confirmed by `bootupctl
+/// mark-boot-successful` (e.g. from greenboot) on a later boot.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct LastUpdate {
+    /// When the update was applied
+    pub timestamp: DateTime<Utc>,
+    /// The boot during which the update was applied, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_id: Option<String>,
+    /// Whether a later boot was marked successful
+    pub confirmed: bool,
+}
+
+/// With `ab-esp`, an EFI update which was only applied to one of the two
+/// ESPs, until the system booted successfully from it.  Until then, the
+/// installed EFI content is still the previous version, on `other`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct UnconfirmedEsp {
+    /// PARTUUID of the updated ESP, which the firmware boots first
+    pub(crate) partuuid: String,
+    /// PARTUUID of the ESP still holding the previous version
+    pub(crate) other: String,
+    /// The EFI content on the updated ESP
+    pub(crate) update: InstalledContent,
+}
+
+/// Identifies a disk backing `/boot`, and its ESP.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DeviceIdentity {
+    /// Path of the disk when recorded; informational only, as it may change
+    pub(crate) device: String,
+    /// World Wide Name of the disk
+    pub(crate) wwn: Option<String>,
+    /// Serial number of the disk
+    pub(crate) serial: Option<String>,
+    /// PARTUUID of the ESP on the disk
+    pub(crate) esp_partuuid: Option<String>,
+}
+
+impl DeviceIdentity {
+    /// Whether `other` looks like the same disk; only attributes known
+    /// for both are compared.  For example, loop devices used while building
+    /// disk images have no WWN or serial.
+    pub(crate) fn matches(&self, other: &Self) -> bool {
+        fn same(a: &Option<String>, b: &Option<String>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+                _ => true,
+            }
+        }
+        same(&self.wwn, &other.wwn)
+            && same(&self.serial, &other.serial)
+            && same(&self.esp_partuuid, &other.esp_partuuid)
+    }
+
+    /// Whether `current` is the same set of disks as `recorded`.
+    pub(crate) fn same_set(recorded: &[Self], current: &[Self]) -> bool {
+        recorded.len() == current.len()
+            && recorded
+                .iter()
+                .all(|r| current.iter().any(|c| r.matches(c)))
+            && current
+                .iter()
+                .all(|c| recorded.iter().any(|r| r.matches(c)))
+    }
+}
+
+impl SavedState {
+    /// The current version of the serialized format.  Bump this whenever
+    /// the format changes, and add a migration from the previous version.
+    ///
+    /// - 0: timestamps may lack a timezone
+    /// - 1: the format read by bootupd versions which reject unknown fields
+    /// - 2: adds `devices`, `unconfirmed-esp`, `last-update`, `last-scrub`,
+    ///   `pinned` and `deferred`
+    pub(crate) const FORMAT_VERSION: u32 = 2;
+
+    /// The oldest format version which can hold this state, which it is
+    /// written in.  Format version 1 is written without a version marker,
+    /// as older bootupd rejects unknown fields; so as long as none of the
+    /// newer features are used, downgrading keeps working.
+    pub(crate) fn disk_format_version(&self) -> u32 {
+        let v1 = self.devices.is_none()
+            && self.unconfirmed_esp.is_none()
+            && self.last_update.is_none()
+            && self.last_scrub.is_none()
+            && self.pinned.is_empty()
+            && self.deferred.is_none();
+        if v1 {
+            1
+        } else {
+            Self::FORMAT_VERSION
+        }
+    }
+}
+
+impl Default for SavedState {
+    fn default() -> Self {
+        Self {
+            format_version: Self::FORMAT_VERSION,
+            installed: Default::default(),
+            pending: None,
+            static_configs: None,
+            devices: None,
+            unconfirmed_esp: None,
+            last_update: None,
+            last_scrub: None,
+            pinned: BTreeMap::new(),
+            deferred: None,
+        }
+    }
 }
 
 /// The status of an individual component.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) enum ComponentUpdatable {
+pub enum ComponentUpdatable {
     NoUpdateAvailable,
     AtLatestVersion,
     Upgradable,
@@ -79,32 +290,200 @@ impl ComponentUpdatable {
             None => ComponentUpdatable::NoUpdateAvailable,
         }
     }
+
+    /// Compare versions of supplemental firmware, which unlike components
+    /// have no timestamps.
+    pub(crate) fn from_versions(from: Option<&str>, to: Option<&str>) -> Self {
+        use std::cmp::Ordering;
+        match (from, to) {
+            (_, None) => ComponentUpdatable::NoUpdateAvailable,
+            (None, Some(_)) => ComponentUpdatable::Upgradable,
+            (Some(from), Some(to)) => match crate::packagesystem::compare_versions(from, to) {
+                Ordering::Less => ComponentUpdatable::Upgradable,
+                Ordering::Equal => ComponentUpdatable::AtLatestVersion,
+                Ordering::Greater => ComponentUpdatable::WouldDowngrade,
+            },
+        }
+    }
 }
 
 /// The status of an individual component.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct ComponentStatus {
+pub struct ComponentStatus {
     /// Currently installed version
-    pub(crate) installed: ContentMetadata,
+    pub installed: ContentMetadata,
     /// In progress update that was interrupted
-    pub(crate) interrupted: Option<ContentMetadata>,
+    pub interrupted: Option<ContentMetadata>,
     /// Update in the deployed filesystem tree
-    pub(crate) update: Option<ContentMetadata>,
+    pub update: Option<ContentMetadata>,
     /// Is true if the version in `update` is different from `installed`
-    pub(crate) updatable: ComponentUpdatable,
+    pub updatable: ComponentUpdatable,
     /// Originally adopted version
-    pub(crate) adopted_from: Option<ContentMetadata>,
+    pub adopted_from: Option<ContentMetadata>,
+    /// Checksums of partitions written directly by the component, keyed
+    /// by PARTUUID
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub partitions: BTreeMap<String, String>,
+    /// Problems with the installed version, e.g. images which a pending
+    /// `dbx` update would revoke
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Supplemental firmware included in the component
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub firmware: Vec<FirmwareStatus>,
 }
 
 /// Information on a component that can be adopted
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct Adoptable {
+pub struct Adoptable {
     /// A synthetic version
-    pub(crate) version: ContentMetadata,
+    pub version: ContentMetadata,
     /// True if we are likely to be able to reliably update this system
-    pub(crate) confident: bool,
+    pub confident: bool,
+}
+
+/// Information about an EFI System Partition
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct EspInfo {
+    /// Device node, e.g. `/dev/vda2`
+    pub device: String,
+    /// GPT partition UUID
+    pub partuuid: Option<String>,
+    /// Filesystem UUID
+    pub uuid: Option<String>,
+    /// Where the filesystem is currently mounted, if anywhere
+    pub mountpoint: Option<String>,
+    /// Partition size in bytes
+    pub size: Option<u64>,
+    /// Free space in bytes; only known if mounted
+    pub free: Option<u64>,
+    /// Whether the bootloader was installed to this ESP, according to the
+    /// disks recorded at installation; absent if none were recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed: Option<bool>,
+}
+
+/// How bootupd finds the disks to install to, as shown by `bootupctl
+/// inspect-disk`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiskTopology {
+    /// `/boot` and, on ostree systems, `/sysroot`
+    pub mounts: Vec<MountTopology>,
+    /// The parent devices of all mounts
+    pub disks: Vec<DiskInfo>,
+}
+
+/// The devices backing a mounted filesystem.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct MountTopology {
+    pub path: String,
+    /// The mounted device, e.g. `/dev/vda3` or `/dev/md126`
+    pub source: Option<String>,
+    /// The whole disks below the mounted device, e.g. both disks of a RAID 1
+    pub parents: Vec<String>,
+    /// Why the devices couldn't be found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A disk, and the partitions bootupd uses on it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiskInfo {
+    pub device: String,
+    /// The paths to the disk, if it is a multipath device
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub multipath_paths: Vec<String>,
+    pub partitions: Vec<BootPartition>,
+}
+
+/// The kinds of partitions bootupd installs to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootPartitionKind {
+    /// EFI System Partition
+    Esp,
+    /// BIOS boot partition, for GRUB's core image on GPT disks
+    BiosBoot,
+    /// PowerPC PReP boot partition
+    Prep,
+}
+
+impl std::fmt::Display for BootPartitionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BootPartitionKind::Esp => f.write_str("ESP"),
+            BootPartitionKind::BiosBoot => f.write_str("BIOS boot"),
+            BootPartitionKind::Prep => f.write_str("PReP"),
+        }
+    }
+}
+
+/// A partition bootupd installs to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct BootPartition {
+    pub kind: BootPartitionKind,
+    /// Device node, e.g. `/dev/vda2`
+    pub device: String,
+    /// Partition number, e.g. 2 for `/dev/vda2`
+    pub number: Option<u32>,
+}
+
+/// How the running system was booted.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootMethod {
+    Efi,
+    Bios,
+}
+
+impl std::fmt::Display for BootMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BootMethod::Efi => f.write_str("EFI"),
+            BootMethod::Bios => f.write_str("BIOS"),
+        }
+    }
+}
+
+/// Information about the firmware and how the running system was booted
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct BootInfo {
+    pub method: BootMethod,
+    /// Whether Secure Boot is enabled; only known when booted via EFI
+    pub secure_boot: Option<bool>,
+    /// Firmware vendor, from SMBIOS
+    pub firmware_vendor: Option<String>,
+    /// Firmware version, from SMBIOS
+    pub firmware_version: Option<String>,
+    /// The `LoaderInfo` EFI variable set by e.g. systemd-boot
+    pub loader_info: Option<String>,
+    /// The `StubInfo` EFI variable set by e.g. systemd-stub
+    pub stub_info: Option<String>,
+}
+
+/// A supplemental payload of a component, e.g. firmware added with
+/// `extend-payload`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct FirmwareStatus {
+    pub package: String,
+    /// Currently installed version
+    pub installed: Option<String>,
+    /// Version in the update payload
+    pub update: Option<String>,
+    /// Whether the version in `update` is newer than `installed`
+    pub updatable: ComponentUpdatable,
+    /// Versions shipped in the deployed filesystem tree, oldest first
+    #[serde(default)]
+    pub available: Vec<String>,
 }
 
 /// Representation of bootupd's worldview at a point in time.
@@ -114,11 +493,113 @@ pub(crate) struct Adoptable {
 #[derive(Serialize, Deserialize, Default, Debug)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
-pub(crate) struct Status {
+pub struct Status {
+    /// When the status was gathered, e.g. to tell how old a snapshot is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated_at: Option<DateTime<Utc>>,
+    /// The system the status is of, for tools collecting it from many
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostInfo>,
     /// Maps a component name to status
-    pub(crate) components: BTreeMap<String, ComponentStatus>,
+    pub components: BTreeMap<String, ComponentStatus>,
     /// Components that appear to be installed, not via bootupd
-    pub(crate) adoptable: BTreeMap<String, Adoptable>,
+    pub adoptable: BTreeMap<String, Adoptable>,
+    /// EFI System Partitions on the devices backing `/boot`
+    #[serde(default)]
+    pub esp: Vec<EspInfo>,
+    /// How the running system was booted; only known on EFI-capable
+    /// architectures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot: Option<BootInfo>,
+    /// What bootupd supports on this system
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<PlatformInfo>,
+    /// The last bootloader update, and whether it was confirmed by a
+    /// successful boot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_update: Option<LastUpdate>,
+    /// The booted ostree deployment, e.g. of a bootc image; absent on
+    /// other systems
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<DeploymentInfo>,
+    /// The result of the last `bootupctl scrub`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_scrub: Option<ScrubResult>,
+    /// Components excluded from updates and adoption by `bootupctl pin`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pinned: BTreeMap<String, Pin>,
+    /// Updates deferred because `bootupctl update` ran outside of the
+    /// maintenance windows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deferred: Option<DeferredUpdate>,
+}
+
+/// Identifies the system and bootupd version a status is from.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HostInfo {
+    /// The kernel hostname
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// The contents of `/etc/machine-id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    /// The version of bootupd which gathered the status
+    pub bootupd_version: String,
+    /// The booted ostree deployment, as `<checksum>.<serial>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub booted_deployment: Option<String>,
+}
+
+/// The booted ostree deployment, and how the bootloader relates to it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeploymentInfo {
+    /// The ostree commit of the deployment
+    pub checksum: String,
+    /// The container image the deployment was created from, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// The digest of the image manifest, e.g. `sha256:...`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_digest: Option<String>,
+    /// Whether the installed components match the update payloads shipped
+    /// in the deployment; if not, the bootloader is behind (or ahead of)
+    /// the OS
+    pub payload_matches: bool,
+    /// The `sysroot.bootloader` of the ostree repo config, if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootloader: Option<String>,
+    /// Why `bootloader` doesn't go with the GRUB config, if it doesn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootloader_warning: Option<String>,
+}
+
+/// The version of the command-line interface and JSON output, as seen by
+/// tools; bumped on incompatible changes, e.g. removing a verb or field.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// The components and optional features supported on the running system,
+/// so that tools don't need to know which are built for which architecture.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PlatformInfo {
+    /// See `bootupctl capabilities`; 0 if unknown, i.e. from before it
+    /// was introduced
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// The `bootupctl` verbs supported by this version
+    #[serde(default)]
+    pub verbs: Vec<String>,
+    /// The CPU architecture, e.g. `x86_64`
+    pub arch: String,
+    /// Components built in for this architecture, whether or not they are
+    /// installed; plugins aren't included
+    pub components: Vec<String>,
+    /// Optional features usable on this system: `nvram` (EFI boot entries
+    /// can be written), `tpm` (`pcr-predict`), `selinux` (labeling of
+    /// static configs) and `extlinux`
+    pub features: Vec<String>,
 }
 
 #[cfg(test)]
@@ -127,31 +608,109 @@ mod test {
     use anyhow::Result;
     use chrono::Duration;
 
+    #[test]
+    fn test_device_identity() {
+        let dev = |device: &str, wwn: Option<&str>, partuuid: &str| DeviceIdentity {
+            device: device.into(),
+            wwn: wwn.map(Into::into),
+            serial: None,
+            esp_partuuid: Some(partuuid.into()),
+        };
+        let a = dev("/dev/sda", Some("0x5000c500a1b2c3d4"), "aaaa");
+        let b = dev("/dev/sdb", Some("0x5000c500a1b2c3d5"), "bbbb");
+        // Device names may change
+        let a2 = dev("/dev/sdb", Some("0x5000C500A1B2C3D4"), "aaaa");
+        // Recorded while building a disk image
+        let a_loop = dev("/dev/loop0", None, "aaaa");
+        // A cloned disk
+        let a_clone = dev("/dev/sda", Some("0x5000c500ffffffff"), "aaaa");
+
+        assert!(a.matches(&a2));
+        assert!(a_loop.matches(&a));
+        assert!(!a.matches(&a_clone));
+        assert!(!a.matches(&b));
+        assert!(DeviceIdentity::same_set(
+            &[a.clone(), b.clone()],
+            &[b.clone(), a2]
+        ));
+        assert!(!DeviceIdentity::same_set(
+            &[a.clone(), b.clone()],
+            std::slice::from_ref(&a)
+        ));
+        assert!(!DeviceIdentity::same_set(&[a, b.clone()], &[a_clone, b]));
+    }
+
     #[test]
     fn test_meta_compare() {
         let t = Utc::now();
         let a = ContentMetadata {
             timestamp: t,
             version: "v1".into(),
+            firmware: None,
         };
         let b = ContentMetadata {
             timestamp: t + Duration::try_seconds(1).unwrap(),
             version: "v2".into(),
+            firmware: None,
         };
         assert!(a.can_upgrade_to(&b));
         assert!(!b.can_upgrade_to(&a));
     }
 
+    #[test]
+    fn test_firmware_updatable() {
+        use ComponentUpdatable::*;
+        for (from, to, expected) in [
+            (Some("1.0"), None, NoUpdateAvailable),
+            (None, None, NoUpdateAvailable),
+            (None, Some("1.0"), Upgradable),
+            (Some("1.0"), Some("1.0"), AtLatestVersion),
+            (Some("1.9-1"), Some("1.10-1"), Upgradable),
+            (Some("1.10-1"), Some("1.10~rc1-1"), WouldDowngrade),
+        ] {
+            assert_eq!(
+                ComponentUpdatable::from_versions(from, to),
+                expected,
+                "{from:?} -> {to:?}"
+            );
+        }
+    }
+
     /// Validate we're not breaking the serialized format of /boot/bootupd-state.json
     #[test]
     fn test_deserialize_state() -> Result<()> {
         let data = include_str!("../tests/fixtures/example-state-v0.json");
         let state: SavedState = serde_json::from_str(data)?;
+        assert_eq!(state.format_version, 0);
         let efi = state.installed.get("EFI").expect("EFI");
         assert_eq!(
             efi.meta.version,
             "grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"
         );
+        assert!(state.pinned.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin() -> Result<()> {
+        let mut state = SavedState::default();
+        let v = serde_json::to_value(&state)?;
+        assert!(v.get("pinned").is_none());
+        let timestamp = DateTime::parse_from_rfc3339("2024-05-01T10:00:00Z")?.into();
+        state.pinned.insert(
+            "EFI".into(),
+            Pin {
+                timestamp,
+                reason: Some("grub2 2.12-5 fails to boot".into()),
+            },
+        );
+        let v = serde_json::to_value(&state)?;
+        assert_eq!(v["pinned"]["EFI"]["reason"], "grub2 2.12-5 fails to boot");
+        let state: SavedState = serde_json::from_value(v)?;
+        assert_eq!(
+            state.pinned["EFI"].to_string(),
+            "since 2024-05-01T10:00:00+00:00: grub2 2.12-5 fails to boot"
+        );
         Ok(())
     }
 
@@ -165,6 +724,48 @@ mod test {
             efi.installed.version,
             "grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"
         );
+        assert_eq!(status.boot, None);
+        assert_eq!(status.last_update, None);
+        assert_eq!(status.deployment, None);
+        assert_eq!(status.generated_at, None);
+        assert_eq!(status.host, None);
+        Ok(())
+    }
+
+    /// Validate the host identity round-trips through `bootupctl status --json`
+    #[test]
+    fn test_deserialize_status_host() -> Result<()> {
+        let data = include_str!("../tests/fixtures/example-status-host.json");
+        let status: Status = serde_json::from_str(data)?;
+        let generated_at = DateTime::parse_from_rfc3339("2024-06-03T08:15:00Z")?;
+        assert_eq!(status.generated_at, Some(generated_at.into()));
+        let host = status.host.as_ref().expect("host");
+        assert_eq!(host.hostname.as_deref(), Some("node-17.example.com"));
+        assert_eq!(
+            host.machine_id.as_deref(),
+            Some("0c1a7e3b5d2f4e6a8b9c0d1e2f3a4b5c")
+        );
+        assert_eq!(host.bootupd_version, "0.2.20");
+        let v: serde_json::Value = serde_json::from_str(data)?;
+        assert_eq!(serde_json::to_value(&status)?, v);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_boot_info() -> Result<()> {
+        let boot = BootInfo {
+            method: BootMethod::Efi,
+            secure_boot: Some(true),
+            firmware_vendor: Some("EDK II".into()),
+            firmware_version: None,
+            loader_info: None,
+            stub_info: None,
+        };
+        let v = serde_json::to_value(&boot)?;
+        assert_eq!(v["method"], "efi");
+        assert_eq!(v["secure-boot"], true);
+        assert_eq!(v["firmware-vendor"], "EDK II");
+        assert_eq!(serde_json::from_value::<BootInfo>(v)?, boot);
         Ok(())
     }
 }
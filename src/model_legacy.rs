@@ -49,6 +49,7 @@ impl ContentMetadata01 {
         NewContentMetadata {
             timestamp,
             version: self.version,
+            firmware: None,
         }
     }
 }
@@ -59,6 +60,9 @@ impl InstalledContent01 {
             meta: self.meta.upconvert(),
             filetree: self.filetree,
             adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
         }
     }
 }
@@ -0,0 +1,161 @@
+//! Native mount handling, rather than forking `mount` and `umount`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rustix::fs::StatVfsMountFlags;
+use rustix::io::Errno;
+use rustix::mount::{MountFlags, MountPropagationFlags, UnmountFlags};
+
+/// Set once we have moved into our own mount namespace.
+static IN_PRIVATE_NAMESPACE: AtomicBool = AtomicBool::new(false);
+
+/// Errors from mount operations.
+#[derive(Debug)]
+pub(crate) enum MountError {
+    /// Creating a private mount namespace failed
+    Namespace(Errno),
+    /// Querying the mount flags of `target` failed
+    Stat { target: PathBuf, err: Errno },
+    /// Mounting `source` on `target` failed
+    Mount {
+        source: PathBuf,
+        target: PathBuf,
+        err: Errno,
+    },
+    /// Unmounting `target` failed
+    Unmount { target: PathBuf, err: Errno },
+    /// Remounting `target` writable failed
+    Remount { target: PathBuf, err: Errno },
+    /// Remounting `target` read-only again failed
+    RemountReadOnly { target: PathBuf, err: Errno },
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountError::Namespace(_) => write!(f, "Failed to create private mount namespace"),
+            MountError::Stat { target, .. } => write!(f, "Failed to query mount {target:?}"),
+            MountError::Mount { source, target, .. } => {
+                write!(f, "Failed to mount {source:?} on {target:?}")
+            }
+            MountError::Unmount { target, .. } => write!(f, "Failed to unmount {target:?}"),
+            MountError::Remount { target, .. } => {
+                write!(f, "Failed to remount {target:?} writable")
+            }
+            MountError::RemountReadOnly { target, .. } => {
+                write!(f, "Failed to remount {target:?} read-only")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MountError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MountError::Namespace(err)
+            | MountError::Stat { err, .. }
+            | MountError::Mount { err, .. }
+            | MountError::Unmount { err, .. }
+            | MountError::Remount { err, .. }
+            | MountError::RemountReadOnly { err, .. } => Some(err),
+        }
+    }
+}
+
+/// Move this process into a new mount namespace with private propagation,
+/// so that mounts we create are neither visible to nor affected by the rest
+/// of the system.  This is idempotent.
+pub(crate) fn enter_private_namespace() -> Result<(), MountError> {
+    if IN_PRIVATE_NAMESPACE.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    rustix::thread::unshare(rustix::thread::UnshareFlags::NEWNS).map_err(MountError::Namespace)?;
+    rustix::mount::mount_change(
+        "/",
+        MountPropagationFlags::PRIVATE | MountPropagationFlags::REC,
+    )
+    .map_err(MountError::Namespace)?;
+    IN_PRIVATE_NAMESPACE.store(true, Ordering::SeqCst);
+    log::debug!("Entered private mount namespace");
+    Ok(())
+}
+
+/// Mount the FAT filesystem on `source` at `target`.
+pub(crate) fn mount_vfat(source: &Path, target: &Path) -> Result<(), MountError> {
+    rustix::mount::mount(source, target, "vfat", MountFlags::empty(), "").map_err(|err| {
+        MountError::Mount {
+            source: source.to_owned(),
+            target: target.to_owned(),
+            err,
+        }
+    })
+}
+
+/// Bind mount `source` on `target`.
+pub(crate) fn bind_mount(source: &Path, target: &Path) -> Result<(), MountError> {
+    rustix::mount::mount_bind(source, target).map_err(|err| MountError::Mount {
+        source: source.to_owned(),
+        target: target.to_owned(),
+        err,
+    })
+}
+
+/// Unmount the filesystem at `target`.
+pub(crate) fn unmount(target: &Path) -> Result<(), MountError> {
+    rustix::mount::unmount(target, UnmountFlags::empty()).map_err(|err| MountError::Unmount {
+        target: target.to_owned(),
+        err,
+    })
+}
+
+/// Remount `target` read-write, preserving its other per-mount flags.
+pub(crate) fn remount_rw(target: &Path) -> Result<(), MountError> {
+    let stat = rustix::fs::statvfs(target).map_err(|err| MountError::Stat {
+        target: target.to_owned(),
+        err,
+    })?;
+    let flags = mount_flags_for_remount(stat.f_flag);
+    rustix::mount::mount_remount(target, flags, "").map_err(|err| MountError::Remount {
+        target: target.to_owned(),
+        err,
+    })
+}
+
+/// Remount `target` read-only, e.g. after [`remount_rw`], preserving its
+/// other per-mount flags.
+pub(crate) fn remount_ro(target: &Path) -> Result<(), MountError> {
+    let stat = rustix::fs::statvfs(target).map_err(|err| MountError::Stat {
+        target: target.to_owned(),
+        err,
+    })?;
+    let flags = mount_flags_for_remount(stat.f_flag) | MountFlags::RDONLY;
+    rustix::mount::mount_remount(target, flags, "").map_err(|err| MountError::RemountReadOnly {
+        target: target.to_owned(),
+        err,
+    })
+}
+
+/// A remount replaces all per-mount flags, so carry over everything
+/// except read-only.
+fn mount_flags_for_remount(current: StatVfsMountFlags) -> MountFlags {
+    let current = current.difference(StatVfsMountFlags::RDONLY);
+    // Both flag sets use the MS_* values.
+    MountFlags::from_bits_truncate(current.bits() as _)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remount_flags() {
+        let current =
+            StatVfsMountFlags::RDONLY | StatVfsMountFlags::NOSUID | StatVfsMountFlags::NODEV;
+        let flags = mount_flags_for_remount(current);
+        assert!(!flags.contains(MountFlags::RDONLY));
+        assert!(flags.contains(MountFlags::NOSUID));
+        assert!(flags.contains(MountFlags::NODEV));
+    }
+}
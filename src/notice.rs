@@ -0,0 +1,119 @@
+//! Notices for admins logging in interactively, as drop-ins for the
+//! message of the day (shown by `pam_motd` after login) and `/etc/issue`
+//! (shown by `agetty` before it), like rpm-ostree does for pending
+//! deployments.
+//!
+//! `bootupctl status` writes a notice about pending updates, and
+//! `bootupctl validate` one about validation failures, when asked to with
+//! `--motd` and/or `--issue`, e.g. from a timer; each removes its notice
+//! again once there's nothing to report.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use openat_ext::OpenatDirExt;
+
+/// Where `pam_motd` looks for drop-ins.
+const MOTD_DIR: &str = "/run/motd.d";
+
+/// Where `agetty` looks for drop-ins; they need the `.issue` suffix.
+const ISSUE_DIR: &str = "/run/issue.d";
+
+/// Which drop-ins to write.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Targets {
+    pub(crate) motd: bool,
+    pub(crate) issue: bool,
+}
+
+/// What a notice is about; each has its own drop-ins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Topic {
+    /// Updates which `bootupctl update` would apply
+    Updates,
+    /// Components which failed validation
+    Validation,
+}
+
+impl Topic {
+    fn name(self) -> &'static str {
+        match self {
+            Topic::Updates => "bootupd",
+            Topic::Validation => "bootupd-validation",
+        }
+    }
+
+    /// The notice about `components`, if there are any.
+    pub(crate) fn message(self, components: &[&str]) -> Option<String> {
+        if components.is_empty() {
+            return None;
+        }
+        let components = components.join(" ");
+        Some(match self {
+            Topic::Updates => format!(
+                "Bootloader updates available: {components}\n\
+                 Run `bootupctl update` to apply them.\n"
+            ),
+            Topic::Validation => format!(
+                "Bootloader validation failed: {components}\n\
+                 Run `bootupctl validate` for details.\n"
+            ),
+        })
+    }
+}
+
+/// Write `msg` to the file `name` in `dir`, or remove it if there's no
+/// message.
+fn write_in(dir: &Path, name: &str, msg: Option<&str>) -> Result<()> {
+    let Some(msg) = msg else {
+        if dir.exists() {
+            openat::Dir::open(dir)?.remove_file_optional(name)?;
+        }
+        return Ok(());
+    };
+    std::fs::create_dir_all(dir)?;
+    openat::Dir::open(dir)?.write_file_contents(name, 0o644, msg)?;
+    Ok(())
+}
+
+/// Write the notice about `components` for `topic` to the drop-ins in
+/// `targets`, or remove them if there are no such components.
+pub(crate) fn write(targets: Targets, topic: Topic, components: &[&str]) -> Result<()> {
+    let msg = topic.message(components);
+    if targets.motd {
+        write_in(Path::new(MOTD_DIR), topic.name(), msg.as_deref())
+            .with_context(|| format!("Writing notice to {MOTD_DIR}"))?;
+    }
+    if targets.issue {
+        let name = format!("{}.issue", topic.name());
+        write_in(Path::new(ISSUE_DIR), &name, msg.as_deref())
+            .with_context(|| format!("Writing notice to {ISSUE_DIR}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_notice() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = td.path().join("motd.d");
+        let msg = Topic::Updates.message(&["EFI", "BIOS"]);
+        assert_eq!(
+            msg.as_deref(),
+            Some("Bootloader updates available: EFI BIOS\nRun `bootupctl update` to apply them.\n")
+        );
+        write_in(&dir, "bootupd", msg.as_deref())?;
+        assert_eq!(std::fs::read_to_string(dir.join("bootupd"))?, msg.unwrap());
+
+        // Nothing to report removes the notice
+        assert!(Topic::Updates.message(&[]).is_none());
+        write_in(&dir, "bootupd", None)?;
+        assert!(!dir.join("bootupd").exists());
+        write_in(&dir, "bootupd", None)?;
+        write_in(&td.path().join("issue.d"), "bootupd.issue", None)?;
+        Ok(())
+    }
+}
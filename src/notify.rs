@@ -0,0 +1,73 @@
+//! Progress reporting to systemd via `sd_notify`, so that e.g.
+//! `systemctl status bootupd` shows what we are doing.  The status is a
+//! structured event of `key=value` fields, e.g. `step=apply component=EFI
+//! files=3/10`, which tools can follow via `systemctl show -p StatusText`.
+//!
+//! Each status update also pings the service watchdog, so hangs can be
+//! detected by setting `WatchdogSec=`.  The units are `Type=oneshot`, so
+//! no `READY=1` is sent; the last status tells how the run ended instead.
+//! All of this is a no-op when not running under systemd (i.e.
+//! `NOTIFY_SOCKET` is unset).
+
+use libsystemd::daemon::{self, NotifyState};
+
+fn send(state: &[NotifyState]) {
+    // This is purely informational, so never fail because of it.
+    if let Err(e) = daemon::notify(false, state) {
+        log::debug!("sd_notify failed: {e}");
+    }
+}
+
+/// The `key=value` fields of an event; values with spaces or quotes are
+/// quoted.
+fn event(step: &str, component: Option<&str>, fields: &[(&str, String)]) -> String {
+    let mut r = format!("step={step}");
+    let component = component.map(|c| ("component", c));
+    for (k, v) in component
+        .into_iter()
+        .chain(fields.iter().map(|(k, v)| (*k, v.as_str())))
+    {
+        if v.contains(|c: char| c.is_whitespace() || c == '"') {
+            r.push_str(&format!(" {k}={v:?}"));
+        } else {
+            r.push_str(&format!(" {k}={v}"));
+        }
+    }
+    r
+}
+
+/// Report that we are at `step`, on `component` if any, with `fields`
+/// detailing it.
+pub(crate) fn progress(step: &str, component: Option<&str>, fields: &[(&str, String)]) {
+    send(&[
+        NotifyState::Status(event(step, component, fields)),
+        NotifyState::Watchdog,
+    ]);
+}
+
+/// Report how the run ended, `step=done` or `step=failed` with the error.
+pub(crate) fn finished<T>(r: &anyhow::Result<T>) {
+    let status = match r {
+        Ok(_) => event("done", None, &[]),
+        Err(e) => event("failed", None, &[("error", format!("{e:#}"))]),
+    };
+    send(&[NotifyState::Status(status)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event() {
+        assert_eq!(event("done", None, &[]), "step=done");
+        assert_eq!(
+            event("apply", Some("EFI"), &[("files", "3/10".into())]),
+            "step=apply component=EFI files=3/10"
+        );
+        assert_eq!(
+            event("pull", None, &[("image", "a b\"c".into())]),
+            r#"step=pull image="a b\"c""#
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Content-addressed storage for the supplemental payloads in
+//! `usr/lib/efi/firmware`, so that identical files (e.g. the same firmware
+//! blob shipped for several boards, or kept in several versions of a
+//! package) are only stored once in the image.
+//!
+//! A store is a directory of `<package>/<version>/` trees.  Each distinct
+//! file is hardlinked as `.objects/<object>` in it, and files in the trees
+//! with the same content are replaced by hardlinks to it.  Hardlinks share
+//! their mode, owner and modification time, so these are part of the object
+//! name along with the SHA-512; files which only have the same content are
+//! stored separately, which keeps e.g. the payload timestamps derived from
+//! the mtimes intact.
+
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+
+use crate::filetree::{FileTree, TMP_PREFIX};
+
+/// The object store, relative to the store directory.
+pub(crate) const OBJECTS_DIR: &str = ".objects";
+
+/// The name of the object for a file with the checksum `sha512` and the
+/// metadata `meta`.
+fn object_name(sha512: &str, meta: &Metadata) -> String {
+    let digest = sha512.strip_prefix("sha512:").unwrap_or(sha512);
+    format!(
+        "{digest}-{:o}-{}-{}-{}.{}",
+        meta.mode() & 0o7777,
+        meta.uid(),
+        meta.gid(),
+        meta.mtime(),
+        meta.mtime_nsec()
+    )
+}
+
+/// Store the files of the `<package>/<version>/` trees in `store`,
+/// replacing those with already stored objects by hardlinks; returns the
+/// number of bytes saved.
+#[context("Deduplicating {}", store.display())]
+pub(crate) fn dedup(store: &Path) -> Result<u64> {
+    let objects = store.join(OBJECTS_DIR);
+    std::fs::create_dir_all(&objects)?;
+    let mut saved = 0;
+    for pkg in std::fs::read_dir(store)? {
+        let pkg = pkg?;
+        if pkg.file_name() == OBJECTS_DIR || !pkg.file_type()?.is_dir() {
+            continue;
+        }
+        for ver in std::fs::read_dir(pkg.path())? {
+            let ver = ver?;
+            if ver.file_type()?.is_dir() {
+                saved += dedup_dir(&objects, &ver.path())?;
+            }
+        }
+    }
+    Ok(saved)
+}
+
+/// Store the files in `dir` in `objects`, see [`dedup`].
+fn dedup_dir(objects: &Path, dir: &Path) -> Result<u64> {
+    let tree = FileTree::new_from_dir(&openat::Dir::open(dir)?)?;
+    let mut saved = 0;
+    for (path, meta) in tree.children.iter() {
+        let file = dir.join(path);
+        let fmeta = std::fs::symlink_metadata(&file)?;
+        if !fmeta.is_file() {
+            continue;
+        }
+        let object = objects.join(object_name(&meta.sha512.0, &fmeta));
+        let ometa = match std::fs::symlink_metadata(&object) {
+            Ok(m) => m,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::hard_link(&file, &object)
+                    .with_context(|| format!("Storing {}", file.display()))?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if (ometa.dev(), ometa.ino()) == (fmeta.dev(), fmeta.ino()) {
+            continue;
+        }
+        // Replace the file atomically
+        let name = file.file_name().context("Invalid payload path")?;
+        let mut tmpname = std::ffi::OsString::from(TMP_PREFIX);
+        tmpname.push(name);
+        let tmp = file.with_file_name(tmpname);
+        std::fs::hard_link(&object, &tmp).with_context(|| format!("Linking {}", file.display()))?;
+        std::fs::rename(&tmp, &file)?;
+        saved += fmeta.len();
+    }
+    Ok(saved)
+}
+
+/// Remove the objects in `store` which are no longer used by any tree,
+/// e.g. after removing old versions.
+#[context("Pruning objects in {}", store.display())]
+pub(crate) fn prune(store: &Path) -> Result<()> {
+    let objects = store.join(OBJECTS_DIR);
+    let entries = match std::fs::read_dir(&objects) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for e in entries {
+        let e = e?;
+        if e.metadata()?.nlink() <= 1 {
+            log::debug!("Removing unused object {:?}", e.file_name());
+            std::fs::remove_file(e.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_dedup() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let store = td.path();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+        let files = [
+            ("fw-a/1.0-1/board1/x.bin", "blob"),
+            ("fw-a/1.0-1/board2/x.bin", "blob"),
+            ("fw-a/1.1-1/x.bin", "blob"),
+            ("fw-b/2.0-1/y.bin", "blob"),
+            ("fw-b/2.0-1/z.bin", "other"),
+        ];
+        crate::testutils::write_tree(store, &files)?;
+        for (path, _) in files {
+            std::fs::File::options()
+                .write(true)
+                .open(store.join(path))?
+                .set_modified(mtime)?;
+        }
+        // Same content, but a different mtime
+        std::fs::File::options()
+            .write(true)
+            .open(store.join("fw-b/2.0-1/y.bin"))?
+            .set_modified(mtime + Duration::from_secs(1))?;
+        let tree = FileTree::new_from_dir(&openat::Dir::open(&store.join("fw-a/1.0-1"))?)?;
+
+        assert_eq!(dedup(store)?, 8);
+        // Idempotent
+        assert_eq!(dedup(store)?, 0);
+
+        let ino = |p: &str| -> Result<u64> { Ok(std::fs::metadata(store.join(p))?.ino()) };
+        assert_eq!(
+            ino("fw-a/1.0-1/board1/x.bin")?,
+            ino("fw-a/1.0-1/board2/x.bin")?
+        );
+        assert_eq!(ino("fw-a/1.0-1/board1/x.bin")?, ino("fw-a/1.1-1/x.bin")?);
+        assert_ne!(ino("fw-a/1.0-1/board1/x.bin")?, ino("fw-b/2.0-1/y.bin")?);
+        assert_ne!(ino("fw-a/1.0-1/board1/x.bin")?, ino("fw-b/2.0-1/z.bin")?);
+        assert_eq!(
+            std::fs::metadata(store.join("fw-b/2.0-1/y.bin"))?.modified()?,
+            mtime + Duration::from_secs(1)
+        );
+        assert_eq!(std::fs::read_dir(store.join(OBJECTS_DIR))?.count(), 3);
+
+        // Removing fw-b leaves its objects unused
+        std::fs::remove_dir_all(store.join("fw-b"))?;
+        prune(store)?;
+        assert_eq!(std::fs::read_dir(store.join(OBJECTS_DIR))?.count(), 1);
+        // FileTree skips our temporary files, and none are left over
+        assert_eq!(
+            FileTree::new_from_dir(&openat::Dir::open(&store.join("fw-a/1.0-1"))?)?,
+            tree
+        );
+        Ok(())
+    }
+}
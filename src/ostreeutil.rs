@@ -4,9 +4,10 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
 use log::debug;
 
 use crate::util::CommandRunExt;
@@ -16,6 +17,19 @@ use crate::util::CommandRunExt;
 pub(crate) const BOOT_PREFIX: &str = "usr/lib/ostree-boot";
 const LEGACY_RPMOSTREE_DBPATH: &str = "usr/share/rpm";
 const SYSIMAGE_RPM_DBPATH: &str = "usr/lib/sysimage/rpm";
+/// Holds `<stateroot>/deploy/<checksum>.<serial>` in an ostree physical root.
+const DEPLOY_DIR: &str = "ostree/deploy";
+/// The repo of the booted ostree system.
+pub(crate) const SYSROOT_REPO: &str = "/sysroot/ostree/repo";
+/// Exists when the running system was booted from an ostree deployment.
+pub(crate) const OSTREE_BOOTED: &str = "/run/ostree-booted";
+/// Commit metadata key holding the digest of the container image manifest
+/// a commit was imported from.
+const MANIFEST_DIGEST_KEY: &str = "ostree.manifest-digest";
+/// The values of `sysroot.bootloader` which ostree accepts.
+pub(crate) const BOOTLOADERS: &[&str] = &[
+    "auto", "none", "grub2", "syslinux", "uboot", "zipl", "aboot",
+];
 
 /// Returns true if the target directory contains at least one file that does
 /// not start with `.`
@@ -66,3 +80,457 @@ pub(crate) fn rpm_cmd<P: AsRef<Path>>(sysroot: P) -> Result<std::process::Comman
     }
     Ok(c)
 }
+
+/// The directories holding the deployments of each stateroot in the
+/// ostree physical root `sysroot`.
+pub(crate) fn deploy_dirs(sysroot: &Path) -> Result<Vec<PathBuf>> {
+    let mut r = Vec::new();
+    for stateroot in std::fs::read_dir(sysroot.join(DEPLOY_DIR))? {
+        let deploydir = stateroot?.path().join("deploy");
+        if deploydir.is_dir() {
+            r.push(deploydir);
+        }
+    }
+    Ok(r)
+}
+
+/// The roots of all deployments in the ostree physical root `sysroot`.
+fn list_deployments(sysroot: &Path) -> Result<Vec<PathBuf>> {
+    let mut deployments = Vec::new();
+    for deploydir in deploy_dirs(sysroot)? {
+        for ent in std::fs::read_dir(&deploydir)? {
+            let ent = ent?;
+            // Skip the `.origin` files alongside each deployment
+            if ent.file_type()?.is_dir() {
+                deployments.push(ent.path());
+            }
+        }
+    }
+    Ok(deployments)
+}
+
+/// Find the root of the deployment in the ostree physical root `sysroot`.
+/// There must be exactly one, as on a freshly installed system.
+#[context("Finding ostree deployment in {}", sysroot.display())]
+pub(crate) fn find_deployment(sysroot: &Path) -> Result<PathBuf> {
+    let mut deployments = list_deployments(sysroot)?;
+    match deployments.len() {
+        0 => bail!("No deployments found"),
+        1 => Ok(deployments.pop().unwrap()),
+        n => bail!("Expected one deployment, found {n}"),
+    }
+}
+
+/// Find the root, under the ostree physical root `sysroot`, of the newest
+/// deployment of the booted system: the staged one if any, else the one
+/// booted by default, as ordered by ostree.
+#[cfg(feature = "ostree")]
+#[context("Finding newest ostree deployment")]
+pub(crate) fn find_newest_deployment(sysroot: &Path) -> Result<PathBuf> {
+    let booted = ostree::Sysroot::new_default();
+    booted.load(ostree::gio::Cancellable::NONE)?;
+    // The staged deployment, if any, comes first
+    let Some(deployment) = booted.deployments().into_iter().next() else {
+        bail!("No deployments found");
+    };
+    Ok(sysroot.join(booted.deployment_dirpath(&deployment).as_str()))
+}
+
+/// Find the root, under the ostree physical root `sysroot`, of the newest
+/// deployment of the booted system: the staged one if any, else the one
+/// booted by default, as ordered by ostree.
+#[cfg(not(feature = "ostree"))]
+#[context("Finding newest ostree deployment")]
+pub(crate) fn find_newest_deployment(sysroot: &Path) -> Result<PathBuf> {
+    let out = std::process::Command::new("ostree")
+        .args(["admin", "status"])
+        .output()?;
+    if !out.status.success() {
+        bail!(
+            "ostree admin status failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    let status = String::from_utf8(out.stdout).context("Decoding ostree output as UTF-8")?;
+    let Some((stateroot, name)) = first_deployment(&status) else {
+        bail!("No deployments found");
+    };
+    Ok(sysroot
+        .join(DEPLOY_DIR)
+        .join(stateroot)
+        .join("deploy")
+        .join(name))
+}
+
+/// The stateroot and name (`<checksum>.<serial>`) of the first deployment
+/// listed by `ostree admin status`, e.g.
+/// `* fedora-coreos 3a1b...e9.0 (staged)`, which lists them in ostree's
+/// order, with details on more deeply indented lines.
+#[cfg(not(feature = "ostree"))]
+fn first_deployment(status: &str) -> Option<(&str, &str)> {
+    status.lines().find_map(|l| {
+        let l = l.strip_prefix("* ").or_else(|| l.strip_prefix("  "))?;
+        if l.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let mut words = l.split_whitespace();
+        let stateroot = words.next()?;
+        let name = words.next()?;
+        name.contains('.').then_some((stateroot, name))
+    })
+}
+
+/// Find the root of the booted deployment in the ostree physical root
+/// `sysroot`, from the `ostree=` argument in the kernel command line
+/// `cmdline`, which is a symlink to it.
+#[context("Finding booted ostree deployment")]
+pub(crate) fn find_booted_deployment(sysroot: &Path, cmdline: &str) -> Result<PathBuf> {
+    let Some(arg) = cmdline
+        .split_ascii_whitespace()
+        .find_map(|a| a.strip_prefix("ostree="))
+    else {
+        bail!("No ostree= kernel argument");
+    };
+    let path = sysroot.join(arg.trim_start_matches('/'));
+    std::fs::canonicalize(&path).with_context(|| format!("Resolving {}", path.display()))
+}
+
+/// The commit of the deployment at `deployment`, from its name
+/// `<checksum>.<serial>`.
+pub(crate) fn deployment_checksum(deployment: &Path) -> Result<String> {
+    deployment
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.split_once('.'))
+        .map(|(checksum, _)| checksum.to_string())
+        .with_context(|| format!("Invalid deployment {}", deployment.display()))
+}
+
+/// The value of `key` in the `[origin]` group of a deployment's origin file.
+fn origin_value(origin: &str, key: &str) -> Option<String> {
+    let mut in_origin = false;
+    for line in origin.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_origin = line == "[origin]";
+        } else if in_origin {
+            if let Some((k, v)) = line.split_once('=') {
+                if k.trim() == key {
+                    return Some(v.trim().to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The container image reference in the origin file of a deployment, if
+/// it was created from one (e.g. by bootc).
+fn origin_image(origin: &str) -> Option<String> {
+    origin_value(origin, "container-image-reference")
+}
+
+/// The origin file of the deployment at `deployment`, if it has one.
+fn read_origin(deployment: &Path) -> Result<Option<String>> {
+    let mut origin = deployment.as_os_str().to_owned();
+    origin.push(".origin");
+    match std::fs::read_to_string(&origin) {
+        Ok(o) => Ok(Some(o)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The container image the deployment at `deployment` was created from,
+/// if any, according to its `.origin` file.
+#[context("Reading origin of {}", deployment.display())]
+pub(crate) fn deployment_image(deployment: &Path) -> Result<Option<String>> {
+    Ok(read_origin(deployment)?.as_deref().and_then(origin_image))
+}
+
+/// What the deployment at `deployment` follows: its container image, or
+/// else its ostree refspec.
+#[context("Reading origin of {}", deployment.display())]
+pub(crate) fn deployment_source(deployment: &Path) -> Result<Option<String>> {
+    let Some(origin) = read_origin(deployment)? else {
+        return Ok(None);
+    };
+    Ok(origin_image(&origin).or_else(|| origin_value(&origin, "refspec")))
+}
+
+/// The digest of the container image manifest the commit `checksum` in the
+/// booted system's repo was imported from, if any.
+#[cfg(feature = "ostree")]
+#[context("Querying image digest of {checksum}")]
+pub(crate) fn manifest_digest(checksum: &str) -> Result<Option<String>> {
+    let (commit, _) = open_sysroot_repo()?.load_commit(checksum)?;
+    let metadata = ostree::glib::VariantDict::new(Some(&commit.child_value(0)));
+    Ok(metadata.lookup::<String>(MANIFEST_DIGEST_KEY)?)
+}
+
+/// The digest of the container image manifest the commit `checksum` in the
+/// booted system's repo was imported from, if any.
+#[cfg(not(feature = "ostree"))]
+#[context("Querying image digest of {checksum}")]
+pub(crate) fn manifest_digest(checksum: &str) -> Result<Option<String>> {
+    let result = std::process::Command::new("ostree")
+        .args(["show", &format!("--repo={SYSROOT_REPO}")])
+        .arg(format!("--print-metadata-key={MANIFEST_DIGEST_KEY}"))
+        .arg(checksum)
+        .output()?;
+    // ostree will exit with a non zero return code if the key does not exist
+    if !result.status.success() {
+        return Ok(None);
+    }
+    let res = String::from_utf8(result.stdout).context("Decoding ostree output as UTF-8")?;
+    // Printed as a GVariant string, e.g. 'sha256:...'
+    Ok(Some(res.trim().trim_matches('\'').to_string()))
+}
+
+/// Open the booted system's repo.
+#[cfg(feature = "ostree")]
+fn open_sysroot_repo() -> Result<ostree::Repo> {
+    let repo = ostree::Repo::new_for_path(SYSROOT_REPO);
+    repo.open(ostree::gio::Cancellable::NONE)
+        .with_context(|| format!("Opening {SYSROOT_REPO}"))?;
+    Ok(repo)
+}
+
+/// Read `sysroot.bootloader` from the config of the booted system's repo;
+/// `None` if it isn't set.
+#[cfg(feature = "ostree")]
+#[context("Querying ostree sysroot.bootloader")]
+pub(crate) fn get_ostree_bootloader() -> Result<Option<String>> {
+    use ostree::glib::KeyFileError;
+    let config = open_sysroot_repo()?
+        .config()
+        .context("Repo has no config")?;
+    match config.string("sysroot", "bootloader") {
+        Ok(v) => Ok(Some(v.to_string())),
+        Err(e)
+            if e.matches(KeyFileError::KeyNotFound) || e.matches(KeyFileError::GroupNotFound) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Set `sysroot.bootloader` in the config of the booted system's repo, or
+/// unset it if `value` is `None`.
+#[cfg(feature = "ostree")]
+#[context("Setting ostree sysroot.bootloader to {value:?}")]
+pub(crate) fn set_ostree_bootloader(value: Option<&str>) -> Result<()> {
+    use ostree::glib::KeyFileError;
+    let repo = open_sysroot_repo()?;
+    let config = repo.copy_config().context("Repo has no config")?;
+    match value {
+        Some(v) => config.set_string("sysroot", "bootloader", v),
+        None => match config.remove_key("sysroot", "bootloader") {
+            Ok(()) => {}
+            Err(e)
+                if e.matches(KeyFileError::KeyNotFound)
+                    || e.matches(KeyFileError::GroupNotFound) => {}
+            Err(e) => return Err(e.into()),
+        },
+    }
+    repo.write_config(&config)?;
+    Ok(())
+}
+
+/// Read `sysroot.bootloader` from the config of the booted system's repo;
+/// `None` if it isn't set.
+#[cfg(not(feature = "ostree"))]
+#[context("Querying ostree sysroot.bootloader")]
+pub(crate) fn get_ostree_bootloader() -> Result<Option<String>> {
+    let result = std::process::Command::new("ostree")
+        .args(["config", &format!("--repo={SYSROOT_REPO}")])
+        .args(["get", "sysroot.bootloader"])
+        .output()?;
+    // ostree will exit with a non zero return code if the key does not exist
+    if !result.status.success() {
+        return Ok(None);
+    }
+    let res = String::from_utf8(result.stdout).context("Decoding ostree output as UTF-8")?;
+    Ok(Some(res.trim_end().to_string()))
+}
+
+/// Set `sysroot.bootloader` in the config of the booted system's repo, or
+/// unset it if `value` is `None`.
+#[cfg(not(feature = "ostree"))]
+#[context("Setting ostree sysroot.bootloader to {value:?}")]
+pub(crate) fn set_ostree_bootloader(value: Option<&str>) -> Result<()> {
+    let mut cmd = std::process::Command::new("ostree");
+    cmd.args(["config", &format!("--repo={SYSROOT_REPO}")]);
+    match value {
+        Some(v) => cmd.args(["set", "sysroot.bootloader", v]),
+        None => cmd.args(["unset", "sysroot.bootloader"]),
+    };
+    cmd.run()
+}
+
+/// Check that `value` is a valid `sysroot.bootloader`.
+pub(crate) fn validate_bootloader(value: &str) -> Result<()> {
+    if !BOOTLOADERS.contains(&value) {
+        bail!(
+            "Invalid sysroot.bootloader {value:?}; expected one of: {}",
+            BOOTLOADERS.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Whether the GRUB config in `bootdir` is static, i.e. a regular file
+/// rather than ostree's symlink to the config it generates; `None` if
+/// there is no GRUB config.
+pub(crate) fn static_grub_config(bootdir: &Path) -> Result<Option<bool>> {
+    match std::fs::symlink_metadata(bootdir.join("grub2/grub.cfg")) {
+        Ok(m) => Ok(Some(!m.file_type().is_symlink())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The problem with `sysroot.bootloader` being `bootloader`, given whether
+/// the GRUB config is static, if they don't go together.
+///
+/// When `sysroot.bootloader` is unset or `auto`, ostree looks for a GRUB
+/// config and keeps generating one on each deployment, even once it was
+/// replaced by a static one; conversely, with `none` and ostree's
+/// symlinked config, new deployments never make it into the boot menu.
+pub(crate) fn bootloader_mismatch(
+    bootloader: Option<&str>,
+    static_config: Option<bool>,
+) -> Option<String> {
+    match (bootloader.unwrap_or("auto"), static_config?) {
+        (b @ ("auto" | "grub2"), true) => Some(format!(
+            "A static GRUB config is installed, but sysroot.bootloader is '{b}'; \
+             run `bootupctl bootloader-config set none`"
+        )),
+        ("none", false) => Some(
+            "sysroot.bootloader is 'none', but the GRUB config is still generated by ostree; \
+             run `bootupctl migrate-static-grub-config`"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_deployment() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path();
+        assert!(find_deployment(sysroot).is_err());
+        let deploydir = sysroot.join("ostree/deploy/default/deploy");
+        std::fs::create_dir_all(&deploydir)?;
+        assert!(find_deployment(sysroot).is_err());
+        let deployment = deploydir.join("abcd.0");
+        std::fs::create_dir(&deployment)?;
+        std::fs::write(deploydir.join("abcd.0.origin"), "")?;
+        assert_eq!(find_deployment(sysroot)?, deployment);
+        let other = sysroot.join("ostree/deploy/other/deploy/ef01.0");
+        std::fs::create_dir_all(&other)?;
+        assert!(find_deployment(sysroot).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "ostree"))]
+    fn test_first_deployment() {
+        // Listed in ostree's order, whenever their directories were created
+        let status = "  fedora-coreos 9c2d57ce33a14bd8f6c8a8b1e87c8c3c7fbc2a0a8d8b5e5a6f2c0d1e2f3a4b5c.0 (pending)
+    Version: 41.20241201.3.0 (2024-12-16T17:03:52Z)
+    origin refspec: fedora:fedora/x86_64/coreos/stable
+* fedora-coreos 3a1bb6b3d3a5c5e1d5f0a9c8b7e6d5c4b3a2f1e0d9c8b7a6f5e4d3c2b1a0f9e8.1
+    Version: 41.20241109.3.0 (2024-11-25T15:09:04Z)
+    origin refspec: fedora:fedora/x86_64/coreos/stable
+  fedora-coreos 0d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e.0 (rollback)
+    Version: 41.20241027.3.0 (2024-11-11T14:07:10Z)
+";
+        assert_eq!(
+            first_deployment(status),
+            Some((
+                "fedora-coreos",
+                "9c2d57ce33a14bd8f6c8a8b1e87c8c3c7fbc2a0a8d8b5e5a6f2c0d1e2f3a4b5c.0"
+            ))
+        );
+        let status = "* default abcd.0\n    Version: 1\n";
+        assert_eq!(first_deployment(status), Some(("default", "abcd.0")));
+        assert_eq!(first_deployment("No deployments.\n"), None);
+    }
+
+    #[test]
+    fn test_find_booted_deployment() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path();
+        let deployment = sysroot.join("ostree/deploy/default/deploy/abcd.0");
+        std::fs::create_dir_all(&deployment)?;
+        let bootlink = sysroot.join("ostree/boot.1/default/1234/0");
+        std::fs::create_dir_all(bootlink.parent().unwrap())?;
+        std::os::unix::fs::symlink("../../../deploy/default/deploy/abcd.0", &bootlink)?;
+        let cmdline = "BOOT_IMAGE=(hd0,gpt3)/ostree/default-1234/vmlinuz ostree=/ostree/boot.1/default/1234/0 rw";
+        let found = find_booted_deployment(sysroot, cmdline)?;
+        assert_eq!(found, std::fs::canonicalize(&deployment)?);
+        assert_eq!(deployment_checksum(&found)?, "abcd");
+        assert!(find_booted_deployment(sysroot, "root=/dev/vda4").is_err());
+
+        assert_eq!(deployment_image(&deployment)?, None);
+        std::fs::write(
+            sysroot.join("ostree/deploy/default/deploy/abcd.0.origin"),
+            "[origin]\ncontainer-image-reference=ostree-unverified-registry:quay.io/fedora/fedora-bootc:41\n",
+        )?;
+        assert_eq!(
+            deployment_image(&deployment)?.as_deref(),
+            Some("ostree-unverified-registry:quay.io/fedora/fedora-bootc:41")
+        );
+        assert_eq!(
+            deployment_source(&deployment)?.as_deref(),
+            Some("ostree-unverified-registry:quay.io/fedora/fedora-bootc:41")
+        );
+        assert_eq!(
+            origin_image("[origin]\nrefspec=fedora:fedora/x86_64/coreos/stable\n"),
+            None
+        );
+        assert_eq!(
+            origin_value(
+                "[origin]\nrefspec=fedora:fedora/x86_64/coreos/stable\n",
+                "refspec"
+            )
+            .as_deref(),
+            Some("fedora:fedora/x86_64/coreos/stable")
+        );
+        assert_eq!(
+            origin_image("[other]\ncontainer-image-reference=x\n[origin]\nrefspec=y\n"),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootloader_mismatch() -> Result<()> {
+        assert!(validate_bootloader("none").is_ok());
+        assert!(validate_bootloader("grub").is_err());
+
+        let td = tempfile::tempdir()?;
+        let boot = td.path();
+        assert_eq!(static_grub_config(boot)?, None);
+        std::fs::create_dir(boot.join("grub2"))?;
+        std::os::unix::fs::symlink("../loader/grub.cfg", boot.join("grub2/grub.cfg"))?;
+        assert_eq!(static_grub_config(boot)?, Some(false));
+        std::fs::remove_file(boot.join("grub2/grub.cfg"))?;
+        std::fs::write(boot.join("grub2/grub.cfg"), "")?;
+        assert_eq!(static_grub_config(boot)?, Some(true));
+
+        assert!(bootloader_mismatch(None, Some(true)).is_some());
+        assert!(bootloader_mismatch(Some("auto"), Some(true)).is_some());
+        assert!(bootloader_mismatch(Some("none"), Some(true)).is_none());
+        assert!(bootloader_mismatch(Some("none"), Some(false)).is_some());
+        assert!(bootloader_mismatch(None, Some(false)).is_none());
+        // Nothing to compare with without a GRUB config, e.g. on s390x
+        assert!(bootloader_mismatch(Some("zipl"), None).is_none());
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
 use chrono::prelude::*;
@@ -25,6 +26,13 @@ fn rpm_parse_metadata(stdout: &[u8]) -> Result<ContentMetadata> {
             }
         })
         .collect::<Result<BTreeMap<&str, DateTime<Utc>>>>()?;
+    package_metadata(pkgs)
+}
+
+/// Describe the content owned by `pkgs`, given as NEVRA and build time.
+pub(crate) fn package_metadata<S: AsRef<str>>(
+    pkgs: BTreeMap<S, DateTime<Utc>>,
+) -> Result<ContentMetadata> {
     if pkgs.is_empty() {
         bail!("Failed to find any RPM packages matching files in source efidir");
     }
@@ -35,12 +43,13 @@ fn rpm_parse_metadata(stdout: &[u8]) -> Result<ContentMetadata> {
         if !s.is_empty() {
             s.push(',');
         }
-        s.push_str(n);
+        s.push_str(n.as_ref());
         s
     });
     Ok(ContentMetadata {
         timestamp: **largest_timestamp,
         version,
+        firmware: None,
     })
 }
 
@@ -52,6 +61,12 @@ pub(crate) fn query_files<T>(
 where
     T: AsRef<Path>,
 {
+    #[cfg(feature = "rpmdb")]
+    if let Some(db) = crate::rpmdb::find(Path::new(sysroot_path)) {
+        let pkgs = crate::rpmdb::query_files(&db, Path::new(sysroot_path), paths)?;
+        return package_metadata(pkgs);
+    }
+
     let mut c = ostreeutil::rpm_cmd(sysroot_path)?;
     c.args(["-q", "--queryformat", "%{nevra},%{buildtime} ", "-f"]);
     for arg in paths {
@@ -67,6 +82,181 @@ where
     rpm_parse_metadata(&rpmout.stdout)
 }
 
+/// Find the NEVRA of the package owning each of `paths` in the rpm
+/// database with a single query, leaving out the paths not owned by any
+/// package.
+pub(crate) fn query_file_owners(
+    sysroot_path: &str,
+    paths: &[PathBuf],
+) -> Result<BTreeMap<PathBuf, String>> {
+    if paths.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    #[cfg(feature = "rpmdb")]
+    if let Some(db) = crate::rpmdb::find(Path::new(sysroot_path)) {
+        return crate::rpmdb::file_owners(&db, Path::new(sysroot_path), paths);
+    }
+
+    let mut c = ostreeutil::rpm_cmd(sysroot_path)?;
+    c.args(["-q", "--queryformat", "[%{filenames}\t%{nevra}\n]", "-f"]);
+    c.args(paths);
+    // This fails if any of the files isn't owned by a package, but the
+    // files of the packages owning the others are still listed.
+    let rpmout = c.output()?;
+    if !rpmout.status.success() {
+        log::debug!(
+            "rpm -qf: {}",
+            String::from_utf8_lossy(&rpmout.stderr).trim()
+        );
+    }
+    let stdout = std::str::from_utf8(&rpmout.stdout).context("Decoding rpm output")?;
+    Ok(parse_file_owners(stdout, paths))
+}
+
+/// Parse the output of `rpm -q --queryformat '[%{filenames}\t%{nevra}\n]'`,
+/// i.e. all the files of the packages queried, keeping those in `paths`.
+fn parse_file_owners(stdout: &str, paths: &[PathBuf]) -> BTreeMap<PathBuf, String> {
+    stdout
+        .lines()
+        .filter_map(|l| l.split_once('\t'))
+        .filter(|(f, _)| paths.iter().any(|p| p == Path::new(f)))
+        .map(|(f, nevra)| (PathBuf::from(f), nevra.to_owned()))
+        .collect()
+}
+
+/// Set by reproducible builds to the time of the last change to the
+/// sources, see <https://reproducible-builds.org/specs/source-date-epoch/>.
+const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Parse the value of `SOURCE_DATE_EPOCH`, in seconds since the epoch.
+fn parse_source_date_epoch(v: &str) -> Result<DateTime<Utc>> {
+    let secs: i64 = v
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid {SOURCE_DATE_EPOCH}: {v:?}"))?;
+    DateTime::from_timestamp(secs, 0)
+        .with_context(|| format!("{SOURCE_DATE_EPOCH} out of range: {v:?}"))
+}
+
+/// The time `SOURCE_DATE_EPOCH` is set to, if it is.
+pub(crate) fn source_date_epoch() -> Result<Option<DateTime<Utc>>> {
+    match std::env::var(SOURCE_DATE_EPOCH) {
+        Ok(v) if v.is_empty() => Ok(None),
+        Ok(v) => parse_source_date_epoch(&v).map(Some),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {SOURCE_DATE_EPOCH}")),
+    }
+}
+
+/// Compare two version strings the way `rpmvercmp()` does: runs of digits
+/// compare numerically and runs of letters lexically, separators are
+/// ignored, `~` sorts before anything, so `1.0~rc1` < `1.0`, and `^` sorts
+/// after the end of the string but before anything else, so
+/// `1.0` < `1.0^git1` < `1.0.1`.  This is also the ordering of the UAPI
+/// version format specification.
+pub(crate) fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn skip_separators(s: &[u8]) -> &[u8] {
+        let n = s
+            .iter()
+            .take_while(|c| !c.is_ascii_alphanumeric() && **c != b'~' && **c != b'^')
+            .count();
+        &s[n..]
+    }
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        a = skip_separators(a);
+        b = skip_separators(b);
+        match (a.first(), b.first()) {
+            (Some(b'~'), Some(b'~')) => {
+                a = &a[1..];
+                b = &b[1..];
+                continue;
+            }
+            (Some(b'~'), _) => return Ordering::Less,
+            (_, Some(b'~')) => return Ordering::Greater,
+            (Some(b'^'), Some(b'^')) => {
+                a = &a[1..];
+                b = &b[1..];
+                continue;
+            }
+            (Some(b'^'), None) => return Ordering::Greater,
+            (None, Some(b'^')) => return Ordering::Less,
+            (Some(b'^'), _) => return Ordering::Less,
+            (_, Some(b'^')) => return Ordering::Greater,
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            _ => {}
+        }
+        let numeric = a[0].is_ascii_digit();
+        let segment = |s: &[u8]| {
+            s.iter()
+                .take_while(|c| {
+                    if numeric {
+                        c.is_ascii_digit()
+                    } else {
+                        c.is_ascii_alphabetic()
+                    }
+                })
+                .count()
+        };
+        let (sa, ra) = a.split_at(segment(a));
+        let (sb, rb) = b.split_at(segment(b));
+        // Segments of different types; numbers are newer
+        if sb.is_empty() {
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+        let ord = if numeric {
+            let trim = |s: &[u8]| -> usize { s.iter().take_while(|c| **c == b'0').count() };
+            let (sa, sb) = (&sa[trim(sa)..], &sb[trim(sb)..]);
+            sa.len().cmp(&sb.len()).then_with(|| sa.cmp(sb))
+        } else {
+            sa.cmp(sb)
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        a = ra;
+        b = rb;
+    }
+}
+
+#[test]
+fn test_compare_versions() {
+    use Ordering::*;
+    for (a, b, expected) in [
+        ("1.0", "1.0", Equal),
+        ("1.0", "1.0.1", Less),
+        ("1.10", "1.9", Greater),
+        ("1.010", "1.10", Equal),
+        ("1.0~rc1", "1.0", Less),
+        ("1.0~rc1", "1.0~rc2", Less),
+        ("1.0a", "1.0.1", Less),
+        ("2024.01", "2023.12", Greater),
+        ("6.8.5-301.fc40", "6.8.11-300.fc40", Less),
+        ("1_0", "1.0", Equal),
+        ("1.0^git1", "1.0", Greater),
+        ("1.0^git1", "1.0.1", Less),
+        ("1.0^git1", "1.0^git2", Less),
+        ("1.0~rc1^git1", "1.0~rc1", Greater),
+    ] {
+        assert_eq!(compare_versions(a, b), expected, "{a} vs {b}");
+        assert_eq!(compare_versions(b, a), expected.reverse(), "{b} vs {a}");
+    }
+}
+
+#[test]
+fn test_parse_source_date_epoch() {
+    let ts = parse_source_date_epoch("1700000000\n").unwrap();
+    assert_eq!(ts.to_rfc3339(), "2023-11-14T22:13:20+00:00");
+    assert!(parse_source_date_epoch("yesterday").is_err());
+    assert!(parse_source_date_epoch("").is_err());
+}
+
 #[test]
 fn test_parse_rpmout() {
     let testdata = "grub2-efi-x64-1:2.06-95.fc38.x86_64,1681321788 grub2-efi-x64-1:2.06-95.fc38.x86_64,1681321788 shim-x64-15.6-2.x86_64,1657222566 shim-x64-15.6-2.x86_64,1657222566 shim-x64-15.6-2.x86_64,1657222566";
@@ -76,3 +266,30 @@ fn test_parse_rpmout() {
         "grub2-efi-x64-1:2.06-95.fc38.x86_64,shim-x64-15.6-2.x86_64"
     );
 }
+
+#[test]
+fn test_parse_file_owners() {
+    let testdata = "\
+/boot/efi/EFI/fedora/shimx64.efi\tshim-x64-15.8-3.x86_64
+/boot/efi/EFI/fedora/mmx64.efi\tshim-x64-15.8-3.x86_64
+/boot/efi/EFI/fedora/grubx64.efi\tgrub2-efi-x64-1:2.12-4.fc41.x86_64
+/usr/share/doc/grub2-efi-x64/README\tgrub2-efi-x64-1:2.12-4.fc41.x86_64
+";
+    let paths = [
+        "/boot/efi/EFI/fedora/shimx64.efi",
+        "/boot/efi/EFI/fedora/grubx64.efi",
+        "/boot/efi/EFI/fedora/grub.cfg",
+    ]
+    .map(PathBuf::from);
+    let owners = parse_file_owners(testdata, &paths);
+    assert_eq!(
+        owners,
+        BTreeMap::from([
+            (paths[0].clone(), "shim-x64-15.8-3.x86_64".to_string()),
+            (
+                paths[1].clone(),
+                "grub2-efi-x64-1:2.12-4.fc41.x86_64".to_string()
+            ),
+        ])
+    );
+}
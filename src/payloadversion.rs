@@ -0,0 +1,304 @@
+//! Versions of the supplemental payloads added by `extend-payload`, which
+//! are stored as `<package>/<version>` directories.
+//!
+//! These normally come from the rpm owning the added files, but can be
+//! given explicitly for files which aren't packaged.  NEVRA parsing follows
+//! rpm's own rules: versions and releases can't contain `-`, so the name is
+//! everything before the second to last one.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+
+/// A package's `name-[epoch:]version-release[.arch]`, as printed by
+/// `rpm -q --queryformat %{nevra}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Nevra {
+    pub(crate) name: String,
+    pub(crate) epoch: Option<u32>,
+    pub(crate) version: String,
+    pub(crate) release: String,
+    /// Absent for packages without an architecture, e.g. `gpg-pubkey`
+    pub(crate) arch: Option<String>,
+}
+
+impl FromStr for Nevra {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid NEVRA: {s:?}");
+        if s.contains(|c: char| c.is_whitespace() || c == ',') {
+            return Err(invalid());
+        }
+        let (nev, release_arch) = s.rsplit_once('-').ok_or_else(invalid)?;
+        let (name, ev) = nev.rsplit_once('-').ok_or_else(invalid)?;
+        let (epoch, version) = match ev.split_once(':') {
+            Some((e, v)) => (Some(e.parse().map_err(|_| invalid())?), v),
+            None => (None, ev),
+        };
+        // The arch never contains a dot, while the release usually does
+        let (release, arch) = match release_arch.rsplit_once('.') {
+            Some((r, a)) => (r, Some(a)),
+            None => (release_arch, None),
+        };
+        if [name, version, release].iter().any(|p| p.is_empty()) || arch == Some("") {
+            return Err(invalid());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            epoch,
+            version: version.to_string(),
+            release: release.to_string(),
+            arch: arch.map(ToOwned::to_owned),
+        })
+    }
+}
+
+impl fmt::Display for Nevra {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-", self.name)?;
+        if let Some(e) = self.epoch {
+            write!(f, "{e}:")?;
+        }
+        write!(f, "{}-{}", self.version, self.release)?;
+        if let Some(a) = self.arch.as_deref() {
+            write!(f, ".{a}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The package and version a supplemental payload is stored under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PayloadVersion {
+    pub(crate) package: String,
+    pub(crate) version: String,
+}
+
+/// Check that `s` is usable as a directory name, and in the comma-separated
+/// version of the update metadata.
+fn check_component(what: &str, s: &str, extra: &[char]) -> Result<()> {
+    let valid = s.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._+".contains(c) || extra.contains(&c));
+    if !valid {
+        bail!("Invalid {what}: {s:?}");
+    }
+    Ok(())
+}
+
+impl PayloadVersion {
+    /// Validate an explicitly given package name and version.
+    pub(crate) fn new(package: &str, version: &str) -> Result<Self> {
+        check_component("package name", package, &[])?;
+        // Allow the characters rpm versions may use
+        check_component("version", version, &['~', '^'])?;
+        Ok(Self {
+            package: package.to_string(),
+            version: version.to_string(),
+        })
+    }
+
+    /// The version of a payload owned by the package `nevra`; the version
+    /// is its `version-release`.
+    pub(crate) fn from_nevra(nevra: &Nevra) -> Result<Self> {
+        Self::new(&nevra.name, &format!("{}-{}", nevra.version, nevra.release))
+    }
+
+    /// Use the given package name and version, looking up those not given
+    /// from the package owning the payload with `owner`.
+    pub(crate) fn resolve(
+        package: Option<&str>,
+        version: Option<&str>,
+        owner: impl FnOnce() -> Result<Nevra>,
+    ) -> Result<Self> {
+        let (package, version) = match (package, version) {
+            (Some(p), Some(v)) => (p.to_string(), v.to_string()),
+            (p, v) => {
+                let from_owner = Self::from_nevra(&owner()?)?;
+                (
+                    p.map_or(from_owner.package, ToOwned::to_owned),
+                    v.map_or(from_owner.version, ToOwned::to_owned),
+                )
+            }
+        };
+        Self::new(&package, &version)
+    }
+}
+
+impl fmt::Display for PayloadVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.package, self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nevra(
+        name: &str,
+        epoch: Option<u32>,
+        version: &str,
+        release: &str,
+        arch: Option<&str>,
+    ) -> Nevra {
+        Nevra {
+            name: name.into(),
+            epoch,
+            version: version.into(),
+            release: release.into(),
+            arch: arch.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn test_parse_nevra() -> Result<()> {
+        let cases = [
+            (
+                "shim-x64-15.8-3.x86_64",
+                nevra("shim-x64", None, "15.8", "3", Some("x86_64")),
+            ),
+            (
+                "grub2-efi-x64-1:2.06-95.fc38.x86_64",
+                nevra("grub2-efi-x64", Some(1), "2.06", "95.fc38", Some("x86_64")),
+            ),
+            (
+                "grub2-efi-aa64-modules-1:2.12-4.fc41.noarch",
+                nevra(
+                    "grub2-efi-aa64-modules",
+                    Some(1),
+                    "2.12",
+                    "4.fc41",
+                    Some("noarch"),
+                ),
+            ),
+            (
+                "uboot-images-armv8-2024.10-1.fc42.noarch",
+                nevra(
+                    "uboot-images-armv8",
+                    None,
+                    "2024.10",
+                    "1.fc42",
+                    Some("noarch"),
+                ),
+            ),
+            (
+                "bcm2711-firmware-20240604-1.fc41.aarch64",
+                nevra(
+                    "bcm2711-firmware",
+                    None,
+                    "20240604",
+                    "1.fc41",
+                    Some("aarch64"),
+                ),
+            ),
+            (
+                "libstdc++-14.2.1-3.fc41.x86_64",
+                nevra("libstdc++", None, "14.2.1", "3.fc41", Some("x86_64")),
+            ),
+            (
+                "python3.12-3.12.7-1.el9_5.x86_64",
+                nevra("python3.12", None, "3.12.7", "1.el9_5", Some("x86_64")),
+            ),
+            (
+                "fwupd-efi-1.6~rc1-1.fc41.x86_64",
+                nevra("fwupd-efi", None, "1.6~rc1", "1.fc41", Some("x86_64")),
+            ),
+            (
+                "linux-firmware-20241017-1.fc41.noarch",
+                nevra("linux-firmware", None, "20241017", "1.fc41", Some("noarch")),
+            ),
+            (
+                "gpg-pubkey-a15b79cc-63d04c2c",
+                nevra("gpg-pubkey", None, "a15b79cc", "63d04c2c", None),
+            ),
+        ];
+        for (s, expected) in cases {
+            let parsed: Nevra = s.parse()?;
+            assert_eq!(parsed, expected, "{s}");
+            assert_eq!(parsed.to_string(), s);
+        }
+        for s in [
+            "",
+            "shim",
+            "shim-15.8",
+            "-15.8-3.x86_64",
+            "shim--3.x86_64",
+            "shim-15.8-.x86_64",
+            "shim-15.8-3.",
+            "shim-x:15.8-3.x86_64",
+            "shim-15.8-3.x86_64,grub2-2.12-4.x86_64",
+            "shim-15.8-3.x86_64 ",
+        ] {
+            assert!(s.parse::<Nevra>().is_err(), "{s:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_nevra_combinations() -> Result<()> {
+        let names = [
+            "a",
+            "shim-x64",
+            "grub2-efi-x64-cdboot",
+            "libstdc++",
+            "x.y-z_1",
+        ];
+        let epochs = [None, Some(0), Some(1), Some(20)];
+        let versions = ["1", "15.8", "2.06~rc1", "20240604^git1", "1_2"];
+        let releases = ["1", "95.fc38", "1.el9_5.1", "0.rc2"];
+        let arches = [None, Some("x86_64"), Some("noarch"), Some("aarch64")];
+        for name in names {
+            for epoch in epochs {
+                for version in versions {
+                    for release in releases {
+                        for arch in arches {
+                            // A release with a dot is ambiguous without an
+                            // arch; rpm always prints one there.
+                            if arch.is_none() && release.contains('.') {
+                                continue;
+                            }
+                            let expected = nevra(name, epoch, version, release, arch);
+                            let s = expected.to_string();
+                            assert_eq!(s.parse::<Nevra>()?, expected, "{s}");
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_version() -> Result<()> {
+        let owner = || "grub2-efi-x64-1:2.06-95.fc38.x86_64".parse::<Nevra>();
+        let v = PayloadVersion::resolve(None, None, owner)?;
+        assert_eq!(v, PayloadVersion::new("grub2-efi-x64", "2.06-95.fc38")?);
+        assert_eq!(v.to_string(), "grub2-efi-x64-2.06-95.fc38");
+        let v = PayloadVersion::resolve(Some("fw"), None, owner)?;
+        assert_eq!(v, PayloadVersion::new("fw", "2.06-95.fc38")?);
+
+        // Not owned by a package
+        let unowned = || -> Result<Nevra> { bail!("not owned") };
+        let v = PayloadVersion::resolve(Some("fw"), Some("1.0"), unowned)?;
+        assert_eq!(v, PayloadVersion::new("fw", "1.0")?);
+        assert!(PayloadVersion::resolve(Some("fw"), None, unowned).is_err());
+
+        for (p, v) in [
+            ("../x", "1"),
+            ("x/y", "1"),
+            (".x", "1"),
+            ("", "1"),
+            ("x", ""),
+            ("x", ".."),
+            ("x", "1,2"),
+            ("x", "1 2"),
+            ("x", "1:2"),
+        ] {
+            assert!(PayloadVersion::new(p, v).is_err(), "{p:?} {v:?}");
+        }
+        Ok(())
+    }
+}
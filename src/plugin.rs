@@ -0,0 +1,302 @@
+//! Components implemented by external executables, so that e.g.
+//! board-specific bootloaders can be managed without patching bootupd.
+//!
+//! A plugin is an executable `/usr/libexec/bootupd/components.d/<name>`,
+//! where `<name>` is the component name.  It is run with a verb as its
+//! only argument, a JSON request on stdin, and must write a JSON response
+//! to stdout and exit successfully; anything on stderr is passed through.
+//! The plugin doesn't have to read the request before writing its response.
+//! The environment variable `BOOTUPD_PLUGIN_PROTOCOL` holds the version of
+//! this contract, currently `1`.
+//!
+//! | Verb           | Request                                                | Response                                       |
+//! |----------------|--------------------------------------------------------|------------------------------------------------|
+//! | `query-update` | `{"sysroot"}`                                          | `{"timestamp", "version"}` or `null`           |
+//! | `install`      | `{"src-root", "dest-root", "device", "update-firmware"}` | `{"timestamp", "version"}` of what was installed |
+//! | `run-update`   | `{"sysroot", "dest-root", "current": {"timestamp", "version"}}` | `{"timestamp", "version"}` of what was installed |
+//! | `validate`     | `{"current": {"timestamp", "version"}}`                | `"valid"`, `"skip"` or `{"errors": [...]}`     |
+//! | `uninstall`    | `{"sysroot", "current": {"timestamp", "version"}}`     | `null`                                         |
+//!
+//! For `run-update`, `sysroot` holds the update payload, which may be a new
+//! ostree deployment rather than the booted root, and `dest-root` is the
+//! root whose bootloader is updated.
+//!
+//! Plugins track their own files; bootupd only records the version.
+//! They are never installed by default, so must be requested with e.g.
+//! `bootupctl backend install --component <name>`.
+
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::component::*;
+use crate::model::*;
+
+/// Where plugins are found.
+const PLUGIN_DIR: &str = "/usr/libexec/bootupd/components.d";
+/// The version of the plugin contract.
+const PROTOCOL: &str = "1";
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct QueryUpdateRequest<'a> {
+    sysroot: &'a Path,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct InstallRequest<'a> {
+    src_root: &'a Path,
+    dest_root: &'a str,
+    device: &'a str,
+    update_firmware: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct RunUpdateRequest<'a> {
+    sysroot: &'a Path,
+    dest_root: &'a Path,
+    current: &'a ContentMetadata,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct UninstallRequest<'a> {
+    sysroot: &'a Path,
+    current: &'a ContentMetadata,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ValidateRequest<'a> {
+    current: &'a ContentMetadata,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The path of the directory `d`.
+fn dir_path(d: &openat::Dir) -> Result<PathBuf> {
+    let fd = format!("/proc/self/fd/{}", d.as_raw_fd());
+    Ok(std::fs::read_link(fd)?)
+}
+
+/// A component implemented by a plugin.
+pub(crate) struct PluginComponent {
+    name: String,
+    path: PathBuf,
+}
+
+impl PluginComponent {
+    /// Find the plugin for the component `name`, if there is one.
+    pub(crate) fn find(name: &str) -> Result<Option<Self>> {
+        Self::find_in(Path::new(PLUGIN_DIR), name)
+    }
+
+    fn find_in(dir: &Path, name: &str) -> Result<Option<Self>> {
+        if !is_valid_name(name) {
+            return Ok(None);
+        }
+        let path = dir.join(name);
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            name: name.to_string(),
+            path,
+        }))
+    }
+
+    /// Run the plugin with `verb`, passing `request`.
+    fn call<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        verb: &str,
+        request: &Req,
+    ) -> Result<Resp> {
+        let mut child = Command::new(&self.path)
+            .arg(verb)
+            .env("BOOTUPD_PLUGIN_PROTOCOL", PROTOCOL)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Running {:?}", self.path))?;
+        let mut stdin = child.stdin.take().expect("stdin");
+        let mut input = serde_json::to_vec(request)?;
+        input.push(b'\n');
+        // Written while we read the response, so that neither side blocks
+        // on a full pipe
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+        let out = child.wait_with_output()?;
+        match writer.join().expect("writer thread") {
+            // The plugin doesn't have to read the request
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                return Err(e).with_context(|| format!("Writing request to plugin {}", self.name));
+            }
+            _ => {}
+        }
+        if !out.status.success() {
+            bail!("Plugin {} {verb} failed: {}", self.name, out.status);
+        }
+        serde_json::from_slice(&out.stdout)
+            .with_context(|| format!("Parsing output of plugin {} {verb}", self.name))
+    }
+}
+
+impl Component for PluginComponent {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        Ok(None)
+    }
+
+    fn adopt_update(&self, _: &openat::Dir, _: &ContentMetadata) -> Result<InstalledContent> {
+        bail!("Adopting plugin component {} is not supported", self.name)
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        device: &str,
+        update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let req = InstallRequest {
+            src_root: &dir_path(src_root)?,
+            dest_root,
+            device,
+            update_firmware,
+        };
+        let meta = self.call("install", &req)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: None,
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn generate_update_metadata(&self, _sysroot: &str) -> Result<ContentMetadata> {
+        bail!("Plugin component {} provides its own updates", self.name)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        let req = QueryUpdateRequest {
+            sysroot: &dir_path(sysroot)?,
+        };
+        self.call("query-update", &req)
+    }
+
+    fn run_update(
+        &self,
+        payload_root: &openat::Dir,
+        dest_root: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let req = RunUpdateRequest {
+            sysroot: &dir_path(payload_root)?,
+            dest_root: &dir_path(dest_root)?,
+            current: &current.meta,
+        };
+        let meta = self.call("run-update", &req)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: None,
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn validate(
+        &self,
+        _sysroot: &openat::Dir,
+        current: &InstalledContent,
+        _fast: bool,
+    ) -> Result<ValidationResult> {
+        let req = ValidateRequest {
+            current: &current.meta,
+        };
+        self.call("validate", &req)
+    }
+
+    fn uninstall(&self, sysroot: &openat::Dir, current: &InstalledContent) -> Result<()> {
+        let req = UninstallRequest {
+            sysroot: &dir_path(sysroot)?,
+            current: &current.meta,
+        };
+        self.call("uninstall", &req)
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const PLUGIN: &str = r#"#!/bin/bash
+set -euo pipefail
+test "${BOOTUPD_PLUGIN_PROTOCOL}" = 1
+req=$(cat)
+case "$1" in
+  query-update) echo '{"timestamp": "2024-01-01T00:00:00Z", "version": "board-2"}' ;;
+  validate) case "$req" in *board-1*) echo '{"errors": ["Changed: u-boot.bin"]}' ;; *) echo '"valid"' ;; esac ;;
+  uninstall) echo null ;;
+  *) exit 1 ;;
+esac
+"#;
+
+    #[test]
+    fn test_plugin() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let path = td.path().join("Board");
+        std::fs::write(&path, PLUGIN)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        assert!(PluginComponent::find_in(td.path(), "Other")?.is_none());
+        assert!(PluginComponent::find_in(td.path(), "../Board")?.is_none());
+        let p = PluginComponent::find_in(td.path(), "Board")?.unwrap();
+        assert_eq!(p.name(), "Board");
+
+        let sysroot = openat::Dir::open(td.path())?;
+        let update = p.query_update(&sysroot)?.unwrap();
+        assert_eq!(update.version, "board-2");
+        let mut installed = InstalledContent {
+            meta: update,
+            filetree: None,
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        };
+        assert!(matches!(
+            p.validate(&sysroot, &installed, false)?,
+            ValidationResult::Valid
+        ));
+        installed.meta.version = "board-1".into();
+        match p.validate(&sysroot, &installed, false)? {
+            ValidationResult::Errors(errs) => assert_eq!(errs, ["Changed: u-boot.bin"]),
+            o => panic!("Unexpected result {o:?}"),
+        }
+        assert!(p.run_update(&sysroot, &sysroot, &installed).is_err());
+        p.uninstall(&sysroot, &installed)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! The packages which the files in update payloads came from, so that e.g.
+//! a shim on the ESP can be traced back to the package that shipped it.
+//!
+//! `generate-update-metadata` records these in `<component>.provenance.json`
+//! next to the update metadata.  Installed files are matched by digest,
+//! since the payload in the image may be newer than what is installed.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use openat_ext::OpenatDirExt;
+use serde::{Deserialize, Serialize};
+
+use crate::component::Component;
+use crate::filetree::FileTree;
+use crate::model::BOOTUPD_UPDATES_DIR;
+use crate::sha512string::SHA512String;
+
+/// Where a payload file came from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct FileProvenance {
+    /// NEVRA of the package owning the file, if any
+    pub(crate) package: Option<String>,
+    pub(crate) size: u64,
+    pub(crate) sha512: SHA512String,
+}
+
+/// Provenance of each file in a payload, keyed by path in the payload.
+pub(crate) type Provenance = BTreeMap<String, FileProvenance>;
+
+/// The name of the provenance file for `component`.
+fn provenance_name(component: &str) -> String {
+    format!("{component}.provenance.json")
+}
+
+/// Find the package of each file in `tree`, the payload of `component`
+/// generated from `sysroot_path`.
+pub(crate) fn generate(
+    sysroot_path: &str,
+    component: &dyn Component,
+    tree: &FileTree,
+) -> Provenance {
+    let sources: BTreeMap<_, _> = tree
+        .children
+        .keys()
+        .filter_map(|path| Some((path, component.payload_source(path)?)))
+        .collect();
+    let paths: Vec<_> = sources.values().cloned().collect();
+    let owners = crate::packagesystem::query_file_owners(sysroot_path, &paths)
+        .map_err(|e| log::debug!("Failed to query packages: {e:#}"))
+        .unwrap_or_default();
+    tree.children
+        .iter()
+        .map(|(path, meta)| {
+            let package = sources.get(path).and_then(|src| owners.get(src)).cloned();
+            let p = FileProvenance {
+                package,
+                size: meta.size,
+                sha512: meta.sha512.clone(),
+            };
+            (path.clone(), p)
+        })
+        .collect()
+}
+
+/// Write the provenance of the payload for `component` to `updates`.
+pub(crate) fn write(updates: &openat::Dir, component: &str, prov: &Provenance) -> Result<()> {
+    updates.write_file_with(provenance_name(component), 0o644, |w| -> Result<_> {
+        Ok(serde_json::to_writer(w, prov)?)
+    })?;
+    Ok(())
+}
+
+/// Load the provenance shipped with the update for `component` in
+/// `sysroot`; empty if there is none.
+pub(crate) fn load(sysroot: &openat::Dir, component: &str) -> Result<Provenance> {
+    let path = format!("{BOOTUPD_UPDATES_DIR}/{}", provenance_name(component));
+    let Some(f) = sysroot.open_file_optional(path.as_str())? else {
+        return Ok(Provenance::new());
+    };
+    serde_json::from_reader(std::io::BufReader::new(f)).with_context(|| format!("Parsing {path}"))
+}
+
+/// The package which shipped a file with the given digest.
+pub(crate) fn package_for<'a>(prov: &'a Provenance, sha512: &SHA512String) -> Option<&'a str> {
+    prov.values()
+        .find(|p| &p.sha512 == sha512)
+        .and_then(|p| p.package.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_for() -> Result<()> {
+        let prov: Provenance = serde_json::from_str(
+            r#"{
+                "fedora/shimx64.efi": {"package": "shim-x64-15.8-3.x86_64", "size": 3, "sha512": "sha512:aa"},
+                "fedora/grub.cfg": {"package": null, "size": 1, "sha512": "sha512:bb"}
+            }"#,
+        )?;
+        let digest = |s: &str| SHA512String(s.into());
+        assert_eq!(
+            package_for(&prov, &digest("sha512:aa")),
+            Some("shim-x64-15.8-3.x86_64")
+        );
+        assert_eq!(package_for(&prov, &digest("sha512:bb")), None);
+        assert_eq!(package_for(&prov, &digest("sha512:cc")), None);
+        Ok(())
+    }
+}
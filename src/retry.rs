@@ -0,0 +1,125 @@
+//! Retrying operations which fail transiently, e.g. mounting the ESP while
+//! udev is still probing devices right after boot, or writing EFI variables
+//! which fails with `EINTR`/`ENOSPC` until the firmware reclaims space.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// Retries after the first attempt, unless configured otherwise.
+const DEFAULT_RETRIES: u32 = 3;
+/// Delay before the first retry, unless configured otherwise; this doubles
+/// for each further retry.
+const DEFAULT_DELAY_MS: u64 = 200;
+
+/// The delay before retry number `attempt` (starting at 0), with up to 50%
+/// added from `jitter` so that concurrent callers don't retry in lockstep.
+fn backoff(base: Duration, attempt: u32, jitter: u32) -> Duration {
+    let delay = base.saturating_mul(1u32 << attempt.min(16));
+    let max_jitter = delay.as_millis() as u64 / 2;
+    let jitter = if max_jitter > 0 {
+        u64::from(jitter) % max_jitter
+    } else {
+        0
+    };
+    delay + Duration::from_millis(jitter)
+}
+
+/// A cheap source of jitter; this doesn't need to be random, only to
+/// differ between processes.
+fn jitter() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default()
+        ^ std::process::id()
+}
+
+fn retry_with<T, E>(
+    what: &str,
+    retries: u32,
+    base: Duration,
+    mut f: impl FnMut() -> std::result::Result<T, E>,
+) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    let mut history = Vec::new();
+    let mut attempt = 0;
+    loop {
+        let err = match f() {
+            Ok(r) => return Ok(r),
+            Err(e) => e.into(),
+        };
+        if attempt >= retries {
+            let r = Err(err);
+            return if history.is_empty() {
+                r
+            } else {
+                r.with_context(|| {
+                    format!(
+                        "{what} failed after {} attempts; earlier errors: {}",
+                        attempt + 1,
+                        history.join("; ")
+                    )
+                })
+            };
+        }
+        let delay = backoff(base, attempt, jitter());
+        log::debug!("{what} failed, retrying in {delay:?}: {err:#}");
+        history.push(format!("{err:#}"));
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// Run `f`, retrying with exponential backoff if it fails, as configured
+/// by `retries` and `retry-delay-ms`.  The final error lists the earlier
+/// failures.
+pub(crate) fn retry<T, E>(what: &str, f: impl FnMut() -> std::result::Result<T, E>) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    let config = crate::config::get();
+    let retries = config.retries.unwrap_or(DEFAULT_RETRIES);
+    let delay = Duration::from_millis(config.retry_delay_ms.unwrap_or(DEFAULT_DELAY_MS));
+    retry_with(what, retries, delay, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let base = Duration::from_millis(100);
+        assert_eq!(backoff(base, 0, 0), base);
+        assert_eq!(backoff(base, 2, 0), Duration::from_millis(400));
+        assert_eq!(backoff(base, 2, 1000), Duration::from_millis(400));
+        assert_eq!(backoff(base, 2, 199), Duration::from_millis(599));
+        assert_eq!(backoff(Duration::ZERO, 5, 7), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_retry() -> Result<()> {
+        let mut calls = 0;
+        let r = retry_with("Test", 3, Duration::ZERO, || {
+            calls += 1;
+            if calls < 3 {
+                anyhow::bail!("failure {calls}")
+            }
+            Ok(calls)
+        })?;
+        assert_eq!(r, 3);
+
+        let e = retry_with("Test", 1, Duration::ZERO, || -> Result<()> {
+            anyhow::bail!("always")
+        })
+        .unwrap_err();
+        assert_eq!(
+            format!("{e:#}"),
+            "Test failed after 2 attempts; earlier errors: always: always"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! Reading the rpm database directly, rather than forking `rpm`, so that
+//! update metadata can be generated in containers without rpm installed.
+//!
+//! Only the sqlite database format is supported, which is the default
+//! since rpm 4.16.  Packages are stored as header blobs, parsed by
+//! [`crate::rpmheader`].
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use fn_error_context::context;
+
+use crate::rpmheader::Package;
+
+/// The database, relative to the root.
+const RPMDB_PATH: &str = "usr/lib/sysimage/rpm/rpmdb.sqlite";
+
+/// Find the sqlite rpm database in `sysroot`, if any.
+pub(crate) fn find(sysroot: &Path) -> Option<PathBuf> {
+    let path = sysroot.join(RPMDB_PATH);
+    path.exists().then_some(path)
+}
+
+fn read_packages(db: &Path) -> Result<Vec<Package>> {
+    use rusqlite::{Connection, OpenFlags};
+    // The database is usually in a read-only image
+    let uri = format!("file:{}?immutable=1", db.display());
+    let conn = Connection::open_with_flags(
+        uri,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+    )?;
+    let mut stmt = conn.prepare("SELECT blob FROM Packages")?;
+    let blobs = stmt
+        .query_map([], |row| row.get::<_, Vec<u8>>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    blobs.iter().map(|b| Package::from_header(b)).collect()
+}
+
+/// Find the packages owning `paths` (absolute paths within `sysroot`, or
+/// under it), returning their NEVRA and build time.
+#[context("Querying rpm database {}", db.display())]
+pub(crate) fn query_files<T>(
+    db: &Path,
+    sysroot: &Path,
+    paths: impl IntoIterator<Item = T>,
+) -> Result<BTreeMap<String, DateTime<Utc>>>
+where
+    T: AsRef<Path>,
+{
+    let packages = read_packages(db)?;
+    let mut r = BTreeMap::new();
+    for path in paths {
+        let path = db_path(sysroot, path.as_ref())?;
+        let owner = owner_of(&packages, &path)
+            .with_context(|| format!("File {path} is not owned by any package"))?;
+        r.insert(owner.nevra.clone(), owner.buildtime);
+    }
+    Ok(r)
+}
+
+/// Find the NEVRA of the package owning each of `paths` (as for
+/// [`query_files`]), leaving out the paths not owned by any package.
+#[context("Querying rpm database {}", db.display())]
+pub(crate) fn file_owners(
+    db: &Path,
+    sysroot: &Path,
+    paths: &[PathBuf],
+) -> Result<BTreeMap<PathBuf, String>> {
+    let packages = read_packages(db)?;
+    let mut r = BTreeMap::new();
+    for path in paths {
+        if let Some(owner) = owner_of(&packages, &db_path(sysroot, path)?) {
+            r.insert(path.clone(), owner.nevra.clone());
+        }
+    }
+    Ok(r)
+}
+
+/// The path of `path`, within `sysroot` or under it, in the database.
+fn db_path(sysroot: &Path, path: &Path) -> Result<String> {
+    let path = match path.strip_prefix(sysroot) {
+        Ok(p) if sysroot != Path::new("/") => Path::new("/").join(p),
+        _ => path.to_owned(),
+    };
+    path.to_str()
+        .map(ToOwned::to_owned)
+        .context("Non-UTF-8 path")
+}
+
+fn owner_of<'a>(packages: &'a [Package], path: &str) -> Option<&'a Package> {
+    packages.iter().find(|p| p.files.iter().any(|f| f == path))
+}
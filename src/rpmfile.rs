@@ -0,0 +1,302 @@
+//! Extracting files from `.rpm` packages, like `rpm2cpio | cpio -i` but
+//! without needing either, so that update payloads can be generated from
+//! packages which aren't installed in the image.
+//!
+//! A package is a 96-byte lead, the signature header (padded to 8 bytes),
+//! the main header, and the payload: a cpio archive in the `newc` format,
+//! compressed with the `payloadcompressor` of the header.  The archive is
+//! decompressed with the matching tool (e.g. `zstd` or `xz`), and parsed
+//! in-process.
+
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+
+use crate::rpmheader::{Header, Package};
+
+const LEAD_SIZE: usize = 96;
+const LEAD_MAGIC: &[u8] = &[0xed, 0xab, 0xee, 0xdb];
+const HEADER_MAGIC: &[u8] = &[0x8e, 0xad, 0xe8, 0x01];
+const RPMTAG_PAYLOADCOMPRESSOR: u32 = 1125;
+
+/// The magic of `newc` cpio entries, without and with checksums.
+const CPIO_MAGICS: &[&[u8]] = &[b"070701", b"070702"];
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+
+/// The header starting at `off` in `data`, after its magic.
+fn header_at(data: &[u8], off: usize) -> Result<Header<'_>> {
+    let magic = data.get(off..off + 8).context("Truncated package")?;
+    if &magic[..4] != HEADER_MAGIC {
+        bail!("Invalid header magic at {off:#x}");
+    }
+    Header::parse(&data[off + 8..])
+}
+
+/// The command to decompress a payload with `compressor`.
+fn decompressor(compressor: &str) -> Result<Command> {
+    let prog = match compressor {
+        "gzip" => "gzip",
+        "bzip2" => "bzip2",
+        "xz" | "lzma" => "xz",
+        "zstd" => "zstd",
+        o => bail!("Unsupported payload compressor {o}"),
+    };
+    let mut c = Command::new(prog);
+    c.args(["-d", "-c"]);
+    Ok(c)
+}
+
+/// Parse a hexadecimal field of a cpio header.
+fn cpio_field(hdr: &[u8], i: usize) -> Result<usize> {
+    let start = 6 + i * 8;
+    let s = std::str::from_utf8(&hdr[start..start + 8])?;
+    usize::from_str_radix(s, 16).with_context(|| format!("Invalid cpio field {s:?}"))
+}
+
+/// Round `n` up to a multiple of 4, as cpio pads names and data.
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// The regular files in the `newc` cpio `archive`, as (path, mode, data);
+/// paths are relative, without the leading `./`.
+fn cpio_files(archive: &[u8]) -> Result<Vec<(&str, u32, &[u8])>> {
+    let mut r = Vec::new();
+    let mut off = 0;
+    loop {
+        let hdr = archive
+            .get(off..off + CPIO_HEADER_SIZE)
+            .context("Truncated cpio archive")?;
+        if !CPIO_MAGICS.contains(&&hdr[..6]) {
+            bail!("Invalid cpio entry at {off:#x}");
+        }
+        let mode = cpio_field(hdr, 1)? as u32;
+        let size = cpio_field(hdr, 6)?;
+        let namesize = cpio_field(hdr, 11)?;
+        let name_start = off + CPIO_HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + namesize)
+            .context("Truncated cpio archive")?;
+        let name = std::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name))?;
+        if name == CPIO_TRAILER {
+            return Ok(r);
+        }
+        let data_start = align4(name_start + namesize);
+        let data = archive
+            .get(data_start..data_start + size)
+            .context("Truncated cpio archive")?;
+        if mode & S_IFMT == S_IFREG {
+            let name = name.trim_start_matches("./").trim_start_matches('/');
+            r.push((name, mode & 0o7777, data));
+        }
+        off = align4(data_start + size);
+    }
+}
+
+/// A package read from an `.rpm` file.
+pub(crate) struct RpmFile {
+    pub(crate) package: Package,
+    compressor: String,
+    payload: Vec<u8>,
+}
+
+impl RpmFile {
+    /// Read the package `path`.
+    #[context("Reading {}", path.display())]
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        if data.get(..4) != Some(LEAD_MAGIC) {
+            bail!("Not an rpm package");
+        }
+        let sig = header_at(&data, LEAD_SIZE)?;
+        let off = LEAD_SIZE + 8 + sig.size();
+        // The main header is aligned to 8 bytes
+        let off = (off + 7) & !7;
+        let hdr = header_at(&data, off)?;
+        let package = Package::from_parsed(&hdr)?;
+        // Packages which don't record a compressor use gzip
+        let compressor = hdr
+            .string(RPMTAG_PAYLOADCOMPRESSOR)?
+            .unwrap_or("gzip")
+            .to_string();
+        let payload_start = off + 8 + hdr.size();
+        Ok(Self {
+            package,
+            compressor,
+            payload: data[payload_start..].to_vec(),
+        })
+    }
+
+    /// Decompress the payload, returning the cpio archive.
+    fn archive(&self) -> Result<Vec<u8>> {
+        let mut input = tempfile::tempfile()?;
+        input.write_all(&self.payload)?;
+        input.rewind()?;
+        let out = decompressor(&self.compressor)?
+            .stdin(Stdio::from(input))
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("Running {} decompressor", self.compressor))?;
+        if !out.status.success() {
+            bail!("Failed to decompress payload: {}", out.status);
+        }
+        Ok(out.stdout)
+    }
+
+    /// Write the files under `prefix` in the package to `dest`, relative
+    /// to `prefix`; returns their paths in the package.
+    pub(crate) fn extract(&self, prefix: &str, dest: &Path) -> Result<Vec<String>> {
+        use std::os::unix::fs::PermissionsExt;
+        let archive = self.archive()?;
+        let mut r = Vec::new();
+        for (name, mode, data) in cpio_files(&archive)? {
+            let Some(rel) = Path::new(name).strip_prefix(prefix).ok() else {
+                continue;
+            };
+            if rel
+                .components()
+                .any(|c| !matches!(c, std::path::Component::Normal(_)))
+            {
+                bail!("Invalid path {name} in package");
+            }
+            let path = dest.join(rel);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, data).with_context(|| format!("Writing {path:?}"))?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            r.push(format!("/{name}"));
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpmheader::tests::{header, package_entries, string_entry};
+
+    /// A `newc` cpio entry.
+    fn cpio_entry(name: &str, mode: u32, data: &[u8]) -> Vec<u8> {
+        let namesize = name.len() + 1;
+        let mut e = format!(
+            "070701{:08x}{mode:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{namesize:08x}{:08x}",
+            1, 0, 0, 1, 0, data.len(), 0, 0, 0, 0, 0
+        )
+        .into_bytes();
+        e.extend(name.as_bytes());
+        e.push(0);
+        e.resize(align4(e.len()), 0);
+        e.extend(data);
+        e.resize(align4(e.len()), 0);
+        e
+    }
+
+    #[test]
+    fn test_cpio_files() -> Result<()> {
+        let mut archive = Vec::new();
+        archive.extend(cpio_entry("./boot/efi/EFI/fedora", 0o40755, b""));
+        archive.extend(cpio_entry(
+            "./boot/efi/EFI/fedora/shimx64.efi",
+            0o100700,
+            b"shim",
+        ));
+        archive.extend(cpio_entry(
+            "./boot/efi/EFI/BOOT/BOOTX64.EFI",
+            0o100700,
+            b"x",
+        ));
+        archive.extend(cpio_entry(CPIO_TRAILER, 0, b""));
+        let files = cpio_files(&archive)?;
+        assert_eq!(
+            files,
+            [
+                ("boot/efi/EFI/fedora/shimx64.efi", 0o700, &b"shim"[..]),
+                ("boot/efi/EFI/BOOT/BOOTX64.EFI", 0o700, &b"x"[..]),
+            ]
+        );
+        assert!(cpio_files(&archive[..archive.len() - 4]).is_err());
+        assert!(cpio_files(b"garbage").is_err());
+        Ok(())
+    }
+
+    /// A package with the header `entries` and the gzipped `archive` as
+    /// payload.
+    fn rpm(entries: &[(u32, u32, u32, Vec<u8>)], archive: &[u8]) -> Result<Vec<u8>> {
+        let mut data = LEAD_MAGIC.to_vec();
+        data.resize(LEAD_SIZE, 0);
+        // A signature header whose size isn't a multiple of 8, so that the
+        // main header is padded
+        let sig = header(&[string_entry(273, "0123456789abcdef0")]);
+        assert_ne!(sig.len() % 8, 0);
+        data.extend(HEADER_MAGIC);
+        data.extend([0; 4]);
+        data.extend(sig);
+        data.resize((data.len() + 7) & !7, 0);
+        // The payload follows the main header without padding
+        data.extend(HEADER_MAGIC);
+        data.extend([0; 4]);
+        data.extend(header(entries));
+        let mut input = tempfile::tempfile()?;
+        input.write_all(archive)?;
+        input.rewind()?;
+        let out = Command::new("gzip")
+            .arg("-c")
+            .stdin(Stdio::from(input))
+            .output()?;
+        assert!(out.status.success());
+        data.extend(out.stdout);
+        Ok(data)
+    }
+
+    #[test]
+    fn test_open() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let mut archive = Vec::new();
+        archive.extend(cpio_entry(
+            "./boot/efi/EFI/fedora/shimx64.efi",
+            0o100700,
+            b"shim",
+        ));
+        archive.extend(cpio_entry("./usr/share/doc/shim/README", 0o100644, b"doc"));
+        archive.extend(cpio_entry(CPIO_TRAILER, 0, b""));
+        let mut entries = package_entries(["shim-x64", "15.8", "3", "x86_64"], 1657222566);
+        let path = td.path().join("shim.rpm");
+        // Without a compressor in the header, the payload is gzip
+        for compressor in [None, Some("gzip")] {
+            if let Some(c) = compressor {
+                entries.push(string_entry(RPMTAG_PAYLOADCOMPRESSOR, c));
+            }
+            std::fs::write(&path, rpm(&entries, &archive)?)?;
+            let pkg = RpmFile::open(&path)?;
+            assert_eq!(pkg.package.nevra, "shim-x64-15.8-3.x86_64");
+            assert_eq!(pkg.package.buildtime.timestamp(), 1657222566);
+            assert_eq!(pkg.compressor, "gzip");
+            let dest = td.path().join("EFI");
+            let files = pkg.extract("boot/efi/EFI", &dest)?;
+            assert_eq!(files, ["/boot/efi/EFI/fedora/shimx64.efi"]);
+            assert_eq!(std::fs::read(dest.join("fedora/shimx64.efi"))?, b"shim");
+            std::fs::remove_dir_all(&dest)?;
+        }
+
+        let data = rpm(&entries, &archive)?;
+        std::fs::write(&path, &data[1..])?;
+        assert!(RpmFile::open(&path).is_err());
+        std::fs::write(&path, &data[..LEAD_SIZE + 20])?;
+        assert!(RpmFile::open(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompressor() {
+        assert!(decompressor("zstd").is_ok());
+        assert!(decompressor("xz").is_ok());
+        assert!(decompressor("lz4").is_err());
+    }
+}
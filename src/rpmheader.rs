@@ -0,0 +1,288 @@
+//! Parsing rpm package headers, as stored in the rpm database and in
+//! `.rpm` files; the format is described in
+//! <https://rpm-software-management.github.io/rpm/manual/format_header.html>.
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+
+const RPMTAG_NAME: u32 = 1000;
+const RPMTAG_VERSION: u32 = 1001;
+const RPMTAG_RELEASE: u32 = 1002;
+const RPMTAG_EPOCH: u32 = 1003;
+const RPMTAG_BUILDTIME: u32 = 1006;
+const RPMTAG_ARCH: u32 = 1022;
+
+const RPM_INT32_TYPE: u32 = 4;
+const RPM_STRING_TYPE: u32 = 6;
+const RPM_I18NSTRING_TYPE: u32 = 9;
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32> {
+    let b = data
+        .get(off..off + 4)
+        .with_context(|| format!("Truncated header at {off:#x}"))?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// An index entry of a header.
+struct Entry {
+    tag: u32,
+    kind: u32,
+    offset: usize,
+    count: usize,
+}
+
+/// A parsed package header.
+pub(crate) struct Header<'a> {
+    entries: Vec<Entry>,
+    data: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+    pub(crate) fn parse(blob: &'a [u8]) -> Result<Self> {
+        let nentries = read_u32(blob, 0)? as usize;
+        let data_len = read_u32(blob, 4)? as usize;
+        let data_start = 8 + nentries * 16;
+        let data = blob
+            .get(data_start..data_start + data_len)
+            .context("Truncated header data")?;
+        let entries = (0..nentries)
+            .map(|i| {
+                let off = 8 + i * 16;
+                Ok(Entry {
+                    tag: read_u32(blob, off)?,
+                    kind: read_u32(blob, off + 4)?,
+                    offset: read_u32(blob, off + 8)? as usize,
+                    count: read_u32(blob, off + 12)? as usize,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { entries, data })
+    }
+
+    /// The size of the header blob.
+    pub(crate) fn size(&self) -> usize {
+        8 + self.entries.len() * 16 + self.data.len()
+    }
+
+    fn entry(&self, tag: u32, kinds: &[u32]) -> Result<Option<&Entry>> {
+        match self.entries.iter().find(|e| e.tag == tag) {
+            Some(e) if kinds.contains(&e.kind) => Ok(Some(e)),
+            Some(e) => bail!("Unexpected type {} for tag {tag}", e.kind),
+            None => Ok(None),
+        }
+    }
+
+    /// The `count` NUL-terminated strings at `offset`.
+    fn strings(&self, offset: usize, count: usize) -> Result<Vec<&'a str>> {
+        let mut data = self.data.get(offset..).context("Invalid string offset")?;
+        let mut r = Vec::with_capacity(count);
+        for _ in 0..count {
+            let end = data
+                .iter()
+                .position(|&c| c == 0)
+                .context("Unterminated string")?;
+            r.push(std::str::from_utf8(&data[..end])?);
+            data = &data[end + 1..];
+        }
+        Ok(r)
+    }
+
+    pub(crate) fn string(&self, tag: u32) -> Result<Option<&'a str>> {
+        let Some(e) = self.entry(tag, &[RPM_STRING_TYPE, RPM_I18NSTRING_TYPE])? else {
+            return Ok(None);
+        };
+        Ok(self.strings(e.offset, 1)?.pop())
+    }
+
+    fn required_string(&self, tag: u32) -> Result<&'a str> {
+        self.string(tag)?
+            .with_context(|| format!("Missing tag {tag}"))
+    }
+
+    fn int32_array(&self, tag: u32) -> Result<Vec<u32>> {
+        match self.entry(tag, &[RPM_INT32_TYPE])? {
+            Some(e) => (0..e.count)
+                .map(|i| read_u32(self.data, e.offset + i * 4))
+                .collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// A package, as described by its header.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Package {
+    pub(crate) nevra: String,
+    pub(crate) buildtime: DateTime<Utc>,
+    #[cfg(any(test, feature = "rpmdb"))]
+    pub(crate) files: Vec<String>,
+}
+
+impl Package {
+    #[cfg(any(test, feature = "rpmdb"))]
+    pub(crate) fn from_header(blob: &[u8]) -> Result<Self> {
+        Self::from_parsed(&Header::parse(blob)?)
+    }
+
+    pub(crate) fn from_parsed(h: &Header) -> Result<Self> {
+        let epoch = match h.int32_array(RPMTAG_EPOCH)?.first() {
+            Some(e) => format!("{e}:"),
+            None => String::new(),
+        };
+        let nevra = format!(
+            "{}-{epoch}{}-{}.{}",
+            h.required_string(RPMTAG_NAME)?,
+            h.required_string(RPMTAG_VERSION)?,
+            h.required_string(RPMTAG_RELEASE)?,
+            h.required_string(RPMTAG_ARCH)?
+        );
+        let buildtime = *h
+            .int32_array(RPMTAG_BUILDTIME)?
+            .first()
+            .context("Missing build time")?;
+        let buildtime =
+            DateTime::from_timestamp(buildtime.into(), 0).context("Invalid build time")?;
+        Ok(Self {
+            nevra,
+            buildtime,
+            #[cfg(any(test, feature = "rpmdb"))]
+            files: files::files(h)?,
+        })
+    }
+}
+
+/// The file list, which only the rpm database queries need.
+#[cfg(any(test, feature = "rpmdb"))]
+mod files {
+    use super::*;
+
+    pub(super) const RPMTAG_DIRINDEXES: u32 = 1116;
+    pub(super) const RPMTAG_BASENAMES: u32 = 1117;
+    pub(super) const RPMTAG_DIRNAMES: u32 = 1118;
+
+    pub(super) const RPM_STRING_ARRAY_TYPE: u32 = 8;
+
+    impl<'a> Header<'a> {
+        fn string_array(&self, tag: u32) -> Result<Vec<&'a str>> {
+            match self.entry(tag, &[RPM_STRING_ARRAY_TYPE])? {
+                Some(e) => self.strings(e.offset, e.count),
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+
+    /// The paths of the files in the package.
+    pub(super) fn files(h: &Header) -> Result<Vec<String>> {
+        let dirnames = h.string_array(RPMTAG_DIRNAMES)?;
+        h.string_array(RPMTAG_BASENAMES)?
+            .into_iter()
+            .zip(h.int32_array(RPMTAG_DIRINDEXES)?)
+            .map(|(base, i)| {
+                let dir = dirnames.get(i as usize).context("Invalid dir index")?;
+                Ok(format!("{dir}{base}"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::files::*;
+    use super::*;
+
+    /// Build a header from (tag, type, count, data) entries.
+    pub(crate) fn header(entries: &[(u32, u32, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut index = Vec::new();
+        let mut data: Vec<u8> = Vec::new();
+        for (tag, kind, count, d) in entries {
+            for v in [*tag, *kind, data.len() as u32, *count] {
+                index.extend(v.to_be_bytes());
+            }
+            data.extend(d);
+        }
+        let mut h = (entries.len() as u32).to_be_bytes().to_vec();
+        h.extend((data.len() as u32).to_be_bytes());
+        h.extend(index);
+        h.extend(data);
+        h
+    }
+
+    fn strings(s: &[&str]) -> Vec<u8> {
+        s.iter().flat_map(|s| s.bytes().chain([0])).collect()
+    }
+
+    fn int32s(v: &[u32]) -> Vec<u8> {
+        v.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// A string entry of a header.
+    pub(crate) fn string_entry(tag: u32, s: &str) -> (u32, u32, u32, Vec<u8>) {
+        (tag, RPM_STRING_TYPE, 1, strings(&[s]))
+    }
+
+    /// The entries of the header of the package `name-version-release.arch`.
+    pub(crate) fn package_entries(
+        [name, version, release, arch]: [&str; 4],
+        buildtime: u32,
+    ) -> Vec<(u32, u32, u32, Vec<u8>)> {
+        vec![
+            string_entry(RPMTAG_NAME, name),
+            string_entry(RPMTAG_VERSION, version),
+            string_entry(RPMTAG_RELEASE, release),
+            (RPMTAG_BUILDTIME, RPM_INT32_TYPE, 1, int32s(&[buildtime])),
+            string_entry(RPMTAG_ARCH, arch),
+        ]
+    }
+
+    #[test]
+    fn test_package_from_header() -> Result<()> {
+        let h = header(&[
+            (RPMTAG_NAME, RPM_STRING_TYPE, 1, strings(&["shim-x64"])),
+            (RPMTAG_VERSION, RPM_STRING_TYPE, 1, strings(&["15.8"])),
+            (RPMTAG_RELEASE, RPM_STRING_TYPE, 1, strings(&["3"])),
+            (RPMTAG_BUILDTIME, RPM_INT32_TYPE, 1, int32s(&[1657222566])),
+            (RPMTAG_ARCH, RPM_STRING_TYPE, 1, strings(&["x86_64"])),
+            (RPMTAG_DIRINDEXES, RPM_INT32_TYPE, 3, int32s(&[0, 1, 1])),
+            (
+                RPMTAG_BASENAMES,
+                RPM_STRING_ARRAY_TYPE,
+                3,
+                strings(&["fedora", "BOOTX64.CSV", "shimx64.efi"]),
+            ),
+            (
+                RPMTAG_DIRNAMES,
+                RPM_STRING_ARRAY_TYPE,
+                2,
+                strings(&["/boot/efi/EFI/", "/boot/efi/EFI/fedora/"]),
+            ),
+        ]);
+        let pkg = Package::from_header(&h)?;
+        assert_eq!(
+            pkg,
+            Package {
+                nevra: "shim-x64-15.8-3.x86_64".into(),
+                buildtime: DateTime::from_timestamp(1657222566, 0).unwrap(),
+                files: vec![
+                    "/boot/efi/EFI/fedora".into(),
+                    "/boot/efi/EFI/fedora/BOOTX64.CSV".into(),
+                    "/boot/efi/EFI/fedora/shimx64.efi".into(),
+                ],
+            }
+        );
+
+        let h = header(&[
+            (RPMTAG_NAME, RPM_STRING_TYPE, 1, strings(&["grub2-efi-x64"])),
+            (RPMTAG_EPOCH, RPM_INT32_TYPE, 1, int32s(&[1])),
+            (RPMTAG_VERSION, RPM_STRING_TYPE, 1, strings(&["2.06"])),
+            (RPMTAG_RELEASE, RPM_STRING_TYPE, 1, strings(&["95.fc38"])),
+            (RPMTAG_BUILDTIME, RPM_INT32_TYPE, 1, int32s(&[1681321788])),
+            (RPMTAG_ARCH, RPM_STRING_TYPE, 1, strings(&["x86_64"])),
+        ]);
+        assert_eq!(
+            Package::from_header(&h)?.nevra,
+            "grub2-efi-x64-1:2.06-95.fc38.x86_64"
+        );
+        assert!(Package::from_header(&h[..h.len() - 1]).is_err());
+        Ok(())
+    }
+}
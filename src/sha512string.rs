@@ -8,8 +8,9 @@ use openssl::hash::Hasher;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
+/// A SHA-512 digest, as `sha512:<hex>`.
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq)]
-pub(crate) struct SHA512String(pub(crate) String);
+pub struct SHA512String(pub String);
 
 impl fmt::Display for SHA512String {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
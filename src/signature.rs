@@ -0,0 +1,291 @@
+//! Verification of update payloads against OpenPGP signatures.
+//!
+//! If any keyrings exist in `/etc/bootupd/trusted.gpg.d`, every update
+//! must be signed: the image build ships a detached signature
+//! `<component>.json.sig` for the update metadata, and for components with
+//! a payload directory a manifest `<component>.manifest.json` (written by
+//! `generate-update-metadata`) along with `<component>.manifest.json.sig`.
+//! Signatures are checked with `gpgv` whenever the update is queried; the
+//! payload is only hashed and compared with the manifest when it's about
+//! to be written to the system.
+
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use openat_ext::OpenatDirExt;
+
+use crate::model::BOOTUPD_UPDATES_DIR;
+
+/// Directory holding trusted keyrings.
+const TRUSTED_KEYS_DIR: &str = "/etc/bootupd/trusted.gpg.d";
+
+/// The keyrings in `dir`, i.e. the files ending in `.gpg`.
+fn keyrings_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {dir:?}")),
+    };
+    let mut r = Vec::new();
+    for e in entries {
+        let path = e?.path();
+        if path.extension().is_some_and(|e| e == "gpg") {
+            r.push(path);
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// The name of the manifest of the payload for `component`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn manifest_name(component: &str) -> String {
+    format!("{component}.manifest.json")
+}
+
+/// Check `data` against the detached signature `<data>.sig` in `dir`.
+fn gpgv(keyrings: &[PathBuf], dir: &openat::Dir, data: &str) -> Result<()> {
+    let sig = format!("{data}.sig");
+    if !dir.exists(sig.as_str())? {
+        bail!("Missing signature {sig}");
+    }
+    // Our descriptors aren't inherited by gpgv, so resolve the path here
+    let dirpath = std::fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd()))?;
+    let mut cmd = Command::new("gpgv");
+    for k in keyrings {
+        cmd.arg("--keyring").arg(k);
+    }
+    cmd.arg(dirpath.join(&sig)).arg(dirpath.join(data));
+    let out = cmd.output().context("Running gpgv")?;
+    if !out.status.success() {
+        bail!(
+            "Invalid signature for {data}: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    log::debug!("Verified signature for {data}");
+    Ok(())
+}
+
+/// The signed manifest of the payload for `component`, if it has a payload
+/// directory.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn signed_manifest(
+    keyrings: &[PathBuf],
+    updates: &openat::Dir,
+    component: &str,
+) -> Result<Option<crate::filetree::FileTree>> {
+    if updates.sub_dir_optional(component)?.is_none() {
+        return Ok(None);
+    }
+    let manifest = manifest_name(component);
+    let Some(f) = updates.open_file_optional(manifest.as_str())? else {
+        bail!("Missing manifest {manifest}");
+    };
+    gpgv(keyrings, updates, &manifest)?;
+    let expected = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {manifest}"))?;
+    Ok(Some(expected))
+}
+
+/// Verify the signatures of the update for `component` in `sysroot`, if
+/// signatures are required.  This doesn't read the payload itself, see
+/// [`verified_payload_tree`].
+pub(crate) fn verify_update(sysroot: &openat::Dir, component: &str) -> Result<()> {
+    let keyrings = keyrings_in(Path::new(TRUSTED_KEYS_DIR))?;
+    verify_update_with(&keyrings, sysroot, component)
+}
+
+/// [`verify_update`] with the trusted `keyrings`.
+fn verify_update_with(keyrings: &[PathBuf], sysroot: &openat::Dir, component: &str) -> Result<()> {
+    if keyrings.is_empty() {
+        return Ok(());
+    }
+    let updates = sysroot.sub_dir(BOOTUPD_UPDATES_DIR)?;
+    gpgv(keyrings, &updates, &format!("{component}.json"))?;
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    signed_manifest(keyrings, &updates, component)?;
+    Ok(())
+}
+
+/// The tree of `updated`, the payload of `component` in `sysroot`, which
+/// is about to be written to the system; if signatures are required, it
+/// must match its signed manifest.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn verified_payload_tree(
+    sysroot: &openat::Dir,
+    component: &str,
+    updated: &openat::Dir,
+) -> Result<crate::filetree::FileTree> {
+    let keyrings = keyrings_in(Path::new(TRUSTED_KEYS_DIR))?;
+    verified_payload_tree_with(&keyrings, sysroot, component, updated)
+}
+
+/// [`verified_payload_tree`] with the trusted `keyrings`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn verified_payload_tree_with(
+    keyrings: &[PathBuf],
+    sysroot: &openat::Dir,
+    component: &str,
+    updated: &openat::Dir,
+) -> Result<crate::filetree::FileTree> {
+    let actual = crate::filetree::FileTree::new_from_dir(updated)?;
+    if keyrings.is_empty() {
+        return Ok(actual);
+    }
+    let updates = sysroot.sub_dir(BOOTUPD_UPDATES_DIR)?;
+    let Some(expected) = signed_manifest(keyrings, &updates, component)? else {
+        bail!("Missing payload for {component}");
+    };
+    let diff = expected.diff(&actual)?;
+    if !diff.is_empty() {
+        bail!("Payload for {component} doesn't match its manifest ({diff})");
+    }
+    Ok(actual)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyrings_in() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert!(keyrings_in(&td.path().join("nonexistent"))?.is_empty());
+        for name in ["vendor.gpg", "README", "local.gpg"] {
+            std::fs::write(td.path().join(name), "")?;
+        }
+        assert_eq!(
+            keyrings_in(td.path())?,
+            [td.path().join("local.gpg"), td.path().join("vendor.gpg")]
+        );
+        Ok(())
+    }
+
+    /// Run `gpg` with its home in `home`.
+    fn gpg(home: &Path, args: &[&str]) -> Result<std::process::Output> {
+        let out = Command::new("gpg")
+            .env("GNUPGHOME", home)
+            .args(["--batch", "--yes", "--quiet", "--pinentry-mode", "loopback"])
+            .args(["--passphrase", ""])
+            .args(args)
+            .output()?;
+        if !out.status.success() {
+            bail!(
+                "gpg {args:?} failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+        Ok(out)
+    }
+
+    /// Create a signing key in `home`, and return the keyring holding it.
+    fn generate_key(home: &Path) -> Result<PathBuf> {
+        std::fs::set_permissions(home, std::os::unix::fs::PermissionsExt::from_mode(0o700))?;
+        gpg(
+            home,
+            &[
+                "--quick-gen-key",
+                "Test <test@example.com>",
+                "ed25519",
+                "sign",
+                "never",
+            ],
+        )?;
+        let keyring = home.join("test.gpg");
+        std::fs::write(&keyring, gpg(home, &["--export"])?.stdout)?;
+        Ok(keyring)
+    }
+
+    /// Sign the update metadata and payload manifest of `component` in
+    /// `root` with the key in `home`.
+    fn sign_update(home: &Path, root: &Path, component: &str) -> Result<()> {
+        let updates = root.join(BOOTUPD_UPDATES_DIR);
+        let updated = openat::Dir::open(&updates.join(component))?;
+        let manifest = updates.join(format!("{component}.manifest.json"));
+        std::fs::write(
+            &manifest,
+            serde_json::to_vec(&crate::filetree::FileTree::new_from_dir(&updated)?)?,
+        )?;
+        for f in [updates.join(format!("{component}.json")), manifest] {
+            let f = f.to_str().unwrap();
+            gpg(home, &["--detach-sign", "--output", &format!("{f}.sig"), f])?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_signature() -> Result<()> {
+        let gnupghome = tempfile::tempdir()?;
+        let keyring = generate_key(gnupghome.path())?;
+        let root = crate::testutils::SyntheticSysroot::new()?;
+        let ts = chrono::DateTime::UNIX_EPOCH;
+        root.write_update("EFI", "shim-1", ts, &[("fedora/shimx64.efi", "shim v1")])?;
+        let keyrings = [keyring];
+        let e = verify_update_with(&keyrings, &root.dir, "EFI").unwrap_err();
+        assert!(format!("{e:#}").contains("Missing signature EFI.json.sig"));
+
+        sign_update(gnupghome.path(), root.path(), "EFI")?;
+        verify_update_with(&keyrings, &root.dir, "EFI")?;
+        // Metadata changed after signing
+        let updates = root.path().join(BOOTUPD_UPDATES_DIR);
+        root.write_update("EFI", "shim-2", ts, &[])?;
+        let e = verify_update_with(&keyrings, &root.dir, "EFI").unwrap_err();
+        assert!(format!("{e:#}").contains("Invalid signature for EFI.json"));
+        // Or signed by a key we don't trust
+        let other = tempfile::tempdir()?;
+        generate_key(other.path())?;
+        sign_update(other.path(), root.path(), "EFI")?;
+        let e = verify_update_with(&keyrings, &root.dir, "EFI").unwrap_err();
+        assert!(format!("{e:#}").contains("Invalid signature for EFI.json"));
+        std::fs::write(updates.join("EFI.json.sig"), "garbage")?;
+        assert!(verify_update_with(&keyrings, &root.dir, "EFI").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn test_payload_manifest_mismatch() -> Result<()> {
+        let gnupghome = tempfile::tempdir()?;
+        let keyrings = [generate_key(gnupghome.path())?];
+        let root = crate::testutils::SyntheticSysroot::new()?;
+        let ts = chrono::DateTime::UNIX_EPOCH;
+        root.write_update("EFI", "shim-1", ts, &[("fedora/shimx64.efi", "shim v1")])?;
+        sign_update(gnupghome.path(), root.path(), "EFI")?;
+        let updated = root.dir.sub_dir("usr/lib/bootupd/updates/EFI")?;
+        let tree = verified_payload_tree_with(&keyrings, &root.dir, "EFI", &updated)?;
+        assert!(tree.children.contains_key("fedora/shimx64.efi"));
+
+        // The payload no longer matches the signed manifest
+        root.write_update("EFI", "shim-1", ts, &[("fedora/shimx64.efi", "tampered")])?;
+        let e = verified_payload_tree_with(&keyrings, &root.dir, "EFI", &updated).unwrap_err();
+        assert!(format!("{e:#}").contains("Payload for EFI doesn't match its manifest"));
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! Container images as the source root of `install`, e.g. for disk image
+//! builders installing the bootloader of a bootc image, which otherwise
+//! would have to mount the image themselves for `--src-root`.
+//!
+//! The image is mounted with `podman image mount`, after pulling it from
+//! its registry (e.g. `quay.io/fedora/fedora-bootc:41`, optionally with the
+//! `docker://` transport), unless it's given as
+//! `containers-storage:<image>`, i.e. already in the local container
+//! storage.  It's unmounted again when we're done.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use fn_error_context::context;
+
+use crate::util::{cmd_output, CommandRunExt};
+
+/// The transport for images in the local container storage.
+const CONTAINERS_STORAGE: &str = "containers-storage:";
+
+/// The transport for images in a registry.
+const DOCKER: &str = "docker://";
+
+/// The name of the local image to mount for `imgref`, and whether it
+/// needs to be pulled first.
+fn parse_ref(imgref: &str) -> Result<(&str, bool)> {
+    let (image, pull) = match imgref.strip_prefix(CONTAINERS_STORAGE) {
+        Some(image) => (image, false),
+        None => (imgref.strip_prefix(DOCKER).unwrap_or(imgref), true),
+    };
+    if image.is_empty() {
+        bail!("Invalid image reference {imgref:?}");
+    }
+    Ok((image, pull))
+}
+
+/// A container image mounted for use as a source root.
+#[derive(Debug)]
+pub(crate) struct SourceImage {
+    image: String,
+    root: PathBuf,
+}
+
+impl SourceImage {
+    /// Pull `imgref` if needed, and mount it.
+    #[context("Mounting source image {imgref}")]
+    pub(crate) fn mount(imgref: &str) -> Result<Self> {
+        let (image, pull) = parse_ref(imgref)?;
+        if pull {
+            crate::notify::progress("pull", None, &[("image", image.to_string())]);
+            Command::new("podman")
+                .args(["pull", "--quiet", imgref])
+                .run()?;
+        }
+        let root = cmd_output(Command::new("podman").args(["image", "mount", image]))?;
+        let root = PathBuf::from(root.trim());
+        if !root.is_dir() {
+            bail!("podman mounted {image} at non-directory {root:?}");
+        }
+        log::debug!("Mounted {image} at {root:?}");
+        Ok(Self {
+            image: image.to_string(),
+            root,
+        })
+    }
+
+    /// Where the image is mounted.
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Drop for SourceImage {
+    fn drop(&mut self) {
+        let r = Command::new("podman")
+            .args(["image", "unmount", &self.image])
+            .run();
+        if let Err(e) = r {
+            log::warn!("Failed to unmount {}: {e:#}", self.image);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ref() -> Result<()> {
+        assert_eq!(
+            parse_ref("quay.io/fedora/fedora-bootc:41")?,
+            ("quay.io/fedora/fedora-bootc:41", true)
+        );
+        assert_eq!(
+            parse_ref("docker://quay.io/fedora/fedora-bootc:41")?,
+            ("quay.io/fedora/fedora-bootc:41", true)
+        );
+        assert_eq!(
+            parse_ref("containers-storage:localhost/my-os")?,
+            ("localhost/my-os", false)
+        );
+        assert!(parse_ref("containers-storage:").is_err());
+        assert!(parse_ref("docker://").is_err());
+        assert!(parse_ref("").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! Filesystem-specific strategies for flushing changes to disk.
+//!
+//! A plain `syncfs()` is not sufficient everywhere; in particular
+//! GRUB does not replay the XFS log, so metadata that has only been
+//! written to the log is invisible to it.  A freeze/thaw cycle forces
+//! the log to be checkpointed.
+
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use rustix::fd::BorrowedFd;
+use rustix::{io, ioctl};
+
+/// How changes to a given filesystem are made durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SyncStrategy {
+    /// `FIFREEZE` followed by `FITHAW`; flushes the XFS log.
+    FreezeThaw,
+    /// The `BTRFS_IOC_SYNC` ioctl, equivalent to `btrfs filesystem sync`.
+    BtrfsSync,
+    /// A plain `syncfs()`; used for ext4, vfat and everything else.
+    Syncfs,
+}
+
+impl SyncStrategy {
+    /// Pick the strategy for a filesystem type as reported by `findmnt`.
+    pub(crate) fn from_fstype(fstype: &str) -> Self {
+        match fstype {
+            "xfs" => Self::FreezeThaw,
+            "btrfs" => Self::BtrfsSync,
+            _ => Self::Syncfs,
+        }
+    }
+}
+
+// FIFREEZE is defined as _IOWR('X', 119, int), but the argument is ignored.
+fn ioctl_fifreeze(fd: BorrowedFd) -> io::Result<()> {
+    unsafe {
+        let ctl = ioctl::NoArg::<ioctl::ReadWriteOpcode<b'X', 119, libc::c_int>>::new();
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
+// FITHAW is defined as _IOWR('X', 120, int), but the argument is ignored.
+fn ioctl_fithaw(fd: BorrowedFd) -> io::Result<()> {
+    unsafe {
+        let ctl = ioctl::NoArg::<ioctl::ReadWriteOpcode<b'X', 120, libc::c_int>>::new();
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
+// BTRFS_IOC_SYNC is defined as _IO(BTRFS_IOCTL_MAGIC, 8).
+fn ioctl_btrfs_sync(fd: BorrowedFd) -> io::Result<()> {
+    unsafe {
+        let ctl = ioctl::NoArg::<ioctl::NoneOpcode<0x94, 8, ()>>::new();
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
+/// Freeze and immediately thaw the filesystem containing `fd`.
+fn fsfreeze_thaw_cycle(fd: BorrowedFd) -> Result<()> {
+    match ioctl_fifreeze(fd) {
+        Ok(()) => {}
+        // Not every filesystem (or kernel) supports freezing; do the
+        // best we can in that case.
+        Err(e) if e == io::Errno::OPNOTSUPP || e == io::Errno::NOTTY => {
+            log::debug!("Freezing unsupported ({e}), falling back to syncfs");
+            return rustix::fs::syncfs(fd).context("syncfs failed");
+        }
+        Err(e) => return Err(e).context("Failed to freeze filesystem"),
+    }
+    ioctl_fithaw(fd).context("Failed to thaw filesystem")
+}
+
+/// Ensure all pending writes on the filesystem containing `dir` are durable,
+/// using a strategy appropriate for its type.
+#[context("Syncing filesystem")]
+pub(crate) fn sync_filesystem(dir: &openat::Dir) -> Result<SyncStrategy> {
+    let fsinfo = crate::filesystem::inspect_filesystem(dir, ".")?;
+    let strategy = SyncStrategy::from_fstype(&fsinfo.fstype);
+    log::debug!(
+        "Syncing {} ({}) via {strategy:?}",
+        fsinfo.source,
+        fsinfo.fstype
+    );
+    let fd = unsafe { BorrowedFd::borrow_raw(dir.as_raw_fd()) };
+    let r = match strategy {
+        SyncStrategy::FreezeThaw => fsfreeze_thaw_cycle(fd),
+        SyncStrategy::BtrfsSync => ioctl_btrfs_sync(fd).context("BTRFS_IOC_SYNC failed"),
+        SyncStrategy::Syncfs => rustix::fs::syncfs(fd).context("syncfs failed"),
+    };
+    r.with_context(|| format!("{} filesystem on {}", fsinfo.fstype, fsinfo.source))?;
+    Ok(strategy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strategy_from_fstype() {
+        assert_eq!(SyncStrategy::from_fstype("xfs"), SyncStrategy::FreezeThaw);
+        assert_eq!(SyncStrategy::from_fstype("btrfs"), SyncStrategy::BtrfsSync);
+        assert_eq!(SyncStrategy::from_fstype("ext4"), SyncStrategy::Syncfs);
+        assert_eq!(SyncStrategy::from_fstype("vfat"), SyncStrategy::Syncfs);
+    }
+}
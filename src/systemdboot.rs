@@ -0,0 +1,398 @@
+//! systemd-boot, for systems installed with it rather than shim and GRUB.
+//!
+//! The `EFI` component refuses to adopt such systems, so this component
+//! is never adopted automatically; it is only used via
+//! `bootupctl adopt-and-update --from systemd-boot`.  Adoption captures
+//! the existing systemd-boot content of the ESP, and updates then replace
+//! it with the binaries shipped in the OS.
+//!
+//! Like for the `EFI` component, files are tracked relative to `EFI/` and
+//! updated one vendor directory at a time: `systemd`, and `BOOT` only if
+//! the fallback loader there was systemd-boot when adopted, as it may
+//! belong to another OS.  `loader/` (`loader.conf`, the boot entries and
+//! the random seed) belongs to the OS, i.e. `bootctl`, `kernel-install`
+//! and `systemd-boot-random-seed.service`, and is neither tracked nor
+//! modified.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use openat_ext::OpenatDirExt;
+
+use crate::component::*;
+use crate::filetree::{self, FileMetadata, FileTree};
+use crate::model::*;
+use crate::packagesystem;
+
+/// The name of this component.
+pub(crate) const NAME: &str = "SystemdBoot";
+
+#[cfg(target_arch = "x86_64")]
+const EFI_ARCH: &str = "x64";
+#[cfg(target_arch = "aarch64")]
+const EFI_ARCH: &str = "aa64";
+
+/// Where systemd ships the systemd-boot binaries, relative to the root.
+const SDBOOT_SRCDIR: &str = "usr/lib/systemd/boot/efi";
+
+/// The vendor directory of systemd-boot in `EFI/`.
+const VENDOR: &str = "systemd";
+
+fn sdboot_binary() -> String {
+    format!("systemd-boot{EFI_ARCH}.efi")
+}
+
+/// The path of systemd-boot in `EFI/`.
+fn sdboot_path() -> String {
+    format!("{VENDOR}/{}", sdboot_binary())
+}
+
+/// The removable media path in `EFI/`, which systemd-boot also installs
+/// itself to.
+fn fallback_path() -> String {
+    format!("BOOT/BOOT{}.EFI", EFI_ARCH.to_uppercase())
+}
+
+/// Build a tree of the systemd-boot content of `EFI/` on the ESP, with the
+/// fallback loader only if it is a copy of systemd-boot.
+fn capture_tree(efidir: &openat::Dir) -> Result<FileTree> {
+    let mut children = BTreeMap::new();
+    if let Some(d) = efidir.sub_dir_optional(VENDOR)? {
+        let tree = FileTree::new_from_dir(&d).with_context(|| format!("Reading EFI/{VENDOR}"))?;
+        for (k, v) in tree.children {
+            children.insert(format!("{VENDOR}/{k}"), v);
+        }
+    }
+    let sdboot = children.get(&sdboot_path()).map(|m| m.sha512.clone());
+    let fallback = fallback_path();
+    if efidir.exists(fallback.as_str())? {
+        let meta = FileMetadata::new_from_path(efidir, fallback.as_str())?;
+        if sdboot.as_ref() == Some(&meta.sha512) {
+            children.insert(fallback, meta);
+        } else {
+            log::info!("Leaving EFI/{fallback} alone, as it isn't systemd-boot");
+        }
+    }
+    Ok(FileTree { children })
+}
+
+/// Drop the fallback loader from the payload `update` unless `current`
+/// has it, as it may belong to another OS.
+fn without_foreign_fallback(current: &FileTree, mut update: FileTree) -> FileTree {
+    let fallback = fallback_path();
+    if !current.children.contains_key(&fallback) {
+        update.children.remove(&fallback);
+    }
+    update
+}
+
+#[derive(Default)]
+pub(crate) struct SystemdBoot {
+    /// Used to find and mount the ESP
+    efi: crate::efi::Efi,
+}
+
+impl SystemdBoot {
+    /// The `EFI/` directory of the ESP.
+    fn open_esp(&self) -> Result<openat::Dir> {
+        let esp = self.efi.open_esp()?;
+        crate::efi::validate_esp(&esp)?;
+        Ok(esp)
+    }
+
+    /// Whether `sysroot` ships systemd-boot, from which an update
+    /// payload can be generated.
+    pub(crate) fn present_in(sysroot_path: &str) -> bool {
+        Path::new(sysroot_path)
+            .join(SDBOOT_SRCDIR)
+            .join(sdboot_binary())
+            .exists()
+    }
+
+    /// Update the ESP from `current` to the payload.
+    fn update_esp(&self, sysroot: &openat::Dir, current: &FileTree) -> Result<FileTree> {
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let updatef = crate::signature::verified_payload_tree(sysroot, self.name(), &updated)
+            .context("reading update dir")?;
+        let updatef = without_foreign_fallback(current, updatef);
+        let plan = crate::efi::plan_update(current.split_toplevel(), &updatef)?;
+        let esp = self.efi.ensure_mounted_esp(Path::new("/"))?;
+        crate::efi::apply_diffs_to_esp(&updated, &esp, &plan.diffs, true)?;
+        let mut filetree = FileTree::merge(plan.vendors.values());
+        filetree.record_mtimes(&self.open_esp()?);
+        Ok(filetree)
+    }
+}
+
+impl Component for SystemdBoot {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        if !crate::efi::is_efi_booted()? {
+            return Ok(None);
+        }
+        let esp = self.open_esp()?;
+        let binary = sdboot_path();
+        let Some(meta) = esp.metadata_optional(binary.as_str())? else {
+            log::trace!("No EFI/{binary} on the ESP");
+            return Ok(None);
+        };
+        let version = crate::efi::get_loader_info()
+            .filter(|v| v.starts_with("systemd-boot"))
+            .unwrap_or_else(|| "unknown".to_string());
+        let timestamp = chrono::DateTime::from_timestamp(meta.stat().st_mtime, 0)
+            .unwrap_or_else(chrono::Utc::now);
+        Ok(Some(Adoptable {
+            version: ContentMetadata {
+                timestamp,
+                version,
+                firmware: None,
+            },
+            confident: true,
+        }))
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        updatemeta: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            bail!("Failed to find systemd-boot on the ESP")
+        };
+        let current = capture_tree(&self.open_esp()?)?;
+        log::debug!("Adopting {} files", current.children.len());
+        let filetree = self.update_esp(sysroot, &current)?;
+        Ok(InstalledContent {
+            meta: updatemeta.clone(),
+            filetree: Some(filetree),
+            adopted_from: Some(meta.version),
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn install(
+        &self,
+        _src_root: &openat::Dir,
+        _dest_root: &str,
+        _device: &str,
+        _update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        bail!("Installing systemd-boot is not supported; use `bootctl install`")
+    }
+
+    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+        let src = Path::new(SDBOOT_SRCDIR).join(sdboot_binary());
+        let dest = component_updatedir(sysroot_path, self);
+        for target in [sdboot_path(), fallback_path()] {
+            let target = dest.join(target);
+            std::fs::create_dir_all(target.parent().expect("parent"))?;
+            std::fs::copy(Path::new(sysroot_path).join(&src), &target)
+                .with_context(|| format!("Copying to {target:?}"))?;
+        }
+        let meta = packagesystem::query_files(sysroot_path, [Path::new("/").join(&src)])?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        payload_root: &openat::Dir,
+        _dest_root: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let updatemeta = self.query_update(payload_root)?.expect("update available");
+        // The ESP is always that of the running system
+        let filetree = self.update_esp(payload_root, currentf)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(filetree),
+            adopted_from: None,
+            vendors: None,
+            partitions: None,
+            warnings: None,
+        })
+    }
+
+    fn validate(
+        &self,
+        _sysroot: &openat::Dir,
+        current: &InstalledContent,
+        fast: bool,
+    ) -> Result<ValidationResult> {
+        if !crate::efi::is_efi_booted()? {
+            return Ok(ValidationResult::Skip);
+        }
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let esp = self.open_esp()?;
+        let diff = currentf.validate_in(&esp, fast)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(format!("Changed: {}", f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(format!("Removed: {}", f));
+        }
+        for p in filetree::leftover_tmp(&esp)? {
+            errs.push(format!("Left over from an interrupted update: {p}"));
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn diff_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Option<Vec<filetree::FileChange>>> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        let Some(updated) = sysroot.sub_dir_optional(&component_updatedirname(self))? else {
+            return Ok(Some(Vec::new()));
+        };
+        let updatef = crate::treecache::payload_tree(sysroot, self, &updated)
+            .context("reading update dir")?;
+        let updatef = without_foreign_fallback(currentf, updatef);
+        let plan = crate::efi::plan_update(currentf.split_toplevel(), &updatef)?;
+        let changes = plan
+            .diffs
+            .iter()
+            .flat_map(|(_, diff)| diff.describe(currentf, &updatef))
+            .collect();
+        Ok(Some(changes))
+    }
+
+    fn uninstall(&self, _sysroot: &openat::Dir, current: &InstalledContent) -> Result<()> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {NAME} found!"))?;
+        filetree::remove_tree(&self.open_esp()?, currentf).context("removing files")
+    }
+
+    fn get_efi_vendor(&self, _sysroot: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::write_tree;
+
+    #[test]
+    fn test_capture_tree() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let fallback = fallback_path();
+        let sdboot = sdboot_path();
+        let efidir = write_tree(
+            td.path(),
+            &[
+                (sdboot.as_str(), "sd-boot"),
+                (fallback.as_str(), "sd-boot"),
+                ("Linux/fedora.efi", "uki"),
+            ],
+        )?;
+        let tree = capture_tree(&efidir)?;
+        assert_eq!(
+            tree.children.keys().collect::<Vec<_>>(),
+            [&fallback, &sdboot]
+        );
+
+        // An update only touches what it ships
+        let mut updatef = FileTree::default();
+        for name in [sdboot.as_str(), fallback.as_str()] {
+            let mut meta = tree.children[name].clone();
+            meta.sha512 = crate::sha512string::SHA512String("sha512:v2".into());
+            updatef.children.insert(name.to_string(), meta);
+        }
+        let plan = crate::efi::plan_update(
+            tree.split_toplevel(),
+            &without_foreign_fallback(&tree, updatef.clone()),
+        )?;
+        assert_eq!(
+            plan.diffs
+                .iter()
+                .map(|(v, _)| v.as_str())
+                .collect::<Vec<_>>(),
+            ["BOOT", "systemd"]
+        );
+
+        // The fallback loader of another OS is left alone
+        std::fs::write(td.path().join(&fallback), "shim")?;
+        let tree = capture_tree(&efidir)?;
+        assert_eq!(tree.children.keys().collect::<Vec<_>>(), [&sdboot]);
+        let plan = crate::efi::plan_update(
+            tree.split_toplevel(),
+            &without_foreign_fallback(&tree, updatef),
+        )?;
+        assert_eq!(
+            plan.diffs
+                .iter()
+                .map(|(v, _)| v.as_str())
+                .collect::<Vec<_>>(),
+            ["systemd"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_loader_untouched() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sdboot = format!("EFI/{}", sdboot_path());
+        let update = format!("update/{}", sdboot_path());
+        let loader = [
+            ("loader/loader.conf", "timeout 3\n"),
+            ("loader/entries/fedora.conf", "title Fedora\n"),
+            ("loader/random-seed", "seed"),
+        ];
+        let files = [
+            (sdboot.as_str(), "sd-boot v1"),
+            (update.as_str(), "sd-boot v2"),
+        ];
+        write_tree(td.path(), &loader)?;
+        write_tree(td.path(), &files)?;
+        let efidir = openat::Dir::open(&td.path().join("EFI"))?;
+        let tree = capture_tree(&efidir)?;
+        assert_eq!(tree.children.keys().collect::<Vec<_>>(), [&sdboot_path()]);
+
+        // What an update writes, see `apply_diffs_to_esp`
+        let updated = openat::Dir::open(&td.path().join("update"))?;
+        let updatef = FileTree::new_from_dir(&updated)?;
+        let plan = crate::efi::plan_update(tree.split_toplevel(), &updatef)?;
+        for (_, diff) in plan.diffs.iter() {
+            filetree::apply_diff(&updated, &efidir, diff, None)?;
+        }
+        assert_eq!(
+            std::fs::read_to_string(td.path().join(&sdboot))?,
+            "sd-boot v2"
+        );
+        for (name, contents) in loader {
+            assert_eq!(std::fs::read_to_string(td.path().join(name))?, contents);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Support for tests which need a system root: a synthetic ESP tree, update
+//! payloads in `usr/lib/bootupd/updates`, and a stub rpm database.  None of
+//! this needs privileges or block devices; note however that the ESP is a
+//! plain directory, not a FAT filesystem.
+//!
+//! Public for unit tests and via the `testutils` feature; otherwise only
+//! [`write_tree`] is used, by the chaos test.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+
+use crate::model::ContentMetadata;
+
+/// Path of the ESP, relative to the sysroot.
+pub const ESP_PATH: &str = "boot/efi";
+/// Path of the stub rpm database, relative to the sysroot; this is
+/// one of the locations `rpm_cmd()` looks for a database.
+const RPMDB_PATH: &str = "usr/lib/sysimage/rpm";
+const RPMDB_NAME: &str = "packages.stub";
+
+/// A stand-in for `rpm -q --queryformat '%{nevra},%{buildtime} ' -f FILE...`
+/// which looks up files in a flat `<path> <nevra>,<buildtime>` database.
+const STUB_RPM: &str = r#"#!/bin/bash
+set -euo pipefail
+dbpath=
+files=()
+while [ $# -gt 0 ]; do
+  case "$1" in
+    --dbpath=*) dbpath=${1#--dbpath=} ;;
+    --queryformat) shift ;;
+    -q|-f) ;;
+    *) files+=("$1") ;;
+  esac
+  shift
+done
+for f in "${files[@]}"; do
+  if ! awk -v f="$f" '$1 == f { printf "%s ", $2; found=1 } END { exit !found }' "${dbpath}/packages.stub"; then
+    echo "file $f is not owned by any package" 1>&2
+    exit 1
+  fi
+done
+"#;
+
+/// A temporary system root.
+pub struct SyntheticSysroot {
+    tempdir: tempfile::TempDir,
+    /// The root directory
+    pub dir: openat::Dir,
+}
+
+/// Write `files` (relative paths and their contents) under `root`,
+/// creating it and the parent directories as needed; returns `root`.
+pub fn write_tree<N, C>(root: &Path, files: &[(N, C)]) -> Result<openat::Dir>
+where
+    N: AsRef<Path>,
+    C: AsRef<[u8]>,
+{
+    std::fs::create_dir_all(root)?;
+    for (name, contents) in files {
+        let path = root.join(name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, contents).with_context(|| format!("Writing {path:?}"))?;
+    }
+    Ok(openat::Dir::open(root)?)
+}
+
+impl SyntheticSysroot {
+    /// Create an empty root, with an empty ESP and rpm database.
+    pub fn new() -> Result<Self> {
+        let tempdir = tempfile::tempdir()?;
+        let root = tempdir.path();
+        std::fs::create_dir_all(root.join(ESP_PATH).join("EFI"))?;
+        for d in [
+            crate::model::BOOTUPD_UPDATES_DIR,
+            RPMDB_PATH,
+            "run",
+            "usr/bin",
+        ] {
+            std::fs::create_dir_all(root.join(d))?;
+        }
+        std::fs::write(root.join(RPMDB_PATH).join(RPMDB_NAME), "")?;
+        let rpm = root.join("usr/bin/rpm");
+        std::fs::write(&rpm, STUB_RPM)?;
+        std::fs::set_permissions(&rpm, std::fs::Permissions::from_mode(0o755))?;
+        let dir = openat::Dir::open(root)?;
+        Ok(Self { tempdir, dir })
+    }
+
+    /// The path to the root.
+    pub fn path(&self) -> &Path {
+        self.tempdir.path()
+    }
+
+    /// The path to the ESP.
+    pub fn esp_path(&self) -> PathBuf {
+        self.path().join(ESP_PATH)
+    }
+
+    /// The directory containing the stub `rpm`; put this first in `PATH`
+    /// when running bootupd against this root.
+    pub fn bindir(&self) -> PathBuf {
+        self.path().join("usr/bin")
+    }
+
+    /// Write files into the ESP, with paths relative to `EFI/`.
+    pub fn write_esp(&self, files: &[(&str, &str)]) -> Result<()> {
+        write_tree(&self.esp_path().join("EFI"), files)?;
+        Ok(())
+    }
+
+    /// Write an update payload for `component` with paths relative to
+    /// its update directory, along with its metadata.
+    pub fn write_update(
+        &self,
+        component: &str,
+        version: &str,
+        timestamp: DateTime<Utc>,
+        files: &[(&str, &str)],
+    ) -> Result<ContentMetadata> {
+        let updates = self.path().join(crate::model::BOOTUPD_UPDATES_DIR);
+        write_tree(&updates.join(component), files)?;
+        let meta = ContentMetadata {
+            timestamp,
+            version: version.to_string(),
+            firmware: None,
+        };
+        std::fs::write(
+            updates.join(format!("{component}.json")),
+            serde_json::to_vec(&meta)?,
+        )?;
+        Ok(meta)
+    }
+
+    /// Record `files` (absolute paths) as owned by a package in the stub
+    /// rpm database.
+    pub fn add_rpm_package(
+        &self,
+        nevra: &str,
+        buildtime: DateTime<Utc>,
+        files: &[&str],
+    ) -> Result<()> {
+        use std::io::Write;
+        let dbpath = self.path().join(RPMDB_PATH).join(RPMDB_NAME);
+        let mut db = std::fs::OpenOptions::new().append(true).open(dbpath)?;
+        for f in files {
+            writeln!(db, "{f} {nevra},{}", buildtime.timestamp())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn test_synthetic_sysroot() -> Result<()> {
+        use openat_ext::OpenatDirExt;
+
+        let root = SyntheticSysroot::new()?;
+        root.write_esp(&[("fedora/shimx64.efi", "shim v1")])?;
+        let ts = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let meta = root.write_update("EFI", "shim-1", ts, &[("fedora/shimx64.efi", "shim v2")])?;
+        assert!(root.dir.exists("boot/efi/EFI/fedora/shimx64.efi")?);
+
+        let efi = crate::component::new_from_name("EFI")?;
+        let update = crate::component::get_component_update(&root.dir, efi.as_ref())?;
+        assert_eq!(update.as_ref(), Some(&meta));
+        assert_eq!(efi.get_efi_vendor(&root.dir)?.as_deref(), Some("fedora"));
+
+        root.add_rpm_package(
+            "shim-x64-15.8-3.x86_64",
+            ts,
+            &["/boot/efi/EFI/fedora/shimx64.efi"],
+        )?;
+        let out = std::process::Command::new(root.bindir().join("rpm"))
+            .arg(format!(
+                "--dbpath={}",
+                root.path().join(RPMDB_PATH).display()
+            ))
+            .args(["-q", "--queryformat", "%{nevra},%{buildtime} ", "-f"])
+            .arg("/boot/efi/EFI/fedora/shimx64.efi")
+            .output()?;
+        assert!(out.status.success());
+        assert_eq!(
+            String::from_utf8(out.stdout)?,
+            format!("shim-x64-15.8-3.x86_64,{} ", ts.timestamp())
+        );
+        Ok(())
+    }
+}
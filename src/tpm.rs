@@ -0,0 +1,434 @@
+//! Prediction of TPM PCR values after an update, by replaying the firmware
+//! event log with the digests of the updated boot images.
+//!
+//! Only the SHA-256 bank is supported.  PCR 4 holds the digests of the
+//! EFI applications loaded during boot (shim, GRUB); PCR 7 holds the
+//! Secure Boot policy, including the certificates shim used to verify
+//! GRUB, which we assume don't change.
+//!
+//! The same log tells which images were loaded during this boot, which
+//! `validate --tpm` compares with the installed ones, to catch an ESP
+//! which doesn't hold what the system booted (e.g. a stale copy on
+//! another disk, or tampering).
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use openssl::sha::Sha256;
+use serde::Serialize;
+
+/// The firmware event log exposed by the kernel.
+const EVENT_LOG_PATH: &str = "/sys/kernel/security/tpm0/binary_bios_measurements";
+/// Current PCR values, on kernels which expose them.
+const PCR_SHA256_DIR: &str = "/sys/class/tpm/tpm0/pcr-sha256";
+
+const TPM_ALG_SHA256: u16 = 0x000b;
+const EV_EFI_BOOT_SERVICES_APPLICATION: u32 = 0x8000_0003;
+
+/// Kernel statistics, including the boot time.
+const PROC_STAT: &str = "/proc/stat";
+
+/// The PCRs we predict.
+const PCRS: &[u32] = &[4, 7];
+
+/// Whether the firmware event log needed for predictions is available.
+pub(crate) fn available() -> bool {
+    std::path::Path::new(EVENT_LOG_PATH).exists()
+}
+
+/// A measurement from the event log.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Event {
+    pub(crate) pcr: u32,
+    pub(crate) event_type: u32,
+    pub(crate) sha256: [u8; 32],
+}
+
+/// A cursor over the binary event log.
+struct Reader<'a> {
+    data: &'a [u8],
+    off: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let r = self
+            .data
+            .get(self.off..self.off + n)
+            .with_context(|| format!("Truncated event log at {:#x}", self.off))?;
+        self.off += n;
+        Ok(r)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn at_end(&self) -> bool {
+        self.off >= self.data.len()
+    }
+}
+
+/// Parse a crypto-agile (TPM 2.0) event log, keeping the SHA-256 digests.
+pub(crate) fn parse_event_log(data: &[u8]) -> Result<Vec<Event>> {
+    let mut r = Reader { data, off: 0 };
+    // The first event uses the TPM 1.2 format, and describes the digests
+    // in the rest of the log.
+    let _pcr = r.u32()?;
+    let _event_type = r.u32()?;
+    r.bytes(20)?;
+    let size = r.u32()? as usize;
+    let mut spec = Reader {
+        data: r.bytes(size)?,
+        off: 0,
+    };
+    if spec.bytes(16)? != b"Spec ID Event03\0" {
+        bail!("Unsupported event log format; only TPM 2.0 logs are supported");
+    }
+    spec.bytes(8)?;
+    let nalgs = spec.u32()?;
+    let mut digest_sizes = HashMap::new();
+    for _ in 0..nalgs {
+        let alg = spec.u16()?;
+        let size = spec.u16()?;
+        digest_sizes.insert(alg, size as usize);
+    }
+    if !digest_sizes.contains_key(&TPM_ALG_SHA256) {
+        bail!("Event log has no SHA-256 digests");
+    }
+
+    let mut events = Vec::new();
+    while !r.at_end() {
+        let pcr = r.u32()?;
+        let event_type = r.u32()?;
+        let ndigests = r.u32()?;
+        let mut sha256 = None;
+        for _ in 0..ndigests {
+            let alg = r.u16()?;
+            let size = *digest_sizes
+                .get(&alg)
+                .with_context(|| format!("Unknown digest algorithm {alg:#x}"))?;
+            let digest = r.bytes(size)?;
+            if alg == TPM_ALG_SHA256 {
+                sha256 = Some(digest.try_into()?);
+            }
+        }
+        let size = r.u32()? as usize;
+        r.bytes(size)?;
+        let sha256 = sha256.context("Event without a SHA-256 digest")?;
+        events.push(Event {
+            pcr,
+            event_type,
+            sha256,
+        });
+    }
+    Ok(events)
+}
+
+/// Extend `pcr` with the digests of its events in `events`, replacing the
+/// digests of loaded images found in `replacements`.
+pub(crate) fn replay(
+    events: &[Event],
+    pcr: u32,
+    replacements: &HashMap<[u8; 32], [u8; 32]>,
+) -> [u8; 32] {
+    let mut value = [0u8; 32];
+    for e in events.iter().filter(|e| e.pcr == pcr) {
+        let digest = if e.event_type == EV_EFI_BOOT_SERVICES_APPLICATION {
+            replacements.get(&e.sha256).unwrap_or(&e.sha256)
+        } else {
+            &e.sha256
+        };
+        let mut h = Sha256::new();
+        h.update(&value);
+        h.update(digest);
+        value = h.finish();
+    }
+    value
+}
+
+/// The Authenticode digests of the EFI applications loaded during this
+/// boot.
+pub(crate) fn measured_images() -> Result<HashSet<[u8; 32]>> {
+    let log = std::fs::read(EVENT_LOG_PATH)
+        .with_context(|| format!("Reading TPM event log {EVENT_LOG_PATH}"))?;
+    Ok(parse_event_log(&log)?
+        .into_iter()
+        .filter(|e| e.event_type == EV_EFI_BOOT_SERVICES_APPLICATION)
+        .map(|e| e.sha256)
+        .collect())
+}
+
+/// The time of this boot, in seconds since the epoch.
+pub(crate) fn boot_time() -> Result<i64> {
+    let stat =
+        std::fs::read_to_string(PROC_STAT).with_context(|| format!("Reading {PROC_STAT}"))?;
+    stat.lines()
+        .find_map(|l| l.strip_prefix("btime "))
+        .context("No btime in /proc/stat")?
+        .trim()
+        .parse()
+        .context("Parsing btime")
+}
+
+/// A boot image installed on the ESP.
+#[derive(Debug)]
+pub(crate) struct InstalledImage {
+    /// Path relative to `EFI/` on the ESP
+    pub(crate) path: String,
+    /// Authenticode digest of the image
+    pub(crate) digest: [u8; 32],
+    /// Whether the image was written after the system booted, so it
+    /// can't have been measured yet
+    pub(crate) updated_since_boot: bool,
+}
+
+/// Compare the installed boot `images` (shim and GRUB) with the
+/// `measured` ones; returns the mismatches.
+///
+/// Only the directory the system booted from, i.e. the one holding a
+/// measured image, is expected to match entirely: the other ones (e.g.
+/// the fallback `EFI/BOOT`) weren't loaded.
+pub(crate) fn check_measurements(
+    images: &[InstalledImage],
+    measured: &HashSet<[u8; 32]>,
+) -> Vec<String> {
+    let dir = |path: &str| path.rsplit_once('/').map(|(d, _)| d.to_string());
+    let booted = images
+        .iter()
+        .filter(|i| measured.contains(&i.digest))
+        .filter_map(|i| dir(&i.path))
+        .collect::<BTreeSet<_>>();
+    let pending = images.iter().filter(|i| !i.updated_since_boot);
+    if booted.is_empty() {
+        let paths = pending.map(|i| i.path.as_str()).collect::<Vec<_>>();
+        if paths.is_empty() {
+            return Vec::new();
+        }
+        return vec![format!(
+            "None of the installed boot images ({}) were measured during this boot",
+            paths.join(", ")
+        )];
+    }
+    pending
+        .filter(|i| dir(&i.path).is_some_and(|d| booted.contains(&d)))
+        .filter(|i| !measured.contains(&i.digest))
+        .map(|i| {
+            format!(
+                "Differs from the image measured during this boot: {}",
+                i.path
+            )
+        })
+        .collect()
+}
+
+/// The Authenticode digests of a boot image which an update replaces.
+#[derive(Debug)]
+pub(crate) struct ImageDigests {
+    /// Path relative to `EFI/` on the ESP
+    pub(crate) path: String,
+    pub(crate) current: [u8; 32],
+    pub(crate) update: [u8; 32],
+}
+
+/// A boot image which an update replaces.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ImageChange {
+    /// Path relative to `EFI/` on the ESP
+    pub(crate) path: String,
+    /// Authenticode digest of the installed image
+    pub(crate) current: String,
+    /// Authenticode digest of the image after the update
+    pub(crate) update: String,
+    /// Whether the installed image was measured during this boot
+    pub(crate) measured: bool,
+}
+
+/// Current and predicted values of a PCR.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PcrValues {
+    /// The value from replaying the event log as is
+    pub(crate) current: String,
+    /// The value after the update
+    pub(crate) predicted: String,
+}
+
+/// Predicted PCR values after an update.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PcrPrediction {
+    pub(crate) bank: &'static str,
+    pub(crate) pcrs: BTreeMap<u32, PcrValues>,
+    pub(crate) images: Vec<ImageChange>,
+    /// Whether replaying the log gave the PCR values reported by the TPM;
+    /// `None` if the kernel doesn't expose them.
+    pub(crate) log_matches_tpm: Option<bool>,
+}
+
+/// Read the current value of `pcr` from sysfs, if available.
+fn read_pcr(pcr: u32) -> Result<Option<String>> {
+    match std::fs::read_to_string(format!("{PCR_SHA256_DIR}/{pcr}")) {
+        Ok(s) => Ok(Some(s.trim().to_ascii_lowercase())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Predict the PCR values after replacing the boot images in `images`,
+/// given as (path, current digest, updated digest).
+pub(crate) fn predict(images: Vec<ImageDigests>) -> Result<PcrPrediction> {
+    let log = std::fs::read(EVENT_LOG_PATH)
+        .with_context(|| format!("Reading TPM event log {EVENT_LOG_PATH}"))?;
+    let events = parse_event_log(&log)?;
+    let replacements: HashMap<_, _> = images.iter().map(|i| (i.current, i.update)).collect();
+    let mut pcrs = BTreeMap::new();
+    let mut log_matches_tpm = None;
+    for &pcr in PCRS {
+        let current = hex::encode(replay(&events, pcr, &HashMap::new()));
+        if let Some(actual) = read_pcr(pcr)? {
+            let matches = actual == current;
+            log_matches_tpm = Some(log_matches_tpm.unwrap_or(true) && matches);
+        }
+        let predicted = hex::encode(replay(&events, pcr, &replacements));
+        pcrs.insert(pcr, PcrValues { current, predicted });
+    }
+    let images = images
+        .into_iter()
+        .map(|i| ImageChange {
+            measured: events
+                .iter()
+                .any(|e| e.event_type == EV_EFI_BOOT_SERVICES_APPLICATION && e.sha256 == i.current),
+            path: i.path,
+            current: hex::encode(i.current),
+            update: hex::encode(i.update),
+        })
+        .collect();
+    Ok(PcrPrediction {
+        bank: "sha256",
+        pcrs,
+        images,
+        log_matches_tpm,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(pcr: u32, event_type: u32, digest: [u8; 32]) -> Vec<u8> {
+        let mut e = Vec::new();
+        e.extend(pcr.to_le_bytes());
+        e.extend(event_type.to_le_bytes());
+        e.extend(2u32.to_le_bytes());
+        e.extend(0x0004u16.to_le_bytes());
+        e.extend([0u8; 20]);
+        e.extend(TPM_ALG_SHA256.to_le_bytes());
+        e.extend(digest);
+        e.extend(3u32.to_le_bytes());
+        e.extend(b"abc");
+        e
+    }
+
+    fn event_log(events: &[Vec<u8>]) -> Vec<u8> {
+        let mut spec = b"Spec ID Event03\0".to_vec();
+        spec.extend([0u8; 8]);
+        spec.extend(2u32.to_le_bytes());
+        spec.extend(0x0004u16.to_le_bytes());
+        spec.extend(20u16.to_le_bytes());
+        spec.extend(TPM_ALG_SHA256.to_le_bytes());
+        spec.extend(32u16.to_le_bytes());
+        spec.push(0);
+        let mut log = Vec::new();
+        log.extend(0u32.to_le_bytes());
+        log.extend(3u32.to_le_bytes());
+        log.extend([0u8; 20]);
+        log.extend((spec.len() as u32).to_le_bytes());
+        log.extend(spec);
+        for e in events {
+            log.extend(e);
+        }
+        log
+    }
+
+    #[test]
+    fn test_replay() -> Result<()> {
+        const EV_SEPARATOR: u32 = 4;
+        let (shim, grub, grub2) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        let log = event_log(&[
+            event(4, EV_SEPARATOR, [0u8; 32]),
+            event(4, EV_EFI_BOOT_SERVICES_APPLICATION, shim),
+            event(7, EV_SEPARATOR, [0u8; 32]),
+            event(4, EV_EFI_BOOT_SERVICES_APPLICATION, grub),
+        ]);
+        let events = parse_event_log(&log)?;
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[1],
+            Event {
+                pcr: 4,
+                event_type: EV_EFI_BOOT_SERVICES_APPLICATION,
+                sha256: shim
+            }
+        );
+
+        let extend = |v: [u8; 32], d: [u8; 32]| {
+            let mut h = Sha256::new();
+            h.update(&v);
+            h.update(&d);
+            h.finish()
+        };
+        let base = extend(extend([0u8; 32], [0u8; 32]), shim);
+        let none = HashMap::new();
+        assert_eq!(replay(&events, 4, &none), extend(base, grub));
+        let replacements = [(grub, grub2)].into_iter().collect();
+        assert_eq!(replay(&events, 4, &replacements), extend(base, grub2));
+        assert_eq!(replay(&events, 7, &replacements), replay(&events, 7, &none));
+
+        assert!(parse_event_log(&log[..log.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_measurements() {
+        let (shim, grub, grub2) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        let image = |path: &str, digest, updated_since_boot| InstalledImage {
+            path: path.to_string(),
+            digest,
+            updated_since_boot,
+        };
+        let measured = [shim, grub].into_iter().collect::<HashSet<_>>();
+        let mut images = vec![
+            image("fedora/shimx64.efi", shim, false),
+            image("fedora/grubx64.efi", grub, false),
+            // Not booted from, so not expected to match
+            image("BOOT/BOOTX64.EFI", [4u8; 32], false),
+        ];
+        assert!(check_measurements(&images, &measured).is_empty());
+
+        // GRUB changed on the ESP since it was loaded
+        images[1].digest = grub2;
+        assert_eq!(
+            check_measurements(&images, &measured),
+            ["Differs from the image measured during this boot: fedora/grubx64.efi"]
+        );
+        // ... which is expected after an update until the next boot
+        images[1].updated_since_boot = true;
+        assert!(check_measurements(&images, &measured).is_empty());
+
+        // Booted from something else entirely
+        let other = [[5u8; 32]].into_iter().collect();
+        assert_eq!(check_measurements(&images, &other).len(), 1);
+        for i in images.iter_mut() {
+            i.updated_since_boot = true;
+        }
+        assert!(check_measurements(&images, &other).is_empty());
+    }
+}
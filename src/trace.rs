@@ -0,0 +1,158 @@
+//! Profiling of the slow steps (mounting the ESP, hashing and diffing the
+//! trees, applying updates, updating the firmware), which are instrumented
+//! with `tracing` spans.  With `--trace-file`, the spans are written as a
+//! Chrome trace, which can be loaded in Perfetto or `chrome://tracing`, so
+//! that e.g. a slow `validate` on a big ESP can be looked at in the field.
+//! With the `otlp` feature, `--otlp-endpoint` exports them to an
+//! OpenTelemetry collector instead of, or as well as, the file.
+
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Flushes the traces when dropped, so this must be kept alive until the
+/// end of the run.
+#[derive(Default)]
+pub(crate) struct Guard {
+    _chrome: Option<tracing_chrome::FlushGuard>,
+    #[cfg(feature = "otlp")]
+    otlp: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.otlp.take() {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("Failed to export traces: {e}");
+            }
+        }
+    }
+}
+
+/// The layer writing the spans to `out` as a Chrome trace; the args of the
+/// spans are written on both their `B` and `E` events, the latter including
+/// the fields recorded while the span was open.
+fn chrome_layer<S, W>(out: W) -> (tracing_chrome::ChromeLayer<S>, tracing_chrome::FlushGuard)
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a> + Send + Sync,
+    W: std::io::Write + Send + 'static,
+{
+    tracing_chrome::ChromeLayerBuilder::new()
+        .writer(out)
+        .include_args(true)
+        .build()
+}
+
+/// The tracer provider exporting the spans to the OTLP/HTTP `endpoint`.
+#[cfg(feature = "otlp")]
+fn otlp_provider(endpoint: &str) -> Result<opentelemetry_sdk::trace::SdkTracerProvider> {
+    use opentelemetry_otlp::WithExportConfig;
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .with_context(|| format!("Creating OTLP exporter for {endpoint}"))?;
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name(clap::crate_name!())
+        .build();
+    Ok(opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build())
+}
+
+/// Write the spans of this process to the Chrome trace at `trace_file`
+/// and/or export them to `otlp_endpoint`.
+pub(crate) fn init(trace_file: Option<&Path>, otlp_endpoint: Option<&str>) -> Result<Guard> {
+    if trace_file.is_none() && otlp_endpoint.is_none() {
+        return Ok(Guard::default());
+    }
+    let mut guard = Guard::default();
+    let chrome = trace_file
+        .map(|path| File::create(path).with_context(|| format!("Creating trace file {path:?}")))
+        .transpose()?
+        .map(|f| {
+            let (layer, flush) = chrome_layer(f);
+            guard._chrome = Some(flush);
+            layer
+        });
+    let subscriber = tracing_subscriber::registry().with(chrome);
+    #[cfg(feature = "otlp")]
+    let subscriber = {
+        use opentelemetry::trace::TracerProvider;
+        let provider = otlp_endpoint.map(otlp_provider).transpose()?;
+        let layer = provider
+            .as_ref()
+            .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer(clap::crate_name!())));
+        guard.otlp = provider;
+        subscriber.with(layer)
+    };
+    tracing::subscriber::set_global_default(subscriber).context("Setting up tracing")?;
+    Ok(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// A buffer shared with the subscriber.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_chrome_trace() -> Result<()> {
+        let buf = SharedBuf::default();
+        let (layer, flush) = chrome_layer(buf.clone());
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _diff = tracing::info_span!("diff", component = "EFI").entered();
+            {
+                let hash = tracing::info_span!("hash", files = tracing::field::Empty).entered();
+                tracing::info!(path = "EFI/fedora/shimx64.efi", "slow");
+                hash.record("files", 3u64);
+            }
+        });
+        drop(flush);
+        let data = String::from_utf8(buf.0.lock().unwrap().clone())?;
+        let mut events: Vec<Value> = serde_json::from_str(&data)?;
+        // Drop the thread names
+        events.retain(|e| e["ph"] != "M");
+        let phases = events
+            .iter()
+            .map(|e| (e["ph"].as_str().unwrap(), e["name"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(phases[0], ("B", "diff"));
+        assert_eq!(phases[1], ("B", "hash"));
+        assert_eq!(phases[2].0, "i");
+        assert_eq!(&phases[3..], [("E", "hash"), ("E", "diff")]);
+        assert_eq!(events[0]["args"]["component"], "\"EFI\"");
+        assert!(events[1]["args"].get("files").is_none());
+        assert_eq!(events[2]["args"]["path"], "\"EFI/fedora/shimx64.efi\"");
+        // The end events carry the args too, including those recorded late;
+        // the values are formatted with `Debug`
+        assert_eq!(events[3]["args"]["files"], "3");
+        assert_eq!(events[4]["args"]["component"], "\"EFI\"");
+        let ts = events
+            .iter()
+            .map(|e| e["ts"].as_f64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(ts.windows(2).all(|w| w[0] <= w[1]));
+        Ok(())
+    }
+}
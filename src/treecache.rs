@@ -0,0 +1,140 @@
+//! A cache of the [`FileTree`]s of update payloads, which otherwise are
+//! re-hashed on every `status` or `diff` even though they only change when
+//! a new OS version is deployed.
+//!
+//! Trees are stored in `/run/bootupd/trees`, so the cache doesn't outlive
+//! the boot.  The key is derived from the inode and timestamps of both the
+//! payload directory and its update metadata file, which
+//! `generate-update-metadata` rewrites along with the payload; each ostree
+//! deployment has its own payload directory, so they are cached separately.
+//! This misses changes deeper in the payload, so the cache is only for
+//! reporting: updates hash the payload they write, see
+//! [`crate::signature::verified_payload_tree`].  The cache is best-effort:
+//! failures to read or write it are only logged.
+
+use std::path::Path;
+
+use anyhow::Result;
+use openat_ext::OpenatDirExt;
+
+use crate::component::Component;
+use crate::filetree::FileTree;
+use crate::model::BOOTUPD_UPDATES_DIR;
+
+/// Where the trees are cached.
+const CACHE_DIR: &str = "/run/bootupd/trees";
+
+/// Identifies a file or directory, and changes whenever it is modified.
+fn stat_key(st: &libc::stat) -> String {
+    format!(
+        "{:x}.{:x}.{}.{}.{}.{}",
+        st.st_dev, st.st_ino, st.st_mtime, st.st_mtime_nsec, st.st_ctime, st.st_ctime_nsec
+    )
+}
+
+/// The cache key for the payload `updated` of the component `name` in
+/// `sysroot`, or `None` if there's no update metadata to key it on.
+fn cache_key(sysroot: &openat::Dir, name: &str, updated: &openat::Dir) -> Result<Option<String>> {
+    let metapath = Path::new(BOOTUPD_UPDATES_DIR).join(format!("{name}.json"));
+    let Some(meta) = sysroot.metadata_optional(&metapath)? else {
+        return Ok(None);
+    };
+    let dir = updated.self_metadata()?;
+    Ok(Some(format!(
+        "{name}-{}-{}",
+        stat_key(dir.stat()),
+        stat_key(meta.stat())
+    )))
+}
+
+/// The tree of `updated`, the payload of component `name` in `sysroot`,
+/// from the cache in `cachedir` if possible.
+fn cached_tree(
+    cachedir: &Path,
+    sysroot: &openat::Dir,
+    name: &str,
+    updated: &openat::Dir,
+) -> Result<FileTree> {
+    let key = match cache_key(sysroot, name, updated) {
+        Ok(Some(k)) => k,
+        Ok(None) => return FileTree::new_from_dir(updated),
+        Err(e) => {
+            log::debug!("Not caching the payload tree of {name}: {e:#}");
+            return FileTree::new_from_dir(updated);
+        }
+    };
+    let filename = format!("{key}.json");
+    let path = cachedir.join(&filename);
+    match std::fs::read(&path) {
+        Ok(buf) => match serde_json::from_slice(&buf) {
+            Ok(tree) => {
+                log::trace!("Using cached payload tree {}", path.display());
+                return Ok(tree);
+            }
+            Err(e) => log::debug!("Ignoring invalid {}: {e}", path.display()),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => log::debug!("Reading {}: {e}", path.display()),
+    }
+    let tree = FileTree::new_from_dir(updated)?;
+    if let Err(e) = store(cachedir, &filename, &tree) {
+        log::debug!("Failed to cache the payload tree of {name}: {e:#}");
+    }
+    Ok(tree)
+}
+
+/// Write `tree` to the file `filename` in `cachedir`.
+fn store(cachedir: &Path, filename: &str, tree: &FileTree) -> Result<()> {
+    std::fs::create_dir_all(cachedir)?;
+    let dir = openat::Dir::open(cachedir)?;
+    dir.write_file_contents(filename, 0o644, serde_json::to_vec(tree)?)?;
+    Ok(())
+}
+
+/// The tree of `updated`, the update payload of `component` in `sysroot`;
+/// only for reporting, as it may be stale.
+pub(crate) fn payload_tree(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+    updated: &openat::Dir,
+) -> Result<FileTree> {
+    cached_tree(Path::new(CACHE_DIR), sysroot, component.name(), updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_tree() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let cachedir = td.path().join("cache");
+        let root = td.path().join("root");
+        let updates = root.join(BOOTUPD_UPDATES_DIR);
+        std::fs::create_dir_all(updates.join("EFI/fedora"))?;
+        std::fs::write(updates.join("EFI/fedora/shimx64.efi"), "shim")?;
+        let sysroot = openat::Dir::open(&root)?;
+        let updated = sysroot.sub_dir(&Path::new(BOOTUPD_UPDATES_DIR).join("EFI"))?;
+
+        // Without update metadata, nothing is cached
+        let tree = cached_tree(&cachedir, &sysroot, "EFI", &updated)?;
+        assert_eq!(tree, FileTree::new_from_dir(&updated)?);
+        assert!(!cachedir.exists());
+
+        std::fs::write(updates.join("EFI.json"), "{}")?;
+        let tree = cached_tree(&cachedir, &sysroot, "EFI", &updated)?;
+        let cached = std::fs::read_dir(&cachedir)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(cached.len(), 1);
+        // A stale entry is returned as long as the key is the same
+        std::fs::write(updates.join("EFI/fedora/shimx64.efi"), "shim2")?;
+        assert_eq!(cached_tree(&cachedir, &sysroot, "EFI", &updated)?, tree);
+
+        // Regenerating the metadata invalidates it
+        std::fs::write(updates.join("EFI.json.tmp"), "{ }")?;
+        std::fs::rename(updates.join("EFI.json.tmp"), updates.join("EFI.json"))?;
+        let tree = cached_tree(&cachedir, &sysroot, "EFI", &updated)?;
+        assert_eq!(tree, FileTree::new_from_dir(&updated)?);
+        assert_eq!(std::fs::read_dir(&cachedir)?.count(), 2);
+        Ok(())
+    }
+}
@@ -19,6 +19,31 @@ impl CommandRunExt for Command {
     }
 }
 
+/// The hex-encoded HMAC-SHA512 of `buf` keyed with `key`.
+pub(crate) fn hmac_sha512(key: &[u8], buf: &[u8]) -> Result<String> {
+    let key = openssl::pkey::PKey::hmac(key)?;
+    let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha512(), &key)?;
+    signer.update(buf)?;
+    Ok(hex::encode(signer.sign_to_vec()?))
+}
+
+/// Read an HMAC key from `f` (opened from `name`), which must be
+/// non-empty and only accessible by root.
+pub(crate) fn read_hmac_key(mut f: std::fs::File, name: &str) -> Result<Vec<u8>> {
+    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
+    let meta = f.metadata()?;
+    if meta.uid() != 0 || meta.mode() & 0o077 != 0 {
+        bail!("{name} must only be accessible by root");
+    }
+    let mut key = Vec::new();
+    f.read_to_end(&mut key)?;
+    if key.is_empty() {
+        bail!("{name} is empty");
+    }
+    Ok(key)
+}
+
 /// Parse an environment variable as UTF-8
 #[allow(dead_code)]
 pub(crate) fn getenv_utf8(n: &str) -> Result<Option<String>> {
@@ -64,18 +89,106 @@ pub(crate) fn filenames(dir: &openat::Dir) -> Result<HashSet<String>> {
     Ok(ret)
 }
 
+/// Whether the filesystem at `p` is mounted read-only.
+pub(crate) fn is_readonly_mount<P: AsRef<Path>>(p: P) -> Result<bool> {
+    let stat = rustix::fs::statvfs(p.as_ref())?;
+    Ok(stat.f_flag.contains(rustix::fs::StatVfsMountFlags::RDONLY))
+}
+
 pub(crate) fn ensure_writable_mount<P: AsRef<Path>>(p: P) -> Result<()> {
     let p = p.as_ref();
-    let stat = rustix::fs::statvfs(p)?;
-    if !stat.f_flag.contains(rustix::fs::StatVfsMountFlags::RDONLY) {
+    if !is_readonly_mount(p)? {
         return Ok(());
     }
-    let status = std::process::Command::new("mount")
-        .args(["-o", "remount,rw"])
-        .arg(p)
-        .status()?;
-    if !status.success() {
-        anyhow::bail!("Failed to remount {:?} writable", p);
+    crate::mount::remount_rw(p)?;
+    Ok(())
+}
+
+/// Waits for changes to the contents of a set of directories.
+pub(crate) struct DirWatcher {
+    fd: rustix::fd::OwnedFd,
+}
+
+impl DirWatcher {
+    /// Start watching `dirs`; those which don't exist are skipped, but
+    /// at least one must exist.
+    pub(crate) fn new(dirs: &[&Path]) -> Result<Self> {
+        use rustix::fs::inotify::{self, CreateFlags, WatchFlags};
+        let fd = inotify::inotify_init(CreateFlags::CLOEXEC).context("inotify_init")?;
+        let flags = WatchFlags::CREATE
+            | WatchFlags::DELETE
+            | WatchFlags::MODIFY
+            | WatchFlags::CLOSE_WRITE
+            | WatchFlags::MOVED_FROM
+            | WatchFlags::MOVED_TO
+            | WatchFlags::DELETE_SELF
+            | WatchFlags::MOVE_SELF;
+        let mut watched = false;
+        for d in dirs {
+            match inotify::inotify_add_watch(&fd, *d, flags) {
+                Ok(_) => watched = true,
+                Err(rustix::io::Errno::NOENT) => log::debug!("Not watching missing {d:?}"),
+                Err(e) => return Err(e).with_context(|| format!("Watching {d:?}")),
+            }
+        }
+        if !watched {
+            bail!("None of {dirs:?} exist");
+        }
+        Ok(Self { fd })
+    }
+
+    /// Block until something changes.
+    pub(crate) fn wait(&self) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        rustix::io::retry_on_intr(|| rustix::io::read(&self.fd, &mut buf))
+            .context("Reading inotify events")?;
+        Ok(())
+    }
+}
+
+/// Changes on every boot.
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// The ID of the current boot.
+pub(crate) fn boot_id() -> Result<String> {
+    let id =
+        std::fs::read_to_string(BOOT_ID_PATH).with_context(|| format!("Reading {BOOT_ID_PATH}"))?;
+    Ok(id.trim().to_string())
+}
+
+/// Give the I/O of this process the idle priority, so that e.g. reading
+/// all files on the ESPs doesn't slow down other processes.
+pub(crate) fn set_idle_io_priority() -> Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if r < 0 {
+        return Err(std::io::Error::last_os_error()).context("Setting I/O priority");
+    }
+    Ok(())
+}
+
+/// Drop the cached content of the files below `path` from the page cache,
+/// so that reading them again reads the underlying storage.
+pub(crate) fn drop_file_caches(path: &Path) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let f = std::fs::File::open(entry.path())
+            .with_context(|| format!("Opening {}", entry.path().display()))?;
+        // Only advisory; failures don't matter
+        unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
     }
     Ok(())
 }